@@ -0,0 +1,200 @@
+//-----------------------------------------------------------------------------
+// Tracked allocations.
+//
+// Tokens, error messages and group lists are allocated by the plugin and
+// owned by Db2 until it hands them back to FreeToken, FreeErrorMsg or
+// FreeGroupListMemory.  Taking back whatever pointer arrives would turn a
+// null, foreign or already freed pointer into undefined behaviour, so
+// every allocation is recorded here by address and kind, and carries a
+// magic tag.  A pointer is only used or freed if the registry knows it as
+// the right kind and its tag is intact, anything else is refused.
+//
+// A token is read by GetAuthIDs and GetGroupsForUser long after the
+// registry said it was live.  So a call marks it in use for as long as it
+// reads it, and FreeToken waits until nobody uses it any more before it
+// takes it out of the registry and frees it.
+//
+// Whatever is still outstanding when the server auth plugin terminates is
+// reported to db2diag as a leak.
+
+use std::collections::HashMap;
+use std::os::raw::{c_char, c_void};
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+// In front of every error message and group list, and in every token.
+pub const ALLOCATION_MAGIC : u64 = 0x5253_4543_5041_4c43;  // "RSECPALC"
+const TAG_SIZE : usize = std::mem::size_of::<u64>();
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AllocationKind {
+    Token,
+    ErrorMessage,
+    GroupList,
+}
+
+impl AllocationKind {
+    pub fn Name( &self ) -> &'static str {
+        match self {
+            AllocationKind::Token => "token",
+            AllocationKind::ErrorMessage => "error message",
+            AllocationKind::GroupList => "group list",
+        }
+    }
+}
+
+struct Allocation {
+    kind : AllocationKind,
+    // The API function that handed it to Db2.
+    caller : String,
+    // Tagged allocations: the start of the tag and the length with it.
+    base : usize,
+    len : usize,
+    // Calls reading the token right now.
+    users : usize,
+}
+
+static ALLOCATIONS : Mutex<Option<HashMap<usize, Allocation>>> = Mutex::new( None );
+// Signalled when a token goes out of use.
+static TOKEN_UNUSED : Condvar = Condvar::new();
+
+fn Allocations() -> MutexGuard<'static, Option<HashMap<usize, Allocation>>> {
+    match ALLOCATIONS.lock() {
+        Ok(a) => a,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn Insert( address : usize, allocation : Allocation ) {
+    Allocations().get_or_insert_with( HashMap::new ).insert( address, allocation );
+}
+
+// Take an allocation out of the registry, if it is there as this kind.
+fn Remove( address : usize, kind : AllocationKind ) -> Result<Allocation, String> {
+    RemoveLocked( &mut Allocations(), address, kind )
+}
+
+fn RemoveLocked( allocations : &mut Option<HashMap<usize, Allocation>>,
+                 address : usize,
+                 kind : AllocationKind ) -> Result<Allocation, String> {
+    if address == 0 {
+        return Err( format!("a null {} was passed", kind.Name()) );
+    }
+
+    let registry = allocations.get_or_insert_with( HashMap::new );
+    match registry.get( &address ) {
+        Some(a) if a.kind == kind => Ok( registry.remove( &address ).unwrap() ),
+        Some(a) => Err( format!("{:#x} is a {} from {}, not a {}", address, a.kind.Name(), a.caller, kind.Name()) ),
+        None => Err( format!("{:#x} is not a {} the plugin handed out, or it was freed already", address, kind.Name()) ),
+    }
+}
+
+//-----------------------------------------------------------------------------
+// Tokens are boxed Rust values whose first field is the magic tag.
+
+pub fn RegisterToken( ptr : * const c_void, caller : &str ) {
+    Insert( ptr as usize, Allocation { kind : AllocationKind::Token, caller : String::from( caller ),
+                                       base : ptr as usize, len : 0, users : 0 } );
+}
+
+// Mark a token in use, if it is a live one.  Every true must be followed
+// by EndTokenUse.
+pub fn BeginTokenUse( ptr : * const c_void ) -> bool {
+    match Allocations().as_mut().and_then( |r| r.get_mut( &(ptr as usize) ) ) {
+        Some(a) if a.kind == AllocationKind::Token => {
+            a.users += 1;
+            true
+        },
+        _ => false,
+    }
+}
+
+pub fn EndTokenUse( ptr : * const c_void ) {
+    if let Some(a) = Allocations().as_mut().and_then( |r| r.get_mut( &(ptr as usize) ) ) {
+        a.users = a.users.saturating_sub( 1 );
+    }
+    TOKEN_UNUSED.notify_all();
+}
+
+// Forget a token before it is freed, once no call uses it.  From then on
+// nobody else can use it.  The caller checks the tag.
+pub fn ReleaseToken( ptr : * const c_void ) -> Result<(), String> {
+    let mut allocations = Allocations();
+    while allocations.as_ref()
+                     .and_then( |r| r.get( &(ptr as usize) ) )
+                     .is_some_and( |a| a.kind == AllocationKind::Token && a.users > 0 ) {
+        allocations = match TOKEN_UNUSED.wait( allocations ) {
+            Ok(a) => a,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+    }
+
+    RemoveLocked( &mut allocations, ptr as usize, AllocationKind::Token ).map( |_| () )
+}
+
+//-----------------------------------------------------------------------------
+// Error messages and group lists are bytes, with the tag just before the
+// address Db2 gets.
+
+pub fn AllocateTagged( data : &[u8], kind : AllocationKind, caller : &str ) -> * mut u8 {
+    let mut bytes = Vec::with_capacity( TAG_SIZE + data.len() );
+    bytes.extend_from_slice( &ALLOCATION_MAGIC.to_ne_bytes() );
+    bytes.extend_from_slice( data );
+
+    let len = bytes.len();
+    let base = Box::into_raw( bytes.into_boxed_slice() ) as * mut u8;
+    let ptr = unsafe { base.add( TAG_SIZE ) };
+    Insert( ptr as usize, Allocation { kind, caller : String::from( caller ), base : base as usize, len, users : 0 } );
+    ptr
+}
+
+// A NUL terminated copy of msg, for an errormsg output parameter.
+pub fn AllocateErrorMessage( msg : &str, caller : &str ) -> * mut c_char {
+    let mut data = Vec::with_capacity( msg.len() + 1 );
+    data.extend_from_slice( msg.as_bytes() );
+    data.push( 0 );
+    AllocateTagged( &data, AllocationKind::ErrorMessage, caller ) as * mut c_char
+}
+
+// Stop tracking an allocation that will never be given back, without
+// freeing it.
+pub fn Abandon( ptr : * const c_void, kind : AllocationKind ) {
+    let _ = Remove( ptr as usize, kind );
+}
+
+pub fn FreeTagged( ptr : * mut c_void, kind : AllocationKind ) -> Result<(), String> {
+    let allocation = Remove( ptr as usize, kind )?;
+
+    let base = allocation.base as * mut u8;
+    let mut tag = [0u8; TAG_SIZE];
+    unsafe { std::ptr::copy_nonoverlapping( base, tag.as_mut_ptr(), TAG_SIZE ) };
+    if u64::from_ne_bytes( tag ) != ALLOCATION_MAGIC {
+        // Something wrote in front of the data, better leak it than free it.
+        return Err( format!("the tag of the {} at {:#x} from {} was overwritten",
+                            kind.Name(), ptr as usize, allocation.caller) );
+    }
+
+    let slice = std::ptr::slice_from_raw_parts_mut( base, allocation.len );
+    drop( unsafe { Box::from_raw( slice ) } );
+    Ok(())
+}
+
+//-----------------------------------------------------------------------------
+
+// What is outstanding, by kind and caller, e.g. "2 tokens from
+// ValidatePassword".  Empty if nothing leaked.
+pub fn OutstandingAllocations() -> Vec<String> {
+    let mut counts : Vec<(AllocationKind, String, usize)> = Vec::new();
+    if let Some(registry) = Allocations().as_ref() {
+        for a in registry.values() {
+            match counts.iter_mut().find( |(k, c, _)| *k == a.kind && *c == a.caller ) {
+                Some((_, _, n)) => *n += 1,
+                None => counts.push( (a.kind, a.caller.clone(), 1) ),
+            }
+        }
+    }
+    counts.sort_by( |a, b| (a.0.Name(), &a.1).cmp( &(b.0.Name(), &b.1) ) );
+
+    counts.into_iter()
+          .map( |(kind, caller, n)| format!("{} {}{} from {}", n, kind.Name(), if n == 1 { "" } else { "s" }, caller) )
+          .collect()
+}
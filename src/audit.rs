@@ -0,0 +1,449 @@
+//-----------------------------------------------------------------------------
+// Audit log.
+//
+// One JSON record is written for every authentication decision the plugin
+// makes, so who connected from where can be reconstructed without digging
+// through db2diag.log.  Records are appended to a single file which is
+// rotated by size and by age.  Rotated files get a UTC timestamp suffix,
+// e.g. audit.log.20231105T101500123Z, and only the newest audit_keep_files of
+// them are kept.
+//
+// Lines are hash chained and, if audit_key_file is set, sealed with signed
+// checkpoints.  See auditchain.rs.
+//
+// Auditing is off unless audit_file is set in the plugin configuration.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::auditchain::{CheckpointMac, GENESIS_HASH, HashLine, LoadOrCreateCheckpointKey,
+                        RecoverChainPosition, RotatedAuditFiles};
+use crate::config::PluginConfig;
+
+// The API call an audit record is for.  Alert records are not for a call
+// but for an attack found across calls, see detect.rs.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub enum AuditCall {
+    ValidatePassword,
+    DoesAuthIDExist,
+    GetGroupsForUser,
+    DoesGroupExist,
+    Alert,
+}
+
+// Collects the details of one call while it runs.  Fields that are not
+// known for a particular call are left as None and omitted from the output.
+pub struct AuditRecord {
+    call : AuditCall,
+    started : Instant,
+    correlationId : String,
+    pub userid : Option<String>,
+    pub authid : Option<String>,
+    pub database : Option<String>,
+    // The groups returned, or the group asked about.
+    pub groups : Option<Vec<String>>,
+    // The rule that decided the outcome, e.g. "password-mismatch".
+    pub rule : Option<&'static str>,
+    // What an alert found, in words.
+    pub detail : Option<String>,
+}
+
+impl AuditRecord {
+    pub fn New( call : AuditCall, correlationId : &str ) -> AuditRecord {
+        AuditRecord {
+            call,
+            started : Instant::now(),
+            correlationId : String::from( correlationId ),
+            userid : None,
+            authid : None,
+            database : None,
+            groups : None,
+            rule : None,
+            detail : None,
+        }
+    }
+
+    pub fn Call( &self ) -> AuditCall {
+        self.call
+    }
+
+    // Complete the record once the call has its result.  client holds the
+    // connection details from Db2, if there is a connection.
+    pub fn Finish( &self, client : &AuditClient, rc : i32, result : String ) -> AuditEvent {
+        let time = SystemTime::now();
+
+        AuditEvent {
+            time,
+            timestamp : FormatTimestamp( time ),
+            call : self.call,
+            correlationId : self.correlationId.clone(),
+            userid : self.userid.clone(),
+            authid : self.authid.clone(),
+            database : self.database.clone().or_else( || client.database.clone() ),
+            groups : self.groups.clone(),
+            clientIP : client.clientIP,
+            platform : client.platform,
+            result,
+            rc,
+            rule : self.rule.unwrap_or( "unexpected-error" ),
+            detail : self.detail.clone(),
+            latencyUs : self.started.elapsed().as_micros(),
+        }
+    }
+}
+
+// Connection details from Db2 that are added to the record when it is written.
+pub struct AuditClient {
+    pub clientIP : Option<IpAddr>,
+    pub platform : Option<u32>,
+    pub database : Option<String>,
+}
+
+// A finished audit record, as handed to the audit log and the SIEM feed.
+// Field order here is the order in the JSON output.
+#[derive(Serialize)]
+pub struct AuditEvent {
+    #[serde(skip)]
+    pub time : SystemTime,
+    pub timestamp : String,
+    pub call : AuditCall,
+    // See correlation.rs.
+    #[serde(rename = "correlation_id")]
+    pub correlationId : String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub userid : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authid : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groups : Option<Vec<String>>,
+    #[serde(rename = "client_ip", skip_serializing_if = "Option::is_none")]
+    pub clientIP : Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform : Option<u32>,
+    // The Db2rc name, e.g. DB2SEC_PLUGIN_BADPWD.
+    pub result : String,
+    pub rc : i32,
+    pub rule : &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail : Option<String>,
+    #[serde(rename = "latency_us")]
+    pub latencyUs : u128,
+}
+
+#[derive(Serialize)]
+struct AuditLine<'a> {
+    seq : u64,
+    prev_hash : &'a str,
+    #[serde(flatten)]
+    event : &'a AuditEvent,
+}
+
+// A signed checkpoint in the hash chain, see auditchain.rs.
+// Added to the chain when the plugin starts after a torn write, see
+// RecoverChainPosition.
+#[derive(Serialize)]
+struct TornTailLine<'a> {
+    seq : u64,
+    prev_hash : &'a str,
+    timestamp : String,
+    torn_tail : TornTailDetails,
+}
+
+#[derive(Serialize)]
+struct TornTailDetails {
+    file : String,
+    bytes : u64,
+}
+
+#[derive(Serialize)]
+struct CheckpointLine<'a> {
+    seq : u64,
+    prev_hash : &'a str,
+    timestamp : String,
+    checkpoint : bool,
+    mac : String,
+}
+
+// One AuditLog is shared by every plugin state that uses the same
+// settings, so a reload does not start a second writer on the same chain.
+pub struct AuditLog {
+    path : PathBuf,
+    maxBytes : u64,
+    rotateAfter : Duration,
+    keepFiles : usize,
+    keyFile : Option<PathBuf>,
+    checkpointKey : Option<Vec<u8>>,
+    checkpointEvery : u64,
+    writer : Mutex<AuditWriter>,
+    // For db2diag, what was torn when the log was opened.
+    recoveryWarning : Option<String>,
+}
+
+struct AuditWriter {
+    file : Option<AuditFile>,
+    // Position in the hash chain.
+    seq : u64,
+    lastHash : String,
+    sinceCheckpoint : u64,
+}
+
+struct AuditFile {
+    file : File,
+    size : u64,
+    opened : SystemTime,
+}
+
+impl AuditLog {
+    // Returns None when auditing is not configured.
+    pub fn Open( config : &PluginConfig ) -> Result<Option<AuditLog>, String> {
+        let path = match &config.auditFile {
+            None => return Ok( None ),
+            Some(p) => p.clone(),
+        };
+
+        let checkpointKey = match &config.auditKeyFile {
+            None => None,
+            Some(k) => Some( LoadOrCreateCheckpointKey( k )? ),
+        };
+
+        // Pick up the chain where the previous writer left it.
+        let recovered = RecoverChainPosition( &path )?;
+        let (seq, lastHash) = match recovered.position {
+            Some(pos) => (pos.seq, pos.hash),
+            None => (0, String::from( GENESIS_HASH )),
+        };
+        let recoveryWarning = recovered.torn.as_ref().map( |t| {
+            format!("RUSTSECP the audit log {} ends in {} bytes of a torn record, a write was cut short. \
+                     The chain carries on from seq {}, the torn record is noted in it.",
+                    t.file.display(), t.bytes, seq)
+        });
+
+        let log = AuditLog {
+            path,
+            maxBytes : config.auditMaxBytes,
+            rotateAfter : Duration::from_secs( config.auditRotateSeconds ),
+            keepFiles : config.auditKeepFiles,
+            keyFile : config.auditKeyFile.clone(),
+            checkpointKey,
+            checkpointEvery : config.auditCheckpointRecords.max( 1 ),
+            writer : Mutex::new( AuditWriter { file : None, seq, lastHash, sinceCheckpoint : 0 } ),
+            recoveryWarning,
+        };
+
+        // Open the file now so a bad path is reported at init/reload time
+        // rather than on the first connect.
+        let mut file = log.OpenFile()?;
+
+        // A torn write leaves no newline, the next record must not be
+        // glued to it.
+        if file.size > 0 && ! EndsWithNewline( &log.path )? {
+            file.file.write_all( b"\n" ).map_err( |e| format!("Cannot write {}: {}", log.path.display(), e) )?;
+            file.size += 1;
+        }
+
+        let mut writer = log.writer.lock().unwrap();
+        writer.file = Some( file );
+        if let Some(torn) = recovered.torn {
+            let seq = writer.seq + 1;
+            let line = TornTailLine { seq,
+                                      prev_hash : &writer.lastHash,
+                                      timestamp : FormatTimestamp( SystemTime::now() ),
+                                      torn_tail : TornTailDetails { file : torn.file.display().to_string(),
+                                                                    bytes : torn.bytes } };
+            let text = serde_json::to_string( &line ).map_err( |e| e.to_string() )?;
+            log.AppendLine( &mut writer, seq, text )?;
+        }
+        drop( writer );
+
+        Ok( Some( log ) )
+    }
+
+    // What was wrong with the log when it was opened, for db2diag.
+    pub fn RecoveryWarning( &self ) -> Option<&str> {
+        self.recoveryWarning.as_deref()
+    }
+
+    // True if this log was opened with the same audit settings.
+    pub fn SameSettings( &self, config : &PluginConfig ) -> bool {
+        config.auditFile.as_ref() == Some( &self.path ) &&
+        config.auditMaxBytes == self.maxBytes &&
+        config.auditRotateSeconds == self.rotateAfter.as_secs() &&
+        config.auditKeepFiles == self.keepFiles &&
+        config.auditKeyFile == self.keyFile &&
+        config.auditCheckpointRecords.max( 1 ) == self.checkpointEvery
+    }
+
+    pub fn Write( &self, event : &AuditEvent ) -> Result<(), String> {
+        let now = event.time;
+        let mut writer = self.writer.lock().unwrap_or_else( |e| e.into_inner() );
+
+        let rotate = match &writer.file {
+            None => true,
+            Some(f) => f.size >= self.maxBytes ||
+                       now.duration_since( f.opened ).unwrap_or_default() >= self.rotateAfter,
+        };
+
+        if rotate {
+            // Seal the file being rotated out, then close it before renaming it.
+            if writer.file.is_some() {
+                self.WriteCheckpoint( &mut writer, now )?;
+            }
+            writer.file = None;
+            self.RotateFile( now )?;
+            writer.file = Some( self.OpenFile()? );
+        }
+
+        let seq = writer.seq + 1;
+        let line = AuditLine { seq, prev_hash : &writer.lastHash, event };
+
+        let text = serde_json::to_string( &line ).map_err( |e| e.to_string() )?;
+        self.AppendLine( &mut writer, seq, text )?;
+
+        writer.sinceCheckpoint += 1;
+        if writer.sinceCheckpoint >= self.checkpointEvery {
+            self.WriteCheckpoint( &mut writer, now )?;
+        }
+
+        Ok(())
+    }
+
+    // Add a line to the chain.  A single write per line, so lines from
+    // concurrent agents never interleave.
+    fn AppendLine( &self, writer : &mut AuditWriter, seq : u64, text : String ) -> Result<(), String> {
+        let hash = HashLine( &text );
+        let mut bytes = text.into_bytes();
+        bytes.push( b'\n' );
+
+        let current = writer.file.as_mut().ok_or( "The audit file is not open" )?;
+        current.file.write_all( &bytes )
+                    .map_err( |e| format!("Cannot write {}: {}", self.path.display(), e) )?;
+        current.size += bytes.len() as u64;
+
+        writer.seq = seq;
+        writer.lastHash = hash;
+        Ok(())
+    }
+
+    fn WriteCheckpoint( &self, writer : &mut AuditWriter, now : SystemTime ) -> Result<(), String> {
+        let key = match &self.checkpointKey {
+            Some(k) => k,
+            None => return Ok(()),
+        };
+        if writer.sinceCheckpoint == 0 {
+            return Ok(());
+        }
+
+        let seq = writer.seq + 1;
+        let timestamp = FormatTimestamp( now );
+        let mac = CheckpointMac( key, seq, &timestamp, &writer.lastHash );
+        let line = CheckpointLine { seq, prev_hash : &writer.lastHash, timestamp, checkpoint : true, mac };
+
+        let text = serde_json::to_string( &line ).map_err( |e| e.to_string() )?;
+        self.AppendLine( writer, seq, text )?;
+        writer.sinceCheckpoint = 0;
+        Ok(())
+    }
+
+    fn OpenFile( &self ) -> Result<AuditFile, String> {
+        let file = OpenOptions::new().create( true ).append( true ).open( &self.path )
+                       .map_err( |e| format!("Cannot open {}: {}", self.path.display(), e) )?;
+
+        let meta = file.metadata()
+                       .map_err( |e| format!("Cannot stat {}: {}", self.path.display(), e) )?;
+
+        // An existing file counts as opened when it was created, so the age
+        // limit still applies across db2stop/db2start and reloads.
+        let opened = meta.created().unwrap_or_else( |_| SystemTime::now() );
+
+        Ok( AuditFile { file, size : meta.len(), opened } )
+    }
+
+    fn RotateFile( &self, now : SystemTime ) -> Result<(), String> {
+        match std::fs::metadata( &self.path ) {
+            Ok(m) if m.len() > 0 => {},
+            // Nothing to rotate.
+            _ => return Ok(()),
+        }
+
+        let stamp : String = FormatTimestamp( now ).chars()
+                                                   .filter( |c| c.is_ascii_digit() || *c == 'T' )
+                                                   .take( 18 )
+                                                   .collect();
+        let mut rotated = AppendToFileName( &self.path, &format!(".{}Z", stamp) );
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = AppendToFileName( &self.path, &format!(".{}Z-{}", stamp, n) );
+            n += 1;
+        }
+
+        std::fs::rename( &self.path, &rotated )
+            .map_err( |e| format!("Cannot rotate {}: {}", self.path.display(), e) )?;
+
+        // Remove the oldest rotated files beyond keepFiles.
+        // Failures here are not worth failing a write for.
+        let mut old = RotatedAuditFiles( &self.path );
+        while old.len() > self.keepFiles {
+            let _ = std::fs::remove_file( old.remove(0) );
+        }
+
+        Ok(())
+    }
+}
+
+// Seal whatever was written since the last checkpoint when the last plugin
+// state using this log goes away, e.g. at db2stop.
+impl Drop for AuditLog {
+    fn drop( &mut self ) {
+        let mut writer = self.writer.lock().unwrap_or_else( |e| e.into_inner() );
+        if writer.file.is_some() {
+            let _ = self.WriteCheckpoint( &mut writer, SystemTime::now() );
+        }
+    }
+}
+
+fn EndsWithNewline( path : &Path ) -> Result<bool, String> {
+    let mut file = File::open( path ).map_err( |e| format!("Cannot open {}: {}", path.display(), e) )?;
+    let mut last = [0u8; 1];
+    file.seek( SeekFrom::End( -1 ) )
+        .and_then( |_| file.read_exact( &mut last ) )
+        .map_err( |e| format!("Cannot read {}: {}", path.display(), e) )?;
+    Ok( last[0] == b'\n' )
+}
+
+fn AppendToFileName( path : &Path, suffix : &str ) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push( suffix );
+    PathBuf::from( name )
+}
+
+// RFC 3339 UTC timestamp with milliseconds, e.g. 2023-11-05T10:15:00.123Z
+pub fn FormatTimestamp( t : SystemTime ) -> String {
+    let since = t.duration_since( UNIX_EPOCH ).unwrap_or_default();
+    let secs = since.as_secs();
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z = days + 719468;
+    let era = z.div_euclid( 146097 );
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day,
+            rem / 3600, (rem / 60) % 60, rem % 60,
+            since.subsec_millis())
+}
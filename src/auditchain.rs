@@ -0,0 +1,340 @@
+//-----------------------------------------------------------------------------
+// Hash chain for the audit log.
+//
+// Every audit line carries a sequence number and the SHA-256 of the line
+// before it (prev_hash), so deleting, reordering or editing a line breaks
+// the chain at the line that follows.  The first line ever written points
+// at GENESIS_HASH.  The chain continues across rotated files.
+//
+// Every so often a checkpoint line is added to the chain.  It carries an
+// HMAC-SHA256 over its own position in the chain, made with a key that
+// only the Db2 instance owner can read.  Without the key nobody can write a
+// valid checkpoint, so the chain cannot be silently rebuilt after an edit.
+// Lines after the last checkpoint are only protected by the chain itself.
+//
+// A crash or a full disk can leave half a line at the end of the log.  The
+// plugin carries on from the last whole record and adds a torn_tail record
+// to the chain, so the gap is on record rather than a reason not to start.
+//
+// This module is shared with the rustsecp-audit-verify tool.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+pub const GENESIS_HASH : &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+pub fn ToHex( bytes : &[u8] ) -> String {
+    bytes.iter().map( |b| format!("{:02x}", b) ).collect()
+}
+
+pub fn FromHex( text : &str ) -> Option<Vec<u8>> {
+    if ! text.len().is_multiple_of( 2 ) {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+                   .map( |i| u8::from_str_radix( text.get( i..i + 2 )?, 16 ).ok() )
+                   .collect()
+}
+
+// The hash that the next line's prev_hash must equal.
+// line is the text as written, without the trailing newline.
+pub fn HashLine( line : &str ) -> String {
+    ToHex( &Sha256::digest( line.as_bytes() ) )
+}
+
+pub fn CheckpointMac( key : &[u8], seq : u64, timestamp : &str, prevHash : &str ) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice( key ).expect( "HMAC accepts any key length" );
+    mac.update( format!("rustsecp-checkpoint:{}:{}:{}", seq, timestamp, prevHash).as_bytes() );
+    ToHex( &mac.finalize().into_bytes() )
+}
+
+// The key file holds the checkpoint key as hex on a single line.
+pub fn LoadCheckpointKey( path : &Path ) -> Result<Vec<u8>, String> {
+    let text = std::fs::read_to_string( path )
+                   .map_err( |e| format!("Cannot read {}: {}", path.display(), e) )?;
+
+    match FromHex( text.trim() ) {
+        Some(k) if k.len() >= 16 => Ok( k ),
+        _ => Err( format!("{} does not contain a hex key of at least 16 bytes", path.display()) ),
+    }
+}
+
+// Load the key, creating a new random one readable only by the owner if
+// the file does not exist yet.
+pub fn LoadOrCreateCheckpointKey( path : &Path ) -> Result<Vec<u8>, String> {
+    if path.exists() {
+        return LoadCheckpointKey( path );
+    }
+
+    let mut key = vec![0u8; 32];
+    getrandom::getrandom( &mut key ).map_err( |e| format!("Cannot generate a key: {}", e) )?;
+
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new().write( true ).create_new( true ).mode( 0o600 )
+                       .open( path )
+                       .map_err( |e| format!("Cannot create {}: {}", path.display(), e) )?;
+    writeln!( file, "{}", ToHex( &key ) )
+        .map_err( |e| format!("Cannot write {}: {}", path.display(), e) )?;
+
+    Ok( key )
+}
+
+// Position in the chain after a given line.
+pub struct ChainPosition {
+    pub seq : u64,
+    pub hash : String,
+}
+
+pub fn ChainPositionOfLine( line : &str ) -> Option<ChainPosition> {
+    let value : serde_json::Value = serde_json::from_str( line ).ok()?;
+    Some( ChainPosition { seq : value.get( "seq" )?.as_u64()?, hash : HashLine( line ) } )
+}
+
+// What a crash or a full disk in the middle of a write leaves behind: the
+// start of a record at the end of a file, with no record after it.
+pub struct TornTail {
+    pub file : PathBuf,
+    pub bytes : u64,
+}
+
+// Where the chain left off, and anything torn after its last record.
+pub struct RecoveredChain {
+    pub position : Option<ChainPosition>,
+    pub torn : Option<TornTail>,
+}
+
+// Find where the chain left off: the last complete record of the audit
+// file, or of the newest rotated file if the current one has none.  A torn
+// tail after it is skipped and reported, the plugin must still start.
+pub fn RecoverChainPosition( path : &Path ) -> Result<RecoveredChain, String> {
+    let mut candidates = vec![ path.to_path_buf() ];
+    candidates.extend( RotatedAuditFiles( path ).into_iter().rev() );
+
+    let mut torn : Option<TornTail> = None;
+    for candidate in candidates {
+        let tail = match ReadTail( &candidate )? {
+            Some(t) => t,
+            None => continue,
+        };
+
+        // Offset just past the last complete record, and where it leaves
+        // the chain.
+        let mut last : Option<(usize, ChainPosition)> = None;
+        let mut offset = 0;
+        for line in tail.text.split_inclusive( '\n' ) {
+            // The first line of a partial tail may be cut off at its start.
+            let partial = offset == 0 && ! tail.whole;
+            offset += line.len();
+            if partial {
+                continue;
+            }
+            if let Some(pos) = ChainPositionOfLine( line.trim_end() ) {
+                last = Some( (offset, pos) );
+            }
+        }
+
+        let end = last.as_ref().map_or( 0, |(end, _)| *end );
+        let rest = &tail.text[end..];
+        if torn.is_none() && ! rest.trim().is_empty() {
+            torn = Some( TornTail { file : candidate.clone(), bytes : rest.trim_end().len() as u64 } );
+        }
+        if let Some((_, pos)) = last {
+            return Ok( RecoveredChain { position : Some( pos ), torn } );
+        }
+    }
+
+    Ok( RecoveredChain { position : None, torn } )
+}
+
+// Rotated files of an audit log, oldest first.  Only names written by
+// AuditLog::RotateFile count, <name>.<stamp>Z or <name>.<stamp>Z-<n> with
+// the 18 character stamp of FormatTimestamp, so e.g. the audit_key_file or
+// a .bak next to the log is never taken for one and pruned.
+pub fn RotatedAuditFiles( path : &Path ) -> Vec<PathBuf> {
+    let dir = match path.parent() {
+        Some(d) if ! d.as_os_str().is_empty() => d.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let prefix = match path.file_name() {
+        Some(n) => format!("{}.", n.to_string_lossy()),
+        None => return Vec::new(),
+    };
+
+    let mut rotated : Vec<((String, u64), PathBuf)> = match std::fs::read_dir( &dir ) {
+        Ok(entries) => entries.filter_map( |e| e.ok() )
+                              .filter_map( |e| {
+                                  let name = e.file_name().to_string_lossy().into_owned();
+                                  let order = RotationOrder( name.strip_prefix( &prefix )? )?;
+                                  Some( (order, e.path()) )
+                              })
+                              .collect(),
+        Err(_) => Vec::new(),
+    };
+
+    // By stamp, then by the number added when a stamp was taken already,
+    // compared as a number so -10 comes after -9.
+    rotated.sort();
+    rotated.into_iter().map( |(_, p)| p ).collect()
+}
+
+// The stamp and number of a rotated file name after "<name>.", None if it
+// is not one.
+fn RotationOrder( suffix : &str ) -> Option<(String, u64)> {
+    let (stamp, rest) = suffix.split_once( 'Z' )?;
+    let bytes = stamp.as_bytes();
+    let validStamp = bytes.len() == 18 && bytes[8] == b'T' &&
+                     bytes.iter().enumerate().all( |(i, b)| i == 8 || b.is_ascii_digit() );
+    if ! validStamp {
+        return None;
+    }
+
+    let n = match rest {
+        "" => 0,
+        _ => {
+            let digits = rest.strip_prefix( '-' )?;
+            if digits.is_empty() || ! digits.bytes().all( |b| b.is_ascii_digit() ) {
+                return None;
+            }
+            digits.parse().ok()?
+        }
+    };
+    Some( (String::from( stamp ), n) )
+}
+
+struct Tail {
+    text : String,
+    // The whole file, not just its end.
+    whole : bool,
+}
+
+fn ReadTail( path : &Path ) -> Result<Option<Tail>, String> {
+    let mut file = match std::fs::File::open( path ) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok( None ),
+        Err(e) => return Err( format!("Cannot open {}: {}", path.display(), e) ),
+    };
+
+    // Audit lines are short, the tail of the file is enough.  A torn write
+    // may end in the middle of a character, so no UTF-8 is required.
+    let len = file.metadata().map( |m| m.len() ).unwrap_or( 0 );
+    if len == 0 {
+        return Ok( None );
+    }
+    let start = len.saturating_sub( 64 * 1024 );
+    let mut bytes = Vec::new();
+    file.seek( SeekFrom::Start( start ) )
+        .and_then( |_| file.read_to_end( &mut bytes ) )
+        .map_err( |e| format!("Cannot read {}: {}", path.display(), e) )?;
+
+    Ok( Some( Tail { text : String::from_utf8_lossy( &bytes ).into_owned(), whole : start == 0 } ) )
+}
+
+//-----------------------------------------------------------------------------
+// Verification
+
+pub struct VerifyReport {
+    pub records : u64,
+    pub checkpoints : u64,
+    pub firstSeq : Option<u64>,
+    pub lastSeq : Option<u64>,
+    // Sequence number of the last checkpoint with a valid MAC.
+    pub lastSealedSeq : Option<u64>,
+    pub problems : Vec<String>,
+}
+
+// Walk the chain through files, which must be given oldest first.
+// With a key, checkpoint MACs are verified as well.
+pub fn VerifyAuditFiles( files : &[PathBuf], key : Option<&[u8]> ) -> VerifyReport {
+    let mut report = VerifyReport { records : 0, checkpoints : 0, firstSeq : None, lastSeq : None,
+                                    lastSealedSeq : None, problems : Vec::new() };
+    let mut expected : Option<ChainPosition> = None;
+
+    for file in files {
+        let text = match std::fs::read_to_string( file ) {
+            Ok(t) => t,
+            Err(e) => {
+                report.problems.push( format!("{}: cannot read: {}", file.display(), e) );
+                continue;
+            }
+        };
+
+        for (lineno, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let at = format!("{}:{}", file.display(), lineno + 1);
+
+            let value : serde_json::Value = match serde_json::from_str( line ) {
+                Ok(v) => v,
+                Err(_) => {
+                    report.problems.push( format!("{}: not a JSON record (edited?)", at) );
+                    continue;
+                }
+            };
+            let seq = value.get( "seq" ).and_then( |v| v.as_u64() );
+            let prevHash = value.get( "prev_hash" ).and_then( |v| v.as_str() );
+            let (seq, prevHash) = match (seq, prevHash) {
+                (Some(s), Some(p)) => (s, p),
+                _ => {
+                    report.problems.push( format!("{}: seq or prev_hash is missing", at) );
+                    continue;
+                }
+            };
+
+            match &expected {
+                None => {
+                    if seq != 1 {
+                        report.problems.push( format!("{}: the chain starts at seq {}, the records before it \
+                                                       are missing (earlier files deleted or pruned)", at, seq) );
+                    } else if prevHash != GENESIS_HASH {
+                        report.problems.push( format!("{}: first record does not start the chain", at) );
+                    }
+                },
+                Some(exp) => {
+                    if seq != exp.seq + 1 {
+                        report.problems.push( format!("{}: seq {} follows {}, records were deleted or reordered",
+                                                      at, seq, exp.seq) );
+                    }
+                    if prevHash != exp.hash {
+                        report.problems.push( format!("{}: prev_hash does not match the record before, \
+                                                       which was edited, deleted or reordered", at) );
+                    }
+                }
+            }
+
+            if let Some(torn) = value.get( "torn_tail" ) {
+                report.problems.push( format!("{}: the plugin found {} bytes of a torn record at the end of {} \
+                                               when it started, a write was cut short by a crash or a full disk",
+                                              at, torn.get( "bytes" ).and_then( |v| v.as_u64() ).unwrap_or( 0 ),
+                                              torn.get( "file" ).and_then( |v| v.as_str() ).unwrap_or( "?" )) );
+            } else if value.get( "checkpoint" ).is_some() {
+                report.checkpoints += 1;
+                if let Some(k) = key {
+                    let timestamp = value.get( "timestamp" ).and_then( |v| v.as_str() ).unwrap_or( "" );
+                    let mac = value.get( "mac" ).and_then( |v| v.as_str() ).unwrap_or( "" );
+                    if mac == CheckpointMac( k, seq, timestamp, prevHash ) {
+                        report.lastSealedSeq = Some( seq );
+                    } else {
+                        report.problems.push( format!("{}: checkpoint MAC is not valid", at) );
+                    }
+                }
+            } else {
+                report.records += 1;
+            }
+
+            report.firstSeq.get_or_insert( seq );
+            report.lastSeq = Some( seq );
+
+            // Carry on from this line so one problem is reported once,
+            // not for every line after it.
+            expected = Some( ChainPosition { seq, hash : HashLine( line ) } );
+        }
+    }
+
+    report
+}
@@ -0,0 +1,45 @@
+//-----------------------------------------------------------------------------
+// Terminal input for the command line tools in src/bin, each of which
+// includes this with `mod cli;`.  It is not part of the library, so the
+// plugin Db2 loads has no code that prompts on or changes the terminal.
+
+use zeroize::Zeroizing;
+
+// A password read from standard input: one line, not echoed when it comes
+// from a terminal.
+pub fn ReadPassword( prompt : &str ) -> Result<Zeroizing<String>, String> {
+    use std::io::{IsTerminal, Write};
+
+    let stdin = std::io::stdin();
+    if ! stdin.is_terminal() {
+        return ReadPasswordLine( &stdin );
+    }
+
+    eprint!("{}", prompt);
+    let _ = std::io::stderr().flush();
+
+    let fd = libc::STDIN_FILENO;
+    let mut saved : libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr( fd, &mut saved ) } != 0 {
+        return Err( String::from("Cannot read the terminal settings") );
+    }
+    let mut noEcho = saved;
+    noEcho.c_lflag &= ! libc::ECHO;
+    unsafe { libc::tcsetattr( fd, libc::TCSANOW, &noEcho ) };
+
+    let line = ReadPasswordLine( &stdin );
+
+    unsafe { libc::tcsetattr( fd, libc::TCSANOW, &saved ) };
+    eprintln!();
+    line
+}
+
+fn ReadPasswordLine( stdin : &std::io::Stdin ) -> Result<Zeroizing<String>, String> {
+    use std::io::BufRead;
+
+    let mut line = Zeroizing::new( String::new() );
+    stdin.lock().read_line( &mut line ).map_err( |e| e.to_string() )?;
+    let trimmed = line.trim_end_matches( ['\r', '\n'] ).len();
+    line.truncate( trimmed );
+    Ok( line )
+}
@@ -0,0 +1,359 @@
+// Maintain the rustsecp user store.
+//
+//    rustsecp-admin [--store FILE] COMMAND [ARGS...]
+//
+// Without --store the user_store of the plugin configuration is used.
+// Every change is written atomically and the previous file is kept as
+// FILE.bak.  The plugin picks changes up on the next reload, e.g. with
+// rustsecp-ctl reload.
+//
+// Passwords are read from the terminal without echo, or with
+// --password-stdin as a single line from standard input.  They are hashed
+// with Argon2id, --argon2 m=KIB,t=ITERATIONS,p=LANES sets the cost, see
+// rustsecp-loadtest to size it.
+//
+// Decoys and canary passwords raise an alert when tried, see userstore.rs.
+// totp-enroll and hotp-enroll print the new secret and an otpauth:// URI
+// for authenticator apps, recovery-issue prints the codes.  None of them is
+// shown again.  Used HOTP counters and recovery codes are read from the
+// .mfa file the plugin keeps next to the store, see mfa.rs.
+
+#![allow(non_snake_case)]
+
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use zeroize::Zeroizing;
+
+use db2rustsecp::ConfiguredUserStore;
+use db2rustsecp::mfa::{MfaState, NewRecoveryCode, RecoveryDigest, ResyncHotp, StatePath, RECOVERY_CODES, RESYNC_WINDOW};
+use db2rustsecp::totp;
+use db2rustsecp::userstore::{HashSettings, UserEntry, UserStore, ValidDb2Name, ValidExpiryDate};
+
+mod cli;
+use cli::ReadPassword;
+
+const USAGE : &str = "\
+usage: rustsecp-admin [--store FILE] [--password-stdin] [--argon2 m=KIB,t=N,p=N] COMMAND [ARGS...]
+
+commands:
+  list                          list all users
+  show USER                     show one user
+  add USER [--no-password]      add a user, asks for the password
+  remove USER                   remove a user
+  rename USER NEWUSER           change a userid, keeping everything else
+  passwd USER                   set the password
+  lock USER                     stop the user from connecting
+  unlock USER                   allow the user to connect again
+  expire USER YYYY-MM-DD|never  last day the user may connect
+  group-add USER GROUP          add the user to a group
+  group-remove USER GROUP       remove the user from a group
+  map USER AUTHID               connect the user as a different authid
+  unmap USER                    connect the user as the userid in upper case
+  decoy USER                    make the user a honeypot that never connects
+  undecoy USER                  make the user an ordinary user again
+  canary-add                    add a canary password, asks for it
+  canary-remove                 remove a canary password, asks for it
+  totp-enroll USER              give the user a new TOTP secret, shown once
+  totp-remove USER              remove the user's TOTP secret
+  hotp-enroll USER              give the user a new HOTP secret, shown once
+  hotp-resync USER CODE CODE    catch up with a HOTP token, from two codes in a row
+  hotp-remove USER              remove the user's HOTP secret
+  recovery-issue USER           replace the user's recovery codes, shown once
+  recovery-revoke USER          remove the user's recovery codes";
+
+fn Usage() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::from( 2 )
+}
+
+struct Options {
+    store : Option<PathBuf>,
+    passwordStdin : bool,
+    noPassword : bool,
+    hashSettings : HashSettings,
+}
+
+fn main() -> ExitCode {
+    let mut options = Options { store : None, passwordStdin : false, noPassword : false,
+                                hashSettings : HashSettings::default() };
+    let mut words : Vec<String> = Vec::new();
+
+    let mut args = std::env::args().skip( 1 );
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--store" => match args.next() {
+                Some(s) => options.store = Some( PathBuf::from( s ) ),
+                None => return Usage(),
+            },
+            "--password-stdin" => options.passwordStdin = true,
+            "--argon2" => match args.next().map( |a| HashSettings::Parse( &a ) ) {
+                Some(Ok(h)) => options.hashSettings = h,
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return ExitCode::from( 2 );
+                },
+                None => return Usage(),
+            },
+            "--no-password" => options.noPassword = true,
+            "-h" | "--help" => return Usage(),
+            _ => words.push( arg ),
+        }
+    }
+
+    if words.is_empty() {
+        return Usage();
+    }
+
+    let store = match options.store.clone().map_or_else( ConfiguredUserStore, Ok ) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from( 2 );
+        }
+    };
+
+    let words : Vec<&str> = words.iter().map( String::as_str ).collect();
+    match Run( &store, &words, &options ) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) if e.is_empty() => Usage(),
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from( 1 )
+        }
+    }
+}
+
+// An empty error means the command line was wrong.
+fn Run( path : &Path, words : &[&str], options : &Options ) -> Result<(), String> {
+    // Only add may create the store.
+    let mut store = match words.first() {
+        Some(&"add") if ! path.exists() => UserStore::New(),
+        _ => UserStore::Load( path )?,
+    };
+
+    match words {
+        ["list"] => {
+            println!("{:<20} {:<20} {:<8} {:<10} GROUPS", "USERID", "AUTHID", "STATUS", "EXPIRES");
+            for u in store.Users() {
+                println!("{:<20} {:<20} {:<8} {:<10} {}",
+                         u.userid, u.Authid(), Status( u ),
+                         u.expires.as_deref().unwrap_or( "never" ), u.groups.join( "," ));
+            }
+            if store.CanaryCount() > 0 {
+                println!("{} canary password(s)", store.CanaryCount());
+            }
+            return Ok(());
+        },
+        ["show", user] => {
+            let u = store.Get( user ).ok_or_else( || format!("no user {}", user) )?;
+            println!("userid   {}", u.userid);
+            println!("authid   {}{}", u.Authid(), if u.authid.is_some() { " (mapped)" } else { "" });
+            println!("status   {}", Status( u ));
+            println!("expires  {}", u.expires.as_deref().unwrap_or( "never" ));
+            println!("groups   {}", u.groups.join( "," ));
+            println!("totp     {}", if u.totp.is_some() { "enrolled" } else { "none" });
+            let state = MfaState::Load( &StatePath( path ) )?;
+            match u.hotp {
+                Some(_) => println!("hotp     enrolled, next counter {}", state.NextHotp( u )),
+                None => println!("hotp     none"),
+            }
+            println!("recovery {} of {} codes left", state.RecoveryCodesLeft( u ), u.recovery.len());
+            return Ok(());
+        },
+        ["add", user] => {
+            CheckUserid( user )?;
+            let password = if options.noPassword { None } else { Some( ReadNewPassword( user, options )? ) };
+            let entry = store.Add( user )?;
+            if let Some(p) = password {
+                entry.SetPasswordWith( &p, &options.hashSettings )?;
+            }
+        },
+        ["remove", user] => {
+            store.Remove( user )?;
+        },
+        ["rename", user, newUser] => {
+            CheckUserid( newUser )?;
+            store.Rename( user, newUser )?;
+        },
+        ["passwd", user] => {
+            store.Get( user ).ok_or_else( || format!("no user {}", user) )?;
+            let password = ReadNewPassword( user, options )?;
+            UserMut( &mut store, user )?.SetPasswordWith( &password, &options.hashSettings )?;
+        },
+        ["lock", user] => UserMut( &mut store, user )?.locked = true,
+        ["unlock", user] => UserMut( &mut store, user )?.locked = false,
+        ["expire", user, "never"] => UserMut( &mut store, user )?.expires = None,
+        ["expire", user, date] => {
+            if ! ValidExpiryDate( date ) {
+                return Err( format!("{} is not a date as YYYY-MM-DD", date) );
+            }
+            UserMut( &mut store, user )?.expires = Some( String::from( *date ) );
+        },
+        ["group-add", user, group] => {
+            let group = CheckDb2Name( group )?;
+            let entry = UserMut( &mut store, user )?;
+            if entry.groups.contains( &group ) {
+                return Err( format!("{} is already in {}", user, group) );
+            }
+            entry.groups.push( group );
+            entry.groups.sort();
+        },
+        ["group-remove", user, group] => {
+            let group = group.to_uppercase();
+            let entry = UserMut( &mut store, user )?;
+            let before = entry.groups.len();
+            entry.groups.retain( |g| *g != group );
+            if entry.groups.len() == before {
+                return Err( format!("{} is not in {}", user, group) );
+            }
+        },
+        ["map", user, authid] => {
+            let authid = CheckDb2Name( authid )?;
+            if let Some(other) = store.ByAuthid( &authid ).filter( |o| ! o.userid.eq_ignore_ascii_case( user ) ) {
+                return Err( format!("{} already connects as {}", other.userid, authid) );
+            }
+            UserMut( &mut store, user )?.authid = Some( authid );
+        },
+        ["unmap", user] => UserMut( &mut store, user )?.authid = None,
+        ["decoy", user] => UserMut( &mut store, user )?.decoy = true,
+        ["undecoy", user] => UserMut( &mut store, user )?.decoy = false,
+        ["totp-enroll", user] => {
+            let secret = totp::NewSecret()?;
+            let entry = UserMut( &mut store, user )?;
+            println!("TOTP secret for {}: {}", entry.userid, secret);
+            println!("otpauth://totp/rustsecp:{}?secret={}&issuer=rustsecp&algorithm=SHA1&digits={}&period=30",
+                     UriEscape( &entry.userid ), secret, totp::CODE_DIGITS);
+            entry.totp = Some( secret );
+        },
+        ["totp-remove", user] => {
+            let entry = UserMut( &mut store, user )?;
+            if entry.totp.take().is_none() {
+                return Err( format!("{} has no TOTP secret", user) );
+            }
+        },
+        ["hotp-enroll", user] => {
+            let secret = totp::NewSecret()?;
+            let entry = UserMut( &mut store, user )?;
+            println!("HOTP secret for {}: {}", entry.userid, secret);
+            println!("otpauth://hotp/rustsecp:{}?secret={}&issuer=rustsecp&algorithm=SHA1&digits={}&counter=0",
+                     UriEscape( &entry.userid ), secret, totp::CODE_DIGITS);
+            entry.hotp = Some( secret );
+            entry.hotpCounter = 0;
+        },
+        ["hotp-resync", user, first, second] => {
+            let state = MfaState::Load( &StatePath( path ) )?;
+            let entry = UserMut( &mut store, user )?;
+            if entry.hotp.is_none() {
+                return Err( format!("{} has no HOTP secret", user) );
+            }
+            entry.hotpCounter = ResyncHotp( entry, &state, first, second ).ok_or_else( ||
+                format!("{} and {} are not two codes in a row within {} counters", first, second, RESYNC_WINDOW) )?;
+            println!("the next HOTP counter of {} is {}", entry.userid, entry.hotpCounter);
+        },
+        ["hotp-remove", user] => {
+            let entry = UserMut( &mut store, user )?;
+            if entry.hotp.take().is_none() {
+                return Err( format!("{} has no HOTP secret", user) );
+            }
+            entry.hotpCounter = 0;
+        },
+        ["recovery-issue", user] => {
+            let codes = (0..RECOVERY_CODES).map( |_| NewRecoveryCode() ).collect::<Result<Vec<String>, String>>()?;
+            let entry = UserMut( &mut store, user )?;
+            println!("Recovery codes for {}, each good once:", entry.userid);
+            for code in &codes {
+                println!("  {}", code);
+            }
+            entry.recovery = codes.iter().map( |c| RecoveryDigest( c ) ).collect();
+        },
+        ["recovery-revoke", user] => {
+            let entry = UserMut( &mut store, user )?;
+            if entry.recovery.is_empty() {
+                return Err( format!("{} has no recovery codes", user) );
+            }
+            entry.recovery.clear();
+        },
+        ["canary-add"] => {
+            let password = ReadNewPassword( "the canary", options )?;
+            if let Some(u) = store.Users().find( |u| u.CheckPassword( &password ) ) {
+                return Err( format!("that is the password of {}, a canary must not be anybody's password", u.userid) );
+            }
+            store.AddCanary( &password )?;
+        },
+        ["canary-remove"] => {
+            let password = ReadNewPassword( "the canary", options )?;
+            store.RemoveCanary( &password )?;
+        },
+        _ => return Err( String::new() ),
+    }
+
+    store.Save( path )?;
+    println!("{} updated, run rustsecp-ctl reload to apply the change", path.display());
+    Ok(())
+}
+
+fn UserMut<'a>( store : &'a mut UserStore, user : &str ) -> Result<&'a mut UserEntry, String> {
+    store.GetMut( user ).ok_or_else( || format!("no user {}", user) )
+}
+
+fn Status( user : &UserEntry ) -> &'static str {
+    match (user.decoy, user.locked, user.password.is_empty()) {
+        (true, _, _) => "decoy",
+        (_, true, _) => "locked",
+        (_, false, true) => "nopw",
+        (_, false, false) => "active",
+    }
+}
+
+// For the label of an otpauth:// URI.
+fn UriEscape( text : &str ) -> String {
+    text.bytes()
+        .map( |b| if b.is_ascii_alphanumeric() || b"-._~".contains( &b ) { (b as char).to_string() } else { format!("%{:02X}", b) } )
+        .collect()
+}
+
+// Userids are what the user types, authids and groups are Db2 names.
+fn CheckUserid( user : &str ) -> Result<(), String> {
+    if user.is_empty() || user.len() > 255 || user.chars().any( |c| c.is_whitespace() || c.is_control() ) {
+        return Err( format!("{} is not a valid userid", user) );
+    }
+    Ok(())
+}
+
+fn CheckDb2Name( name : &str ) -> Result<String, String> {
+    let name = name.to_uppercase();
+    if ! ValidDb2Name( &name ) {
+        return Err( format!("{} is not a valid Db2 name", name) );
+    }
+    Ok( name )
+}
+
+//-----------------------------------------------------------------------------
+// Passwords
+
+// whose is the userid or "the canary", for the prompt.
+fn ReadNewPassword( whose : &str, options : &Options ) -> Result<Zeroizing<String>, String> {
+    if options.passwordStdin {
+        let password = ReadPassword( "" )?;
+        if password.is_empty() {
+            return Err( String::from("the password is empty") );
+        }
+        return Ok( password );
+    }
+
+    if ! std::io::stdin().is_terminal() {
+        return Err( String::from("standard input is not a terminal, use --password-stdin") );
+    }
+
+    let password = ReadPassword( &format!("New password for {}: ", whose) )?;
+    let again = ReadPassword( "Again: " )?;
+    if password.is_empty() {
+        return Err( String::from("the password is empty") );
+    }
+    if *password != *again {
+        return Err( String::from("the passwords do not match") );
+    }
+    Ok( password )
+}
@@ -0,0 +1,82 @@
+// Verify the hash chain and signed checkpoints of a rustsecp audit log.
+//
+//    rustsecp-audit-verify [--key KEYFILE] AUDITFILE...
+//
+// Each AUDITFILE is checked together with its rotated files, oldest first,
+// so pointing it at the live audit_file covers everything that is still
+// kept.  Pass --key with the audit_key_file to check checkpoint signatures.
+// Exits with 0 if the log is intact, 1 if any problem was found.
+
+#![allow(non_snake_case)]
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use db2rustsecp::auditchain::{LoadCheckpointKey, RotatedAuditFiles, VerifyAuditFiles};
+
+fn Usage() -> ExitCode {
+    eprintln!("usage: rustsecp-audit-verify [--key KEYFILE] AUDITFILE...");
+    ExitCode::from( 2 )
+}
+
+fn main() -> ExitCode {
+    let mut keyFile : Option<PathBuf> = None;
+    let mut files : Vec<PathBuf> = Vec::new();
+
+    let mut args = std::env::args().skip( 1 );
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key" => match args.next() {
+                Some(k) => keyFile = Some( PathBuf::from( k ) ),
+                None => return Usage(),
+            },
+            "-h" | "--help" => return Usage(),
+            _ => {
+                let path = PathBuf::from( arg );
+                files.extend( RotatedAuditFiles( &path ) );
+                files.push( path );
+            }
+        }
+    }
+
+    if files.is_empty() {
+        return Usage();
+    }
+
+    let key = match &keyFile {
+        None => None,
+        Some(k) => match LoadCheckpointKey( k ) {
+            Ok(key) => Some( key ),
+            Err(e) => {
+                eprintln!("{}", e);
+                return ExitCode::from( 2 );
+            }
+        },
+    };
+
+    let report = VerifyAuditFiles( &files, key.as_deref() );
+
+    for problem in &report.problems {
+        println!("PROBLEM {}", problem);
+    }
+
+    println!("{} records and {} checkpoints in {} files, seq {} to {}",
+             report.records, report.checkpoints, files.len(),
+             report.firstSeq.map_or( String::from("-"), |s| s.to_string() ),
+             report.lastSeq.map_or( String::from("-"), |s| s.to_string() ));
+
+    match (key.is_some(), report.lastSealedSeq, report.lastSeq) {
+        (false, _, _) => println!("Checkpoint signatures were not checked, use --key"),
+        (true, None, Some(_)) => println!("No valid checkpoint, nothing is covered by a signature"),
+        (true, Some(sealed), Some(last)) if sealed < last =>
+            println!("Records after seq {} are not covered by a signature yet", sealed),
+        _ => {},
+    }
+
+    if report.problems.is_empty() {
+        println!("OK");
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from( 1 )
+    }
+}
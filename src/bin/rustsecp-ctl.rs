@@ -0,0 +1,87 @@
+// Send a command to the control socket of a running rustsecp plugin.
+//
+//    rustsecp-ctl [--socket PATH] COMMAND [ARGS...]
+//
+// The socket is the control_socket of the plugin configuration.  Without
+// --socket it is taken from the configuration file the plugin would read.
+// See control.rs for the commands.
+// Exits with 0 if the plugin answered OK, 1 if it answered with an error.
+
+#![allow(non_snake_case)]
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use db2rustsecp::ConfiguredControlSocket;
+
+fn Usage() -> ExitCode {
+    eprintln!("usage: rustsecp-ctl [--socket PATH] COMMAND [ARGS...]");
+    eprintln!("commands: status, reload, locked, unlock USER, metrics,");
+    eprintln!("          log-level LEVEL [MODULE], log-level reset");
+    ExitCode::from( 2 )
+}
+
+fn main() -> ExitCode {
+    let mut socket : Option<PathBuf> = None;
+    let mut command : Vec<String> = Vec::new();
+
+    let mut args = std::env::args().skip( 1 );
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" if command.is_empty() => match args.next() {
+                Some(s) => socket = Some( PathBuf::from( s ) ),
+                None => return Usage(),
+            },
+            "-h" | "--help" if command.is_empty() => return Usage(),
+            _ => command.push( arg ),
+        }
+    }
+
+    if command.is_empty() {
+        return Usage();
+    }
+
+    let socket = match socket.map_or_else( ConfiguredControlSocket, Ok ) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from( 2 );
+        }
+    };
+
+    let mut stream = match UnixStream::connect( &socket ) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Cannot connect to {}: {}", socket.display(), e);
+            return ExitCode::from( 2 );
+        }
+    };
+
+    let mut reply = String::new();
+    let sent = stream.write_all( format!("{}\n", command.join( " " )).as_bytes() )
+                     .and_then( |_| stream.read_to_string( &mut reply ) );
+    if let Err(e) = sent {
+        eprintln!("{}: {}", socket.display(), e);
+        return ExitCode::from( 2 );
+    }
+
+    let mut lines = reply.lines();
+    match lines.next() {
+        Some("OK") => {
+            for l in lines {
+                println!("{}", l);
+            }
+            ExitCode::SUCCESS
+        },
+        Some(error) => {
+            eprintln!("{}", error.strip_prefix( "ERR " ).unwrap_or( error ));
+            ExitCode::from( 1 )
+        },
+        None => {
+            eprintln!("{}: no reply", socket.display());
+            ExitCode::from( 1 )
+        }
+    }
+}
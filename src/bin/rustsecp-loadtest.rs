@@ -0,0 +1,323 @@
+// Load test of the plugin, as a morning login storm would hit it.
+//
+//    rustsecp-loadtest [OPTIONS]
+//
+// Initializes the plugin through db2secServerAuthPluginInit, the entry
+// point Db2 calls, and runs ValidatePassword, GetAuthIDs and FreeToken
+// cycles from many threads at once, the way Db2 agents do.  Each backend
+// and Argon2 setting is measured on its own and reported with throughput
+// and p50, p99 and p999 latency of a whole cycle.
+//
+// Backends are "builtin", the demo users, and "userstore", a generated
+// user store with --users users whose passwords are hashed with each
+// --argon2 setting in turn.  Nothing but the generated files is touched,
+// auditing, the SIEM feed, metrics and the control socket are off.
+//
+// Build with --release, the numbers of a debug build mean little.
+
+#![allow(non_snake_case)]
+
+use std::ffi::{c_char, c_void};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Barrier};
+use std::time::{Duration, Instant};
+
+use db2rustsecp::db2secServerAuthPluginInit;
+use db2rustsecp::userstore::{HashPasswordWith, HashSettings, UserStore};
+
+const USAGE : &str = "\
+usage: rustsecp-loadtest [OPTIONS]
+
+options:
+  --threads N          concurrent agents, default 64
+  --cycles N           ValidatePassword/GetAuthIDs/FreeToken cycles per run, default 2000
+  --users N            users in the generated user store, default 1000
+  --backend NAME       builtin or userstore, may be repeated, default both
+  --argon2 m=KIB,t=N,p=N
+                       hash setting for the user store, may be repeated,
+                       default the built in setting
+  --wrong-password N   percent of attempts with a wrong password, default 0";
+
+fn Usage() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::from( 2 )
+}
+
+const DB2SEC_PLUGIN_OK : i32 = 0;
+const DB2SEC_PLUGIN_NO_CON_DETAILS : i32 = -24;
+const DB2SEC_VALIDATING_ON_SERVER_SIDE : u32 = 0x4;
+const DB2SEC_MAX_AUTHID_LENGTH : usize = 255;
+
+const PASSWORD : &str = "Load-test-password-1";
+
+//-----------------------------------------------------------------------------
+// The function table db2secServerAuthPluginInit fills in, as in
+// db2secPlugin.h.
+
+type ValidatePasswordFuncT = extern "C" fn( * const c_char, i32, * const c_char, i32, i32,
+                                            * const c_char, i32, * const c_char, i32,
+                                            * const c_char, i32, u32,
+                                            * mut * mut c_void, * mut * mut c_char, * mut i32 ) -> i32;
+type GetAuthIDsFuncT = extern "C" fn( * const c_char, i32, * const c_char, i32, i32,
+                                      * const c_char, i32, * mut * mut c_void,
+                                      * mut c_char, * mut i32, * mut c_char, * mut i32,
+                                      * mut c_char, * mut i32, * mut i32,
+                                      * mut * mut c_char, * mut i32 ) -> i32;
+type DoesAuthIDExistFuncT = extern "C" fn( * const c_char, i32, * mut * mut c_char, * mut i32 ) -> i32;
+type FreeTokenFuncT = extern "C" fn( * mut c_void, * mut * mut c_char, * mut i32 ) -> i32;
+type FreeErrormsgFuncT = extern "C" fn( * mut c_char ) -> i32;
+type PluginTermFuncT = extern "C" fn( * mut * mut c_char, * mut i32 ) -> i32;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct ServerAuthFunctions {
+    version : i32,
+    plugintype : i32,
+    validatePassword : Option<ValidatePasswordFuncT>,
+    getAuthIDs : Option<GetAuthIDsFuncT>,
+    doesAuthIDExist : Option<DoesAuthIDExistFuncT>,
+    freeToken : Option<FreeTokenFuncT>,
+    freeErrormsg : Option<FreeErrormsgFuncT>,
+    pluginTerm : Option<PluginTermFuncT>,
+}
+
+// No connection details, auditing is off so they are not asked for.
+extern "C" fn GetConDetails( _version : i32, _details : * mut c_void ) -> i32 {
+    DB2SEC_PLUGIN_NO_CON_DETAILS
+}
+
+extern "C" fn LogMessage( _level : i32, _data : * const c_char, _length : i32 ) -> i32 {
+    DB2SEC_PLUGIN_OK
+}
+
+//-----------------------------------------------------------------------------
+
+struct Options {
+    threads : usize,
+    cycles : usize,
+    users : usize,
+    backends : Vec<String>,
+    hashSettings : Vec<HashSettings>,
+    wrongPasswordPercent : usize,
+}
+
+struct Run {
+    backend : String,
+    hash : String,
+    users : Vec<(String, String)>,
+    config : String,
+}
+
+struct Measured {
+    latencies : Vec<Duration>,
+    failures : usize,
+    wall : Duration,
+}
+
+fn main() -> ExitCode {
+    let mut options = Options { threads : 64, cycles : 2000, users : 1000, backends : Vec::new(),
+                                hashSettings : Vec::new(), wrongPasswordPercent : 0 };
+
+    let mut args = std::env::args().skip( 1 );
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "-h" | "--help" => return Usage(),
+            _ => match args.next() {
+                Some(v) => v,
+                None => return Usage(),
+            },
+        };
+        let number = || value.parse::<usize>().map_err( |_| format!("{} is not a number", value) );
+        let parsed = match arg.as_str() {
+            "--threads" => number().map( |n| options.threads = n.max( 1 ) ),
+            "--cycles" => number().map( |n| options.cycles = n.max( 1 ) ),
+            "--users" => number().map( |n| options.users = n.max( 1 ) ),
+            "--wrong-password" => number().map( |n| options.wrongPasswordPercent = n.min( 100 ) ),
+            "--backend" if value == "builtin" || value == "userstore" => {
+                options.backends.push( value.clone() );
+                Ok(())
+            },
+            "--backend" => Err( format!("unknown backend {}", value) ),
+            "--argon2" => HashSettings::Parse( &value ).map( |h| options.hashSettings.push( h ) ),
+            _ => return Usage(),
+        };
+        if let Err(e) = parsed {
+            eprintln!("{}", e);
+            return ExitCode::from( 2 );
+        }
+    }
+    if options.backends.is_empty() {
+        options.backends = vec![ String::from( "builtin" ), String::from( "userstore" ) ];
+    }
+    if options.hashSettings.is_empty() {
+        options.hashSettings.push( HashSettings::default() );
+    }
+
+    let dir = std::env::temp_dir().join( format!("rustsecp-loadtest-{}", std::process::id()) );
+    let result = Prepare( &options, &dir ).and_then( |runs| Report( &options, &dir, &runs ) );
+    let _ = std::fs::remove_dir_all( &dir );
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from( 1 )
+        }
+    }
+}
+
+// The runs to make, with their user store and configuration written out.
+fn Prepare( options : &Options, dir : &Path ) -> Result<Vec<Run>, String> {
+    std::fs::create_dir_all( dir ).map_err( |e| format!("Cannot create {}: {}", dir.display(), e) )?;
+    let mut runs = Vec::new();
+
+    for backend in &options.backends {
+        if backend == "builtin" {
+            let users = [("gstager", "temp4Now"), ("newton", "newtonpw"), ("zurbie", "zurbiepw")]
+                            .iter().map( |(u, p)| (String::from( *u ), String::from( *p )) ).collect();
+            runs.push( Run { backend : backend.clone(), hash : HashSettings::default().Name(),
+                             users, config : String::from( "log_level = none\n" ) } );
+            continue;
+        }
+
+        for settings in &options.hashSettings {
+            // One hash serves every user, it costs the same to check.
+            let hash = HashPasswordWith( PASSWORD, settings )?;
+            let mut store = UserStore::New();
+            let mut users = Vec::new();
+            for i in 0..options.users {
+                let userid = format!("load{:05}", i);
+                store.Add( &userid )?.password = hash.clone();
+                users.push( (userid, String::from( PASSWORD )) );
+            }
+
+            let path : PathBuf = dir.join( format!("users-{}.json", settings.Name().replace( ',', "-" )) );
+            store.Save( &path )?;
+            runs.push( Run { backend : backend.clone(), hash : settings.Name(), users,
+                             config : format!("log_level = none\nuser_store = {}\n", path.display()) } );
+        }
+    }
+
+    for (i, run) in runs.iter().enumerate() {
+        let path = dir.join( format!("rustsecp-{}.cfg", i) );
+        std::fs::write( &path, &run.config ).map_err( |e| format!("Cannot write {}: {}", path.display(), e) )?;
+    }
+    Ok( runs )
+}
+
+fn Report( options : &Options, dir : &Path, runs : &[Run] ) -> Result<(), String> {
+    println!("{} threads, {} cycles per run, {}% wrong passwords", options.threads, options.cycles, options.wrongPasswordPercent);
+    println!("{:<10} {:<22} {:>9} {:>10} {:>9} {:>9} {:>9} {:>9}",
+             "BACKEND", "HASH", "FAILURES", "CYCLES/S", "P50 MS", "P99 MS", "P999 MS", "MAX MS");
+
+    for (i, run) in runs.iter().enumerate() {
+        std::env::set_var( "DB2RUSTSECP_CONFIG", dir.join( format!("rustsecp-{}.cfg", i) ) );
+        let mut m = Measure( options, run )?;
+        m.latencies.sort();
+
+        let ms = |q : f64| {
+            let i = ((m.latencies.len() as f64 * q).ceil() as usize).clamp( 1, m.latencies.len() ) - 1;
+            m.latencies[i].as_secs_f64() * 1000.0
+        };
+        println!("{:<10} {:<22} {:>9} {:>10.1} {:>9.2} {:>9.2} {:>9.2} {:>9.2}",
+                 run.backend, run.hash, m.failures,
+                 m.latencies.len() as f64 / m.wall.as_secs_f64(),
+                 ms( 0.50 ), ms( 0.99 ), ms( 0.999 ), ms( 1.0 ));
+    }
+    Ok(())
+}
+
+fn Measure( options : &Options, run : &Run ) -> Result<Measured, String> {
+    let mut fns = ServerAuthFunctions::default();
+    let mut errormsg : * mut c_char = std::ptr::null_mut();
+    let mut errormsglen : i32 = 0;
+    let rc = db2secServerAuthPluginInit( 1, &mut fns as * mut ServerAuthFunctions as * mut c_void,
+                                         Some( GetConDetails ), Some( LogMessage ),
+                                         &mut errormsg, &mut errormsglen );
+    if rc != DB2SEC_PLUGIN_OK {
+        return Err( format!("The plugin did not initialize for {} {}: rc {}", run.backend, run.hash, rc) );
+    }
+
+    let users = Arc::new( run.users.clone() );
+    let start = Arc::new( Barrier::new( options.threads + 1 ) );
+    let threads : Vec<_> = (0..options.threads).map( |t| {
+        let users = users.clone();
+        let start = start.clone();
+        let cycles = options.cycles / options.threads + usize::from( t < options.cycles % options.threads );
+        let wrongPercent = options.wrongPasswordPercent;
+        std::thread::spawn( move || {
+            let mut latencies = Vec::with_capacity( cycles );
+            let mut failures = 0;
+            start.wait();
+            for i in 0..cycles {
+                let n = t * 7919 + i;
+                let (userid, password) = &users[n % users.len()];
+                let wrong = n % 100 < wrongPercent;
+                let begun = Instant::now();
+                if ! Cycle( &fns, userid, if wrong { "not-the-password" } else { password } ) {
+                    failures += 1;
+                }
+                latencies.push( begun.elapsed() );
+            }
+            (latencies, failures)
+        })
+    }).collect();
+
+    start.wait();
+    let begun = Instant::now();
+    let mut measured = Measured { latencies : Vec::with_capacity( options.cycles ), failures : 0, wall : Duration::ZERO };
+    for t in threads {
+        let (latencies, failures) = t.join().map_err( |_| String::from( "A load thread panicked" ) )?;
+        measured.latencies.extend( latencies );
+        measured.failures += failures;
+    }
+    measured.wall = begun.elapsed();
+
+    (fns.pluginTerm.unwrap())( &mut errormsg, &mut errormsglen );
+    Ok( measured )
+}
+
+// One connection as Db2 makes it.  Returns false if it was refused.
+fn Cycle( fns : &ServerAuthFunctions, userid : &str, password : &str ) -> bool {
+    let dbname = "LOADTEST";
+    let mut token : * mut c_void = std::ptr::null_mut();
+    let mut errormsg : * mut c_char = std::ptr::null_mut();
+    let mut errormsglen : i32 = 0;
+
+    let rc = (fns.validatePassword.unwrap())( userid.as_ptr() as * const c_char, userid.len() as i32,
+                                              std::ptr::null(), 0, 0,
+                                              password.as_ptr() as * const c_char, password.len() as i32,
+                                              std::ptr::null(), 0,
+                                              dbname.as_ptr() as * const c_char, dbname.len() as i32,
+                                              DB2SEC_VALIDATING_ON_SERVER_SIDE,
+                                              &mut token, &mut errormsg, &mut errormsglen );
+    if ! errormsg.is_null() {
+        (fns.freeErrormsg.unwrap())( errormsg );
+        errormsg = std::ptr::null_mut();
+    }
+    if rc != DB2SEC_PLUGIN_OK {
+        return false;
+    }
+
+    let mut systemAuthid = [0 as c_char; DB2SEC_MAX_AUTHID_LENGTH];
+    let mut initialAuthid = [0 as c_char; DB2SEC_MAX_AUTHID_LENGTH];
+    let mut username = [0 as c_char; DB2SEC_MAX_AUTHID_LENGTH];
+    let (mut systemLen, mut initialLen, mut usernameLen, mut idType) = (0i32, 0i32, 0i32, 0i32);
+    let rc = (fns.getAuthIDs.unwrap())( userid.as_ptr() as * const c_char, userid.len() as i32,
+                                        std::ptr::null(), 0, 0,
+                                        dbname.as_ptr() as * const c_char, dbname.len() as i32,
+                                        &mut token,
+                                        systemAuthid.as_mut_ptr(), &mut systemLen,
+                                        initialAuthid.as_mut_ptr(), &mut initialLen,
+                                        username.as_mut_ptr(), &mut usernameLen,
+                                        &mut idType,
+                                        &mut errormsg, &mut errormsglen );
+    if ! errormsg.is_null() {
+        (fns.freeErrormsg.unwrap())( errormsg );
+        errormsg = std::ptr::null_mut();
+    }
+
+    (fns.freeToken.unwrap())( token, &mut errormsg, &mut errormsglen );
+    rc == DB2SEC_PLUGIN_OK
+}
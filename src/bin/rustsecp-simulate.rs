@@ -0,0 +1,122 @@
+// Explain why a login would succeed or fail.
+//
+//    rustsecp-simulate [OPTIONS] USERID
+//
+// Runs the decisions of ValidatePassword, GetAuthIDs and GetGroupsForUser
+// for one connection attempt, with the configuration and user store the
+// plugin would use, and prints every step.  Db2 is not needed.  See
+// simulate.rs.
+//
+// The password is read from standard input, not echoed on a terminal.
+// Exits with 0 if the connection would be allowed, 1 if not.
+
+#![allow(non_snake_case)]
+
+use std::net::IpAddr;
+use std::process::ExitCode;
+
+use db2rustsecp::simulate::{ParseConnectionFlags, SimulateLogin, SimulatedLogin};
+
+mod cli;
+use cli::ReadPassword;
+
+const USAGE : &str = "\
+usage: rustsecp-simulate [OPTIONS] USERID
+
+options:
+  --config FILE      plugin configuration, instead of the one the plugin reads
+  --no-password      connect without a password, see --flags
+  --database DB      database name
+  --client-ip IP     address the client connects from
+  --platform N       client platform number, as Db2 reports it
+  --flags FLAGS      connection flags, e.g. USERID_FROM_OS,CONNECTION_ISLOCAL
+                     (VALIDATING_ON_SERVER_SIDE is always added)
+  --locked-out       take the user as locked out
+  --not-locked-out   take the user as not locked out, instead of asking
+                     the running plugin through its control socket";
+
+fn Usage() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::from( 2 )
+}
+
+fn main() -> ExitCode {
+    let mut login = SimulatedLogin { userid : String::new(),
+                                     password : None,
+                                     database : None,
+                                     clientIP : None,
+                                     platform : None,
+                                     connectionFlags : ParseConnectionFlags( "VALIDATING_ON_SERVER_SIDE" ).unwrap_or( 0 ),
+                                     lockedOut : None };
+    let mut noPassword = false;
+    let mut userid : Option<String> = None;
+
+    let mut args = std::env::args().skip( 1 );
+    while let Some(arg) = args.next() {
+        let parsed = match arg.as_str() {
+            "--config" => args.next().map( |c| std::env::set_var( "DB2RUSTSECP_CONFIG", c ) ).ok_or( String::new() ),
+            "--no-password" => { noPassword = true; Ok(()) },
+            "--database" => args.next().map( |d| login.database = Some( d ) ).ok_or( String::new() ),
+            "--client-ip" => match args.next() {
+                Some(ip) => ip.parse::<IpAddr>()
+                              .map( |ip| login.clientIP = Some( ip ) )
+                              .map_err( |_| format!("{} is not an IP address", ip) ),
+                None => Err( String::new() ),
+            },
+            "--platform" => match args.next() {
+                Some(p) => p.parse::<u32>()
+                            .map( |p| login.platform = Some( p ) )
+                            .map_err( |_| format!("{} is not a platform number", p) ),
+                None => Err( String::new() ),
+            },
+            "--flags" => match args.next() {
+                Some(f) => ParseConnectionFlags( &f ).map( |f| login.connectionFlags |= f ),
+                None => Err( String::new() ),
+            },
+            "--locked-out" => { login.lockedOut = Some( true ); Ok(()) },
+            "--not-locked-out" => { login.lockedOut = Some( false ); Ok(()) },
+            "-h" | "--help" => Err( String::new() ),
+            _ if arg.starts_with( "--" ) || userid.is_some() => Err( String::new() ),
+            _ => { userid = Some( arg ); Ok(()) },
+        };
+
+        match parsed {
+            Ok(()) => {},
+            Err(e) if e.is_empty() => return Usage(),
+            Err(e) => {
+                eprintln!("{}", e);
+                return ExitCode::from( 2 );
+            }
+        }
+    }
+
+    login.userid = match userid {
+        Some(u) => u,
+        None => return Usage(),
+    };
+
+    if ! noPassword {
+        match ReadPassword( &format!("Password for {}: ", login.userid) ) {
+            Ok(p) => login.password = Some( p ),
+            Err(e) => {
+                eprintln!("{}", e);
+                return ExitCode::from( 2 );
+            }
+        }
+    }
+
+    let simulation = match SimulateLogin( &login ) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from( 2 );
+        }
+    };
+
+    for (i, step) in simulation.steps.iter().enumerate() {
+        println!("{:>2}. {}", i + 1, step);
+    }
+    println!("result: {} ({})", simulation.result, simulation.rc);
+
+    if simulation.rc == 0 { ExitCode::SUCCESS } else { ExitCode::from( 1 ) }
+}
@@ -0,0 +1,278 @@
+//-----------------------------------------------------------------------------
+// Plugin configuration.
+//
+// The configuration is a plain text file of "key = value" lines.  Blank
+// lines and lines starting with '#' are ignored.  The file is located via
+// the DB2RUSTSECP_CONFIG environment variable of the Db2 instance, or
+// rustsecp.cfg next to the plugin in ~/sqllib/security64/plugin/server.
+// If the file does not exist the built in defaults are used, which keeps
+// the demo working without any setup.
+//
+// The file is read at init time and again on every reload.
+
+use std::path::PathBuf;
+
+use crate::Db2LogLevels;
+use crate::codepage::CodePage;
+use crate::diaglog::{LogModule, ParseLogFilter};
+use crate::ratelimit::AttemptRate;
+use crate::siem::{SiemFormat, SyslogTarget, FacilityFromName};
+
+pub struct PluginConfig {
+    // User store file, see userstore.rs.  None means the built in demo users.
+    pub userStore : Option<PathBuf>,
+
+    // Diagnostic logging to db2diag.log, see diaglog.rs.
+    pub logLevel : Db2LogLevels,
+    pub logFilter : Vec<(LogModule, Db2LogLevels)>,
+    pub logRateLimit : u32,
+
+    // Code page used for input that is not valid UTF-8.
+    // UTF-8 means such input is rejected.
+    pub clientCodepage : CodePage,
+
+    // Audit log, see audit.rs.  No file means no auditing.
+    pub auditFile : Option<PathBuf>,
+    pub auditMaxBytes : u64,
+    pub auditRotateSeconds : u64,
+    pub auditKeepFiles : usize,
+    // Key for signed checkpoints, created on first use.  None means the
+    // audit log is hash chained but never signed.
+    pub auditKeyFile : Option<PathBuf>,
+    pub auditCheckpointRecords : u64,
+
+    // SIEM feed over syslog, see siem.rs.  No target means no feed.
+    pub siemFormat : SiemFormat,
+    pub siemTarget : Option<SyslogTarget>,
+    pub siemFacility : u8,
+
+    // Prometheus textfile, see metrics.rs.  No file means no export.
+    pub metricsFile : Option<PathBuf>,
+    pub metricsIntervalSeconds : u64,
+
+    // See lockout.rs.  A threshold of 0 means no lockout, 0 seconds means
+    // locked until unlocked.
+    pub lockoutThreshold : u32,
+    pub lockoutSeconds : u64,
+
+    // See control.rs.  None means no control socket.
+    pub controlSocket : Option<PathBuf>,
+
+    // See token.rs.  0 means tokens do not expire.
+    pub tokenMaxAgeSeconds : u64,
+
+    // Report unknown users, wrong passwords and lockouts alike, see
+    // decision.rs.
+    pub uniformFailures : bool,
+
+    // Progressive delay, see tarpit.rs.  A base of 0 means no delay.
+    pub delayBaseMs : u64,
+    pub delayMaxMs : u64,
+    pub delayMaxThreads : u32,
+    pub delayForgetSeconds : u64,
+
+    // Login attempts, see ratelimit.rs.  None means no limit.
+    pub rateLimitIp : Option<AttemptRate>,
+    pub rateLimitUser : Option<AttemptRate>,
+    pub rateLimitGlobal : Option<AttemptRate>,
+
+    // Spray and stuffing detection, see detect.rs.  0 turns a pattern off.
+    pub stuffingUserids : usize,
+    pub sprayUserids : usize,
+    pub detectWindowSeconds : u64,
+    pub detectBlockSeconds : u64,
+
+    // TOTP second factor, see totp.rs.  Group names are upper case.
+    pub totpSeparator : Option<String>,
+    pub totpSkewSteps : u64,
+    pub totpRequiredGroups : Vec<String>,
+    // Local OS users without a password skip the second factor.
+    pub totpLocalOsExempt : bool,
+    // See mfa.rs.
+    pub hotpWindow : u64,
+}
+
+impl Default for PluginConfig {
+    fn default() -> PluginConfig {
+        PluginConfig {
+            userStore : None,
+            logLevel : Db2LogLevels::DB2SEC_LOG_WARNING,
+            logFilter : Vec::new(),
+            logRateLimit : 60,
+            clientCodepage : CodePage::Utf8,
+            auditFile : None,
+            auditMaxBytes : 10 * 1024 * 1024,
+            auditRotateSeconds : 24 * 60 * 60,
+            auditKeepFiles : 10,
+            auditKeyFile : None,
+            auditCheckpointRecords : 100,
+            siemFormat : SiemFormat::Json,
+            siemTarget : None,
+            // authpriv
+            siemFacility : 10,
+            metricsFile : None,
+            metricsIntervalSeconds : 15,
+            lockoutThreshold : 0,
+            lockoutSeconds : 15 * 60,
+            controlSocket : None,
+            tokenMaxAgeSeconds : 5 * 60,
+            uniformFailures : false,
+            delayBaseMs : 0,
+            delayMaxMs : 5000,
+            delayMaxThreads : 32,
+            delayForgetSeconds : 15 * 60,
+            rateLimitIp : None,
+            rateLimitUser : None,
+            rateLimitGlobal : None,
+            stuffingUserids : 0,
+            sprayUserids : 0,
+            detectWindowSeconds : 10 * 60,
+            detectBlockSeconds : 0,
+            totpSeparator : None,
+            totpSkewSteps : 1,
+            totpRequiredGroups : Vec::new(),
+            totpLocalOsExempt : false,
+            hotpWindow : 10,
+        }
+    }
+}
+
+pub fn PluginConfigPath() -> PathBuf {
+    if let Some(p) = std::env::var_os( "DB2RUSTSECP_CONFIG" ) {
+        return PathBuf::from( p );
+    }
+
+    let home = std::env::var_os( "HOME" ).unwrap_or_default();
+    PathBuf::from( home ).join( "sqllib/security64/plugin/server/rustsecp.cfg" )
+}
+
+pub fn LoadPluginConfig() -> Result<PluginConfig, String> {
+    let path = PluginConfigPath();
+
+    match std::fs::read_to_string( &path ) {
+        Ok(text) => ParsePluginConfig( &text )
+                        .map_err( |e| format!("{}: {}", path.display(), e) ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok( PluginConfig::default() ),
+        Err(e) => Err( format!("Cannot read {}: {}", path.display(), e) ),
+    }
+}
+
+pub fn ParsePluginConfig( text : &str ) -> Result<PluginConfig, String> {
+    let mut config = PluginConfig::default();
+
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => return Err( format!("line {}: expected key = value", lineno + 1) ),
+        };
+
+        match key {
+            "user_store" => {
+                config.userStore = if value.is_empty() { None } else { Some( PathBuf::from( value ) ) };
+            },
+            "log_level" => {
+                config.logLevel = Db2LogLevels::FromName( value )
+                    .ok_or_else( || format!("line {}: unknown log level {}", lineno + 1, value) )?;
+            },
+            "log_filter" => {
+                config.logFilter = ParseLogFilter( value ).map_err( |e| format!("line {}: {}", lineno + 1, e) )?;
+            },
+            "log_rate_limit" => config.logRateLimit = ParseNumber( lineno, value )?,
+            "client_codepage" => {
+                config.clientCodepage = CodePage::FromName( value )
+                    .ok_or_else( || format!("line {}: unknown code page {}", lineno + 1, value) )?;
+            },
+            "audit_file" => {
+                config.auditFile = if value.is_empty() { None } else { Some( PathBuf::from( value ) ) };
+            },
+            "audit_max_bytes"      => config.auditMaxBytes = ParseNumber( lineno, value )?,
+            "audit_rotate_seconds" => config.auditRotateSeconds = ParseNumber( lineno, value )?,
+            "audit_keep_files"     => config.auditKeepFiles = ParseNumber( lineno, value )?,
+            "audit_key_file" => {
+                config.auditKeyFile = if value.is_empty() { None } else { Some( PathBuf::from( value ) ) };
+            },
+            "audit_checkpoint_records" => config.auditCheckpointRecords = ParseNumber( lineno, value )?,
+            "siem_format" => {
+                config.siemFormat = SiemFormat::FromName( value )
+                    .ok_or_else( || format!("line {}: unknown SIEM format {}", lineno + 1, value) )?;
+            },
+            "siem_target" => {
+                config.siemTarget = if value.is_empty() || value == "none" {
+                    None
+                } else {
+                    Some( SyslogTarget::Parse( value )
+                              .ok_or_else( || format!("line {}: {} is not unix:PATH, udp:HOST:PORT or tcp:HOST:PORT",
+                                                      lineno + 1, value) )? )
+                };
+            },
+            "siem_facility" => {
+                config.siemFacility = FacilityFromName( value )
+                    .ok_or_else( || format!("line {}: unknown syslog facility {}", lineno + 1, value) )?;
+            },
+            "metrics_file" => {
+                config.metricsFile = if value.is_empty() { None } else { Some( PathBuf::from( value ) ) };
+            },
+            "metrics_interval_seconds" => config.metricsIntervalSeconds = ParseNumber( lineno, value )?,
+            "lockout_threshold" => config.lockoutThreshold = ParseNumber( lineno, value )?,
+            "lockout_seconds"   => config.lockoutSeconds = ParseNumber( lineno, value )?,
+            "control_socket" => {
+                config.controlSocket = if value.is_empty() { None } else { Some( PathBuf::from( value ) ) };
+            },
+            "token_max_age_seconds" => config.tokenMaxAgeSeconds = ParseNumber( lineno, value )?,
+            "delay_base_ms"         => config.delayBaseMs = ParseNumber( lineno, value )?,
+            "delay_max_ms"          => config.delayMaxMs = ParseNumber( lineno, value )?,
+            "delay_max_threads"     => config.delayMaxThreads = ParseNumber( lineno, value )?,
+            "delay_forget_seconds"  => config.delayForgetSeconds = ParseNumber( lineno, value )?,
+            "rate_limit_ip"     => config.rateLimitIp = ParseRate( lineno, value )?,
+            "rate_limit_user"   => config.rateLimitUser = ParseRate( lineno, value )?,
+            "rate_limit_global" => config.rateLimitGlobal = ParseRate( lineno, value )?,
+            "stuffing_userids"      => config.stuffingUserids = ParseNumber( lineno, value )?,
+            "spray_userids"         => config.sprayUserids = ParseNumber( lineno, value )?,
+            "detect_window_seconds" => config.detectWindowSeconds = ParseNumber( lineno, value )?,
+            "detect_block_seconds"  => config.detectBlockSeconds = ParseNumber( lineno, value )?,
+            "totp_separator" => {
+                config.totpSeparator = if value.is_empty() || value == "none" { None } else { Some( String::from( value ) ) };
+            },
+            "totp_skew_steps" => config.totpSkewSteps = ParseNumber( lineno, value )?,
+            "totp_required_groups" => {
+                config.totpRequiredGroups = value.split( ',' )
+                                                 .map( |g| g.trim().to_uppercase() )
+                                                 .filter( |g| ! g.is_empty() )
+                                                 .collect();
+            },
+            "totp_local_os_exempt" => config.totpLocalOsExempt = ParseSwitch( lineno, value )?,
+            "hotp_window" => config.hotpWindow = ParseNumber( lineno, value )?,
+            "uniform_failures" => config.uniformFailures = ParseSwitch( lineno, value )?,
+            _ => return Err( format!("line {}: unknown setting {}", lineno + 1, key) ),
+        }
+    }
+
+    Ok( config )
+}
+
+fn ParseNumber<T : std::str::FromStr>( lineno : usize, value : &str ) -> Result<T, String> {
+    value.parse::<T>().map_err( |_| format!("line {}: {} is not a valid number", lineno + 1, value) )
+}
+
+fn ParseSwitch( lineno : usize, value : &str ) -> Result<bool, String> {
+    match value {
+        "on" => Ok( true ),
+        "off" => Ok( false ),
+        _ => Err( format!("line {}: {} is not on or off", lineno + 1, value) ),
+    }
+}
+
+// ATTEMPTS/SECONDS, or off.
+fn ParseRate( lineno : usize, value : &str ) -> Result<Option<AttemptRate>, String> {
+    if value.is_empty() || value == "off" {
+        return Ok( None );
+    }
+    AttemptRate::Parse( value )
+        .map( Some )
+        .ok_or_else( || format!("line {}: {} is not ATTEMPTS/SECONDS, e.g. 30/60, or off", lineno + 1, value) )
+}
@@ -0,0 +1,278 @@
+//-----------------------------------------------------------------------------
+// Control socket.
+//
+// A Unix domain socket served by the plugin inside the Db2 engine, so an
+// operator can act on the running plugin without db2stop/db2start.  The
+// socket is bound inside a private 0700 directory, set to mode 0600 and only
+// then moved to its path, so there is no moment at which others could
+// connect.  A client must also run as the user of the Db2 engine, the
+// instance owner, or it is turned away (SO_PEERCRED).
+//
+// A client sends one command line and reads the reply until the socket is
+// closed.  The first line of the reply is "OK" or "ERR reason", any further
+// lines are data.  rustsecp-ctl does this, or e.g.
+//
+//    echo status | socat - UNIX-CONNECT:/path/to/rustsecp.sock
+//
+// Commands:
+//
+//    status                     what the plugin is running with
+//    reload                     re-read the configuration, see state.rs
+//    locked                     list locked out users
+//    unlock USER                end the lockout of a user
+//    metrics                    the Prometheus metrics, see metrics.rs
+//    log-level LEVEL [MODULE]   override the log level until the next reload
+//    log-level reset            back to the configured log levels
+//
+// There is no command to flush cached credentials, as there are none: every
+// decision reads the user store as it was at init or the last reload, so a
+// change to it takes effect with reload.
+//
+// The socket is off unless control_socket is set in the plugin configuration.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::{Db2LogLevels, LogMessageToDb2Diag};
+use crate::audit::FormatTimestamp;
+use crate::config::PluginConfigPath;
+use crate::diaglog::{LogModule, ClearLogLevelOverrides, SetLogLevelOverride};
+use crate::lockout;
+use crate::metrics;
+use crate::state::{CurrentPluginState, ReloadPluginState};
+
+// A client that does not send its command in time is dropped, it must not
+// hold up the next one.
+const CLIENT_TIMEOUT : Duration = Duration::from_secs( 5 );
+const MAX_COMMAND_LENGTH : u64 = 4096;
+
+pub struct ControlServer {
+    path : PathBuf,
+    stopping : Arc<AtomicBool>,
+    thread : Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ControlServer {
+    // Returns None when control_socket is not configured.
+    pub fn Open( path : Option<&Path> ) -> Result<Option<ControlServer>, String> {
+        let path = match path {
+            None => return Ok( None ),
+            Some(p) => p.to_path_buf(),
+        };
+
+        // A socket left behind by a Db2 engine that did not stop cleanly.
+        // Anything else at that path is not ours to remove.
+        if let Ok(meta) = std::fs::symlink_metadata( &path ) {
+            if ! meta.file_type().is_socket() {
+                return Err( format!("{} exists and is not a socket", path.display()) );
+            }
+            let _ = std::fs::remove_file( &path );
+        }
+
+        let listener = BindPrivately( &path )?;
+
+        let stopping = Arc::new( AtomicBool::new( false ) );
+        let threadStopping = stopping.clone();
+
+        let thread = std::thread::Builder::new()
+            .name( String::from( "rustsecp-control" ) )
+            .spawn( move || {
+                for stream in listener.incoming() {
+                    if threadStopping.load( Ordering::SeqCst ) {
+                        break;
+                    }
+                    if let Ok(s) = stream {
+                        let _ = std::panic::catch_unwind( std::panic::AssertUnwindSafe( || ServeClient( s ) ) );
+                    }
+                }
+            })
+            .map_err( |e| format!("Cannot start the control socket: {}", e) )?;
+
+        Ok( Some( ControlServer { path, stopping, thread : Mutex::new( Some( thread ) ) } ) )
+    }
+
+    pub fn SameSettings( &self, path : Option<&Path> ) -> bool {
+        path == Some( self.path.as_path() )
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop( &mut self ) {
+        // Wake the accept loop so it sees the flag.
+        self.stopping.store( true, Ordering::SeqCst );
+        let _ = UnixStream::connect( &self.path );
+        let _ = std::fs::remove_file( &self.path );
+
+        // Wait for the thread, Db2 may unload the library after term.  A
+        // reload command that replaces this server runs on the thread
+        // itself, which then ends once the command is answered.
+        let thread = match self.thread.lock() {
+            Ok(mut t) => t.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        if let Some(t) = thread {
+            if t.thread().id() != std::thread::current().id() {
+                let _ = t.join();
+            }
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+// Bind the socket where nobody else can reach it yet.  bind() creates the
+// socket under the process umask, a chmod after it leaves a window, and
+// changing the umask of the Db2 engine would affect its other threads.
+
+fn BindPrivately( path : &Path ) -> Result<UnixListener, String> {
+    let parent = match path.parent() {
+        Some(p) if ! p.as_os_str().is_empty() => p,
+        _ => Path::new( "." ),
+    };
+    let privateDir = parent.join( format!(".rustsecp-control.{}", std::process::id()) );
+    let _ = std::fs::remove_dir_all( &privateDir );
+    std::fs::DirBuilder::new().mode( 0o700 ).create( &privateDir )
+        .map_err( |e| format!("Cannot create {}: {}", privateDir.display(), e) )?;
+
+    let privatePath = privateDir.join( "socket" );
+    let result = UnixListener::bind( &privatePath )
+        .map_err( |e| format!("Cannot create {}: {}", path.display(), e) )
+        .and_then( |listener| {
+            std::fs::set_permissions( &privatePath, std::fs::Permissions::from_mode( 0o600 ) )
+                .map_err( |e| format!("Cannot restrict {}: {}", path.display(), e) )?;
+            std::fs::rename( &privatePath, path )
+                .map_err( |e| format!("Cannot create {}: {}", path.display(), e) )?;
+            Ok( listener )
+        });
+
+    let _ = std::fs::remove_dir_all( &privateDir );
+    result
+}
+
+// The uid of the process at the other end of the socket.
+fn PeerUid( stream : &UnixStream ) -> Option<u32> {
+    let mut cred : libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let rc = unsafe { libc::getsockopt( stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                                        &mut cred as * mut libc::ucred as * mut libc::c_void, &mut len ) };
+    if rc != 0 || len as usize != std::mem::size_of::<libc::ucred>() {
+        return None;
+    }
+    Some( cred.uid )
+}
+
+//-----------------------------------------------------------------------------
+
+fn ServeClient( stream : UnixStream ) {
+    let _ = stream.set_read_timeout( Some( CLIENT_TIMEOUT ) );
+    let _ = stream.set_write_timeout( Some( CLIENT_TIMEOUT ) );
+
+    // The mode of the socket should keep others out already, this holds
+    // even if someone loosens it.
+    let owner = unsafe { libc::geteuid() };
+    let peer = PeerUid( &stream );
+    if peer != Some( owner ) {
+        LogMessageToDb2Diag( LogModule::Control, Db2LogLevels::DB2SEC_LOG_WARNING,
+                             &format!("RUSTSECP control socket connection from uid {} refused, only the instance owner may connect",
+                                      peer.map_or( String::from( "unknown" ), |u| u.to_string() )) );
+        let mut stream = stream;
+        let _ = stream.write_all( b"ERR only the instance owner may use the control socket\n" );
+        return;
+    }
+
+    let mut line = String::new();
+    let reader = match stream.try_clone() {
+        Ok(r) => r,
+        Err(_) => return,
+    };
+    if BufReader::new( reader.take( MAX_COMMAND_LENGTH ) ).read_line( &mut line ).is_err() {
+        return;
+    }
+
+    let reply = match RunCommand( line.trim() ) {
+        Ok(lines) => {
+            let mut reply = String::from( "OK\n" );
+            for l in lines {
+                reply.push_str( &l );
+                reply.push( '\n' );
+            }
+            reply
+        },
+        Err(e) => format!("ERR {}\n", e),
+    };
+
+    let mut stream = stream;
+    let _ = stream.write_all( reply.as_bytes() );
+}
+
+fn RunCommand( line : &str ) -> Result<Vec<String>, String> {
+    let words : Vec<&str> = line.split_whitespace().collect();
+    let state = CurrentPluginState().ok_or( "The plugin is not initialized" )?;
+
+    match words.as_slice() {
+        ["status"] => {
+            let config = &state.config;
+            let onOff = |b : bool| if b { "on" } else { "off" };
+            Ok( vec![
+                format!("version {}", env!("CARGO_PKG_VERSION")),
+                format!("pid {}", std::process::id()),
+                format!("config {}", PluginConfigPath().display()),
+                format!("audit {}", onOff( state.audit.is_some() )),
+                format!("siem {}", onOff( state.siem.is_some() )),
+                format!("metrics_file {}", onOff( state.metrics.is_some() )),
+                format!("lockout_threshold {}", config.lockoutThreshold),
+                format!("locked_users {}", lockout::LockedAccounts( config ).len()),
+                format!("users {}", state.users.Len()),
+            ])
+        },
+        ["reload"] => {
+            let started = Instant::now();
+            ReloadPluginState()?;
+            LogMessageToDb2Diag( LogModule::Control, Db2LogLevels::DB2SEC_LOG_WARNING,
+                                 "RUSTSECP configuration reloaded through the control socket" );
+            Ok( vec![ format!("reloaded in {} ms", started.elapsed().as_millis()) ] )
+        },
+        ["locked"] => {
+            Ok( lockout::LockedAccounts( &state.config )
+                    .into_iter()
+                    .map( |(user, at)| format!("{} locked since {}", user, FormatTimestamp( at )) )
+                    .collect() )
+        },
+        ["unlock", user] => {
+            if ! lockout::Unlock( user ) {
+                return Err( format!("{} is not locked", user) );
+            }
+            LogMessageToDb2Diag( LogModule::Control, Db2LogLevels::DB2SEC_LOG_WARNING,
+                                 &format!("RUSTSECP user {} unlocked through the control socket", user) );
+            Ok( Vec::new() )
+        },
+        ["metrics"] => {
+            Ok( metrics::RenderMetrics().lines().map( String::from ).collect() )
+        },
+        ["log-level", "reset"] => {
+            ClearLogLevelOverrides();
+            Ok( Vec::new() )
+        },
+        ["log-level", level] | ["log-level", level, _] => {
+            let level = Db2LogLevels::FromName( level ).ok_or( format!("unknown log level {}", level) )?;
+            let module = match words.get( 2 ) {
+                None => None,
+                Some(m) => Some( LogModule::FromName( m ).ok_or( format!("unknown log module {}", m) )? ),
+            };
+            SetLogLevelOverride( module, level );
+            LogMessageToDb2Diag( LogModule::Control, Db2LogLevels::DB2SEC_LOG_WARNING,
+                                 &format!("RUSTSECP log level of {} set to {:?} through the control socket",
+                                          module.map_or( "all modules", |m| m.Name() ), level) );
+            Ok( Vec::new() )
+        },
+        [] => Err( String::from("no command") ),
+        _ => Err( format!("unknown command: {}", line) ),
+    }
+}
@@ -0,0 +1,69 @@
+//-----------------------------------------------------------------------------
+// Correlation IDs.
+//
+// One connection attempt is several calls from Db2: ValidatePassword,
+// GetAuthIDs, the group lookups and finally FreeToken.  ValidatePassword
+// makes up a correlation ID and keeps it in the token, the later calls take
+// it from there.  While a call runs its ID is the current one for the
+// thread, and db2diag messages, error messages and audit records all carry
+// it, so one attempt can be followed end to end.
+//
+// Calls that are not part of a connection, such as DoesAuthIDExist, get an
+// ID of their own.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::auditchain::ToHex;
+
+thread_local! {
+    static CURRENT_ID : RefCell<Option<String>> = const { RefCell::new( None ) };
+}
+
+// 16 hex digits.  Random, so IDs from different members or restarts do
+// not collide.
+pub fn NewCorrelationId() -> String {
+    let mut bytes = [0u8; 8];
+
+    if getrandom::getrandom( &mut bytes ).is_err() {
+        // Unique enough within the process, which is all a trace needs.
+        static COUNTER : AtomicU64 = AtomicU64::new( 0 );
+        let nanos = SystemTime::now().duration_since( UNIX_EPOCH ).unwrap_or_default().as_nanos() as u64;
+        bytes = (nanos ^ COUNTER.fetch_add( 1, Ordering::Relaxed ).rotate_left( 48 )).to_be_bytes();
+    }
+
+    ToHex( &bytes )
+}
+
+pub fn CurrentCorrelationId() -> Option<String> {
+    CURRENT_ID.with( |c| c.borrow().clone() )
+}
+
+// Makes an ID the current one until the scope is dropped.  Enter it inside
+// CatchPanics, as working out the ID may panic, but keep it in a variable
+// of the API function, so a panic is still reported with the ID.
+pub struct CorrelationScope {
+    previous : Option<String>,
+}
+
+impl CorrelationScope {
+    pub fn Enter( id : Option<String> ) -> CorrelationScope {
+        CorrelationScope { previous : CURRENT_ID.with( |c| c.replace( id ) ) }
+    }
+}
+
+impl Drop for CorrelationScope {
+    fn drop( &mut self ) {
+        let previous = self.previous.take();
+        CURRENT_ID.with( |c| *c.borrow_mut() = previous );
+    }
+}
+
+// Mark a message with the current ID, if there is one.
+pub fn WithCorrelationId( msg : &str ) -> String {
+    match CurrentCorrelationId() {
+        Some(id) => format!("{} (correlation id {})", msg, id),
+        None => String::from( msg ),
+    }
+}
@@ -0,0 +1,393 @@
+//-----------------------------------------------------------------------------
+// Login decisions.
+//
+// What ValidatePassword decides for a userid and password, apart from the
+// Db2 calling convention around it.  The plugin and rustsecp-simulate run
+// this same code, the simulator with a Trace that records every step so an
+// operator can see why a user can or cannot connect.
+//
+// The order of the checks matters: lockout first, then the password, and
+// only once the password is right whether the account may be used, so
+// guessing does not reveal which accounts are disabled or expired.
+//
+// With uniform_failures on, an unknown user, a wrong password and a
+// lockout all look the same from outside: DB2SEC_PLUGIN_BADPWD with the
+// same message, after the same password check.  An unknown user is
+// checked against a dummy hash, a locked out user against the real one.
+// The real reason is still the rule, which only goes to the audit log.
+//
+// A decoy user or a canary password never connects, see userstore.rs.  To
+// whoever tries one it looks like any wrong password, after as long a
+// check, but the rule is honeypot-account or canary-password, and for those
+// ValidatePassword raises an alert.
+//
+// A user with a second factor, a TOTP or HOTP secret, has the code checked
+// once the password is right, see totp.rs and mfa.rs.  Recovery codes stand
+// in for the code, they are no factor of their own.  A missing, wrong or
+// reused code is answered like a wrong password and counts towards lockout
+// like one.  The code is only used up once the account checks have passed
+// as well, so a login that is refused after all does not burn a recovery
+// code or move a HOTP counter.  A local OS user without a password has no
+// way to give a code, and is refused if a code would be needed unless
+// totp_local_os_exempt is on.
+
+use std::time::{Instant, SystemTime};
+
+use crate::{ConnectionFlags, Db2LogLevels, Db2rc, LogMessageToDb2Diag};
+use crate::config::PluginConfig;
+use crate::diaglog::LogModule;
+use crate::lockout;
+use crate::metrics;
+use crate::secret::SecretString;
+use crate::mfa::{self, Accepted, Factor};
+use crate::userstore::UserStore;
+
+// What a client is told with uniform_failures on.
+const UNIFORM_MESSAGE : &str = "The userid or password is not valid";
+
+// A connection without a password must be all of these.
+const NO_PASSWORD_FLAGS : ConnectionFlags = ConnectionFlags::DB2SEC_USERID_FROM_OS
+                                            .union( ConnectionFlags::DB2SEC_CONNECTION_ISLOCAL )
+                                            .union( ConnectionFlags::DB2SEC_VALIDATING_ON_SERVER_SIDE );
+
+pub struct LoginAttempt<'a> {
+    pub userid : &'a str,
+    pub password : Option<&'a SecretString>,
+    pub flags : ConnectionFlags,
+    // None in the plugin: the lockout table of this process is consulted
+    // and updated.  The simulator passes what the running plugin reported
+    // and records nothing.
+    pub lockedOut : Option<bool>,
+}
+
+// What kind of failure a decision is.  uniform_failures, the delays of
+// tarpit.rs and the detection of detect.rs go by this, not by the rule.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Failure {
+    // The user connects.
+    None,
+    // The password does not match the stored hash.
+    WrongPassword,
+    // The password was right, the one-time or recovery code was missing,
+    // wrong or used already.
+    WrongCode,
+    UnknownUser,
+    // Locked out, the password was not looked at for the outcome.
+    LockedOut,
+    // A decoy user or canary password.
+    Trap,
+    // The password was right, or not needed, but the account may not be
+    // used: disabled, expired, or without a second factor it needs.
+    AccountRefused,
+    // No password, and not a local OS user.
+    NoPassword,
+    // The plugin could not decide, e.g. the .mfa file cannot be written.
+    PluginError,
+}
+
+impl Failure {
+    // A guess at a userid, password or code.  uniform_failures answers
+    // these alike, and they are delayed and watched for attacks.
+    pub fn IsGuess( self ) -> bool {
+        matches!( self, Failure::WrongPassword | Failure::WrongCode | Failure::UnknownUser |
+                        Failure::LockedOut | Failure::Trap )
+    }
+
+    // Whether the password given is known to be wrong.  It is not for a
+    // locked out user, and was right with a wrong code.
+    pub fn PasswordWasWrong( self ) -> bool {
+        matches!( self, Failure::WrongPassword | Failure::UnknownUser | Failure::Trap )
+    }
+}
+
+pub struct Decision {
+    pub rc : Db2rc,
+    // For the audit record, e.g. "password-mismatch".
+    pub rule : &'static str,
+    pub failure : Failure,
+    // The authid the user connects as, when the decision is OK.
+    pub authid : Option<String>,
+    // The error message for Db2, if any.
+    pub message : Option<String>,
+}
+
+impl Decision {
+    fn Reject( rc : Db2rc, rule : &'static str, failure : Failure, message : Option<String> ) -> Decision {
+        Decision { rc, rule, failure, authid : None, message }
+    }
+}
+
+// The steps of a decision, only collected when asked for.
+pub struct Trace {
+    steps : Option<Vec<String>>,
+}
+
+impl Trace {
+    pub fn Off() -> Trace {
+        Trace { steps : None }
+    }
+
+    pub fn On() -> Trace {
+        Trace { steps : Some( Vec::new() ) }
+    }
+
+    // The text is only built when tracing.
+    pub fn Step<F : FnOnce() -> String>( &mut self, text : F ) {
+        if let Some(steps) = self.steps.as_mut() {
+            steps.push( text() );
+        }
+    }
+
+    pub fn Steps( self ) -> Vec<String> {
+        self.steps.unwrap_or_default()
+    }
+}
+
+pub fn DecideLogin( users : &UserStore,
+                    config : &PluginConfig,
+                    attempt : &LoginAttempt,
+                    now : SystemTime,
+                    trace : &mut Trace ) -> Decision {
+    let decision = Decide( users, config, attempt, now, trace );
+    if ! config.uniformFailures || ! decision.failure.IsGuess() {
+        return decision;
+    }
+
+    trace.Step( || format!("uniform_failures: reported as DB2SEC_PLUGIN_BADPWD instead of {:?}", decision.rc) );
+    Decision::Reject( Db2rc::DB2SEC_PLUGIN_BADPWD, decision.rule, decision.failure, Some( String::from( UNIFORM_MESSAGE ) ) )
+}
+
+fn Decide( users : &UserStore,
+           config : &PluginConfig,
+           attempt : &LoginAttempt,
+           now : SystemTime,
+           trace : &mut Trace ) -> Decision {
+    let userid = attempt.userid;
+    let user = users.Get( userid );
+    trace.Step( || match user {
+        Some(u) => format!("user store: found {} (authid {}, groups {})",
+                           u.userid, u.Authid(),
+                           if u.groups.is_empty() { String::from( "none" ) } else { u.groups.join( "," ) }),
+        None => format!("user store: no user {}", userid),
+    });
+
+    let decoy = user.is_some_and( |u| u.decoy );
+    if decoy {
+        trace.Step( || String::from( "user store: a decoy, it never connects" ) );
+    }
+
+    // A second factor that was right, to be used up once the rest passes.
+    let mut factorToUse = None;
+    if let Some(pw) = attempt.password {
+        trace.Step( || format!("password given, {} bytes", pw.Len()) );
+
+        // With a second factor the code comes after the password.
+        let mfaUser = user.filter( |u| mfa::HasSecondFactor( u ) );
+        let split = mfaUser.and_then( |u| mfa::SplitFactor( pw.Expose(), config.totpSeparator.as_deref(), u ) );
+        let password = split.map_or( pw.Expose(), |(p, _)| p );
+        if mfaUser.is_some() {
+            trace.Step( || match split {
+                Some((_, Factor::Code(_))) => String::from( "mfa: the user has a second factor, the last part of the password is a one-time code" ),
+                Some((_, Factor::Recovery(_))) => String::from( "mfa: the user has a second factor, the last part of the password is a recovery code" ),
+                None => String::from( "mfa: the user has a second factor, but no code was given with the password" ),
+            });
+        }
+
+        // Whatever the stored hash says, these are wrong passwords.
+        let canary = users.IsCanary( pw.Expose() ) || (split.is_some() && users.IsCanary( password ));
+        if canary {
+            trace.Step( || String::from( "password: a canary, it never connects" ) );
+        }
+        let trap = if decoy { Some( "honeypot-account" ) } else if canary { Some( "canary-password" ) } else { None };
+        // The rule and failure of a refusal, unless it was a trap.
+        let trapOr = |rule, failure| match trap {
+            Some(t) => (t, Failure::Trap),
+            None => (rule, failure),
+        };
+
+        let locked = match attempt.lockedOut {
+            Some(l) => l,
+            None => lockout::IsLocked( userid, config ),
+        };
+        trace.Step( || match (config.lockoutThreshold, locked) {
+            (0, _) => String::from( "lockout: off, lockout_threshold is 0" ),
+            (_, false) => String::from( "lockout: not locked out" ),
+            (_, true) => String::from( "lockout: locked out after too many wrong passwords" ),
+        });
+        if locked {
+            if config.uniformFailures {
+                // Take as long as a login that is not locked out.
+                match user {
+                    Some(u) => { u.CheckPassword( password ); },
+                    None => users.CheckDummyPassword( pw.Expose() ),
+                }
+            }
+            let (rule, failure) = trapOr( "account-locked", Failure::LockedOut );
+            return Decision::Reject( Db2rc::DB2SEC_PLUGIN_USER_SUSPENDED, rule, failure,
+                                     Some( format!("The user is locked out: {}", userid) ) );
+        }
+
+        let backendStarted = Instant::now();
+        let matches = user.map( |u| match u.password.is_empty() {
+            // Decoys often have no password, take as long as if they had.
+            true if decoy => { users.CheckDummyPassword( pw.Expose() ); false },
+            _ => u.CheckPassword( password ) && trap.is_none(),
+        });
+        metrics::ObserveBackendLatency( backendStarted.elapsed() );
+
+        match matches {
+            None if config.uniformFailures => {
+                users.CheckDummyPassword( pw.Expose() );
+                trace.Step( || String::from( "password: checked against a dummy hash, the user does not exist" ) );
+                let (rule, failure) = trapOr( "unknown-user", Failure::UnknownUser );
+                return Decision::Reject( Db2rc::DB2SEC_PLUGIN_BADUSER, rule, failure, None );
+            },
+            None => {
+                trace.Step( || String::from( "password: not checked, the user does not exist" ) );
+                let (rule, failure) = trapOr( "unknown-user", Failure::UnknownUser );
+                return Decision::Reject( Db2rc::DB2SEC_PLUGIN_BADUSER, rule, failure,
+                                         Some( format!("The password is bad for user: {}", userid) ) );
+            },
+            Some(false) => {
+                trace.Step( || String::from( "password: does not match the stored hash" ) );
+                let (rule, failure) = trapOr( "password-mismatch", Failure::WrongPassword );
+                return WrongPassword( userid, rule, failure, attempt, config );
+            },
+            Some(true) => {
+                trace.Step( || String::from( "password: matches the stored hash" ) );
+                if let Some(u) = mfaUser {
+                    let checked = match split {
+                        None => Err( mfa::Refusal { rule : "otp-missing", error : None } ),
+                        Some((_, factor)) => mfa::CheckFactor( u, factor, config.userStore.as_deref(),
+                                                               config.totpSkewSteps, config.hotpWindow,
+                                                               now, false ),
+                    };
+                    trace.Step( || match &checked {
+                        Ok(Accepted::Totp) => String::from( "mfa: the TOTP code is right" ),
+                        Ok(Accepted::Hotp(c)) => format!("mfa: the HOTP code is right, counter {}", c),
+                        Ok(Accepted::Recovery(left)) => format!("mfa: the recovery code is right, {} left", left),
+                        Err(r) => match (r.rule, &r.error) {
+                            (_, Some(e)) => format!("mfa: cannot check the code: {}", e),
+                            ("totp-replayed", _) => String::from( "mfa: the TOTP code was used already" ),
+                            ("recovery-code-reused", _) => String::from( "mfa: the recovery code was used already" ),
+                            _ => format!("mfa: the code is wrong or missing, {} TOTP steps of skew and {} HOTP counters allowed",
+                                         config.totpSkewSteps, config.hotpWindow),
+                        },
+                    });
+                    if let Err(r) = checked {
+                        return FactorRefused( userid, r, attempt, config );
+                    }
+                    factorToUse = split.map( |(_, f)| (u, f) );
+                }
+                if attempt.lockedOut.is_none() {
+                    lockout::RecordSuccess( userid );
+                }
+            },
+        }
+    }
+    else {
+        /* No password was supplied.  This is okay as long
+         * as the following conditions are true:
+         *
+         *  - The username came from WhoAmI(), and
+         *  - If we're on the server side, the connection must
+         *    be "local" (originating from the same machine)
+         *
+         * Note that "DB2SEC_USERID_FROM_OS" means that the userid
+         * was obtained from the plugin by calling the function
+         * supplied for "db2secGetDefaultLoginContext".
+         */
+        let local = attempt.flags.contains( NO_PASSWORD_FLAGS );
+        trace.Step( || format!("no password: connection flags {:?}, {}", attempt.flags,
+                               if local { "a local OS user" }
+                               else { "not allowed, a local OS user needs USERID_FROM_OS, CONNECTION_ISLOCAL and VALIDATING_ON_SERVER_SIDE" }) );
+        if ! local {
+            return Decision::Reject( Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR, "no-password-not-local", Failure::NoPassword, None );
+        }
+    }
+
+    // The password is right, or not needed.  Only now tell whether the
+    // account may be used.
+    if let Some(u) = user {
+        if decoy {
+            // Only a local OS user without a password gets here.
+            return Decision::Reject( Db2rc::DB2SEC_PLUGIN_USER_REVOKED, "honeypot-account", Failure::Trap,
+                                     Some( format!("The user is locked: {}", userid) ) );
+        }
+        if u.locked {
+            trace.Step( || String::from( "account: disabled in the user store" ) );
+            return Decision::Reject( Db2rc::DB2SEC_PLUGIN_USER_REVOKED, "account-disabled", Failure::AccountRefused,
+                                     Some( format!("The user is locked: {}", userid) ) );
+        }
+        if u.IsExpired( now ) {
+            trace.Step( || format!("account: expired, the last day was {}", u.expires.as_deref().unwrap_or( "" )) );
+            return Decision::Reject( Db2rc::DB2SEC_PLUGIN_UID_EXPIRED, "account-expired", Failure::AccountRefused,
+                                     Some( format!("The user has expired: {}", userid) ) );
+        }
+        let mustHaveTotp = u.groups.iter().find( |g| config.totpRequiredGroups.contains( g ) );
+        let enrolled = mfa::HasSecondFactor( u );
+        let exempt = attempt.password.is_none() && config.totpLocalOsExempt;
+        if let (Some(group), false, false) = (mustHaveTotp, enrolled, exempt) {
+            trace.Step( || format!("account: in {}, which totp_required_groups needs a TOTP or HOTP secret for, it has none", group) );
+            return Decision::Reject( Db2rc::DB2SEC_PLUGIN_USER_REVOKED, "totp-not-enrolled", Failure::AccountRefused,
+                                     Some( format!("The user needs a one-time code and has no TOTP or HOTP secret: {}", userid) ) );
+        }
+        if enrolled && attempt.password.is_none() && ! exempt {
+            trace.Step( || String::from( "account: has a second factor, which a login without a password cannot give, \
+                                          and totp_local_os_exempt is off" ) );
+            return Decision::Reject( Db2rc::DB2SEC_PLUGIN_USER_REVOKED, "local-os-needs-otp", Failure::AccountRefused,
+                                     Some( format!("The user needs a one-time code, which a login without a password cannot give: {}", userid) ) );
+        }
+        trace.Step( || format!("account: enabled, expires {}", u.expires.as_deref().unwrap_or( "never" )) );
+    }
+
+    // Everything else passed, use the code up.  Checked again, another
+    // login may have used it in the meantime.
+    if let (Some((u, factor)), None) = (factorToUse, attempt.lockedOut) {
+        match mfa::CheckFactor( u, factor, config.userStore.as_deref(), config.totpSkewSteps, config.hotpWindow, now, true ) {
+            Ok(Accepted::Recovery(left)) => {
+                LogMessageToDb2Diag( LogModule::Auth, Db2LogLevels::DB2SEC_LOG_WARNING,
+                                     &format!("RUSTSECP user {} connected with a recovery code, {} left", userid, left) );
+            },
+            Ok(_) => {},
+            Err(r) => return FactorRefused( userid, r, attempt, config ),
+        }
+    }
+
+    // A user can be mapped to a different authid in the user store.
+    let authid = user.map_or_else( || userid.to_uppercase(), |u| u.Authid() );
+    trace.Step( || format!("authid: {} ({})", authid,
+                           if user.is_some_and( |u| u.authid.is_some() ) { "mapped in the user store" }
+                           else { "the userid in upper case" }) );
+
+    Decision {
+        rc : Db2rc::DB2SEC_PLUGIN_OK,
+        rule : if attempt.password.is_some() { "password-match" } else { "local-os-user" },
+        failure : Failure::None,
+        authid : Some( authid ),
+        message : None,
+    }
+}
+
+// A second factor that is wrong, used already, or cannot be checked.
+fn FactorRefused( userid : &str, refusal : mfa::Refusal, attempt : &LoginAttempt, config : &PluginConfig ) -> Decision {
+    match refusal {
+        mfa::Refusal { rule, error : Some(e) } => {
+            LogMessageToDb2Diag( LogModule::Auth, Db2LogLevels::DB2SEC_LOG_ERROR,
+                                 &format!("RUSTSECP cannot check the one-time code of {}: {}", userid, e) );
+            Decision::Reject( Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR, rule, Failure::PluginError,
+                              Some( String::from( "The one-time code cannot be checked" ) ) )
+        },
+        mfa::Refusal { rule, error : None } => WrongPassword( userid, rule, Failure::WrongCode, attempt, config ),
+    }
+}
+
+// A wrong password, or a wrong code, counts towards lockout.
+fn WrongPassword( userid : &str, rule : &'static str, failure : Failure, attempt : &LoginAttempt, config : &PluginConfig ) -> Decision {
+    if attempt.lockedOut.is_none() && lockout::RecordFailure( userid, config ) {
+        LogMessageToDb2Diag( LogModule::Auth, Db2LogLevels::DB2SEC_LOG_WARNING,
+                             &format!("RUSTSECP user {} locked out after {} wrong passwords",
+                                      userid, config.lockoutThreshold) );
+    }
+    Decision::Reject( Db2rc::DB2SEC_PLUGIN_BADPWD, rule, failure, Some( String::from( "The password is bad for the user" ) ) )
+}
@@ -0,0 +1,202 @@
+//-----------------------------------------------------------------------------
+// Password spray and credential stuffing detection.
+//
+// Lockout and the progressive delay count failures per user, so an attack
+// spread thinly over many userids stays under both.  Two patterns are
+// watched for across users instead, within detect_window_seconds:
+//
+//    credential stuffing  stuffing_userids distinct userids fail from one
+//                         client address
+//    password spray       the same wrong password fails for spray_userids
+//                         distinct userids, from any number of addresses
+//
+// Wrong passwords are never kept.  Each is reduced to a fingerprint, an
+// HMAC under a random key that is made once per process and never leaves
+// its memory, so the table can neither be used to recover a password nor
+// be compared with another process.  Passwords of locked accounts may have
+// been right and are not fingerprinted.
+//
+// When a pattern is found ValidatePassword logs it to db2diag and writes an
+// alert record to the audit log and SIEM feed, once per window for the same
+// address or password.  With detect_block_seconds set, the address the
+// attempt came from is then refused with DB2SEC_PLUGIN_CONNECTION_DISALLOWED
+// for that long, before any password is checked.  A spray from many
+// addresses gets each of them blocked as it tries the password again.
+//
+// Setting both thresholds to 0 turns all of this off, blocks included.  The
+// tables are bounded and process wide, a reload keeps them.  In a full
+// table the least recently seen entry makes room, see lru.rs.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::PluginConfig;
+use crate::lru::LruTable;
+use crate::secret::SecretString;
+
+const MAX_ENTRIES : usize = 100_000;
+
+type Fingerprint = [u8; 16];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Pattern {
+    CredentialStuffing,
+    PasswordSpray,
+}
+
+impl Pattern {
+    // Also the rule of the alert record.
+    pub fn Name( &self ) -> &'static str {
+        match self {
+            Pattern::CredentialStuffing => "credential-stuffing",
+            Pattern::PasswordSpray => "password-spray",
+        }
+    }
+}
+
+// A failed login that completed a pattern.
+pub struct Detection {
+    pub pattern : Pattern,
+    // Distinct userids seen failing within the window.
+    pub userids : usize,
+    // False if this address or password was alerted on within the window.
+    pub alert : bool,
+    // Whether the client address was blocked for it.
+    pub blocked : bool,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Client(IpAddr),
+    Password(Fingerprint),
+}
+
+struct Sightings {
+    // Lower case userids and when each last failed.  Never more than the
+    // threshold, the oldest gives way.
+    userids : HashMap<String, Instant>,
+    alerted : Option<Instant>,
+}
+
+struct Detector {
+    sightings : LruTable<Key, Sightings>,
+    // Blocked addresses and until when.
+    blocked : LruTable<IpAddr, Instant>,
+}
+
+static DETECTOR : Mutex<Option<Detector>> = Mutex::new( None );
+
+fn Tables() -> MutexGuard<'static, Option<Detector>> {
+    match DETECTOR.lock() {
+        Ok(d) => d,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn FingerprintKey() -> &'static [u8; 32] {
+    static KEY : OnceLock<[u8; 32]> = OnceLock::new();
+    KEY.get_or_init( || {
+        let mut key = [0u8; 32];
+        // Without randomness fingerprints could be matched offline, better fail the call.
+        getrandom::getrandom( &mut key ).expect( "no random numbers for the fingerprint key" );
+        key
+    })
+}
+
+fn FingerprintOf( password : &SecretString ) -> Fingerprint {
+    let mut mac = Hmac::<Sha256>::new_from_slice( FingerprintKey() ).expect( "HMAC accepts any key length" );
+    mac.update( b"rustsecp-spray" );
+    mac.update( password.Expose().as_bytes() );
+    let mut fingerprint = Fingerprint::default();
+    fingerprint.copy_from_slice( &mac.finalize().into_bytes()[..16] );
+    fingerprint
+}
+
+// Whether attempts from client are refused for now.
+pub fn IsBlocked( client : Option<IpAddr> ) -> bool {
+    let client = match client {
+        None => return false,
+        Some(c) => c,
+    };
+
+    let mut tables = Tables();
+    let blocked = match tables.as_mut() {
+        None => return false,
+        Some(t) => &mut t.blocked,
+    };
+    match blocked.Get( &client ) {
+        None => false,
+        Some(until) if Instant::now() >= *until => {
+            blocked.Remove( &client );
+            false
+        },
+        Some(_) => true,
+    }
+}
+
+// A failed login.  password is None when it was not checked against the
+// user's own.  Returns the patterns this failure completed.
+pub fn RecordFailure( userid : &str,
+                      password : Option<&SecretString>,
+                      client : Option<IpAddr>,
+                      config : &PluginConfig ) -> Vec<Detection> {
+    let mut found = Vec::new();
+    let mut sightings : Vec<(Pattern, Key, usize)> = Vec::new();
+    if let (Some(c), n) = (client, config.stuffingUserids) {
+        if n > 0 {
+            sightings.push( (Pattern::CredentialStuffing, Key::Client( c ), n) );
+        }
+    }
+    if let (Some(p), n) = (password, config.sprayUserids) {
+        if n > 0 {
+            sightings.push( (Pattern::PasswordSpray, Key::Password( FingerprintOf( p ) ), n) );
+        }
+    }
+    if sightings.is_empty() {
+        return found;
+    }
+
+    let window = Duration::from_secs( config.detectWindowSeconds );
+    let now = Instant::now();
+    let userid = userid.to_lowercase();
+
+    let mut tables = Tables();
+    let tables = tables.get_or_insert_with( || Detector { sightings : LruTable::New(), blocked : LruTable::New() } );
+    tables.sightings.DropStale( |_, s| s.userids.values().all( |t| now.duration_since( *t ) >= window ) );
+    tables.blocked.DropStale( |_, until| now >= *until );
+
+    for (pattern, key, threshold) in sightings {
+        let s = tables.sightings.Touch( key, MAX_ENTRIES, || Sightings { userids : HashMap::new(), alerted : None } );
+        s.userids.retain( |_, t| now.duration_since( *t ) < window );
+        s.userids.insert( userid.clone(), now );
+        if s.userids.len() > threshold {
+            let oldest = s.userids.iter().min_by_key( |(_, t)| **t ).map( |(u, _)| u.clone() );
+            if let Some(u) = oldest {
+                s.userids.remove( &u );
+            }
+        }
+        if s.userids.len() < threshold {
+            continue;
+        }
+
+        let alert = s.alerted.is_none_or( |t| now.duration_since( t ) >= window );
+        if alert {
+            s.alerted = Some( now );
+        }
+        let userids = s.userids.len();
+
+        let mut blocked = false;
+        if let (Some(c), true) = (client, config.detectBlockSeconds > 0) {
+            *tables.blocked.Touch( c, MAX_ENTRIES, || now ) = now + Duration::from_secs( config.detectBlockSeconds );
+            blocked = true;
+        }
+
+        found.push( Detection { pattern, userids, alert, blocked } );
+    }
+    found
+}
@@ -0,0 +1,189 @@
+//-----------------------------------------------------------------------------
+// Diagnostic logging to db2diag.log.
+//
+// Every message belongs to a module of the plugin and has a Db2 log level.
+// The plugin configuration sets the level that is logged, for the plugin as
+// a whole (log_level) and per module (log_filter).  Both take effect on the
+// next reload.  An operator can override them through the control socket
+// until the next reload.
+//
+// Each module may log at most log_rate_limit messages per minute, so a
+// password spraying attack cannot flood db2diag.log.  Messages over the
+// limit are counted, and the count is logged ahead of the module's next
+// message once a new minute has started.
+// Critical messages are never held back.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::Db2LogLevels;
+
+const RATE_WINDOW : Duration = Duration::from_secs( 60 );
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogModule {
+    // Init, term and memory management.
+    Plugin = 0,
+    // Authentication decisions.
+    Auth,
+    // Decoding of input from Db2.
+    Input,
+    Audit,
+    Siem,
+    // Operator commands on the control socket.
+    Control,
+}
+
+const MODULE_COUNT : usize = 6;
+
+impl LogModule {
+    pub fn FromName( name : &str ) -> Option<LogModule> {
+        match name.to_ascii_lowercase().as_str() {
+            "plugin"  => Some( LogModule::Plugin ),
+            "auth"    => Some( LogModule::Auth ),
+            "input"   => Some( LogModule::Input ),
+            "audit"   => Some( LogModule::Audit ),
+            "siem"    => Some( LogModule::Siem ),
+            "control" => Some( LogModule::Control ),
+            _         => None,
+        }
+    }
+
+    pub fn Name( &self ) -> &'static str {
+        match self {
+            LogModule::Plugin  => "plugin",
+            LogModule::Auth    => "auth",
+            LogModule::Input   => "input",
+            LogModule::Audit   => "audit",
+            LogModule::Siem    => "siem",
+            LogModule::Control => "control",
+        }
+    }
+}
+
+impl Db2LogLevels {
+    pub fn FromName( name : &str ) -> Option<Db2LogLevels> {
+        match name.to_ascii_lowercase().as_str() {
+            "none"     => Some( Db2LogLevels::DB2SEC_LOG_NONE ),
+            "critical" => Some( Db2LogLevels::DB2SEC_LOG_CRITICAL ),
+            "error"    => Some( Db2LogLevels::DB2SEC_LOG_ERROR ),
+            "warning"  => Some( Db2LogLevels::DB2SEC_LOG_WARNING ),
+            "info"     => Some( Db2LogLevels::DB2SEC_LOG_INFO ),
+            _          => None,
+        }
+    }
+
+    fn FromCode( code : i32 ) -> Option<Db2LogLevels> {
+        [Db2LogLevels::DB2SEC_LOG_NONE, Db2LogLevels::DB2SEC_LOG_CRITICAL, Db2LogLevels::DB2SEC_LOG_ERROR,
+         Db2LogLevels::DB2SEC_LOG_WARNING, Db2LogLevels::DB2SEC_LOG_INFO]
+            .into_iter()
+            .find( |l| *l as i32 == code )
+    }
+}
+
+// "module=level, module=level"
+pub fn ParseLogFilter( text : &str ) -> Result<Vec<(LogModule, Db2LogLevels)>, String> {
+    let mut filter = Vec::new();
+
+    for item in text.split(',').map( str::trim ).filter( |s| ! s.is_empty() ) {
+        let (module, level) = item.split_once('=')
+                                  .ok_or_else( || format!("{} is not module=level", item) )?;
+        let module = LogModule::FromName( module.trim() )
+                         .ok_or_else( || format!("unknown log module {}", module.trim()) )?;
+        let level = Db2LogLevels::FromName( level.trim() )
+                        .ok_or_else( || format!("unknown log level {}", level.trim()) )?;
+        filter.push( (module, level) );
+    }
+
+    Ok( filter )
+}
+
+//-----------------------------------------------------------------------------
+// Operator overrides, one per module and the last one for all modules.
+// NO_OVERRIDE means the configuration applies.
+
+const NO_OVERRIDE : i32 = -1;
+
+static OVERRIDES : [AtomicI32; MODULE_COUNT + 1] = [const { AtomicI32::new( NO_OVERRIDE ) }; MODULE_COUNT + 1];
+
+// None sets the level for all modules.
+pub fn SetLogLevelOverride( module : Option<LogModule>, level : Db2LogLevels ) {
+    let slot = module.map_or( MODULE_COUNT, |m| m as usize );
+    OVERRIDES[slot].store( level as i32, Ordering::Relaxed );
+}
+
+pub fn ClearLogLevelOverrides() {
+    for o in OVERRIDES.iter() {
+        o.store( NO_OVERRIDE, Ordering::Relaxed );
+    }
+}
+
+fn LogLevelOverride( slot : usize ) -> Option<Db2LogLevels> {
+    Db2LogLevels::FromCode( OVERRIDES[slot].load( Ordering::Relaxed ) )
+}
+
+// Is a message of this module and level to be logged at all?
+pub fn LogEnabled( level : Db2LogLevels,
+                   filter : &[(LogModule, Db2LogLevels)],
+                   module : LogModule,
+                   msgLevel : Db2LogLevels ) -> bool {
+    let threshold = LogLevelOverride( module as usize )
+        .or_else( || LogLevelOverride( MODULE_COUNT ) )
+        .unwrap_or_else( || filter.iter()
+                                  .rev()
+                                  .find( |(m, _)| *m == module )
+                                  .map_or( level, |(_, l)| *l ) );
+
+    msgLevel != Db2LogLevels::DB2SEC_LOG_NONE && msgLevel as i32 <= threshold as i32
+}
+
+//-----------------------------------------------------------------------------
+// Rate limiting.  The windows are process wide so they carry over a reload.
+
+#[derive(Clone, Copy)]
+struct RateWindow {
+    start : Option<Instant>,
+    logged : u32,
+    suppressed : u64,
+}
+
+static RATE_WINDOWS : Mutex<[RateWindow; MODULE_COUNT]> =
+    Mutex::new( [RateWindow { start : None, logged : 0, suppressed : 0 }; MODULE_COUNT] );
+
+pub enum RateDecision {
+    // Log the message.  The number is how many messages of the module were
+    // held back in the window before, to be reported first.
+    Log( u64 ),
+    Suppress,
+}
+
+// limit is messages per minute, 0 means no limit.
+pub fn RateLimit( module : LogModule, msgLevel : Db2LogLevels, limit : u32 ) -> RateDecision {
+    if limit == 0 || msgLevel == Db2LogLevels::DB2SEC_LOG_CRITICAL {
+        return RateDecision::Log( 0 );
+    }
+
+    let mut windows = match RATE_WINDOWS.lock() {
+        Ok(w) => w,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let window = &mut windows[module as usize];
+    let now = Instant::now();
+
+    let mut held = 0;
+    match window.start {
+        Some(start) if now.duration_since( start ) < RATE_WINDOW => {},
+        _ => {
+            held = window.suppressed;
+            *window = RateWindow { start : Some( now ), logged : 0, suppressed : 0 };
+        }
+    }
+
+    if window.logged >= limit {
+        window.suppressed += 1;
+        return RateDecision::Suppress;
+    }
+    window.logged += 1;
+    RateDecision::Log( held )
+}
@@ -0,0 +1,326 @@
+//-----------------------------------------------------------------------------
+// Group plugin.
+//
+// Db2 does not take groups from the server auth plugin, it asks the group
+// plugin named by the group_plugin database manager setting.  Without one
+// the groups kept in the user store would never reach Db2 and authorities
+// granted to those groups would not apply.  So the same library is also a
+// group plugin: GetGroupsForUser and DoesGroupExist answer from the user
+// store the server auth plugin uses.
+//
+// When Db2 loads both, they share one PluginState, whichever is initialized
+// first builds it and it goes once both are terminated, see state.rs.
+// During a connect GetGroupsForUser gets the token of ValidatePassword and
+// carries on with its correlation ID, so the group lookup is in the same
+// trace as the login.
+
+use std::os::raw::{c_char, c_void};
+use std::time::Instant;
+
+use crate::{AbandonInitErrorMessage, AllocateDb2ErrorMessage, CatchPanics, CheckedToken, ConvertEngineString,
+            ConvertToOptionalString, Db2LogLevels, Db2rc, FreeErrorMsg, IsOwnPluginName, LogMessageFuncT,
+            LogMessageToDb2Diag, LogMessageWithCallback, RejectFree, TokenCorrelationId, TokenUser, WriteAuditRecord,
+            db2secGroupFunctions_1, SQL_API_RC, DB2SEC_GENERIC, DB2SEC_GROUP_FUNCTIONS_VERSION_1, DB2SEC_MAX_AUTHID_LENGTH,
+            DB2SEC_MAX_USERID_LENGTH, DB2SEC_MAX_USERNAMESPACE_LENGTH, DB2SEC_PLUGIN_TYPE_GROUP};
+use crate::allocations::{self, AllocationKind};
+use crate::audit::{AuditCall, AuditRecord};
+use crate::correlation::{CorrelationScope, NewCorrelationId};
+use crate::diaglog::LogModule;
+use crate::metrics;
+use crate::state::{PluginState, CurrentPluginState, InstallPluginState, AttachPluginState, DetachPluginState,
+                   GROUP_PLUGIN};
+
+extern "C" fn GetGroupsForUser
+(
+    authid : * const c_char,
+    authidlen : i32,
+    userid : * const c_char,
+    useridlen : i32,
+    usernamespace : * const c_char,
+    usernamespacelen : i32,
+    usernamespacetype : i32,
+    dbname : * const c_char,
+    dbnamelen : i32,
+    token : * mut c_void,
+    tokentype : i32,
+    location : i32,
+    authpluginname : * const c_char,
+    authpluginnamelen : i32,
+    grouplist : * mut * mut c_void,
+    numgroups : * mut i32,
+    errormsg : * mut * mut c_char,
+    errormsglen : * mut i32
+) -> SQL_API_RC {
+    let mut correlation = None;
+    let mut audit = None;
+    let rc = CatchPanics( "GetGroupsForUser", errormsg, errormsglen, || {
+        // During a connect the token comes from ValidatePassword, carry on
+        // with its correlation ID.
+        let ownToken = tokentype == DB2SEC_GENERIC && IsOwnPluginName( authpluginname, authpluginnamelen );
+        let correlationId = if ownToken { TokenCorrelationId( token ) } else { None }.unwrap_or_else( NewCorrelationId );
+        correlation = Some( CorrelationScope::Enter( Some( correlationId.clone() ) ) );
+        let audit = audit.insert( AuditRecord::New( AuditCall::GetGroupsForUser, &correlationId ) );
+
+        audit.rule = Some( "invalid-input" );
+
+        if grouplist.is_null() || numgroups.is_null() {
+            return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+        }
+
+        let optAuthid = match ConvertEngineString( authid,
+                                                   authidlen,
+                                                   DB2SEC_MAX_AUTHID_LENGTH,
+                                                   "GetGroupsForUser",
+                                                   "authid",
+                                                   errormsg, errormsglen ) {
+            Ok(o) => o,
+            Err(e) => {return e as SQL_API_RC;}
+        };
+        audit.authid = optAuthid.clone();
+
+        let optUserid = match ConvertToOptionalString( userid,
+                                                       useridlen,
+                                                       DB2SEC_MAX_USERID_LENGTH,
+                                                       "GetGroupsForUser",
+                                                       "userid",
+                                                       errormsg, errormsglen ) {
+            Ok(o) => o,
+            Err(e) => {return e as SQL_API_RC;}
+        };
+        audit.userid = optUserid.clone();
+
+        let optUserNamespace = match ConvertToOptionalString( usernamespace,
+                                                              usernamespacelen,
+                                                              DB2SEC_MAX_USERNAMESPACE_LENGTH,
+                                                              "GetGroupsForUser",
+                                                              "usernamespace",
+                                                              errormsg, errormsglen ) {
+            Ok(o) => o,
+            Err(e) => {return e as SQL_API_RC;}
+        };
+
+        let state = match CurrentPluginState() {
+            None => {
+                audit.rule = Some( "plugin-not-initialized" );
+                return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+            }
+            Some(st) => st
+        };
+
+        // Our own token must be for the user whose groups are asked for.
+        if ownToken && ! token.is_null() {
+            let user = TokenUser { userid : optUserid.as_deref(),
+                                   usernamespace : optUserNamespace.as_deref(),
+                                   authid : optAuthid.as_deref() };
+            if let Err(rc) = CheckedToken( "GetGroupsForUser", token, &user, &state.config, errormsg, errormsglen ) {
+                audit.rule = Some( "token-refused" );
+                return rc;
+            }
+        }
+
+        let backendStarted = Instant::now();
+        let user = state.users.ForGroups( optAuthid.as_deref(), optUserid.as_deref() );
+        metrics::ObserveBackendLatency( backendStarted.elapsed() );
+
+        // A user Db2 authenticated some other way is simply in no groups.
+        let groups : Vec<String> = match user {
+            Some(u) => {
+                audit.rule = Some( "groups-found" );
+                u.groups.clone()
+            },
+            None => {
+                audit.rule = Some( "user-not-found" );
+                Vec::new()
+            }
+        };
+
+        let (list, written) = GroupList( &groups );
+
+        // Db2 hands the list back to FreeGroupListMemory.
+        let mem = allocations::AllocateTagged( &list, AllocationKind::GroupList, "GetGroupsForUser" );
+        unsafe {
+            *grouplist = mem as * mut c_void;
+            *numgroups = written;
+        }
+        audit.groups = Some( groups );
+
+        Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+    });
+
+    if let Some(audit) = &audit {
+        WriteAuditRecord( audit, rc );
+    }
+    drop( correlation );
+    rc
+}
+
+// The list GetGroupsForUser returns: the groups one after the other, each
+// preceded by a single byte with its length, and how many are in it.
+// Group names are at most 128 bytes, the store makes sure of that, and
+// numgroups must count only what is in the list or Db2 reads past its end.
+pub(crate) fn GroupList( groups : &[String] ) -> (Vec<u8>, i32) {
+    let mut list : Vec<u8> = Vec::new();
+    let mut written = 0;
+    for g in groups.iter().filter( |g| g.len() <= u8::MAX as usize ) {
+        list.push( g.len() as u8 );
+        list.extend_from_slice( g.as_bytes() );
+        written += 1;
+    }
+    (list, written)
+}
+
+extern "C" fn DoesGroupExist
+(
+    groupname : * const c_char,
+    groupnamelen : i32,
+    errormsg : * mut * mut c_char,
+    errormsglen : * mut i32
+) -> SQL_API_RC {
+    let mut correlation = None;
+    let mut audit = None;
+    let rc = CatchPanics( "DoesGroupExist", errormsg, errormsglen, || {
+        let correlationId = NewCorrelationId();
+        correlation = Some( CorrelationScope::Enter( Some( correlationId.clone() ) ) );
+        let audit = audit.insert( AuditRecord::New( AuditCall::DoesGroupExist, &correlationId ) );
+
+        audit.rule = Some( "invalid-input" );
+
+        let group = match ConvertEngineString( groupname,
+                                               groupnamelen,
+                                               DB2SEC_MAX_AUTHID_LENGTH,
+                                               "DoesGroupExist",
+                                               "groupname",
+                                               errormsg, errormsglen ) {
+            Ok(Some(g)) => g,
+            Ok(None) => {return Db2rc::DB2SEC_PLUGIN_INVALIDUSERORGROUP as SQL_API_RC;}
+            Err(e) => {return e as SQL_API_RC;}
+        };
+        audit.groups = Some( vec![ group.clone() ] );
+
+        let state = match CurrentPluginState() {
+            None => {
+                audit.rule = Some( "plugin-not-initialized" );
+                return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+            }
+            Some(st) => st
+        };
+
+        if state.users.GroupExists( &group ) {
+            audit.rule = Some( "group-found" );
+            Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+        }
+        else {
+            audit.rule = Some( "group-not-found" );
+            Db2rc::DB2SEC_PLUGIN_INVALIDUSERORGROUP as SQL_API_RC
+        }
+    });
+
+    if let Some(audit) = &audit {
+        WriteAuditRecord( audit, rc );
+    }
+    drop( correlation );
+    rc
+}
+
+extern "C" fn FreeGroupListMemory
+(
+    ptr : * mut c_void,
+    errormsg : * mut * mut c_char,
+    errormsglen : * mut i32
+) -> SQL_API_RC {
+    CatchPanics( "FreeGroupListMemory", errormsg, errormsglen, || {
+        // Allocated with AllocateTagged in GetGroupsForUser.
+        match allocations::FreeTagged( ptr, AllocationKind::GroupList ) {
+            Ok(()) => Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC,
+            Err(e) => RejectFree( "FreeGroupListMemory", &e, errormsg, errormsglen ),
+        }
+    })
+}
+
+extern "C" fn GroupPluginTerm
+(
+    errormsg : * mut * mut c_char,
+    errormsglen : * mut i32
+) -> SQL_API_RC
+{
+    CatchPanics( "GroupPluginTerm", errormsg, errormsglen, || {
+        DetachPluginState( GROUP_PLUGIN );
+
+        Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+    })
+}
+
+// Db2 does not give the group plugin a connection details callback, this
+// stands in for it until the server auth plugin is initialized as well.
+extern "C" fn NoConnectionDetails
+(
+    conDetailsVersion : i32,
+    pConDetails : * mut c_void,
+) -> SQL_API_RC {
+    Db2rc::DB2SEC_PLUGIN_NO_CON_DETAILS as SQL_API_RC
+}
+
+#[no_mangle]
+pub extern "C" fn db2secGroupPluginInit(
+    version : i32,
+    group_fns : * mut c_void,
+    logMessage_fn : Option<LogMessageFuncT>,
+    errormsg : * mut * mut c_char,
+    errormsglen : * mut i32,
+) -> SQL_API_RC {
+    let rc = CatchPanics( "db2secGroupPluginInit", errormsg, errormsglen, || {
+        let logMessageFn = match logMessage_fn {
+            Some(f) => f,
+            None => {
+                return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+            }
+        };
+
+        if group_fns.is_null() ||
+           errormsg.is_null() ||
+           errormsglen.is_null() {
+            return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+        }
+
+        if version < DB2SEC_GROUP_FUNCTIONS_VERSION_1 {
+            AllocateDb2ErrorMessage( "db2secGroupPluginInit",
+                                     "Invalidate function version",
+                                     errormsg, errormsglen );
+
+            return Db2rc::DB2SEC_PLUGIN_INCOMPATIBLE_VER as SQL_API_RC;
+        }
+
+        // The server auth plugin may have set up the state already.
+        if CurrentPluginState().is_none() {
+            match PluginState::Build( NoConnectionDetails, logMessageFn, None ) {
+                Ok(st) => InstallPluginState( st ),
+                Err(e) => {
+                    LogMessageWithCallback( logMessageFn, Db2LogLevels::DB2SEC_LOG_ERROR,
+                                            &format!("RUSTSECP cannot be initialized: {}", e) );
+                    AllocateDb2ErrorMessage( "db2secGroupPluginInit", &e, errormsg, errormsglen );
+                    return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+                }
+            }
+        }
+        AttachPluginState( GROUP_PLUGIN );
+
+        let groupFns : &mut db2secGroupFunctions_1 =
+               unsafe { &mut *(group_fns as *mut db2secGroupFunctions_1) };
+
+        groupFns.version                   = DB2SEC_GROUP_FUNCTIONS_VERSION_1;
+        groupFns.plugintype                = DB2SEC_PLUGIN_TYPE_GROUP;
+        groupFns.db2secGetGroupsForUser    = Some( GetGroupsForUser );
+        groupFns.db2secDoesGroupExist      = Some( DoesGroupExist );
+        groupFns.db2secFreeGroupListMemory = Some( FreeGroupListMemory );
+        groupFns.db2secFreeErrormsg        = Some( FreeErrorMsg );
+        groupFns.db2secPluginTerm          = Some( GroupPluginTerm );
+
+        LogMessageToDb2Diag( LogModule::Plugin, Db2LogLevels::DB2SEC_LOG_INFO,
+                             "RUST based group plugin is initialized" );
+
+        Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+    });
+
+    AbandonInitErrorMessage( rc, errormsg );
+    rc
+}
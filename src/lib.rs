@@ -27,16 +27,54 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
 
-use std::collections::HashMap;
 use std::os::raw::{c_int,c_char,c_void};
-use std::ffi::{CString,CStr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Instant, SystemTime};
 use bitflags::bitflags;
 
+mod outbuf;
+mod allocations;
+use allocations::{AllocationKind, ALLOCATION_MAGIC};
+pub mod token;
+use token::{SealToken, TokenAgeError, TokenMac, TokenSealMatches};
+use outbuf::{Db2OutputBuffer, OverflowPolicy};
+mod audit;
+pub mod auditchain;
+pub mod userstore;
+pub mod simulate;
+pub mod totp;
+pub mod mfa;
+use audit::{AuditCall, AuditClient, AuditRecord};
+mod codepage;
+use codepage::CodePage;
+mod config;
+mod correlation;
+mod decision;
+use decision::{DecideLogin, Failure, LoginAttempt, Trace};
+use correlation::{CorrelationScope, NewCorrelationId, WithCorrelationId};
+mod diaglog;
+mod metrics;
+mod lockout;
+mod tarpit;
+mod ratelimit;
+mod detect;
+mod lru;
+mod control;
+use diaglog::{LogModule, RateDecision, LogEnabled, RateLimit};
+mod secret;
+mod siem;
+use secret::SecretString;
+mod state;
+mod groupplugin;
+pub use groupplugin::db2secGroupPluginInit;
+use state::{PluginState, CurrentPluginState, InstallPluginState, AttachPluginState, DetachPluginState,
+            SERVER_AUTH_PLUGIN};
 
 // Type corresponding to SQL_API_RC
 type SQL_API_RC = c_int;
 
 #[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum Db2LogLevels {
     DB2SEC_LOG_NONE     = 0,
     DB2SEC_LOG_CRITICAL = 1,
@@ -46,6 +84,7 @@ enum Db2LogLevels {
 }
 
 const DB2SEC_USERID_PASSWORD_SERVER_AUTH_FUNCTIONS_VERSION_1 : i32 = 1;
+const DB2SEC_GROUP_FUNCTIONS_VERSION_1 : i32 = 1;
 
 const DB2SEC_MAX_AUTHID_LENGTH           : i32 = 255;
 const DB2SEC_MAX_USERID_LENGTH           : i32 = 255;
@@ -65,6 +104,14 @@ const DB2SEC_PLUGIN_TYPE_GROUP           : i32 = 3;
 
 const DB2SEC_ID_TYPE_AUTHID              : i32 = 0;
 
+const DB2SEC_GENERIC                     : i32 = 0;
+
+// The name Db2 knows this plugin by, the library name without extension.
+// Only a token from a plugin of this name is ours to look into.
+const OWN_PLUGIN_NAME                    : &str = "libdb2rustsecp";
+
+const DB2SEC_CON_DETAILS_VERSION_3       : i32 = 3;
+
 
 bitflags! {
     #[repr(C)]
@@ -78,6 +125,7 @@ bitflags! {
 
 #[repr(i32)]
 #[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum Db2rc {
     DB2SEC_PLUGIN_OK = 0,
     DB2SEC_PLUGIN_UNKNOWNERROR = -1,
@@ -118,13 +166,31 @@ enum Db2rc {
     DB2SEC_PLUGIN_AUTH_SYSERR = -31,
 }
 
-
-// Global variables set during init.
-// These are callback into the Db2 engine.
-// TODO: Implement as singleton
-static mut DB2_GET_CON_DETAILS_CB : Option<GetConDetailsFuncT> = None;
-static mut DB2_LOG_MESSAGE_CB : Option<LogMessageFuncT> = None;
-static mut DB2_USERPW_MAP : Option<HashMap<String, String> > = None;
+impl Db2rc {
+    // Map a return code back to its name, e.g. for the audit log.
+    fn FromCode( code : SQL_API_RC ) -> Option<Db2rc> {
+        use Db2rc::*;
+        const ALL : [Db2rc; 32] = [
+            DB2SEC_PLUGIN_OK, DB2SEC_PLUGIN_UNKNOWNERROR, DB2SEC_PLUGIN_BADUSER,
+            DB2SEC_PLUGIN_INVALIDUSERORGROUP, DB2SEC_PLUGIN_USERSTATUSNOTKNOWN,
+            DB2SEC_PLUGIN_GROUPSTATUSNOTKNOWN, DB2SEC_PLUGIN_UID_EXPIRED,
+            DB2SEC_PLUGIN_PWD_EXPIRED, DB2SEC_PLUGIN_USER_REVOKED,
+            DB2SEC_PLUGIN_USER_SUSPENDED, DB2SEC_PLUGIN_BADPWD,
+            DB2SEC_PLUGIN_BAD_NEWPASSWORD, DB2SEC_PLUGIN_CHANGEPASSWORD_NOTSUPPORTED,
+            DB2SEC_PLUGIN_NOMEM, DB2SEC_PLUGIN_DISKERROR, DB2SEC_PLUGIN_NOPERM,
+            DB2SEC_PLUGIN_NETWORKERROR, DB2SEC_PLUGIN_CANTLOADLIBRARY,
+            DB2SEC_PLUGIN_CANT_OPEN_FILE, DB2SEC_PLUGIN_FILENOTFOUND,
+            DB2SEC_PLUGIN_CONNECTION_DISALLOWED, DB2SEC_PLUGIN_NO_CRED,
+            DB2SEC_PLUGIN_CRED_EXPIRED, DB2SEC_PLUGIN_BAD_PRINCIPAL_NAME,
+            DB2SEC_PLUGIN_NO_CON_DETAILS, DB2SEC_PLUGIN_BAD_INPUT_PARAMETERS,
+            DB2SEC_PLUGIN_INCOMPATIBLE_VER, DB2SEC_PLUGIN_PROCESS_LIMIT,
+            DB2SEC_PLUGIN_NO_LICENSES, DB2SEC_PLUGIN_ROOT_NEEDED,
+            DB2SEC_PLUGIN_UNEXPECTED_SYSTEM_ERROR, DB2SEC_PLUGIN_AUTH_SYSERR,
+        ];
+
+        ALL.iter().copied().find( |rc| *rc as SQL_API_RC == code )
+    }
+}
 
 
 //-----------------------------------------------------------------------------
@@ -175,7 +241,7 @@ type CDoesAuthIDExistFuncT = extern "C" fn (
     errormsglen : * mut i32
 ) -> SQL_API_RC;
 
-type CFreeTokenFuncT = extern fn (
+type CFreeTokenFuncT = extern "C" fn (
     token : * mut c_void,
     errormsg : * mut * mut c_char,
     errormsglen : * mut i32
@@ -190,6 +256,45 @@ type CServerAuthPluginTermFuncT = extern "C" fn (
     errormsglen : * mut i32
 ) -> SQL_API_RC;
 
+type CGetGroupsForUserFuncT = extern "C" fn (
+    authid : * const c_char,
+    authidlen : i32,
+    userid : * const c_char,
+    useridlen : i32,
+    usernamespace : * const c_char,
+    usernamespacelen : i32,
+    usernamespacetype : i32,
+    dbname : * const c_char,
+    dbnamelen : i32,
+    token : * mut c_void,
+    tokentype : i32,
+    location : i32,
+    authpluginname : * const c_char,
+    authpluginnamelen : i32,
+    grouplist : * mut * mut c_void,
+    numgroups : * mut i32,
+    errormsg : * mut * mut c_char,
+    errormsglen : * mut i32
+) -> SQL_API_RC;
+
+type CDoesGroupExistFuncT = extern "C" fn (
+    groupname : * const c_char,
+    groupnamelen : i32,
+    errormsg : * mut * mut c_char,
+    errormsglen : * mut i32
+) -> SQL_API_RC;
+
+type CFreeGroupListMemoryFuncT = extern "C" fn (
+    ptr : * mut c_void,
+    errormsg : * mut * mut c_char,
+    errormsglen : * mut i32
+) -> SQL_API_RC;
+
+type CGroupPluginTermFuncT = extern "C" fn (
+    errormsg : * mut * mut c_char,
+    errormsglen : * mut i32
+) -> SQL_API_RC;
+
 type GetConDetailsFuncT = extern "C" fn (
    conDetailsVersion : i32,
    pConDetails : * mut c_void,
@@ -230,12 +335,145 @@ pub struct db2secUseridPasswordServerAuthFunctions_1 {
     db2secServerAuthPluginTerm : Option<CServerAuthPluginTermFuncT>,
 }
 
+#[repr(C)]
+pub struct db2secGroupFunctions_1 {
+    version : i32,
+    plugintype : i32,
+    db2secGetGroupsForUser     : Option<CGetGroupsForUserFuncT>,
+    db2secDoesGroupExist       : Option<CDoesGroupExistFuncT>,
+    db2secFreeGroupListMemory  : Option<CFreeGroupListMemoryFuncT>,
+    db2secFreeErrormsg         : Option<CFreeErrormsgFuncT>,
+    db2secPluginTerm           : Option<CGroupPluginTermFuncT>,
+}
+
 
-// This structure will be used as the token between Db2 calls.
+// This structure will be used as the token between Db2 calls.  Laid out as
+// in C, so the tag is the first field as allocations.rs expects.
+#[repr(C)]
 struct TokenBetweenDb2Calls {
+    // ALLOCATION_MAGIC while the token is alive, see allocations.rs.
+    magic : u64,
     firstVal : i32,
     secondVal : i16,
     authid : String,
+    // The userid exactly as the user typed it, returned as the username.
+    username : String,
+    // Ties the later calls for this connection to ValidatePassword.
+    correlationId : String,
+    // The namespace ValidatePassword was given, empty if none.
+    usernamespace : String,
+    created : SystemTime,
+    // Over the fields above, see token.rs.
+    mac : TokenMac,
+}
+
+// Who Db2 says a token is being used for.  None for what it did not pass.
+struct TokenUser<'a> {
+    userid : Option<&'a str>,
+    usernamespace : Option<&'a str>,
+    authid : Option<&'a str>,
+}
+
+impl TokenBetweenDb2Calls {
+    fn Sealed( &self ) -> [&str; 4] {
+        [ &self.authid, &self.username, &self.usernamespace, &self.correlationId ]
+    }
+
+    fn Seal( &mut self ) {
+        self.mac = SealToken( self.created, &self.Sealed() );
+    }
+
+    // Whether Db2 may use this token for the user it passed along with it.
+    // The userid and authid are only compared when Db2 passes them.
+    fn Check( &self, user : &TokenUser, maxAgeSeconds : u64 ) -> Result<(), String> {
+        let TokenUser { userid, usernamespace, authid } = *user;
+        if ! TokenSealMatches( &self.mac, self.created, &self.Sealed() ) {
+            return Err( String::from( "The token was altered after ValidatePassword made it" ) );
+        }
+        if let Some(e) = TokenAgeError( self.created, maxAgeSeconds, SystemTime::now() ) {
+            return Err( e );
+        }
+        // Userids are matched without regard to case, as in the user store.
+        if let Some(u) = userid.filter( |u| u.to_lowercase() != self.username.to_lowercase() ) {
+            return Err( format!("The token was made for userid {}, not {}", self.username, u) );
+        }
+        if usernamespace.unwrap_or_default() != self.usernamespace {
+            return Err( format!("The token was made for namespace {:?}, not {:?}",
+                                self.usernamespace, usernamespace.unwrap_or_default()) );
+        }
+        if let Some(a) = authid.filter( |a| ! a.eq_ignore_ascii_case( &self.authid ) ) {
+            return Err( format!("The token was made for authid {}, not {}", self.authid, a) );
+        }
+        Ok(())
+    }
+}
+
+// Is this the name of the plugin, i.e. did the token come from us?
+fn IsOwnPluginName( name : * const c_char, namelen : i32 ) -> bool {
+    if name.is_null() || namelen != OWN_PLUGIN_NAME.len() as i32 {
+        return false;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts( name as * const u8, namelen as usize ) };
+    bytes == OWN_PLUGIN_NAME.as_bytes()
+}
+
+// The correlation ID kept in a token, if there is a token.
+fn TokenCorrelationId( token : * const c_void ) -> Option<String> {
+    TokenRef( token ).map( |t| t.correlationId.clone() )
+}
+
+// A token Db2 passed us, marked in use until it is dropped, so FreeToken
+// cannot free it while it is read.  See allocations.rs.
+struct TokenInUse {
+    token : * const TokenBetweenDb2Calls,
+}
+
+impl std::ops::Deref for TokenInUse {
+    type Target = TokenBetweenDb2Calls;
+
+    fn deref( &self ) -> &TokenBetweenDb2Calls {
+        unsafe { &*self.token }
+    }
+}
+
+impl Drop for TokenInUse {
+    fn drop( &mut self ) {
+        allocations::EndTokenUse( self.token as * const c_void );
+    }
+}
+
+// The token behind a pointer Db2 passed us, if it is one we handed out and
+// have not freed.
+fn TokenRef( token : * const c_void ) -> Option<TokenInUse> {
+    if ! allocations::BeginTokenUse( token ) {
+        return None;
+    }
+    let t = TokenInUse { token : token as * const TokenBetweenDb2Calls };
+    if t.magic == ALLOCATION_MAGIC { Some( t ) } else { None }
+}
+
+// The token for a call that relies on it, or the return code if Db2 may
+// not use it for this user.
+fn CheckedToken( caller : &str,
+                 token : * const c_void,
+                 user : &TokenUser,
+                 config : &config::PluginConfig,
+                 errormsg : * mut * mut c_char,
+                 errormsglen : * mut i32 ) -> Result<TokenInUse, SQL_API_RC> {
+    let checked = match TokenRef( token ) {
+        Some(t) => match t.Check( user, config.tokenMaxAgeSeconds ) {
+            Ok(()) => Ok( t ),
+            Err(e) => Err( e ),
+        },
+        None => Err( String::from( "The token was not made by ValidatePassword or was freed already" ) ),
+    };
+
+    checked.map_err( |msg| {
+        LogMessageToDb2Diag( LogModule::Auth, Db2LogLevels::DB2SEC_LOG_ERROR,
+                             &format!("{}: token refused: {}", caller, msg) );
+        AllocateDb2ErrorMessage( caller, &msg, errormsg, errormsglen );
+        Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC
+    })
 }
 
 //-----------------------------------------------------------------------------
@@ -259,124 +497,206 @@ extern "C" fn ValidatePassword
     errormsg : * mut * mut c_char,
     errormsglen : * mut i32
 ) -> SQL_API_RC {
-    if ! newpasswd.is_null() {
-        return Db2rc::DB2SEC_PLUGIN_CHANGEPASSWORD_NOTSUPPORTED as SQL_API_RC;
-    }
-
-    let optUserid = match ConvertToOptionalString( userid,
-                                                   useridlen,
-                                                   "ValidatePassword",
-                                                   "userid",
-                                                   errormsg, errormsglen ) {
-        Ok(o) => o,
-        Err(e) => {return e as SQL_API_RC;}
-    };
+    let mut correlation = None;
+    let mut audit = None;
+    let rc = CatchPanics( "ValidatePassword", errormsg, errormsglen, || {
+        let correlationId = NewCorrelationId();
+        correlation = Some( CorrelationScope::Enter( Some( correlationId.clone() ) ) );
+        let audit = audit.insert( AuditRecord::New( AuditCall::ValidatePassword, &correlationId ) );
+
+        if ! newpasswd.is_null() {
+            audit.rule = Some( "change-password-not-supported" );
+            return Db2rc::DB2SEC_PLUGIN_CHANGEPASSWORD_NOTSUPPORTED as SQL_API_RC;
+        }
 
-    // Userid is mandatory.
-    let localUserid = match optUserid {
-        None => {return Db2rc::DB2SEC_PLUGIN_BADUSER as SQL_API_RC},
-        Some(s) => s
-    };
+        // Any early return below is due to bad input unless it says otherwise.
+        audit.rule = Some( "invalid-input" );
+
+        let optUserid = match ConvertToOptionalString( userid,
+                                                       useridlen,
+                                                       DB2SEC_MAX_USERID_LENGTH,
+                                                       "ValidatePassword",
+                                                       "userid",
+                                                       errormsg, errormsglen ) {
+            Ok(o) => o,
+            Err(e) => {return e as SQL_API_RC;}
+        };
 
-    let optUserNamespace = match ConvertToOptionalString( usernamespace,
-                                                          usernamespacelen,
-                                                          "ValidatePassword",
-                                                          "usernamespace",
-                                                          errormsg, errormsglen ) {
-        Ok(o) => o,
-        Err(e) => {return e as SQL_API_RC;}
-    };
+        // Userid is mandatory.
+        let localUserid = match optUserid {
+            None => {return Db2rc::DB2SEC_PLUGIN_BADUSER as SQL_API_RC},
+            Some(s) => s
+        };
+        audit.userid = Some( localUserid.clone() );
+
+        let optUserNamespace = match ConvertToOptionalString( usernamespace,
+                                                              usernamespacelen,
+                                                              DB2SEC_MAX_USERNAMESPACE_LENGTH,
+                                                              "ValidatePassword",
+                                                              "usernamespace",
+                                                              errormsg, errormsglen ) {
+            Ok(o) => o,
+            Err(e) => {return e as SQL_API_RC;}
+        };
 
-    let optPassword = match ConvertToOptionalString( password,
-                                                     passwordlen,
-                                                     "ValidatePassword",
-                                                     "password",
-                                                     errormsg, errormsglen ) {
-        Ok(o) => o,
-        Err(e) => {return e as SQL_API_RC;}
-    };
+        let optPassword = match ConvertToOptionalSecret( password,
+                                                         passwordlen,
+                                                         DB2SEC_MAX_PASSWORD_LENGTH,
+                                                         "ValidatePassword",
+                                                         "password",
+                                                         errormsg, errormsglen ) {
+            Ok(o) => o,
+            Err(e) => {return e as SQL_API_RC;}
+        };
 
-    let optDbname = match ConvertToOptionalString( dbname,
-                                                   dbnamelen,
-                                                   "ValidatePassword",
-                                                   "dbname",
-                                                   errormsg, errormsglen ) {
-        Ok(o) => o,
-        Err(e) => {return e as SQL_API_RC;}
-    };
+        let optDbname = match ConvertToOptionalString( dbname,
+                                                       dbnamelen,
+                                                       DB2SEC_MAX_DBNAME_LENGTH,
+                                                       "ValidatePassword",
+                                                       "dbname",
+                                                       errormsg, errormsglen ) {
+            Ok(o) => o,
+            Err(e) => {return e as SQL_API_RC;}
+        };
+        audit.database = optDbname.clone();
 
-    let connDetails : ConnectionFlags = ConnectionFlags::from_bits_truncate( connection_details );
+        let connDetails : ConnectionFlags = ConnectionFlags::from_bits_truncate( connection_details );
 
 
-    #[cfg(debug_assertions)]
-    LogMessageToDb2Diag( Db2LogLevels::DB2SEC_LOG_ERROR,
+        LogMessageToDb2Diag( LogModule::Auth, Db2LogLevels::DB2SEC_LOG_INFO,
                              &format!("ValidatePassword: Local connect: {:?}", connDetails ) );
 
-    if let Some(pw) = optPassword {
+        let state = match CurrentPluginState() {
+            None => {
+                audit.rule = Some( "plugin-not-initialized" );
+                return Db2rc::DB2SEC_PLUGIN_BADUSER as SQL_API_RC;
+            }
+            Some(st) => st
+        };
 
-        // This code is safe because DB2_USERPW_MAP is only allocated and written to
-        // during db2secServerAuthPluginInit which is single threaded at that time.
-        // Once we are in a multi-threaded env, this is read only.
-        unsafe {
-            let pwMap = match &DB2_USERPW_MAP {
-                None => {return Db2rc::DB2SEC_PLUGIN_BADUSER as SQL_API_RC;}
-                Some(lpw) => lpw
-            };
-       
-            match pwMap.get( &localUserid ) {
-                None => {
-                    AllocateDb2ErrorMessage( "ValidatePassword",
-                                            &format!("The password is bad for user: {}", &localUserid ),
-                                            errormsg, errormsglen);
-                    return Db2rc::DB2SEC_PLUGIN_BADUSER as SQL_API_RC;
-                },
-                Some(lpw) => {
-                    if lpw.ne(&pw) {
-                        AllocateDb2ErrorMessage( "ValidatePassword",
-                                        "The password is bad for the user",
-                                        errormsg, errormsglen);
-                        return Db2rc::DB2SEC_PLUGIN_BADPWD as SQL_API_RC;
-                    }
-                }
+        // Rate limits, delays and detection only apply to attempts with a
+        // password.  The client address is only asked for when one is on.
+        let config = &state.config;
+        let limited = optPassword.is_some() &&
+                      (config.rateLimitIp.is_some() || config.rateLimitUser.is_some() || config.rateLimitGlobal.is_some());
+        let tarpit = optPassword.is_some() && config.delayBaseMs > 0;
+        let detecting = optPassword.is_some() && (config.stuffingUserids > 0 || config.sprayUserids > 0);
+        let client = if limited || tarpit || detecting { GetConnectionDetails().and_then( |d| d.ClientIP() ) } else { None };
+
+        // Addresses caught at an attack, see detect.rs.
+        if detecting && detect::IsBlocked( client ) {
+            audit.rule = Some( "address-blocked" );
+            LogMessageToDb2Diag( LogModule::Auth, Db2LogLevels::DB2SEC_LOG_WARNING,
+                                 &format!("RUSTSECP login for {} from {} refused, the address is blocked",
+                                          localUserid, client.map_or( String::new(), |c| c.to_string() )) );
+            AllocateDb2ErrorMessage( "ValidatePassword", "Too many login attempts, try again later",
+                                     errormsg, errormsglen );
+            return Db2rc::DB2SEC_PLUGIN_CONNECTION_DISALLOWED as SQL_API_RC;
+        }
+
+        // Before anything expensive, see ratelimit.rs.
+        if limited {
+            if let Err(limit) = ratelimit::TakeAttempt( &localUserid, client, config ) {
+                audit.rule = Some( match limit {
+                    ratelimit::Limit::Ip => "rate-limit-ip",
+                    ratelimit::Limit::User => "rate-limit-user",
+                    ratelimit::Limit::Global => "rate-limit-global",
+                });
+                metrics::CountRateLimited( limit.Name() );
+                LogMessageToDb2Diag( LogModule::Auth, Db2LogLevels::DB2SEC_LOG_WARNING,
+                                     &format!("RUSTSECP login for {} from {} refused by rate_limit_{}", localUserid,
+                                              client.map_or( String::from( "an unknown address" ), |c| c.to_string() ),
+                                              limit.Name()) );
+                AllocateDb2ErrorMessage( "ValidatePassword", "Too many login attempts, try again later",
+                                         errormsg, errormsglen );
+                return Db2rc::DB2SEC_PLUGIN_CONNECTION_DISALLOWED as SQL_API_RC;
             }
         }
 
-        // If we get here, the password is valid
-    }
-    else {
-         /* No password was supplied.  This is okay as long
-         * as the following conditions are true:
-         *
-         *  - The username came from WhoAmI(), and
-         *  - If we're on the server side, the connection must
-         *    be "local" (originating from the same machine)
-         *
-         * Note that "DB2SEC_USERID_FROM_OS" means that the userid
-         * was obtained from the plugin by calling the function
-         * supplied for "db2secGetDefaultLoginContext".
-         */
-
-        if ! connDetails.contains( ConnectionFlags::DB2SEC_USERID_FROM_OS |
-                                   ConnectionFlags::DB2SEC_CONNECTION_ISLOCAL |
-                                   ConnectionFlags::DB2SEC_VALIDATING_ON_SERVER_SIDE ) {
-            return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+        // Slow down guessing after failures, see tarpit.rs.
+        let delayed = if tarpit { tarpit::Delay( &localUserid, client, &state.config ) } else { tarpit::Delayed::None() };
+        if ! delayed.wait.is_zero() && ! tarpit::Wait( &delayed, &state.config ) {
+            audit.rule = Some( "delay-limit" );
+            LogMessageToDb2Diag( LogModule::Auth, Db2LogLevels::DB2SEC_LOG_WARNING,
+                                 &format!("RUSTSECP login for {} from {} refused, {} logins are waiting out a delay \
+                                           and this user or address holds its share of them",
+                                          localUserid, client.map_or( String::from( "an unknown address" ), |c| c.to_string() ),
+                                          state.config.delayMaxThreads) );
+            AllocateDb2ErrorMessage( "ValidatePassword", "Too many logins are being delayed, try again later",
+                                     errormsg, errormsglen );
+            return Db2rc::DB2SEC_PLUGIN_CONNECTION_DISALLOWED as SQL_API_RC;
         }
-    }
 
-    // Create an token to pass between calls.
-    // firstVal and secondVal are just demo values and not really used.
-    let rust_object = Box::new(TokenBetweenDb2Calls { firstVal: 5,
-                                                      secondVal: 6,
-                                                      authid : localUserid.to_uppercase() });
+        let attempt = LoginAttempt { userid : &localUserid,
+                                     password : optPassword.as_ref(),
+                                     flags : connDetails,
+                                     lockedOut : None };
+        let decision = DecideLogin( &state.users, &state.config, &attempt, SystemTime::now(), &mut Trace::Off() );
+
+        let failure = decision.failure;
+        if tarpit {
+            if failure == Failure::None {
+                tarpit::RecordSuccess( &localUserid );
+            } else if failure.IsGuess() {
+                tarpit::RecordFailure( &localUserid, client, &state.config );
+            }
+        }
 
-    unsafe {
-        // This transfers ownership of rust_object into the raw pointer token.
-        // This is then given back to Db2 to own/manage the memory.
-        // Db2 will call FreeToken to deallocate the heap memory.
-        *token = Box::into_raw( rust_object ) as * mut c_void;
-    }
+        if failure == Failure::LockedOut {
+            metrics::CountLockout();
+        }
 
-    Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+        if failure == Failure::Trap {
+            ReportTrap( decision.rule, &localUserid, connDetails, &correlationId, decision.rc );
+        }
+
+        if detecting && failure.IsGuess() {
+            let password = if failure.PasswordWasWrong() { optPassword.as_ref() } else { None };
+            for found in detect::RecordFailure( &localUserid, password, client, config ) {
+                ReportDetection( &found, &localUserid, client, &correlationId, decision.rc, config );
+            }
+        }
+
+        audit.rule = Some( decision.rule );
+        if let Some(m) = &decision.message {
+            AllocateDb2ErrorMessage( "ValidatePassword", m, errormsg, errormsglen );
+        }
+        let authid = match decision.authid {
+            Some(a) if decision.rc == Db2rc::DB2SEC_PLUGIN_OK => a,
+            _ => return decision.rc as SQL_API_RC,
+        };
+        audit.authid = Some( authid.clone() );
+
+        // Create an token to pass between calls.
+        // firstVal and secondVal are just demo values and not really used.
+        let mut rust_object = Box::new(TokenBetweenDb2Calls { magic : ALLOCATION_MAGIC,
+                                                              firstVal: 5,
+                                                              secondVal: 6,
+                                                              authid,
+                                                              username : localUserid,
+                                                              correlationId,
+                                                              usernamespace : optUserNamespace.unwrap_or_default(),
+                                                              created : SystemTime::now(),
+                                                              mac : TokenMac::default() });
+        rust_object.Seal();
+
+        unsafe {
+            // This transfers ownership of rust_object into the raw pointer token.
+            // This is then given back to Db2 to own/manage the memory.
+            // Db2 will call FreeToken to deallocate the heap memory.
+            *token = Box::into_raw( rust_object ) as * mut c_void;
+            allocations::RegisterToken( *token, "ValidatePassword" );
+        }
+        metrics::TokenCreated();
+
+        Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+    });
+
+    if let Some(audit) = &audit {
+        WriteAuditRecord( audit, rc );
+    }
+    drop( correlation );
+    rc
 }
 
 // Note regarding the SystemAuthID ,InitialSessionAuthID and username parameters.
@@ -404,8 +724,9 @@ extern "C" fn ValidatePassword
 // to check the lengths for us, we will need to do that ourselves.
 //    SystemAuthID : * mut c_char,
 //    InitialSessionAuthID : * mut c_char,
+// That checking is done by Db2OutputBuffer in outbuf.rs.
 
-extern fn GetAuthIDs
+extern "C" fn GetAuthIDs
 (
     userid : * const c_char,
     useridlen: i32,
@@ -425,136 +746,239 @@ extern fn GetAuthIDs
     errormsg : * mut * mut c_char,
     errormsglen : * mut i32
 ) -> SQL_API_RC {
-    #[cfg(debug_assertions)]
-    LogMessageToDb2Diag( Db2LogLevels::DB2SEC_LOG_WARNING,
-                         "Entering GetAuthIDs" );
+    let mut correlation = None;
+    let rc = CatchPanics( "GetAuthIDs", errormsg, errormsglen, || {
+        let tokenValue = if token.is_null() { std::ptr::null_mut() } else { unsafe { *token } };
+        correlation = Some( CorrelationScope::Enter( TokenCorrelationId( tokenValue ) ) );
+
+        LogMessageToDb2Diag( LogModule::Auth, Db2LogLevels::DB2SEC_LOG_INFO,
+                             "Entering GetAuthIDs" );
+
+        let optUserid = match ConvertToOptionalString( userid,
+                                                       useridlen,
+                                                       DB2SEC_MAX_USERID_LENGTH,
+                                                       "GetAuthIDs",
+                                                       "userid",
+                                                       errormsg, errormsglen ) {
+            Ok(o) => o,
+            Err(e) => {return e as SQL_API_RC;}
+        };
 
-    unsafe {
-        // A token is necessary, it should have been allocated in ValidatePassword.
-        if token.is_null() || (*token).is_null() {
-            return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
-        }
+        let optUserNamespace = match ConvertToOptionalString( usernamespace,
+                                                              usernamespacelen,
+                                                              DB2SEC_MAX_USERNAMESPACE_LENGTH,
+                                                              "GetAuthIDs",
+                                                              "usernamespace",
+                                                              errormsg, errormsglen ) {
+            Ok(o) => o,
+            Err(e) => {return e as SQL_API_RC;}
+        };
 
-        // We will use the authid that was setup for us during
-        // ValidatePassword.  We could just grab the username here, but
-        // it's an oppourtunity to show token passing.
+        let state = match CurrentPluginState() {
+            None => return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC,
+            Some(st) => st
+        };
 
-        // We don't want to take owership of token, just use it
-        // as a read only variable
-        let pToken = *token as * const TokenBetweenDb2Calls;
+        unsafe {
+            // A token is necessary, it should have been allocated in ValidatePassword.
+            if token.is_null() || (*token).is_null() {
+                return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+            }
 
-        let authid : & String = &(*pToken).authid;
+            // We will use the authid that was setup for us during
+            // ValidatePassword.  We could just grab the username here, but
+            // it's an oppourtunity to show token passing.
+
+            // We don't want to take owership of token, just use it
+            // as a read only variable.  It must be for the same user.
+            let user = TokenUser { userid : optUserid.as_deref(),
+                                   usernamespace : optUserNamespace.as_deref(),
+                                   authid : None };
+            let pToken = match CheckedToken( "GetAuthIDs", *token, &user, &state.config, errormsg, errormsglen ) {
+                Ok(t) => t,
+                Err(rc) => return rc,
+            };
 
+            let authid : & String = &pToken.authid;
+            let loginName : & String = &pToken.username;
 
-        std::ptr::copy( authid.as_ptr(),
-                        SystemAuthID as *mut u8,
-                        authid.len() );
+            if initsessionidtype.is_null() {
+                return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+            }
 
-        *SystemAuthIDlen = authid.len() as i32;
+            // All or nothing, see WriteAll.
+            let buffers = [ (SystemAuthID, SystemAuthIDlen),
+                            (InitialSessionAuthID, InitialSessionAuthIDlen),
+                            (username, usernamelen) ];
+            let outputs : Vec<(Db2OutputBuffer, &str, OverflowPolicy)> =
+                AuthIDOutputs( authid, loginName ).into_iter()
+                                                  .zip( buffers )
+                                                  .map( |((field, value, maxlen, policy), (buffer, length))|
+                                                        (Db2OutputBuffer::New( buffer, length, maxlen, field ), value, policy) )
+                                                  .collect();
+            if let Err(e) = outbuf::WriteAll( &outputs, "GetAuthIDs", errormsg, errormsglen ) {
+                return e as SQL_API_RC;
+            }
 
-        std::ptr::copy( authid.as_ptr(),
-                        InitialSessionAuthID as *mut u8,
-                        authid.len() );
+            *initsessionidtype = DB2SEC_ID_TYPE_AUTHID;
+        }
 
-        *InitialSessionAuthIDlen = authid.len() as i32;
+        Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+    });
 
-        *initsessionidtype = DB2SEC_ID_TYPE_AUTHID;
-    }
+    drop( correlation );
+    rc
+}
 
-    Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+// What GetAuthIDs returns for a token: the output, its value, the most
+// bytes Db2 has room for and what happens to a longer value.
+pub(crate) fn AuthIDOutputs<'a>( authid : &'a str, username : &'a str )
+   -> [(&'static str, &'a str, i32, OverflowPolicy); 3] {
+    [ ("SystemAuthID", authid, DB2SEC_MAX_AUTHID_LENGTH, OverflowPolicy::Reject),
+      ("InitialSessionAuthID", authid, DB2SEC_MAX_AUTHID_LENGTH, OverflowPolicy::Reject),
+      ("username", username, DB2SEC_MAX_USERID_LENGTH, OverflowPolicy::Truncate) ]
 }
 
-extern fn DoesAuthIDExist
+extern "C" fn DoesAuthIDExist
 (
     authid : * const c_char,
     authidlen : i32,
     errormsg : * mut * mut c_char,
     errormsglen : * mut i32
 ) -> SQL_API_RC {
+    let mut correlation = None;
+    let mut audit = None;
+    let rc = CatchPanics( "DoesAuthIDExist", errormsg, errormsglen, || {
+        let correlationId = NewCorrelationId();
+        correlation = Some( CorrelationScope::Enter( Some( correlationId.clone() ) ) );
+        let audit = audit.insert( AuditRecord::New( AuditCall::DoesAuthIDExist, &correlationId ) );
+
+        audit.rule = Some( "invalid-input" );
 
-    let optAuthid = match ConvertToOptionalString( authid,
+        let optAuthid = match ConvertEngineString( authid,
                                                    authidlen,
+                                                   DB2SEC_MAX_AUTHID_LENGTH,
                                                    "DoesAuthIdExist",
                                                    "authid",
                                                    errormsg, errormsglen ) {
-        Ok(o) => o,
-        Err(e) => {return e as SQL_API_RC;}
-    };
+            Ok(o) => o,
+            Err(e) => {return e as SQL_API_RC;}
+        };
 
-    // Userid is mandatory.
-    let localAuthid = match optAuthid {
-        None => {return Db2rc::DB2SEC_PLUGIN_BADUSER as SQL_API_RC},
-        Some(s) => s
-    };
+        // Userid is mandatory.
+        let localAuthid = match optAuthid {
+            None => {return Db2rc::DB2SEC_PLUGIN_BADUSER as SQL_API_RC},
+            Some(s) => s
+        };
+        audit.authid = Some( localAuthid.clone() );
 
-    // This code is safe because DB2_USERPW_MAP is only allocated and written to
-    // during db2secServerAuthPluginInit which is single threaded at that time.
-    // Once we are in a multi-threaded env, this is read only.
-    unsafe {
-        let pwMap = match &DB2_USERPW_MAP {
-            None => {return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;}
-            Some(lpw) => lpw
+        let state = match CurrentPluginState() {
+            None => {
+                audit.rule = Some( "plugin-not-initialized" );
+                return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+            }
+            Some(st) => st
         };
-    
-        match pwMap.get( &localAuthid.to_lowercase() ) {
-            None => { 
-                #[cfg(debug_assertions)]
-                LogMessageToDb2Diag( Db2LogLevels::DB2SEC_LOG_WARNING,
-                     &format!("DoesAuthidExist: authid not found: {:?}", localAuthid ) );
-                Db2rc::DB2SEC_PLUGIN_INVALIDUSERORGROUP as SQL_API_RC
-            },
-            Some(s) => { Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC }
+
+        let backendStarted = Instant::now();
+        let known = state.users.ByAuthid( &localAuthid ).is_some();
+        metrics::ObserveBackendLatency( backendStarted.elapsed() );
+
+        if known {
+            audit.rule = Some( "authid-found" );
+            Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
         }
+        else {
+            LogMessageToDb2Diag( LogModule::Auth, Db2LogLevels::DB2SEC_LOG_INFO,
+                 &format!("DoesAuthidExist: authid not found: {:?}", localAuthid ) );
+            audit.rule = Some( "authid-not-found" );
+            Db2rc::DB2SEC_PLUGIN_INVALIDUSERORGROUP as SQL_API_RC
+        }
+    });
+
+    if let Some(audit) = &audit {
+        WriteAuditRecord( audit, rc );
     }
+    drop( correlation );
+    rc
 }
 
-extern fn FreeToken
+extern "C" fn FreeToken
 (
     token : * mut c_void,
     errormsg : * mut * mut c_char,
     errormsglen : * mut i32
 ) -> SQL_API_RC {
-    // Simply taking ownership of the pointer will call destructors
-    // at the end of this function.
-    let boxToFree = unsafe { Box::from_raw( token as * mut TokenBetweenDb2Calls) };
+    let mut correlation = None;
+    let rc = CatchPanics( "FreeToken", errormsg, errormsglen, || {
+        correlation = Some( CorrelationScope::Enter( TokenCorrelationId( token ) ) );
+
+        LogMessageToDb2Diag( LogModule::Auth, Db2LogLevels::DB2SEC_LOG_INFO,
+                             "Freeing the token" );
+
+        // Only a token we handed out and have not freed yet is taken
+        // back, once no other call is using it, see allocations.rs.
+        if let Err(e) = allocations::ReleaseToken( token ) {
+            return RejectFree( "FreeToken", &e, errormsg, errormsglen );
+        }
+        // Out of the registry, nobody else can reach it any more.
+        if unsafe { (*(token as * const TokenBetweenDb2Calls)).magic } != ALLOCATION_MAGIC {
+            let msg = String::from( "The tag of the token was overwritten, it is left alone rather than freed" );
+            return RejectFree( "FreeToken", &msg, errormsg, errormsglen );
+        }
 
-    Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+        // Simply taking ownership of the pointer will call destructors
+        // at the end of this function.  The tag goes first, so a stale
+        // copy of the pointer is never taken for a live token.
+        let mut boxToFree = unsafe { Box::from_raw( token as * mut TokenBetweenDb2Calls) };
+        boxToFree.magic = 0;
+        metrics::TokenFreed();
+
+        Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+    });
+
+    drop( correlation );
+    rc
 }
 
 
-extern fn FreeErrorMsg
+extern "C" fn FreeErrorMsg
 (
   errormsg : * mut c_char,
 ) -> SQL_API_RC {
-    // Any error messages should have been allocated from
-    // AllocateDb2ErrorMessage.
-    // It used CString::into_raw to pass ownership to Db2.
-    // We are taking it back, and memory will be freed when this
-    // function ends
-    let msgToFree = unsafe { CString::from_raw( errormsg ) };
-
-    // TODO: Remove this block
-    if let Ok(newstring) = msgToFree.into_string() {
-        #[cfg(debug_assertions)]
-        LogMessageToDb2Diag( Db2LogLevels::DB2SEC_LOG_WARNING,
-                             &format!( "Freeing this string: {}", newstring ) );
-    }
-
-    Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+    CatchPanics( "FreeErrorMsg", std::ptr::null_mut(), std::ptr::null_mut(), || {
+        // Any error messages should have been allocated from
+        // AllocateDb2ErrorMessage, which recorded them in the registry.
+        // Anything else is refused, there is nowhere to put an error
+        // message so it only goes to db2diag.
+        match allocations::FreeTagged( errormsg as * mut c_void, AllocationKind::ErrorMessage ) {
+            Ok(()) => Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC,
+            Err(e) => RejectFree( "FreeErrorMsg", &e, std::ptr::null_mut(), std::ptr::null_mut() ),
+        }
+    })
 }
 
-extern fn ServerAuthPluginTerm
+extern "C" fn ServerAuthPluginTerm
 (
     errormsg : * mut * mut c_char,
     errormsglen : * mut i32
 ) -> SQL_API_RC
 {
-    unsafe {
-        DB2_GET_CON_DETAILS_CB = None;
-        DB2_LOG_MESSAGE_CB = None;
-    }
+    CatchPanics( "ServerAuthPluginTerm", errormsg, errormsglen, || {
+        // Whatever Db2 has not given back by now it never will.
+        let leaks = allocations::OutstandingAllocations();
+        if ! leaks.is_empty() {
+            LogMessageToDb2Diag( LogModule::Plugin, Db2LogLevels::DB2SEC_LOG_WARNING,
+                                 &format!("ServerAuthPluginTerm: allocations were never freed: {}",
+                                          leaks.join( ", " )) );
+        }
+
+        // Any call still in flight keeps its own reference to the state,
+        // it is freed once the last one finishes.
+        DetachPluginState( SERVER_AUTH_PLUGIN );
 
-    Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+        Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+    })
 }
 
 
@@ -568,85 +992,292 @@ pub extern "C" fn db2secServerAuthPluginInit(
     errormsg : * mut * mut c_char,
     errormsglen : * mut i32,
 ) -> SQL_API_RC {
-    // Unrecoverable error if we don't get a logging function.
-    match logMessage_fn {
-        Some(f) => {unsafe {DB2_LOG_MESSAGE_CB = Some(f);}},
-        None =>
-        {
+    let rc = CatchPanics( "db2secServerAuthPluginInit", errormsg, errormsglen, || {
+        // Unrecoverable error if we don't get a logging function.
+        let logMessageFn = match logMessage_fn {
+            Some(f) => f,
+            None =>
+            {
+                return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+            }
+        };
+
+        // Ensure we have valid pointers, that the are not null.
+        if server_fns.is_null() ||
+           errormsg.is_null() ||
+           errormsglen.is_null() {
+            // These pointers should never be null.
             return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
         }
-    }
 
-    // At this point we appear to have a valid logging function, so can make use of it.
-    LogMessageToDb2Diag( Db2LogLevels::DB2SEC_LOG_WARNING,
-                         "RUST based security u/pw plugin is being initialized" );
+        // Ensure we have a valid getConDetails callback.
+        let getConDetailsFn = match getConDetails_fn {
+            Some(f) => f,
+            None =>
+            {
+                return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+            }
+        };
+
+        // Ensure we are given a valid version of the functions.
+        // We require at least version 1 of the APIs.
+        // Should never have version 0, but we will check.
+        if version < DB2SEC_USERID_PASSWORD_SERVER_AUTH_FUNCTIONS_VERSION_1 {
+            AllocateDb2ErrorMessage( "db2secServerAuthPluginInit",
+                                     "Invalidate function version",
+                                     errormsg, errormsglen );
+
+            return Db2rc::DB2SEC_PLUGIN_INCOMPATIBLE_VER as SQL_API_RC;
+        }
+
+        // All of the input looks good.  We can now start doing things.
+        // If there is any one time initialization, now is the time to do it.
+
+        // This replaces any state left over from an earlier init.
+        match PluginState::Build( getConDetailsFn, logMessageFn, CurrentPluginState().as_deref() ) {
+            Ok(st) => {
+                InstallPluginState( st );
+                AttachPluginState( SERVER_AUTH_PLUGIN );
+            },
+            Err(e) => {
+                // The plugin state is not set up, so use the callback directly.
+                LogMessageWithCallback( logMessageFn, Db2LogLevels::DB2SEC_LOG_ERROR,
+                                        &format!("RUSTSECP cannot be initialized: {}", e) );
+                AllocateDb2ErrorMessage( "db2secServerAuthPluginInit", &e, errormsg, errormsglen );
+                return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+            }
+        }
+
+        // Cast the void * parameter to the function structure.
+        let serverFns : &mut db2secUseridPasswordServerAuthFunctions_1 =
+               unsafe { &mut *(server_fns as *mut db2secUseridPasswordServerAuthFunctions_1) };
 
-    // Ensure we have valid pointers, that the are not null.
-    if server_fns.is_null() ||
-       errormsg.is_null() ||
-       errormsglen.is_null() {
-        // These pointers should never be null.
-        return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+        serverFns.version                    = DB2SEC_USERID_PASSWORD_SERVER_AUTH_FUNCTIONS_VERSION_1;
+        serverFns.plugintype                 = DB2SEC_PLUGIN_TYPE_USERID_PASSWORD;
+        serverFns.db2secValidatePassword     = Some( ValidatePassword );
+        serverFns.db2secGetAuthIDs           = Some( GetAuthIDs );
+        serverFns.db2secDoesAuthIDExist      = Some( DoesAuthIDExist );
+        serverFns.db2secFreeToken            = Some( FreeToken );
+        serverFns.db2secFreeErrormsg         = Some( FreeErrorMsg );
+        serverFns.db2secServerAuthPluginTerm = Some( ServerAuthPluginTerm );
+
+        LogMessageToDb2Diag( LogModule::Plugin, Db2LogLevels::DB2SEC_LOG_INFO,
+                             "RUST based security u/pw plugin is initialized" );
+
+        Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+    });
+
+    AbandonInitErrorMessage( rc, errormsg );
+    rc
+}
+
+//-----------------------------------------------------------------------------
+// Helper function to log messages to the db2diag.log
+// The configured log level, module filter and rate limit apply, see diaglog.rs.
+fn LogMessageToDb2Diag( module : LogModule, level : Db2LogLevels, msg : &str  ) {
+    let state = match CurrentPluginState() {
+        Some(st) => st,
+        None => return,
+    };
+    let config = &state.config;
+
+    if ! LogEnabled( config.logLevel, &config.logFilter, module, level ) {
+        return;
     }
 
-    // Ensure we have a valid getConDetails callback.
-    match getConDetails_fn {
-        Some(f) => {unsafe {DB2_GET_CON_DETAILS_CB = Some(f);}},
-        None =>
-        {
-            return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC;
+    match RateLimit( module, level, config.logRateLimit ) {
+        RateDecision::Suppress => return,
+        RateDecision::Log(0) => {},
+        RateDecision::Log(held) => {
+            LogMessageWithCallback( state.logMessage, Db2LogLevels::DB2SEC_LOG_WARNING,
+                                    &format!("RUSTSECP {} messages from {} were suppressed by log_rate_limit",
+                                             held, module.Name()) );
         }
     }
 
-    // Ensure we are given a valid version of the functions.
-    // We require at least version 1 of the APIs.
-    // Should never have version 0, but we will check.
-    if version < DB2SEC_USERID_PASSWORD_SERVER_AUTH_FUNCTIONS_VERSION_1 {
-        AllocateDb2ErrorMessage( "db2secServerAuthPluginInit",
-                                 "Invalidate function version",
-                                 errormsg, errormsglen );
+    LogMessageWithCallback( state.logMessage, level, &WithCorrelationId( msg ) );
+}
+
+fn LogMessageWithCallback( logcb : LogMessageFuncT, level : Db2LogLevels, msg : &str ) {
+    logcb( level as i32, msg.as_ptr() as * const c_char , msg.len() as i32 );
+}
+
+//-----------------------------------------------------------------------------
+// Helper function to ask Db2 about the connection we are being called for.
+// Returns None outside of a connection, e.g. for DoesAuthIDExist during a GRANT.
+fn GetConnectionDetails() -> Option<db2sec_con_details_3> {
+    let state = CurrentPluginState()?;
+
+    // All zeroes is a valid value for every field of this C struct.
+    let mut details : db2sec_con_details_3 = unsafe { std::mem::zeroed() };
 
-        return Db2rc::DB2SEC_PLUGIN_INCOMPATIBLE_VER as SQL_API_RC;
+    let rc = (state.getConDetails)( DB2SEC_CON_DETAILS_VERSION_3,
+                                    &mut details as * mut db2sec_con_details_3 as * mut c_void );
+
+    if rc == Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC { Some( details ) } else { None }
+}
+
+impl db2sec_con_details_3 {
+    // The IPv4 address is in network byte order.  An IPv6 client has a zero
+    // IPv4 address and fills in clientIP6Address instead.
+    fn ClientIP( &self ) -> Option<IpAddr> {
+        if self.clientIPAddress != 0 {
+            return Some( IpAddr::V4( Ipv4Addr::from( u32::from_be( self.clientIPAddress ) ) ) );
+        }
+        if self.clientIP6Address != [0; 4] {
+            let mut octets = [0u8; 16];
+            for (i, word) in self.clientIP6Address.iter().enumerate() {
+                octets[i * 4..i * 4 + 4].copy_from_slice( &word.to_ne_bytes() );
+            }
+            return Some( IpAddr::V6( Ipv6Addr::from( octets ) ) );
+        }
+        None
     }
 
-    // All of the input looks good.  We can now start doing things.
-    // If there is any one time initialization, now is the time to do it.
+    fn Dbname( &self ) -> Option<String> {
+        let len = (self.dbnameLen.max( 0 ) as usize).min( self.dbname.len() );
+        let bytes : Vec<u8> = self.dbname[..len].iter().map( |c| *c as u8 ).collect();
+        String::from_utf8( bytes ).ok().filter( |s| ! s.is_empty() )
+    }
+}
 
-    unsafe {
-        // Setup a global map of userid/password.
-        // Obviously you wouldn't do this for a real system, this is just a demo.
-        let mut pwMap = HashMap::new();
+//-----------------------------------------------------------------------------
+// Helper function to count a finished API call and write its audit record.
+// A failure to audit is logged but does not change the outcome of the call.
+fn WriteAuditRecord( record : &AuditRecord, rc : SQL_API_RC ) {
+    let _ = std::panic::catch_unwind( std::panic::AssertUnwindSafe( || {
+        let result = match Db2rc::FromCode( rc ) {
+            Some(r) => format!("{:?}", r),
+            None => rc.to_string(),
+        };
+        metrics::CountAttempt( &format!("{:?}", record.Call()), &result );
+        PublishAuditEvent( record, rc, result );
+    }));
+}
 
-        pwMap.insert(String::from("gstager"), String::from("temp4Now") );
-        pwMap.insert(String::from("newton"), String::from("newtonpw") );
-        pwMap.insert(String::from("zurbie"), String::from("zurbiepw") );
+// An alert goes to the audit log and SIEM feed like any other record, but
+// it is not a call and is not counted as one.  rc is that of the attempt
+// that raised it.
+fn WriteAlertRecord( record : &AuditRecord, rc : SQL_API_RC ) {
+    let _ = std::panic::catch_unwind( std::panic::AssertUnwindSafe( || {
+        PublishAuditEvent( record, rc, String::from( "RUSTSECP_ALERT" ) );
+    }));
+}
 
-        DB2_USERPW_MAP = Some( pwMap );
+fn PublishAuditEvent( record : &AuditRecord, rc : SQL_API_RC, result : String ) {
+    let state = match CurrentPluginState() {
+        Some(st) => st,
+        None => return,
+    };
+    if state.audit.is_none() && state.siem.is_none() {
+        return;
     }
 
-    // Cast the void * parameter to the function structure.
-    let serverFns : &mut db2secUseridPasswordServerAuthFunctions_1 =
-           unsafe { &mut *(server_fns as *mut db2secUseridPasswordServerAuthFunctions_1) };
+    let details = GetConnectionDetails();
+    let client = AuditClient {
+        clientIP : details.as_ref().and_then( |d| d.ClientIP() ),
+        platform : details.as_ref().map( |d| d.clientPlatform ),
+        database : details.as_ref().and_then( |d| d.Dbname() ),
+    };
 
-    serverFns.version                    = DB2SEC_USERID_PASSWORD_SERVER_AUTH_FUNCTIONS_VERSION_1;
-    serverFns.plugintype                 = DB2SEC_PLUGIN_TYPE_USERID_PASSWORD;
-    serverFns.db2secValidatePassword     = Some( ValidatePassword );
-    serverFns.db2secGetAuthIDs           = Some( GetAuthIDs );
-    serverFns.db2secDoesAuthIDExist      = Some( DoesAuthIDExist );
-    serverFns.db2secFreeToken            = Some( FreeToken );
-    serverFns.db2secFreeErrormsg         = Some( FreeErrorMsg );
-    serverFns.db2secServerAuthPluginTerm = Some( ServerAuthPluginTerm );
+    let event = record.Finish( &client, rc, result );
 
-    Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC
+    if let Some(audit) = &state.audit {
+        if let Err(e) = audit.Write( &event ) {
+            LogMessageToDb2Diag( LogModule::Audit, Db2LogLevels::DB2SEC_LOG_ERROR,
+                                 &format!("RUSTSECP audit record lost: {}", e) );
+        }
+    }
+    if let Some(siem) = &state.siem {
+        if ! siem.Send( &event ) {
+            LogMessageToDb2Diag( LogModule::Siem, Db2LogLevels::DB2SEC_LOG_WARNING,
+                                 "RUSTSECP SIEM queue is full, event dropped from the feed" );
+        }
+    }
 }
 
+// Log what detect.rs found and raise the alert for it.
+fn ReportDetection( found : &detect::Detection, userid : &str, client : Option<IpAddr>,
+                    correlationId : &str, rc : Db2rc, config : &config::PluginConfig ) {
+    let address = client.map_or( String::from( "an unknown address" ), |c| c.to_string() );
+    if found.alert {
+        let what = match found.pattern {
+            detect::Pattern::CredentialStuffing =>
+                format!("{} userids failed from {} within {} seconds",
+                        found.userids, address, config.detectWindowSeconds),
+            detect::Pattern::PasswordSpray =>
+                format!("the same wrong password failed for {} userids within {} seconds, the latest from {}",
+                        found.userids, config.detectWindowSeconds, address),
+        };
+        RaiseAlert( found.pattern.Name(), what, Db2LogLevels::DB2SEC_LOG_WARNING, userid, correlationId, rc );
+    }
+    if found.blocked {
+        LogMessageToDb2Diag( LogModule::Auth, Db2LogLevels::DB2SEC_LOG_WARNING,
+                             &format!("RUSTSECP blocking {} for {} seconds after {}",
+                                      address, config.detectBlockSeconds, found.pattern.Name()) );
+    }
+}
+
+// A decoy user or canary password was tried, see userstore.rs.  Nobody has
+// a reason to, so this is logged as an error, with everything Db2 told
+// about the connection.
+fn ReportTrap( rule : &'static str, userid : &str, flags : ConnectionFlags, correlationId : &str, rc : Db2rc ) {
+    let what = if rule == "honeypot-account" { "the decoy user was tried" } else { "a canary password was tried" };
+    let connection = match GetConnectionDetails() {
+        None => format!("no connection details, connection flags {:?}", flags),
+        Some(d) => format!("client {}, protocol {}, platform {}, database {}, connection flags {:?}",
+                           d.ClientIP().map_or( String::from( "unknown" ), |c| c.to_string() ),
+                           d.clientProtocol, d.clientPlatform,
+                           d.Dbname().unwrap_or_else( || String::from( "none" ) ), flags),
+    };
+    RaiseAlert( rule, format!("{} for userid {}: {}", what, userid, connection),
+                Db2LogLevels::DB2SEC_LOG_ERROR, userid, correlationId, rc );
+}
+
+// Log an alert, count it and write its record.  rule names what was found,
+// e.g. "password-spray".
+fn RaiseAlert( rule : &'static str, what : String, level : Db2LogLevels,
+               userid : &str, correlationId : &str, rc : Db2rc ) {
+    LogMessageToDb2Diag( LogModule::Auth, level, &format!("RUSTSECP {} detected: {}", rule, what) );
+    metrics::CountDetection( rule );
+
+    let mut alert = AuditRecord::New( AuditCall::Alert, correlationId );
+    alert.userid = Some( String::from( userid ) );
+    alert.rule = Some( rule );
+    alert.detail = Some( what );
+    WriteAlertRecord( &alert, rc as SQL_API_RC );
+}
 
 //-----------------------------------------------------------------------------
-// Helper function to log messages to the db2diag.log
-fn LogMessageToDb2Diag( level : Db2LogLevels, msg : &str  ) {
-    unsafe {
-        if let Some(logcb) = DB2_LOG_MESSAGE_CB {
-            logcb( level as i32, msg.as_ptr() as * const c_char , msg.len() as i32 );
+// Helper function to run the body of an API function with panics caught.
+// Every extern "C" function Db2 can call must go through this, a panic that
+// reaches the FFI boundary would abort the whole Db2 engine process.
+fn CatchPanics<F>( caller : &str,
+                   errormsg : * mut * mut c_char,
+                   errormsglen : * mut i32,
+                   body : F ) -> SQL_API_RC
+   where F : FnOnce() -> SQL_API_RC {
+    match std::panic::catch_unwind( std::panic::AssertUnwindSafe( body ) ) {
+        Ok(rc) => rc,
+        Err(payload) => {
+            let reason = if let Some(s) = payload.downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                String::from("unknown panic")
+            };
+
+            let msg = format!("Unexpected panic: {}", reason);
+
+            // Neither of these may panic again, we are outside catch_unwind now.
+            let _ = std::panic::catch_unwind( std::panic::AssertUnwindSafe( || {
+                LogMessageToDb2Diag( LogModule::Plugin, Db2LogLevels::DB2SEC_LOG_CRITICAL,
+                                     &format!("RUSTSECP {}: {}", caller, msg) );
+                AllocateDb2ErrorMessage( caller, &msg, errormsg, errormsglen );
+            }));
+
+            Db2rc::DB2SEC_PLUGIN_UNEXPECTED_SYSTEM_ERROR as SQL_API_RC
         }
     }
 }
@@ -661,54 +1292,230 @@ fn AllocateDb2ErrorMessage( caller : &str,
         return;
     }
 
-    let msg = format!("RUSTSECP Error from function {}: {}", caller, message);
+    let msg = WithCorrelationId( &format!("RUSTSECP Error from function {}: {}", caller, message) );
 
-    if let Ok(cmsg) = CString::new(msg) {
-        unsafe {
-            *errormsglen = cmsg.as_bytes().len() as i32;
-            *errormsg = cmsg.into_raw();
-        }
+    // Db2 reads up to errormsglen, a NUL in the message would cut it short.
+    let msg = msg.replace( '\0', " " );
+    unsafe {
+        *errormsglen = msg.len() as i32;
+        *errormsg = allocations::AllocateErrorMessage( &msg, caller );
     }
+}
 
-    // For now, we are not handling allocation errors from new()
+// When init fails Db2 has no function table to free the error message
+// with, so it is not counted as a leak.
+fn AbandonInitErrorMessage( rc : SQL_API_RC, errormsg : * mut * mut c_char ) {
+    if rc != Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC && ! errormsg.is_null() {
+        allocations::Abandon( unsafe { *errormsg } as * const c_void, AllocationKind::ErrorMessage );
+    }
+}
+
+// A free function was given a pointer that is not ours to free.  Leaking
+// it is the only safe thing to do.
+fn RejectFree( caller : &str,
+               message : &str,
+               errormsg : * mut * mut c_char,
+               errormsglen : * mut i32 ) -> SQL_API_RC {
+    LogMessageToDb2Diag( LogModule::Plugin, Db2LogLevels::DB2SEC_LOG_ERROR,
+                         &format!("{}: refused to free: {}", caller, message) );
+    AllocateDb2ErrorMessage( caller, message, errormsg, errormsglen );
+    Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as SQL_API_RC
 }
 
 
+// Convert a string passed in by Db2 into a Rust string.
+// The value is written to db2diag at log level info, so never use this for
+// passwords, use ConvertToOptionalSecret.
 fn ConvertToOptionalString( cstring : * const c_char,
                             cstringlen : i32,
+                            maxlen : i32,
                             caller : &str,
                             field  : &str,
                             errormsg : * mut * mut c_char,
                             errormsglen : * mut i32 )
+   -> Result< Option<String>, Db2rc> {
+    let optString = DecodeDb2String( cstring, cstringlen, maxlen, caller, field, true, errormsg, errormsglen )?;
+
+    if let Some(s) = &optString {
+        LogMessageToDb2Diag( LogModule::Input, Db2LogLevels::DB2SEC_LOG_INFO,
+                             &format!("ToString: from {}, field {} is {}", caller, field, s) );
+    }
+
+    Ok( optString )
+}
+
+// Same as ConvertToOptionalString, for authids and group names that come
+// from the engine rather than the client.  These are always UTF-8, the
+// client code page does not apply.
+fn ConvertEngineString( cstring : * const c_char,
+                        cstringlen : i32,
+                        maxlen : i32,
+                        caller : &str,
+                        field  : &str,
+                        errormsg : * mut * mut c_char,
+                        errormsglen : * mut i32 )
+   -> Result< Option<String>, Db2rc> {
+    let optString = DecodeDb2String( cstring, cstringlen, maxlen, caller, field, false, errormsg, errormsglen )?;
+
+    if let Some(s) = &optString {
+        LogMessageToDb2Diag( LogModule::Input, Db2LogLevels::DB2SEC_LOG_INFO,
+                             &format!("ToString: from {}, field {} is {}", caller, field, s) );
+    }
+
+    Ok( optString )
+}
+
+// Same as ConvertToOptionalString, for passwords and other secrets.
+// Only the length of the value is ever logged.
+fn ConvertToOptionalSecret( cstring : * const c_char,
+                            cstringlen : i32,
+                            maxlen : i32,
+                            caller : &str,
+                            field  : &str,
+                            errormsg : * mut * mut c_char,
+                            errormsglen : * mut i32 )
+   -> Result< Option<SecretString>, Db2rc> {
+    let optSecret = DecodeDb2String( cstring, cstringlen, maxlen, caller, field, true, errormsg, errormsglen )?
+                        .map( SecretString::New );
+
+    if let Some(s) = &optSecret {
+        LogMessageToDb2Diag( LogModule::Input, Db2LogLevels::DB2SEC_LOG_INFO,
+                             &format!("ToString: from {}, field {} is {}", caller, field, s) );
+    }
+
+    Ok( optSecret )
+}
+
+// Db2 gives us the length explicitly and does not promise a terminating
+// NUL, so exactly cstringlen bytes are read and decoded as UTF-8.  What
+// the client sent, fromClient, falls back to the configured client code
+// page, see codepage.rs.
+#[allow(clippy::too_many_arguments)]
+fn DecodeDb2String( cstring : * const c_char,
+                    cstringlen : i32,
+                    maxlen : i32,
+                    caller : &str,
+                    field  : &str,
+                    fromClient : bool,
+                    errormsg : * mut * mut c_char,
+                    errormsglen : * mut i32 )
    -> Result< Option<String>, Db2rc> {
     // It's OK for the string to be null.  Just return none option.
     if cstring.is_null() || cstringlen == 0 {
-        #[cfg(debug_assertions)]
-        LogMessageToDb2Diag( Db2LogLevels::DB2SEC_LOG_WARNING,
+        LogMessageToDb2Diag( LogModule::Input, Db2LogLevels::DB2SEC_LOG_INFO,
                              &format!("ToString: from {}, field {} is null", caller, field) );
 
         return Ok( None );
     }
 
-    let slice = unsafe { CStr::from_ptr( cstring ) };
+    if let Err(e) = CheckInputLength( field, cstringlen as i64, maxlen ) {
+        AllocateDb2ErrorMessage( caller, &e, errormsg, errormsglen );
+        return Err( Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR );
+    }
 
-    match slice.to_str() {
-        Ok(s)  => {
-            #[cfg(debug_assertions)]
-            LogMessageToDb2Diag( Db2LogLevels::DB2SEC_LOG_WARNING,
-                                 &format!("ToString: from {}, field {} is {}", caller, field, s) );
+    let bytes = unsafe { std::slice::from_raw_parts( cstring as * const u8, cstringlen as usize ) };
 
-            let mut news = String::from( s );
-            news.truncate(cstringlen as usize);
+    let codepage = match CurrentPluginState() {
+        Some(state) if fromClient => state.config.clientCodepage,
+        _ => CodePage::Utf8,
+    };
 
-            Ok( Some( news ) )
-        },
+    match codepage.Decode( bytes ) {
+        Ok(s)  => Ok( Some( s ) ),
         Err(e) => {
-            AllocateDb2ErrorMessage( caller,
-                                     &format!("{} is not utf8. Valid up to {}", field, e.valid_up_to() ),
-                                     errormsg, errormsglen);
+            let msg = if fromClient {
+                format!("{} is not valid UTF-8 and client_codepage is {}, valid up to byte {}",
+                        field, codepage.Name(), e.valid_up_to())
+            } else {
+                format!("{} is not valid UTF-8, valid up to byte {}", field, e.valid_up_to())
+            };
+            AllocateDb2ErrorMessage( caller, &msg, errormsg, errormsglen );
             Err( Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR )
         }
     }
 }
 
+// An input longer than Db2 allows is refused with DB2SEC_PLUGIN_UNKNOWNERROR.
+pub(crate) fn CheckInputLength( field : &str, length : i64, maxlen : i32 ) -> Result<(), String> {
+    if length < 0 || length > maxlen as i64 {
+        return Err( format!("{} has length {}, the maximum is {}", field, length, maxlen) );
+    }
+    Ok(())
+}
+
+//-----------------------------------------------------------------------------
+// For the command line tools, files named in the configuration as it is on
+// disk.
+pub fn ConfiguredControlSocket() -> Result<std::path::PathBuf, String> {
+    config::LoadPluginConfig()?
+        .controlSocket
+        .ok_or_else( || format!("control_socket is not set in {}", config::PluginConfigPath().display()) )
+}
+
+pub fn ConfiguredUserStore() -> Result<std::path::PathBuf, String> {
+    config::LoadPluginConfig()?
+        .userStore
+        .ok_or_else( || format!("user_store is not set in {}", config::PluginConfigPath().display()) )
+}
+
+//-----------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn MessageText( errormsg : * mut c_char, errormsglen : i32 ) -> String {
+        let bytes = unsafe { std::slice::from_raw_parts( errormsg as * const u8, errormsglen as usize ) };
+        String::from_utf8_lossy( bytes ).into_owned()
+    }
+
+    #[test]
+    fn CatchPanicsTurnsAPanicIntoAnErrorMessage() {
+        let mut errormsg : * mut c_char = std::ptr::null_mut();
+        let mut errormsglen : i32 = 0;
+
+        let rc = CatchPanics( "UnitTest", &mut errormsg, &mut errormsglen, || panic!("the body gave up") );
+        assert_eq!( rc, Db2rc::DB2SEC_PLUGIN_UNEXPECTED_SYSTEM_ERROR as SQL_API_RC );
+        assert!( ! errormsg.is_null() );
+        let text = MessageText( errormsg, errormsglen );
+        assert!( text.contains( "UnitTest: Unexpected panic: the body gave up" ), "{}", text );
+
+        // Db2 hands the message back, once.
+        assert_eq!( FreeErrorMsg( errormsg ), Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC );
+        assert_ne!( FreeErrorMsg( errormsg ), Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC );
+
+        // Any payload, not only a message.
+        let mut errormsg : * mut c_char = std::ptr::null_mut();
+        let rc = CatchPanics( "UnitTest", &mut errormsg, &mut errormsglen, || std::panic::panic_any( 42 ) );
+        assert_eq!( rc, Db2rc::DB2SEC_PLUGIN_UNEXPECTED_SYSTEM_ERROR as SQL_API_RC );
+        assert!( MessageText( errormsg, errormsglen ).contains( "Unexpected panic: unknown panic" ) );
+        assert_eq!( FreeErrorMsg( errormsg ), Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC );
+
+        // Without somewhere to put a message the panic is still caught.
+        let rc = CatchPanics( "UnitTest", std::ptr::null_mut(), std::ptr::null_mut(), || panic!("quietly") );
+        assert_eq!( rc, Db2rc::DB2SEC_PLUGIN_UNEXPECTED_SYSTEM_ERROR as SQL_API_RC );
+
+        // A body that returns is left alone.
+        let rc = CatchPanics( "UnitTest", &mut errormsg, &mut errormsglen, || Db2rc::DB2SEC_PLUGIN_BADPWD as SQL_API_RC );
+        assert_eq!( rc, Db2rc::DB2SEC_PLUGIN_BADPWD as SQL_API_RC );
+    }
+
+    #[test]
+    fn PanicIsReportedWithTheCorrelationIdEnteredInTheBody() {
+        let mut errormsg : * mut c_char = std::ptr::null_mut();
+        let mut errormsglen : i32 = 0;
+
+        // As the API functions do it.
+        let mut correlation = None;
+        let rc = CatchPanics( "UnitTest", &mut errormsg, &mut errormsglen, || {
+            correlation = Some( CorrelationScope::Enter( Some( String::from( "00c0ffee00c0ffee" ) ) ) );
+            panic!("after the scope")
+        });
+        assert_eq!( rc, Db2rc::DB2SEC_PLUGIN_UNEXPECTED_SYSTEM_ERROR as SQL_API_RC );
+        let text = MessageText( errormsg, errormsglen );
+        assert!( text.contains( "after the scope (correlation id 00c0ffee00c0ffee)" ), "{}", text );
+        assert_eq!( FreeErrorMsg( errormsg ), Db2rc::DB2SEC_PLUGIN_OK as SQL_API_RC );
+
+        drop( correlation );
+        assert_eq!( correlation::CurrentCorrelationId(), None );
+    }
+}
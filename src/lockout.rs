@@ -0,0 +1,117 @@
+//-----------------------------------------------------------------------------
+// Account lockout.
+//
+// Passwords in the user store are checked by this plugin alone, nothing in
+// Db2 or the OS counts the wrong ones.  Without a lockout a client could
+// guess at the password of one user for as long as it liked.  The delays
+// of tarpit.rs and the limits of ratelimit.rs slow guessing down, lockout
+// stops it for the user guessed at.
+//
+// After lockout_threshold wrong passwords in a row a user is locked out and
+// ValidatePassword returns DB2SEC_PLUGIN_USER_SUSPENDED, even for the right
+// password.  The lock ends by itself after lockout_seconds, or when an
+// operator unlocks the user through the control socket.  A threshold of 0
+// turns lockout off.
+//
+// Only users that exist are tracked, so guessing at random userids cannot
+// grow the table.  The table is process wide, a reload does not unlock
+// anybody.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use crate::config::PluginConfig;
+
+struct Account {
+    // Wrong passwords since the last good one.
+    failures : u32,
+    lockedAt : Option<SystemTime>,
+}
+
+static ACCOUNTS : Mutex<Option<HashMap<String, Account>>> = Mutex::new( None );
+
+fn Accounts() -> MutexGuard<'static, Option<HashMap<String, Account>>> {
+    match ACCOUNTS.lock() {
+        Ok(a) => a,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// Userids are matched without regard to case, so a locked user cannot get
+// another set of attempts by changing case.
+fn Key( userid : &str ) -> String {
+    userid.to_lowercase()
+}
+
+fn LockExpired( lockedAt : SystemTime, config : &PluginConfig ) -> bool {
+    config.lockoutSeconds != 0 &&
+    lockedAt.elapsed().unwrap_or_default() >= Duration::from_secs( config.lockoutSeconds )
+}
+
+pub fn IsLocked( userid : &str, config : &PluginConfig ) -> bool {
+    if config.lockoutThreshold == 0 {
+        return false;
+    }
+
+    let mut accounts = Accounts();
+    let table = accounts.get_or_insert_with( HashMap::new );
+    let key = Key( userid );
+
+    match table.get( &key ).and_then( |a| a.lockedAt ) {
+        None => false,
+        Some(t) if LockExpired( t, config ) => {
+            table.remove( &key );
+            false
+        },
+        Some(_) => true,
+    }
+}
+
+// A wrong password for a user that exists.  Returns true if this locked the
+// user out.
+pub fn RecordFailure( userid : &str, config : &PluginConfig ) -> bool {
+    if config.lockoutThreshold == 0 {
+        return false;
+    }
+
+    let mut accounts = Accounts();
+    let account = accounts.get_or_insert_with( HashMap::new )
+                          .entry( Key( userid ) )
+                          .or_insert( Account { failures : 0, lockedAt : None } );
+
+    account.failures += 1;
+    if account.lockedAt.is_none() && account.failures >= config.lockoutThreshold {
+        account.lockedAt = Some( SystemTime::now() );
+        return true;
+    }
+    false
+}
+
+pub fn RecordSuccess( userid : &str ) {
+    if let Some(table) = Accounts().as_mut() {
+        table.remove( &Key( userid ) );
+    }
+}
+
+// Returns false if the user was not locked.
+pub fn Unlock( userid : &str ) -> bool {
+    match Accounts().as_mut().and_then( |t| t.remove( &Key( userid ) ) ) {
+        Some(a) => a.lockedAt.is_some(),
+        None => false,
+    }
+}
+
+// Users locked out right now, with the time they were locked.
+pub fn LockedAccounts( config : &PluginConfig ) -> Vec<(String, SystemTime)> {
+    let accounts = Accounts();
+    let mut locked : Vec<(String, SystemTime)> =
+        accounts.iter()
+                .flat_map( |t| t.iter() )
+                .filter_map( |(user, a)| a.lockedAt.map( |t| (user.clone(), t) ) )
+                .filter( |(_, t)| ! LockExpired( *t, config ) )
+                .collect();
+
+    locked.sort();
+    locked
+}
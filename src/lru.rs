@@ -0,0 +1,84 @@
+//-----------------------------------------------------------------------------
+// Least recently used order for the bounded, process wide tables.
+//
+// The rate limit buckets, the progressive delay failures and the spray and
+// stuffing sightings are looked up under a global mutex by every login.
+// Sweeping a full table there would stall every Db2 agent for as long as
+// the sweep takes.  So each table keeps its entries in the order they were
+// last used: a call drops at most a few stale entries from the old end, and
+// a new entry in a full table takes the place of the least recently used.
+// Both cost O(log n), whatever the size of the table.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+// Stale entries one call drops at most.
+pub const DROP_PER_CALL : usize = 16;
+
+pub struct LruTable<K, V> {
+    // The value and when it was last used.
+    entries : HashMap<K, (V, u64)>,
+    // Keys by when they were last used, oldest first.
+    order : BTreeMap<u64, K>,
+    clock : u64,
+}
+
+impl<K : Clone + Eq + Hash, V> LruTable<K, V> {
+    pub fn New() -> LruTable<K, V> {
+        LruTable { entries : HashMap::new(), order : BTreeMap::new(), clock : 0 }
+    }
+
+    // Looking at an entry does not count as using it.
+    pub fn Get( &self, key : &K ) -> Option<&V> {
+        self.entries.get( key ).map( |(v, _)| v )
+    }
+
+    pub fn GetMut( &mut self, key : &K ) -> Option<&mut V> {
+        self.entries.get_mut( key ).map( |(v, _)| v )
+    }
+
+    pub fn Remove( &mut self, key : &K ) -> Option<V> {
+        let (value, used) = self.entries.remove( key )?;
+        self.order.remove( &used );
+        Some( value )
+    }
+
+    // The entry for key, made with new if there is none, now the most
+    // recently used.  In a table with capacity entries the least recently
+    // used one makes room for a new one.
+    pub fn Touch<F : FnOnce() -> V>( &mut self, key : K, capacity : usize, new : F ) -> &mut V {
+        self.clock += 1;
+        let now = self.clock;
+
+        if let Some((_, used)) = self.entries.get_mut( &key ) {
+            self.order.remove( used );
+            *used = now;
+        } else {
+            if self.entries.len() >= capacity.max( 1 ) {
+                if let Some((_, oldest)) = self.order.pop_first() {
+                    self.entries.remove( &oldest );
+                }
+            }
+            self.entries.insert( key.clone(), (new(), now) );
+        }
+        self.order.insert( now, key.clone() );
+
+        let (value, _) = self.entries.get_mut( &key ).expect( "the entry was just made" );
+        value
+    }
+
+    // Drop the least recently used entries for as long as stale says so,
+    // at most DROP_PER_CALL of them.
+    pub fn DropStale<F : Fn( &K, &V ) -> bool>( &mut self, stale : F ) {
+        for _ in 0..DROP_PER_CALL {
+            let oldest = match self.order.first_key_value() {
+                Some((_, k)) => k.clone(),
+                None => return,
+            };
+            match self.entries.get( &oldest ) {
+                Some((v, _)) if stale( &oldest, v ) => { self.Remove( &oldest ); },
+                _ => return,
+            }
+        }
+    }
+}
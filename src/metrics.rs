@@ -0,0 +1,243 @@
+//-----------------------------------------------------------------------------
+// Prometheus metrics.
+//
+// The plugin counts what it does in process wide counters, which survive a
+// reload.  A background thread writes them every metrics_interval_seconds
+// to metrics_file in the Prometheus text format, for the textfile collector
+// of node_exporter to pick up.  The file is written under a temporary name
+// and renamed, so the collector never sees half a file.
+//
+// Nothing is written unless metrics_file is set in the plugin configuration,
+// the counters are kept regardless.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::config::PluginConfig;
+
+// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS : [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+struct Histogram {
+    // Not cumulative, summed up when rendered.
+    buckets : [AtomicU64; LATENCY_BUCKETS.len()],
+    count : AtomicU64,
+    sumMicros : AtomicU64,
+}
+
+impl Histogram {
+    const fn New() -> Histogram {
+        Histogram {
+            buckets : [const { AtomicU64::new( 0 ) }; LATENCY_BUCKETS.len()],
+            count : AtomicU64::new( 0 ),
+            sumMicros : AtomicU64::new( 0 ),
+        }
+    }
+
+    fn Observe( &self, elapsed : Duration ) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position( |b| seconds <= *b ) {
+            self.buckets[i].fetch_add( 1, Ordering::Relaxed );
+        }
+        self.count.fetch_add( 1, Ordering::Relaxed );
+        self.sumMicros.fetch_add( elapsed.as_micros() as u64, Ordering::Relaxed );
+    }
+
+    fn Render( &self, out : &mut String, name : &str ) {
+        let mut cumulative = 0;
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            cumulative += self.buckets[i].load( Ordering::Relaxed );
+            let _ = writeln!( out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative );
+        }
+        let count = self.count.load( Ordering::Relaxed );
+        let _ = writeln!( out, "{}_bucket{{le=\"+Inf\"}} {}", name, count );
+        let _ = writeln!( out, "{}_sum {}", name, self.sumMicros.load( Ordering::Relaxed ) as f64 / 1e6 );
+        let _ = writeln!( out, "{}_count {}", name, count );
+    }
+}
+
+// (call, result) -> count
+static ATTEMPTS : Mutex<BTreeMap<(String, String), u64>> = Mutex::new( BTreeMap::new() );
+static BACKEND_LATENCY : Histogram = Histogram::New();
+static LOCKOUTS : AtomicU64 = AtomicU64::new( 0 );
+static RELOADS : AtomicU64 = AtomicU64::new( 0 );
+static OUTSTANDING_TOKENS : AtomicI64 = AtomicI64::new( 0 );
+// By limit: ip, user and global.
+static RATE_LIMITED : [AtomicU64; 3] = [const { AtomicU64::new( 0 ) }; 3];
+const RATE_LIMITS : [&str; 3] = [ "ip", "user", "global" ];
+// By pattern, see detect.rs, and for decoys and canaries, see userstore.rs.
+static DETECTIONS : [AtomicU64; 4] = [const { AtomicU64::new( 0 ) }; 4];
+const PATTERNS : [&str; 4] = [ "credential-stuffing", "password-spray", "honeypot-account", "canary-password" ];
+
+// result is the Db2rc name, e.g. DB2SEC_PLUGIN_BADPWD.
+pub fn CountAttempt( call : &str, result : &str ) {
+    let mut attempts = match ATTEMPTS.lock() {
+        Ok(a) => a,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    *attempts.entry( (String::from( call ), String::from( result )) ).or_insert( 0 ) += 1;
+}
+
+// A login refused because the user is locked out.  Counted from the
+// decision, uniform_failures answers these with DB2SEC_PLUGIN_BADPWD.
+pub fn CountLockout() {
+    LOCKOUTS.fetch_add( 1, Ordering::Relaxed );
+}
+
+// Time spent looking up and checking the user in the user store.
+pub fn ObserveBackendLatency( elapsed : Duration ) {
+    BACKEND_LATENCY.Observe( elapsed );
+}
+
+pub fn CountReload() {
+    RELOADS.fetch_add( 1, Ordering::Relaxed );
+}
+
+// limit is the name of the rate limit, see ratelimit.rs.
+pub fn CountRateLimited( limit : &str ) {
+    if let Some(i) = RATE_LIMITS.iter().position( |l| *l == limit ) {
+        RATE_LIMITED[i].fetch_add( 1, Ordering::Relaxed );
+    }
+}
+
+pub fn CountDetection( pattern : &str ) {
+    if let Some(i) = PATTERNS.iter().position( |p| *p == pattern ) {
+        DETECTIONS[i].fetch_add( 1, Ordering::Relaxed );
+    }
+}
+
+pub fn TokenCreated() {
+    OUTSTANDING_TOKENS.fetch_add( 1, Ordering::Relaxed );
+}
+
+pub fn TokenFreed() {
+    OUTSTANDING_TOKENS.fetch_sub( 1, Ordering::Relaxed );
+}
+
+fn LabelEscape( s : &str ) -> String {
+    s.replace( '\\', "\\\\" ).replace( '"', "\\\"" ).replace( '\n', "\\n" )
+}
+
+pub fn RenderMetrics() -> String {
+    let mut out = String::new();
+
+    out.push_str( "# HELP rustsecp_attempts_total Plugin calls by API function and Db2 result code.\n" );
+    out.push_str( "# TYPE rustsecp_attempts_total counter\n" );
+    {
+        let attempts = match ATTEMPTS.lock() {
+            Ok(a) => a,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for ((call, result), count) in attempts.iter() {
+            let _ = writeln!( out, "rustsecp_attempts_total{{call=\"{}\",result=\"{}\"}} {}",
+                              LabelEscape( call ), LabelEscape( result ), count );
+        }
+    }
+
+    out.push_str( "# HELP rustsecp_backend_latency_seconds Time to look up and check a user in the user store.\n" );
+    out.push_str( "# TYPE rustsecp_backend_latency_seconds histogram\n" );
+    BACKEND_LATENCY.Render( &mut out, "rustsecp_backend_latency_seconds" );
+
+    out.push_str( "# HELP rustsecp_lockouts_total Attempts rejected because the user is locked out.\n" );
+    out.push_str( "# TYPE rustsecp_lockouts_total counter\n" );
+    let _ = writeln!( out, "rustsecp_lockouts_total {}", LOCKOUTS.load( Ordering::Relaxed ) );
+
+    out.push_str( "# HELP rustsecp_rate_limited_total Login attempts refused by a rate limit, by limit.\n" );
+    out.push_str( "# TYPE rustsecp_rate_limited_total counter\n" );
+    for (i, limit) in RATE_LIMITS.iter().enumerate() {
+        let _ = writeln!( out, "rustsecp_rate_limited_total{{limit=\"{}\"}} {}", limit, RATE_LIMITED[i].load( Ordering::Relaxed ) );
+    }
+
+    out.push_str( "# HELP rustsecp_attacks_detected_total Alerts raised, by what was detected.\n" );
+    out.push_str( "# TYPE rustsecp_attacks_detected_total counter\n" );
+    for (i, pattern) in PATTERNS.iter().enumerate() {
+        let _ = writeln!( out, "rustsecp_attacks_detected_total{{pattern=\"{}\"}} {}", pattern, DETECTIONS[i].load( Ordering::Relaxed ) );
+    }
+
+    out.push_str( "# HELP rustsecp_reloads_total Successful configuration reloads.\n" );
+    out.push_str( "# TYPE rustsecp_reloads_total counter\n" );
+    let _ = writeln!( out, "rustsecp_reloads_total {}", RELOADS.load( Ordering::Relaxed ) );
+
+    out.push_str( "# HELP rustsecp_outstanding_tokens Tokens handed to Db2 and not freed yet.\n" );
+    out.push_str( "# TYPE rustsecp_outstanding_tokens gauge\n" );
+    let _ = writeln!( out, "rustsecp_outstanding_tokens {}", OUTSTANDING_TOKENS.load( Ordering::Relaxed ) );
+
+    out
+}
+
+fn WriteMetricsFile( path : &Path ) -> std::io::Result<()> {
+    let mut tmpName = path.as_os_str().to_os_string();
+    tmpName.push( format!(".{}.tmp", std::process::id()) );
+    let tmp = PathBuf::from( tmpName );
+
+    std::fs::write( &tmp, RenderMetrics() )?;
+    std::fs::rename( &tmp, path )
+}
+
+//-----------------------------------------------------------------------------
+// The writer thread runs until its MetricsWriter is dropped, then writes the
+// file one last time.  The drop waits for that, Db2 may unload the library
+// right after ServerAuthPluginTerm and a thread still running would run
+// unmapped code.
+
+pub struct MetricsWriter {
+    path : PathBuf,
+    interval : Duration,
+    // Never sent on, dropping it stops the thread.
+    stop : Option<Sender<()>>,
+    thread : Option<JoinHandle<()>>,
+}
+
+impl MetricsWriter {
+    // Returns None when metrics_file is not configured.
+    pub fn Open( config : &PluginConfig ) -> Result<Option<MetricsWriter>, String> {
+        let path = match &config.metricsFile {
+            None => return Ok( None ),
+            Some(p) => p.clone(),
+        };
+        let interval = Duration::from_secs( config.metricsIntervalSeconds.max( 1 ) );
+
+        // Fail the init or reload now rather than silently later.
+        WriteMetricsFile( &path ).map_err( |e| format!("Cannot write {}: {}", path.display(), e) )?;
+
+        let (stop, stopped) = channel::<()>();
+        let threadPath = path.clone();
+
+        let thread = std::thread::Builder::new()
+            .name( String::from( "rustsecp-metrics" ) )
+            .spawn( move || {
+                loop {
+                    let last = matches!( stopped.recv_timeout( interval ), Err(RecvTimeoutError::Disconnected) );
+                    // There is nobody to report a failure to, the next
+                    // interval tries again.
+                    let _ = WriteMetricsFile( &threadPath );
+                    if last {
+                        break;
+                    }
+                }
+            })
+            .map_err( |e| format!("Cannot start the metrics writer: {}", e) )?;
+
+        Ok( Some( MetricsWriter { path, interval, stop : Some( stop ), thread : Some( thread ) } ) )
+    }
+
+    pub fn SameSettings( &self, config : &PluginConfig ) -> bool {
+        config.metricsFile.as_ref() == Some( &self.path ) &&
+        Duration::from_secs( config.metricsIntervalSeconds.max( 1 ) ) == self.interval
+    }
+}
+
+impl Drop for MetricsWriter {
+    fn drop( &mut self ) {
+        self.stop = None;
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
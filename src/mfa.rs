@@ -0,0 +1,436 @@
+//-----------------------------------------------------------------------------
+// Second factors: HOTP, recovery codes, and how they are checked with TOTP.
+//
+// Besides a TOTP secret (totp.rs) a user can have a HOTP secret, for
+// machines whose clock cannot be trusted, and recovery codes, for when the
+// authenticator is lost.  Either goes after the password like a TOTP code:
+//
+//    HOTP      RFC 4226, HMAC-SHA1 and six digits.  A code is accepted from
+//              the next unused counter to hotp_window counters ahead, which
+//              catches up with a token that was pressed a few times in
+//              between.  A token further ahead is resynchronised with two
+//              codes in a row, by rustsecp-admin hotp-resync.
+//    recovery  xxxx-xxxx-xxxx-xxxx, 80 random bits, issued by rustsecp-admin
+//              recovery-issue and shown once.  The store has only their
+//              SHA-256 digests, a slow hash adds nothing for codes this
+//              random.  Each code is good once, in place of the TOTP or
+//              HOTP code of a user who has a secret; they are no second
+//              factor of their own.
+//
+// A recovery code is the last 19 characters of the password field and a
+// HOTP or TOTP code the last six, with totp_separator before them if it is
+// set.  The separator may be any text, even a '-' or a digit.
+//
+// Counters that moved, recovery codes that were used and the step of the
+// last TOTP code must survive a restart, or the codes could be used again.  The plugin keeps them in
+// USER_STORE.mfa next to the user store and rewrites that atomically
+// before a login with such a code succeeds.  If it cannot, the login fails.
+// A TOTP code without a user_store is only remembered in memory.
+// The user store itself is only ever written by rustsecp-admin, which
+// reads the .mfa file to show and resynchronise the counters.
+//
+// This module is shared with the rustsecp-admin tool.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::auditchain::ToHex;
+use crate::totp::{self, CodeRefusal, DecodeSecret, CODE_DIGITS};
+use crate::userstore::UserEntry;
+
+const STATE_VERSION : u32 = 1;
+pub const RECOVERY_CODES : usize = 10;
+const RECOVERY_GROUPS : usize = 4;
+const RECOVERY_LEN : usize = RECOVERY_GROUPS * 5 - 1;
+// How far rustsecp-admin hotp-resync looks ahead.
+pub const RESYNC_WINDOW : u64 = 1000;
+
+//-----------------------------------------------------------------------------
+// The .mfa file
+
+#[derive(Clone, Serialize, Deserialize)]
+struct HotpPosition {
+    // SecretId of the secret the counter is for.  When the user gets a new
+    // secret the old counter no longer applies.
+    secret : String,
+    next : u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct TotpPosition {
+    // SecretId of the secret, as for HotpPosition.
+    secret : String,
+    step : u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MfaState {
+    version : u32,
+    // By lower case userid.
+    #[serde(default)]
+    hotp : BTreeMap<String, HotpPosition>,
+    // The step of the last TOTP code used, by lower case userid.
+    #[serde(default)]
+    totp : BTreeMap<String, TotpPosition>,
+    // Digests of used recovery codes, by lower case userid.
+    #[serde(default)]
+    used : BTreeMap<String, BTreeSet<String>>,
+}
+
+// Held while the .mfa file is read, changed and written.
+static STATE_LOCK : Mutex<()> = Mutex::new( () );
+
+pub fn StatePath( userStore : &Path ) -> PathBuf {
+    let mut name = userStore.as_os_str().to_os_string();
+    name.push( ".mfa" );
+    PathBuf::from( name )
+}
+
+fn SecretId( secret : &str ) -> String {
+    ToHex( &Sha256::digest( secret.as_bytes() )[..8] )
+}
+
+impl MfaState {
+    // A missing file is an empty state.
+    pub fn Load( path : &Path ) -> Result<MfaState, String> {
+        let text = match std::fs::read_to_string( path ) {
+            Ok(t) => t,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok( MfaState { version : STATE_VERSION, hotp : BTreeMap::new(), totp : BTreeMap::new(),
+                                     used : BTreeMap::new() } );
+            },
+            Err(e) => return Err( format!("Cannot read {}: {}", path.display(), e) ),
+        };
+        let state : MfaState = serde_json::from_str( &text ).map_err( |e| format!("{}: {}", path.display(), e) )?;
+        if state.version != STATE_VERSION {
+            return Err( format!("{}: version {} is not supported", path.display(), state.version) );
+        }
+        Ok( state )
+    }
+
+    // Written next to the old file and renamed over it.
+    fn Save( &self, path : &Path ) -> Result<(), String> {
+        let mut text = serde_json::to_string_pretty( self ).map_err( |e| e.to_string() )?;
+        text.push( '\n' );
+
+        let mut tmp = path.as_os_str().to_os_string();
+        tmp.push( format!(".tmp.{}", std::process::id()) );
+        let tmp = PathBuf::from( tmp );
+        let written = std::fs::OpenOptions::new().write( true ).create( true ).truncate( true ).mode( 0o600 )
+                          .open( &tmp )
+                          .and_then( |mut f| { f.write_all( text.as_bytes() )?; f.sync_all() } )
+                          .and_then( |_| std::fs::rename( &tmp, path ) );
+        written.map_err( |e| {
+            let _ = std::fs::remove_file( &tmp );
+            format!("Cannot write {}: {}", path.display(), e)
+        })
+    }
+
+    // The first HOTP counter the user may use.
+    pub fn NextHotp( &self, user : &UserEntry ) -> u64 {
+        let stored = user.hotpCounter;
+        match (&user.hotp, self.hotp.get( &user.userid.to_lowercase() )) {
+            (Some(secret), Some(p)) if p.secret == SecretId( secret ) => p.next.max( stored ),
+            _ => stored,
+        }
+    }
+
+    fn LastTotpStep( &self, user : &UserEntry ) -> Option<u64> {
+        match (&user.totp, self.totp.get( &user.userid.to_lowercase() )) {
+            (Some(secret), Some(p)) if p.secret == SecretId( secret ) => Some( p.step ),
+            _ => None,
+        }
+    }
+
+    pub fn RecoveryCodesLeft( &self, user : &UserEntry ) -> usize {
+        let used = self.used.get( &user.userid.to_lowercase() );
+        user.recovery.iter().filter( |d| ! used.is_some_and( |u| u.contains( *d ) ) ).count()
+    }
+}
+
+//-----------------------------------------------------------------------------
+// Recovery codes
+
+pub fn NewRecoveryCode() -> Result<String, String> {
+    let mut random = [0u8; RECOVERY_GROUPS * 5 / 2];
+    getrandom::getrandom( &mut random ).map_err( |e| format!("Cannot generate a recovery code: {}", e) )?;
+    let text = totp::EncodeSecret( &random ).to_lowercase();
+    let groups : Vec<&str> = (0..RECOVERY_GROUPS).map( |i| &text[i * 4..i * 4 + 4] ).collect();
+    Ok( groups.join( "-" ) )
+}
+
+pub fn RecoveryDigest( code : &str ) -> String {
+    let mut hash = Sha256::new();
+    hash.update( b"rustsecp-recovery" );
+    hash.update( code.to_lowercase().as_bytes() );
+    ToHex( &hash.finalize() )
+}
+
+fn LooksLikeRecoveryCode( text : &str ) -> bool {
+    text.len() == RECOVERY_LEN &&
+    text.split( '-' ).all( |g| g.len() == 4 && g.bytes().all( |b| b.is_ascii_alphabetic() || (b'2'..=b'7').contains( &b ) ) )
+}
+
+//-----------------------------------------------------------------------------
+// Checking
+
+// What came after the password.
+#[derive(Clone, Copy)]
+pub enum Factor<'a> {
+    Code( &'a str ),
+    Recovery( &'a str ),
+}
+
+// How the second factor was accepted, for the trace.
+pub enum Accepted {
+    Totp,
+    Hotp( u64 ),
+    Recovery( usize ),
+}
+
+pub struct Refusal {
+    pub rule : &'static str,
+    // Set when the plugin failed, rather than the code.
+    pub error : Option<String>,
+}
+
+fn Refuse( rule : &'static str ) -> Refusal {
+    Refusal { rule, error : None }
+}
+
+// Recovery codes are a way around a lost authenticator, a user with only
+// those has no second factor.
+pub fn HasSecondFactor( user : &UserEntry ) -> bool {
+    user.totp.is_some() || user.hotp.is_some()
+}
+
+// The password and the second factor in what was typed into the password
+// field, None if there is no second factor where there should be one.
+pub fn SplitFactor<'a>( given : &'a str, separator : Option<&str>, user : &UserEntry ) -> Option<(&'a str, Factor<'a>)> {
+    let hasRecovery = ! user.recovery.is_empty();
+    // Split off the last len characters and the separator before them.  The
+    // lengths are known, so a separator that is also in a recovery code or
+    // the password does not split in the wrong place.
+    let sep = separator.unwrap_or( "" );
+    let tail = |len : usize| given.len().checked_sub( len + sep.len() )
+                                  .and_then( |at| given.split_at_checked( at ) )
+                                  .and_then( |(p, r)| Some( (p, r.strip_prefix( sep )?) ) );
+    let (password, rest) = match tail( RECOVERY_LEN ).filter( |(_, r)| hasRecovery && LooksLikeRecoveryCode( r ) ) {
+        Some(split) => split,
+        None => tail( CODE_DIGITS )?,
+    };
+
+    if hasRecovery && LooksLikeRecoveryCode( rest ) {
+        Some( (password, Factor::Recovery( rest )) )
+    } else if rest.len() == CODE_DIGITS && rest.bytes().all( |b| b.is_ascii_digit() ) {
+        Some( (password, Factor::Code( rest )) )
+    } else {
+        None
+    }
+}
+
+// Check the second factor of a user whose password was right.  With
+// record set a code that was accepted is used up, the simulator leaves it
+// unset.  userStore is where the .mfa file goes.
+pub fn CheckFactor( user : &UserEntry,
+                    factor : Factor,
+                    userStore : Option<&Path>,
+                    skewSteps : u64,
+                    hotpWindow : u64,
+                    now : SystemTime,
+                    record : bool ) -> Result<Accepted, Refusal> {
+    match factor {
+        Factor::Code(code) => {
+            let mut refusal = Refuse( "otp-mismatch" );
+            if let Some((encoded, Some(secret))) = user.totp.as_deref().map( |t| (t, DecodeSecret( t )) ) {
+                match CheckTotp( user, encoded, &secret, code, userStore, skewSteps, now, record ) {
+                    Ok(a) => return Ok( a ),
+                    Err(r) if r.error.is_some() => return Err( r ),
+                    Err(r) => refusal = r,
+                }
+            }
+            match user.hotp.as_deref().map( |h| (h, DecodeSecret( h )) ) {
+                Some((encoded, Some(secret))) => CheckHotp( user, encoded, &secret, code, userStore, hotpWindow, record )
+                                                     .map_err( |r| if r.error.is_some() { r } else { refusal } ),
+                _ => Err( refusal ),
+            }
+        },
+        Factor::Recovery(code) => UseRecoveryCode( user, code, userStore, record ),
+    }
+}
+
+fn StateFor( userStore : Option<&Path> ) -> Result<(PathBuf, MfaState), Refusal> {
+    let path = userStore.map( StatePath ).ok_or_else( || Refusal {
+        rule : "mfa-state-error",
+        error : Some( String::from( "HOTP and recovery codes need a user_store" ) ),
+    })?;
+    let state = MfaState::Load( &path ).map_err( |e| Refusal { rule : "mfa-state-error", error : Some( e ) } )?;
+    Ok( (path, state) )
+}
+
+fn Saved( state : &MfaState, path : &Path ) -> Result<(), Refusal> {
+    state.Save( path ).map_err( |e| Refusal { rule : "mfa-state-error", error : Some( e ) } )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn CheckTotp( user : &UserEntry, encoded : &str, secret : &[u8], code : &str,
+              userStore : Option<&Path>, skewSteps : u64, now : SystemTime, record : bool ) -> Result<Accepted, Refusal> {
+    let _lock = STATE_LOCK.lock().unwrap_or_else( |e| e.into_inner() );
+    let stored = match userStore {
+        Some(_) => Some( StateFor( userStore )? ),
+        None => None,
+    };
+
+    let lastStep = stored.as_ref().and_then( |(_, state)| state.LastTotpStep( user ) );
+    let step = match totp::CheckCode( &user.userid, secret, code, skewSteps, now, lastStep ) {
+        Ok(s) => s,
+        Err(CodeRefusal::Replayed) => return Err( Refuse( "totp-replayed" ) ),
+        Err(CodeRefusal::Mismatch) => return Err( Refuse( "otp-mismatch" ) ),
+    };
+    if record {
+        // Only a step that was saved is used up in memory, the lock keeps
+        // another login from checking the same code in between.
+        if let Some((path, mut state)) = stored {
+            state.totp.insert( user.userid.to_lowercase(), TotpPosition { secret : SecretId( encoded ), step } );
+            Saved( &state, &path )?;
+        }
+        totp::RecordStep( &user.userid, step );
+    }
+    Ok( Accepted::Totp )
+}
+
+fn CheckHotp( user : &UserEntry, encoded : &str, secret : &[u8], code : &str,
+              userStore : Option<&Path>, window : u64, record : bool ) -> Result<Accepted, Refusal> {
+    let _lock = STATE_LOCK.lock().unwrap_or_else( |e| e.into_inner() );
+    let (path, mut state) = StateFor( userStore )?;
+
+    // Every counter in the window is compared, as for TOTP, and the lowest
+    // that matches is the one used.
+    let next = state.NextHotp( user );
+    let mut matched = None;
+    for counter in (next..=next.saturating_add( window )).rev() {
+        if totp::SameCode( &totp::Code( secret, counter ), code ) {
+            matched = Some( counter );
+        }
+    }
+    let counter = matched.ok_or( Refuse( "otp-mismatch" ) )?;
+    if record {
+        state.hotp.insert( user.userid.to_lowercase(), HotpPosition { secret : SecretId( encoded ), next : counter + 1 } );
+        Saved( &state, &path )?;
+    }
+    Ok( Accepted::Hotp( counter ) )
+}
+
+fn UseRecoveryCode( user : &UserEntry, code : &str, userStore : Option<&Path>, record : bool ) -> Result<Accepted, Refusal> {
+    let digest = RecoveryDigest( code );
+    if ! user.recovery.contains( &digest ) {
+        return Err( Refuse( "recovery-code-mismatch" ) );
+    }
+
+    let _lock = STATE_LOCK.lock().unwrap_or_else( |e| e.into_inner() );
+    let (path, mut state) = StateFor( userStore )?;
+    let key = user.userid.to_lowercase();
+    if state.used.get( &key ).is_some_and( |u| u.contains( &digest ) ) {
+        return Err( Refuse( "recovery-code-reused" ) );
+    }
+    if record {
+        let used = state.used.entry( key ).or_default();
+        used.insert( digest );
+        // Codes the user no longer has need not be remembered.
+        used.retain( |d| user.recovery.contains( d ) );
+        Saved( &state, &path )?;
+    }
+    // Without record the code is still among them.
+    let left = state.RecoveryCodesLeft( user );
+    Ok( Accepted::Recovery( if record { left } else { left.saturating_sub( 1 ) } ) )
+}
+
+// The counter after two HOTP codes in a row, searched for from the next
+// unused one.  For rustsecp-admin hotp-resync.
+pub fn ResyncHotp( user : &UserEntry, state : &MfaState, first : &str, second : &str ) -> Option<u64> {
+    let secret = user.hotp.as_deref().and_then( DecodeSecret )?;
+    let next = state.NextHotp( user );
+    (next..next + RESYNC_WINDOW).find( |c| totp::Code( &secret, *c ) == first && totp::Code( &secret, c + 1 ) == second )
+                               .map( |c| c + 2 )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::userstore::UserStore;
+
+    fn Lovelace( store : &mut UserStore, next : u64 ) -> &UserEntry {
+        let user = store.Add( "lovelace" ).unwrap();
+        user.hotp = Some( totp::EncodeSecret( b"12345678901234567890" ) );
+        user.hotpCounter = next;
+        user.recovery = vec![ RecoveryDigest( "abcd-efgh-ijkl-mnop" ) ];
+        user
+    }
+
+    // No .mfa file is written without record, a missing one is empty.
+    fn Hotp( user : &UserEntry, code : &str, window : u64 ) -> Option<u64> {
+        let store = std::env::temp_dir().join( format!("rustsecp-unit-{}-none.json", std::process::id()) );
+        match CheckFactor( user, Factor::Code( code ), Some( &store ), 1, window, SystemTime::now(), false ) {
+            Ok(Accepted::Hotp(counter)) => Some( counter ),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn HotpWindowEndsAtItsLastCounter() {
+        let mut store = UserStore::New();
+        let user = Lovelace( &mut store, 0 );
+        // RFC 4226 appendix D, counters 0 to 3 with a window of 3.
+        assert_eq!( Hotp( user, "755224", 3 ), Some( 0 ) );
+        assert_eq!( Hotp( user, "969429", 3 ), Some( 3 ) );
+        assert_eq!( Hotp( user, "338314", 3 ), None );
+        assert_eq!( Hotp( user, "338314", 4 ), Some( 4 ) );
+
+        // Nothing before the next unused counter.
+        let mut store = UserStore::New();
+        let user = Lovelace( &mut store, 5 );
+        assert_eq!( Hotp( user, "338314", 3 ), None );
+        assert_eq!( Hotp( user, "254676", 3 ), Some( 5 ) );
+        assert_eq!( Hotp( user, "399871", 3 ), Some( 8 ) );
+        assert_eq!( Hotp( user, "520489", 3 ), None );
+        assert_eq!( Hotp( user, "520489", 0 ), None );
+    }
+
+    fn Split<'a>( given : &'a str, separator : Option<&str>, user : &UserEntry ) -> Option<(&'a str, String)> {
+        SplitFactor( given, separator, user ).map( |(p, f)| match f {
+            Factor::Code(c) => (p, format!("code {}", c)),
+            Factor::Recovery(c) => (p, format!("recovery {}", c)),
+        })
+    }
+
+    #[test]
+    fn SplitFactorFindsTheCodeWhateverTheSeparator() {
+        let mut store = UserStore::New();
+        let user = Lovelace( &mut store, 0 );
+        let recovery = |p : &'static str| Some( (p, String::from( "recovery abcd-efgh-ijkl-mnop" )) );
+        let code = |p : &'static str| Some( (p, String::from( "code 755224" )) );
+
+        assert_eq!( Split( "engine755224", None, user ), code( "engine" ) );
+        assert_eq!( Split( "engineabcd-efgh-ijkl-mnop", None, user ), recovery( "engine" ) );
+        assert_eq!( Split( "engine:755224", Some( ":" ), user ), code( "engine" ) );
+        assert_eq!( Split( "engine:abcd-efgh-ijkl-mnop", Some( ":" ), user ), recovery( "engine" ) );
+        // A separator that is in the recovery code, the password or the code.
+        assert_eq!( Split( "engine-abcd-efgh-ijkl-mnop", Some( "-" ), user ), recovery( "engine" ) );
+        assert_eq!( Split( "analytical-engine-755224", Some( "-" ), user ), code( "analytical-engine" ) );
+        assert_eq!( Split( "eng-ine-abcd-efgh-ijkl-mnop", Some( "-" ), user ), recovery( "eng-ine" ) );
+        assert_eq!( Split( "engine5755224", Some( "5" ), user ), code( "engine" ) );
+        assert_eq!( Split( "engineaabcd-efgh-ijkl-mnop", Some( "a" ), user ), recovery( "engine" ) );
+
+        assert_eq!( Split( "engine755224", Some( ":" ), user ), None );
+        assert_eq!( Split( "engine:75522", Some( ":" ), user ), None );
+        assert_eq!( Split( "engine-abcd-efgh-ijkl-mnop", Some( ":" ), user ), None );
+        assert_eq!( Split( "75522", None, user ), None );
+    }
+}
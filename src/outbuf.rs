@@ -0,0 +1,184 @@
+//-----------------------------------------------------------------------------
+// Fixed size output buffers.
+//
+// Several API functions hand us a caller allocated char array plus a
+// pointer to its length, e.g. SystemAuthID and SystemAuthIDlen in
+// db2secGetAuthIDs.  The arrays are declared with a fixed size in
+// db2secPlugin.h but arrive here as plain pointers (see the note above
+// GetAuthIDs), so every write has to be checked against that size by hand.
+// All such writes should go through Db2OutputBuffer.
+
+use std::os::raw::c_char;
+
+use crate::{AllocateDb2ErrorMessage, Db2rc};
+
+// What to do when a value does not fit into the buffer.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // Fail the call.  Used for anything Db2 makes security decisions on,
+    // such as authids, where a shortened value would be a different id.
+    Reject,
+    // Cut the value at the last complete character that fits.  Only for
+    // informational values such as the username.
+    Truncate,
+}
+
+#[derive(Clone, Copy)]
+pub struct Db2OutputBuffer {
+    buffer : * mut c_char,
+    length : * mut i32,
+    capacity : usize,
+    field : &'static str,
+}
+
+impl Db2OutputBuffer {
+    pub fn New( buffer : * mut c_char,
+                length : * mut i32,
+                capacity : i32,
+                field : &'static str ) -> Db2OutputBuffer {
+        Db2OutputBuffer { buffer, length, capacity : capacity as usize, field }
+    }
+
+    // Copy value into the buffer and set the length output.
+    // On failure an error message is allocated for Db2 and the buffer and
+    // length are left untouched.
+    pub fn Write( &self,
+                  value : &str,
+                  policy : OverflowPolicy,
+                  caller : &str,
+                  errormsg : * mut * mut c_char,
+                  errormsglen : * mut i32 ) -> Result<(), Db2rc> {
+        WriteAll( &[(*self, value, policy)], caller, errormsg, errormsglen )
+    }
+
+    // value as it will be written, or why it cannot be.
+    fn Checked<'a>( &self, value : &'a str, policy : OverflowPolicy ) -> Result<&'a str, String> {
+        if self.buffer.is_null() || self.length.is_null() {
+            return Err( format!("No output buffer was supplied for {}", self.field) );
+        }
+        Fitted( value, self.capacity, policy, self.field )
+    }
+
+    // Only for a value Checked returned.
+    fn Copy( &self, value : &str ) {
+        let bytes = value.as_bytes();
+
+        // Safe as long as Db2 honours the documented buffer size, which is
+        // what capacity was set from.
+        unsafe {
+            std::ptr::copy_nonoverlapping( bytes.as_ptr(),
+                                           self.buffer as * mut u8,
+                                           bytes.len() );
+            *self.length = bytes.len() as i32;
+        }
+    }
+}
+
+// Write several outputs of one call.  Every value is checked against its
+// buffer before any is written, so on failure Db2 gets none of them rather
+// than some, e.g. a SystemAuthID without the InitialSessionAuthID.
+pub fn WriteAll( outputs : &[(Db2OutputBuffer, &str, OverflowPolicy)],
+                 caller : &str,
+                 errormsg : * mut * mut c_char,
+                 errormsglen : * mut i32 ) -> Result<(), Db2rc> {
+    let checked : Result<Vec<&str>, String> = outputs.iter()
+                                                     .map( |(outBuf, value, policy)| outBuf.Checked( value, *policy ) )
+                                                     .collect();
+    let values = match checked {
+        Ok(v) => v,
+        Err(e) => {
+            AllocateDb2ErrorMessage( caller, &e, errormsg, errormsglen );
+            return Err( Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR );
+        }
+    };
+
+    for ((outBuf, _, _), value) in outputs.iter().zip( values ) {
+        outBuf.Copy( value );
+    }
+    Ok(())
+}
+
+// value as it goes into a buffer of capacity bytes, or why it cannot.
+// rustsecp-simulate uses this to show what GetAuthIDs would return.
+pub fn Fitted<'a>( value : &'a str,
+                   capacity : usize,
+                   policy : OverflowPolicy,
+                   field : &str ) -> Result<&'a str, String> {
+    if value.len() <= capacity {
+        return Ok( value );
+    }
+    match policy {
+        OverflowPolicy::Reject => Err( format!("{} is {} bytes long, the maximum is {}", field, value.len(), capacity) ),
+        OverflowPolicy::Truncate => {
+            let mut end = capacity;
+            while ! value.is_char_boundary( end ) {
+                end -= 1;
+            }
+            Ok( &value[..end] )
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAPACITY : usize = 8;
+
+    // Writes value into a fresh buffer of CAPACITY bytes, filled with '#'.
+    fn WriteInto( value : &str, policy : OverflowPolicy ) -> (Result<(), Db2rc>, String, i32) {
+        let mut buffer = [b'#' as c_char; CAPACITY];
+        let mut length : i32 = -1;
+        let outBuf = Db2OutputBuffer::New( buffer.as_mut_ptr(), &mut length, CAPACITY as i32, "test" );
+        let rc = outBuf.Write( value, policy, "UnitTest", std::ptr::null_mut(), std::ptr::null_mut() );
+        let bytes : Vec<u8> = buffer.iter().map( |c| *c as u8 ).collect();
+        (rc, String::from_utf8_lossy( &bytes ).into_owned(), length)
+    }
+
+    #[test]
+    fn RejectTakesExactlyTheCapacity() {
+        assert_eq!( WriteInto( "ABCDEFGH", OverflowPolicy::Reject ), (Ok(()), String::from( "ABCDEFGH" ), 8) );
+
+        let (rc, buffer, length) = WriteInto( "ABCDEFGHI", OverflowPolicy::Reject );
+        assert_eq!( rc, Err( Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR ) );
+        assert_eq!( (buffer.as_str(), length), ("########", -1) );
+    }
+
+    #[test]
+    fn TruncateCutsOneByteOver() {
+        assert_eq!( WriteInto( "abcdefgh", OverflowPolicy::Truncate ), (Ok(()), String::from( "abcdefgh" ), 8) );
+        assert_eq!( WriteInto( "abcdefghi", OverflowPolicy::Truncate ), (Ok(()), String::from( "abcdefgh" ), 8) );
+
+        // Never in the middle of a character: "é" takes bytes 8 and 9.
+        let (rc, buffer, length) = WriteInto( "abcdefgé", OverflowPolicy::Truncate );
+        assert_eq!( (rc, length), (Ok(()), 7) );
+        assert_eq!( &buffer[..7], "abcdefg" );
+    }
+
+    #[test]
+    fn WriteAllWritesNothingIfOneValueDoesNotFit() {
+        let mut first = [b'#' as c_char; CAPACITY];
+        let mut firstLength : i32 = -1;
+        let mut second = [b'#' as c_char; CAPACITY];
+        let mut secondLength : i32 = -1;
+        let outputs = [
+            (Db2OutputBuffer::New( first.as_mut_ptr(), &mut firstLength, CAPACITY as i32, "first" ),
+             "ABCDEFGH", OverflowPolicy::Reject),
+            (Db2OutputBuffer::New( second.as_mut_ptr(), &mut secondLength, CAPACITY as i32, "second" ),
+             "ABCDEFGHI", OverflowPolicy::Reject),
+        ];
+
+        let rc = WriteAll( &outputs, "UnitTest", std::ptr::null_mut(), std::ptr::null_mut() );
+        assert_eq!( rc, Err( Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR ) );
+        assert_eq!( (firstLength, secondLength), (-1, -1) );
+        assert!( first.iter().chain( second.iter() ).all( |c| *c == b'#' as c_char ) );
+    }
+
+    #[test]
+    fn FittedSaysWhy() {
+        assert_eq!( Fitted( "ABCDEFGHI", CAPACITY, OverflowPolicy::Reject, "SystemAuthID" ),
+                    Err( String::from( "SystemAuthID is 9 bytes long, the maximum is 8" ) ) );
+        assert_eq!( Fitted( "ABCDEFGH", CAPACITY, OverflowPolicy::Reject, "SystemAuthID" ), Ok( "ABCDEFGH" ) );
+    }
+}
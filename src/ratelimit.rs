@@ -0,0 +1,148 @@
+//-----------------------------------------------------------------------------
+// Rate limits on login attempts.
+//
+// Token buckets per client address, per userid and for the whole instance,
+// set with rate_limit_ip, rate_limit_user and rate_limit_global as
+// "ATTEMPTS/SECONDS": a bucket holds ATTEMPTS tokens and refills at
+// ATTEMPTS per SECONDS.  Every ValidatePassword with a password takes one
+// token from each bucket that applies.  When any of them is empty the
+// attempt is refused with DB2SEC_PLUGIN_CONNECTION_DISALLOWED before the
+// password is hashed, and no token is taken from the others, so an address
+// over its own limit does not use up the global one.
+//
+// Connections without a password are local OS users, they are not limited.
+//
+// Userids are limited whether they exist or not, so the limit does not tell
+// which do.  The table is bounded: buckets that have filled up again are
+// dropped a few at a time, and in a full table the least recently used
+// bucket makes room for a new one, see lru.rs.  The table is process wide,
+// a reload keeps it.
+
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+use crate::config::PluginConfig;
+use crate::lru::LruTable;
+
+const MAX_BUCKETS : usize = 100_000;
+
+// ATTEMPTS per SECONDS, as in the configuration.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AttemptRate {
+    pub attempts : u32,
+    pub seconds : u32,
+}
+
+impl AttemptRate {
+    pub fn Parse( text : &str ) -> Option<AttemptRate> {
+        let (attempts, seconds) = text.split_once( '/' )?;
+        let rate = AttemptRate { attempts : attempts.trim().parse().ok()?, seconds : seconds.trim().parse().ok()? };
+        if rate.attempts == 0 || rate.seconds == 0 { None } else { Some( rate ) }
+    }
+
+    fn PerSecond( &self ) -> f64 {
+        self.attempts as f64 / self.seconds as f64
+    }
+}
+
+// Which limit refused an attempt.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Limit {
+    Ip,
+    User,
+    Global,
+}
+
+impl Limit {
+    pub fn Name( &self ) -> &'static str {
+        match self {
+            Limit::Ip => "ip",
+            Limit::User => "user",
+            Limit::Global => "global",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Global,
+    Client(IpAddr),
+    // Lower case, as userids are matched without regard to case.
+    User(String),
+}
+
+struct Bucket {
+    tokens : f64,
+    refilled : Instant,
+}
+
+impl Bucket {
+    fn Full( rate : &AttemptRate ) -> Bucket {
+        Bucket { tokens : rate.attempts as f64, refilled : Instant::now() }
+    }
+
+    fn Refill( &mut self, rate : &AttemptRate, now : Instant ) {
+        let elapsed = now.saturating_duration_since( self.refilled ).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.PerSecond()).min( rate.attempts as f64 );
+        self.refilled = now;
+    }
+
+    // Whether it would be full by now, and so no different from a new one.
+    fn RefilledBy( &self, rate : &AttemptRate, now : Instant ) -> bool {
+        let elapsed = now.saturating_duration_since( self.refilled ).as_secs_f64();
+        self.tokens + elapsed * rate.PerSecond() >= rate.attempts as f64
+    }
+}
+
+static BUCKETS : Mutex<Option<LruTable<Key, Bucket>>> = Mutex::new( None );
+
+fn Buckets() -> MutexGuard<'static, Option<LruTable<Key, Bucket>>> {
+    match BUCKETS.lock() {
+        Ok(b) => b,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// Take a token for this attempt from every bucket that applies, or return
+// the first limit that has none left.
+pub fn TakeAttempt( userid : &str, client : Option<IpAddr>, config : &PluginConfig ) -> Result<(), Limit> {
+    let limits : Vec<(Limit, Key, AttemptRate)> =
+        [ (Limit::Ip, client.map( Key::Client ), config.rateLimitIp),
+          (Limit::User, Some( Key::User( userid.to_lowercase() ) ), config.rateLimitUser),
+          (Limit::Global, Some( Key::Global ), config.rateLimitGlobal) ]
+        .into_iter()
+        .filter_map( |(limit, key, rate)| Some( (limit, key?, rate?) ) )
+        .collect();
+    if limits.is_empty() {
+        return Ok(());
+    }
+
+    let now = Instant::now();
+    let mut buckets = Buckets();
+    let buckets = buckets.get_or_insert_with( LruTable::New );
+
+    buckets.DropStale( |key, bucket| {
+        let rate = match key {
+            Key::Global => config.rateLimitGlobal,
+            Key::Client(_) => config.rateLimitIp,
+            Key::User(_) => config.rateLimitUser,
+        };
+        rate.is_none_or( |r| bucket.RefilledBy( &r, now ) )
+    });
+
+    for (limit, key, rate) in &limits {
+        let bucket = buckets.Touch( key.clone(), MAX_BUCKETS, || Bucket::Full( rate ) );
+        bucket.Refill( rate, now );
+        if bucket.tokens < 1.0 {
+            return Err( *limit );
+        }
+    }
+
+    for (_, key, _) in &limits {
+        if let Some(b) = buckets.GetMut( key ) {
+            b.tokens -= 1.0;
+        }
+    }
+    Ok(())
+}
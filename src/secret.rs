@@ -0,0 +1,47 @@
+//-----------------------------------------------------------------------------
+// Secret values.
+//
+// Passwords and anything similar are held in a SecretString rather than a
+// plain String.  Its Debug and Display output never include the value, only
+// its length, so it is safe to put one in a log message by accident.  The
+// memory holding the value is overwritten when it is dropped.
+//
+// Use Expose() only where the actual value is needed, e.g. to compare it.
+
+use std::fmt;
+
+use zeroize::Zeroizing;
+
+pub struct SecretString( Zeroizing<String> );
+
+impl SecretString {
+    pub fn New( value : String ) -> SecretString {
+        SecretString( Zeroizing::new( value ) )
+    }
+
+    pub fn Expose( &self ) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn Len( &self ) -> usize {
+        self.0.len()
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from( value : &str ) -> SecretString {
+        SecretString::New( String::from( value ) )
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt( &self, f : &mut fmt::Formatter<'_> ) -> fmt::Result {
+        write!( f, "<redacted, {} bytes>", self.Len() )
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt( &self, f : &mut fmt::Formatter<'_> ) -> fmt::Result {
+        fmt::Debug::fmt( self, f )
+    }
+}
@@ -0,0 +1,418 @@
+//-----------------------------------------------------------------------------
+// SIEM feed.
+//
+// Sends every audit event as an RFC 5424 syslog message, to the local
+// syslog socket or to a remote collector over UDP or TCP.  The message body
+// is the event in one of the formats SIEM products ingest directly:
+//
+//    json  the same JSON as the audit log, without the hash chain fields
+//    cef   ArcSight Common Event Format
+//    leef  QRadar Log Event Extended Format 1.0
+//
+// Messages are handed to a background thread through a bounded queue, so a
+// slow or unreachable collector never holds up a Db2 agent.  If the queue
+// is full the event is dropped from the feed.  The audit log file, when
+// configured, still has it.  When the plugin terminates, or a reload
+// changes the feed, the thread sends what is queued for up to STOP_TIMEOUT
+// and is waited for, Db2 may unload the library right after.
+//
+// The feed is off unless siem_target is set in the plugin configuration.
+
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::audit::{AuditCall, AuditEvent};
+use crate::config::PluginConfig;
+
+const QUEUE_LENGTH : usize = 4096;
+const NETWORK_TIMEOUT : Duration = Duration::from_secs( 2 );
+const STOP_TIMEOUT : Duration = Duration::from_secs( 5 );
+const APP_NAME : &str = "db2rustsecp";
+const VENDOR : &str = "db2rustsecp";
+const PRODUCT : &str = "Db2 security plugin";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SiemFormat {
+    Json,
+    Cef,
+    Leef,
+}
+
+impl SiemFormat {
+    pub fn FromName( name : &str ) -> Option<SiemFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Some( SiemFormat::Json ),
+            "cef"  => Some( SiemFormat::Cef ),
+            "leef" => Some( SiemFormat::Leef ),
+            _      => None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SyslogTarget {
+    // A local syslog daemon socket such as /dev/log.
+    Unix( PathBuf ),
+    // host:port
+    Udp( String ),
+    Tcp( String ),
+}
+
+impl SyslogTarget {
+    // unix:/dev/log, udp:host:port or tcp:host:port
+    pub fn Parse( text : &str ) -> Option<SyslogTarget> {
+        let (scheme, rest) = text.split_once(':')?;
+        if rest.is_empty() {
+            return None;
+        }
+        match scheme {
+            "unix" => Some( SyslogTarget::Unix( PathBuf::from( rest ) ) ),
+            "udp"  => Some( SyslogTarget::Udp( String::from( rest ) ) ),
+            "tcp"  => Some( SyslogTarget::Tcp( String::from( rest ) ) ),
+            _      => None,
+        }
+    }
+}
+
+// Syslog facility codes, RFC 5424 section 6.2.1.
+pub fn FacilityFromName( name : &str ) -> Option<u8> {
+    match name.to_ascii_lowercase().as_str() {
+        "user"     => Some( 1 ),
+        "auth"     => Some( 4 ),
+        "authpriv" => Some( 10 ),
+        "local0"   => Some( 16 ),
+        "local1"   => Some( 17 ),
+        "local2"   => Some( 18 ),
+        "local3"   => Some( 19 ),
+        "local4"   => Some( 20 ),
+        "local5"   => Some( 21 ),
+        "local6"   => Some( 22 ),
+        "local7"   => Some( 23 ),
+        _          => None,
+    }
+}
+
+// Severity of an event by its result.  Returns the syslog severity
+// (0 emergency .. 7 debug) and the CEF/LEEF severity (0 .. 10).
+pub fn EventSeverity( result : &str ) -> (u8, u8) {
+    match result {
+        "DB2SEC_PLUGIN_OK" => (6, 1),
+        "DB2SEC_PLUGIN_BADPWD" |
+        "DB2SEC_PLUGIN_BADUSER" |
+        "DB2SEC_PLUGIN_INVALIDUSERORGROUP" |
+        "DB2SEC_PLUGIN_CHANGEPASSWORD_NOTSUPPORTED" => (5, 5),
+        "DB2SEC_PLUGIN_UID_EXPIRED" |
+        "DB2SEC_PLUGIN_PWD_EXPIRED" |
+        "DB2SEC_PLUGIN_CRED_EXPIRED" => (5, 4),
+        "DB2SEC_PLUGIN_USER_REVOKED" |
+        "DB2SEC_PLUGIN_USER_SUSPENDED" |
+        "DB2SEC_PLUGIN_CONNECTION_DISALLOWED" => (4, 7),
+        "DB2SEC_PLUGIN_UNEXPECTED_SYSTEM_ERROR" => (2, 9),
+        "RUSTSECP_ALERT" => (1, 9),
+        // Everything else is the plugin or the system failing.
+        _ => (3, 8),
+    }
+}
+
+pub struct SiemSender {
+    format : SiemFormat,
+    target : SyslogTarget,
+    facility : u8,
+    hostname : String,
+    // Taken when the sender is dropped, which ends the thread's loop.
+    queue : Option<SyncSender<Vec<u8>>>,
+    stopping : Arc<AtomicBool>,
+    thread : Mutex<Option<JoinHandle<()>>>,
+}
+
+impl SiemSender {
+    // Returns None when the feed is not configured.
+    pub fn Open( config : &PluginConfig ) -> Result<Option<SiemSender>, String> {
+        let target = match &config.siemTarget {
+            None => return Ok( None ),
+            Some(t) => t.clone(),
+        };
+
+        let (queue, received) = sync_channel::<Vec<u8>>( QUEUE_LENGTH );
+        let mut transport = Transport::New( target.clone() );
+        let stopping = Arc::new( AtomicBool::new( false ) );
+        let threadStopping = stopping.clone();
+
+        // The thread ends once the queue is closed and drained, or once it
+        // has been stopping for STOP_TIMEOUT.
+        let thread = std::thread::Builder::new()
+            .name( String::from( "rustsecp-siem" ) )
+            .spawn( move || {
+                let mut giveUpAt = None;
+                for message in received {
+                    if threadStopping.load( Ordering::SeqCst ) &&
+                       Instant::now() > *giveUpAt.get_or_insert_with( || Instant::now() + STOP_TIMEOUT ) {
+                        break;
+                    }
+                    transport.Send( &message );
+                }
+            })
+            .map_err( |e| format!("Cannot start the SIEM sender: {}", e) )?;
+
+        Ok( Some( SiemSender {
+            format : config.siemFormat,
+            target,
+            facility : config.siemFacility,
+            hostname : LocalHostname(),
+            queue : Some( queue ),
+            stopping,
+            thread : Mutex::new( Some( thread ) ),
+        }))
+    }
+
+    pub fn SameSettings( &self, config : &PluginConfig ) -> bool {
+        config.siemTarget.as_ref() == Some( &self.target ) &&
+        config.siemFormat == self.format &&
+        config.siemFacility == self.facility
+    }
+
+    // Queue an event.  Returns false if it had to be dropped.
+    pub fn Send( &self, event : &AuditEvent ) -> bool {
+        let message = self.FormatSyslog( event );
+
+        match self.queue.as_ref().map( |q| q.try_send( message.into_bytes() ) ) {
+            Some(Ok(())) => true,
+            Some(Err(TrySendError::Full(_))) | Some(Err(TrySendError::Disconnected(_))) | None => false,
+        }
+    }
+
+    // RFC 5424: <PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID SD MSG
+    pub fn FormatSyslog( &self, event : &AuditEvent ) -> String {
+        let (severity, _) = EventSeverity( &event.result );
+        let body = match self.format {
+            SiemFormat::Json => serde_json::to_string( event ).unwrap_or_default(),
+            SiemFormat::Cef  => FormatCef( event ),
+            SiemFormat::Leef => FormatLeef( event ),
+        };
+
+        format!("<{}>1 {} {} {} {} {:?} - {}",
+                self.facility as u32 * 8 + severity as u32,
+                event.timestamp,
+                self.hostname,
+                APP_NAME,
+                std::process::id(),
+                event.call,
+                body)
+    }
+}
+
+impl Drop for SiemSender {
+    fn drop( &mut self ) {
+        self.stopping.store( true, Ordering::SeqCst );
+        self.queue = None;
+
+        let thread = match self.thread.lock() {
+            Ok(mut t) => t.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        if let Some(t) = thread {
+            let _ = t.join();
+        }
+    }
+}
+
+fn EventName( event : &AuditEvent ) -> &'static str {
+    match event.call {
+        AuditCall::Alert => "Db2 authentication attack detected",
+        _ if event.rc == 0 => "Db2 authentication succeeded",
+        _ => "Db2 authentication failed",
+    }
+}
+
+fn EventMillis( event : &AuditEvent ) -> u128 {
+    event.time.duration_since( UNIX_EPOCH ).unwrap_or_default().as_millis()
+}
+
+//-----------------------------------------------------------------------------
+// CEF
+
+fn CefHeaderEscape( s : &str ) -> String {
+    s.replace( '\\', "\\\\" ).replace( '|', "\\|" )
+}
+
+fn CefValueEscape( s : &str ) -> String {
+    s.replace( '\\', "\\\\" ).replace( '=', "\\=" ).replace( '\r', "\\r" ).replace( '\n', "\\n" )
+}
+
+pub fn FormatCef( event : &AuditEvent ) -> String {
+    let (_, severity) = EventSeverity( &event.result );
+
+    let mut ext : Vec<(&str, String)> = vec![
+        ("rt", EventMillis( event ).to_string()),
+        ("act", format!("{:?}", event.call)),
+        ("outcome", String::from( if event.rc == 0 { "success" } else { "failure" } )),
+        ("reason", event.result.clone()),
+        ("externalId", event.correlationId.clone()),
+    ];
+    if let Some(u) = &event.userid   { ext.push( ("suser", u.clone()) ); }
+    if let Some(a) = &event.authid   { ext.push( ("duser", a.clone()) ); }
+    if let Some(ip) = &event.clientIP { ext.push( ("src", ip.to_string()) ); }
+    if let Some(d) = &event.database {
+        ext.push( ("cs1Label", String::from("database")) );
+        ext.push( ("cs1", d.clone()) );
+    }
+    if let Some(g) = &event.groups {
+        ext.push( ("cs3Label", String::from("groups")) );
+        ext.push( ("cs3", g.join( "," )) );
+    }
+    ext.push( ("cs2Label", String::from("rule")) );
+    ext.push( ("cs2", String::from( event.rule )) );
+    if let Some(d) = &event.detail { ext.push( ("msg", d.clone()) ); }
+    if let Some(p) = event.platform {
+        ext.push( ("cn1Label", String::from("clientPlatform")) );
+        ext.push( ("cn1", p.to_string()) );
+    }
+    ext.push( ("cn2Label", String::from("latencyMicros")) );
+    ext.push( ("cn2", event.latencyUs.to_string()) );
+
+    let extension : Vec<String> = ext.iter().map( |(k, v)| format!("{}={}", k, CefValueEscape( v )) ).collect();
+
+    format!("CEF:0|{}|{}|{}|{}|{}|{}|{}",
+            CefHeaderEscape( VENDOR ),
+            CefHeaderEscape( PRODUCT ),
+            env!("CARGO_PKG_VERSION"),
+            CefHeaderEscape( event.rule ),
+            CefHeaderEscape( EventName( event ) ),
+            severity,
+            extension.join( " " ))
+}
+
+//-----------------------------------------------------------------------------
+// LEEF
+
+fn LeefEscape( s : &str ) -> String {
+    s.replace( '\\', "\\\\" ).replace( '\t', "\\t" ).replace( '\r', "\\r" ).replace( '\n', "\\n" )
+}
+
+pub fn FormatLeef( event : &AuditEvent ) -> String {
+    let (_, severity) = EventSeverity( &event.result );
+
+    let mut attrs : Vec<(&str, String)> = vec![
+        ("devTime", EventMillis( event ).to_string()),
+        ("devTimeFormat", String::from("Milliseconds")),
+        ("cat", format!("{:?}", event.call)),
+        ("sev", severity.to_string()),
+        ("result", event.result.clone()),
+        ("rule", String::from( event.rule )),
+        ("correlationId", event.correlationId.clone()),
+    ];
+    if let Some(u) = &event.userid   { attrs.push( ("usrName", u.clone()) ); }
+    if let Some(a) = &event.authid   { attrs.push( ("authid", a.clone()) ); }
+    if let Some(ip) = &event.clientIP { attrs.push( ("src", ip.to_string()) ); }
+    if let Some(d) = &event.database { attrs.push( ("database", d.clone()) ); }
+    if let Some(g) = &event.groups   { attrs.push( ("groups", g.join( "," )) ); }
+    if let Some(p) = event.platform  { attrs.push( ("clientPlatform", p.to_string()) ); }
+    if let Some(d) = &event.detail   { attrs.push( ("detail", d.clone()) ); }
+    attrs.push( ("latencyMicros", event.latencyUs.to_string()) );
+
+    let attributes : Vec<String> = attrs.iter().map( |(k, v)| format!("{}={}", k, LeefEscape( v )) ).collect();
+
+    format!("LEEF:1.0|{}|{}|{}|{}|{}",
+            VENDOR, PRODUCT, env!("CARGO_PKG_VERSION"), event.rule,
+            attributes.join( "\t" ))
+}
+
+//-----------------------------------------------------------------------------
+// Transports, only used on the sender thread.
+
+enum Connection {
+    None,
+    Unix( UnixDatagram ),
+    Udp( UdpSocket ),
+    Tcp( TcpStream ),
+}
+
+struct Transport {
+    target : SyslogTarget,
+    connection : Connection,
+}
+
+impl Transport {
+    fn New( target : SyslogTarget ) -> Transport {
+        Transport { target, connection : Connection::None }
+    }
+
+    // Send one message, reconnecting once if the connection has gone away.
+    // There is nobody to report a failure to, the message is dropped.
+    fn Send( &mut self, message : &[u8] ) {
+        for _ in 0..2 {
+            if let Connection::None = self.connection {
+                match self.Connect() {
+                    Ok(c) => self.connection = c,
+                    Err(_) => return,
+                }
+            }
+
+            let sent = match &mut self.connection {
+                Connection::None => false,
+                Connection::Unix(s) => match &self.target {
+                    SyslogTarget::Unix(path) => s.send_to( message, path ).is_ok(),
+                    _ => false,
+                },
+                Connection::Udp(s) => s.send( message ).is_ok(),
+                // RFC 6587 octet counting framing.
+                Connection::Tcp(s) => {
+                    let mut framed = format!("{} ", message.len()).into_bytes();
+                    framed.extend_from_slice( message );
+                    s.write_all( &framed ).is_ok()
+                },
+            };
+
+            if sent {
+                return;
+            }
+            self.connection = Connection::None;
+        }
+    }
+
+    fn Connect( &self ) -> std::io::Result<Connection> {
+        match &self.target {
+            SyslogTarget::Unix(_) => Ok( Connection::Unix( UnixDatagram::unbound()? ) ),
+            SyslogTarget::Udp(hostport) => {
+                let addr = FirstAddress( hostport )?;
+                let bind = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                let socket = UdpSocket::bind( bind )?;
+                socket.connect( addr )?;
+                Ok( Connection::Udp( socket ) )
+            },
+            SyslogTarget::Tcp(hostport) => {
+                let stream = TcpStream::connect_timeout( &FirstAddress( hostport )?, NETWORK_TIMEOUT )?;
+                stream.set_write_timeout( Some( NETWORK_TIMEOUT ) )?;
+                Ok( Connection::Tcp( stream ) )
+            },
+        }
+    }
+}
+
+fn FirstAddress( hostport : &str ) -> std::io::Result<std::net::SocketAddr> {
+    hostport.to_socket_addrs()?
+            .next()
+            .ok_or_else( || std::io::Error::new( std::io::ErrorKind::NotFound,
+                                                 format!("{} does not resolve", hostport) ) )
+}
+
+fn LocalHostname() -> String {
+    let mut buf = [0u8; 256];
+    let rc = unsafe { libc::gethostname( buf.as_mut_ptr() as * mut libc::c_char, buf.len() ) };
+    if rc != 0 {
+        return String::from("-");
+    }
+    let len = buf.iter().position( |b| *b == 0 ).unwrap_or( buf.len() );
+    match std::str::from_utf8( &buf[..len] ) {
+        Ok(h) if ! h.is_empty() => String::from( h ),
+        _ => String::from("-"),
+    }
+}
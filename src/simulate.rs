@@ -0,0 +1,239 @@
+//-----------------------------------------------------------------------------
+// Login simulation.
+//
+// Runs a connection attempt through the same decision code as
+// ValidatePassword, GetAuthIDs and GetGroupsForUser, without Db2, and
+// explains every step.  This is what rustsecp-simulate prints, to answer
+// "why can't user X connect".
+//
+// Only the configuration and the user store are loaded.  The audit log,
+// SIEM feed, metrics file and control socket are left alone, the plugin
+// running inside Db2 owns them.  Lockout state lives in the running plugin,
+// so it is asked through the control socket when there is one.  Address
+// blocks, rate limits and delays also go by what the running plugin has
+// counted, which the control socket does not tell, so they are not
+// evaluated and the output says so.
+
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use zeroize::Zeroizing;
+
+use crate::{AuthIDOutputs, CheckInputLength, ConnectionFlags, Db2rc, DB2SEC_MAX_DBNAME_LENGTH,
+            DB2SEC_MAX_PASSWORD_LENGTH, DB2SEC_MAX_USERID_LENGTH};
+use crate::config::{LoadPluginConfig, PluginConfig, PluginConfigPath};
+use crate::decision::{DecideLogin, LoginAttempt, Trace};
+use crate::groupplugin::GroupList;
+use crate::outbuf::Fitted;
+use crate::secret::SecretString;
+use crate::userstore::UserStore;
+
+const CONTROL_TIMEOUT : Duration = Duration::from_secs( 5 );
+
+pub struct SimulatedLogin {
+    pub userid : String,
+    // None for a connection without a password.
+    pub password : Option<Zeroizing<String>>,
+    pub database : Option<String>,
+    pub clientIP : Option<IpAddr>,
+    pub platform : Option<u32>,
+    // The connection_details bits, see ParseConnectionFlags.
+    pub connectionFlags : u32,
+    // Overrides what the running plugin says about lockout.
+    pub lockedOut : Option<bool>,
+}
+
+pub struct Simulation {
+    pub steps : Vec<String>,
+    pub rc : i32,
+    // The Db2rc name, e.g. DB2SEC_PLUGIN_BADPWD.
+    pub result : String,
+}
+
+// Connection flags by name, e.g. "USERID_FROM_OS,CONNECTION_ISLOCAL", with
+// or without the DB2SEC_ prefix, or as a number such as 0x7.
+pub fn ParseConnectionFlags( text : &str ) -> Result<u32, String> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix( "0x" ) {
+        return u32::from_str_radix( hex, 16 ).map_err( |_| format!("{} is not a number", text) );
+    }
+    if let Ok(n) = text.parse::<u32>() {
+        return Ok( n );
+    }
+
+    let mut flags = ConnectionFlags::empty();
+    for name in text.split( ',' ).map( |n| n.trim().to_uppercase() ).filter( |n| ! n.is_empty() ) {
+        flags |= match name.trim_start_matches( "DB2SEC_" ) {
+            "USERID_FROM_OS" => ConnectionFlags::DB2SEC_USERID_FROM_OS,
+            "CONNECTION_ISLOCAL" => ConnectionFlags::DB2SEC_CONNECTION_ISLOCAL,
+            "VALIDATING_ON_SERVER_SIDE" => ConnectionFlags::DB2SEC_VALIDATING_ON_SERVER_SIDE,
+            _ => return Err( format!("unknown connection flag {}", name) ),
+        };
+    }
+    Ok( flags.bits() )
+}
+
+pub fn SimulateLogin( login : &SimulatedLogin ) -> Result<Simulation, String> {
+    let mut trace = Trace::On();
+
+    let config = LoadPluginConfig()?;
+    trace.Step( || format!("configuration: {}", PluginConfigPath().display()) );
+
+    let users = match &config.userStore {
+        Some(path) => UserStore::Load( path )?,
+        None => UserStore::BuiltIn(),
+    };
+    trace.Step( || match &config.userStore {
+        Some(path) => format!("user store: {}, {} users", path.display(), users.Len()),
+        None => format!("user store: the built in demo users, {} users", users.Len()),
+    });
+
+    trace.Step( || format!("ValidatePassword: userid {}, database {}, client {}, platform {}",
+                           login.userid,
+                           login.database.as_deref().unwrap_or( "(none)" ),
+                           login.clientIP.map_or( String::from( "(none)" ), |ip| ip.to_string() ),
+                           login.platform.map_or( String::from( "(none)" ), |p| p.to_string() )) );
+
+    let rc = Validate( login, &config, &users, &mut trace );
+    let result = match Db2rc::FromCode( rc ) {
+        Some(r) => format!("{:?}", r),
+        None => rc.to_string(),
+    };
+    Ok( Simulation { steps : trace.Steps(), rc, result } )
+}
+
+fn Validate( login : &SimulatedLogin, config : &PluginConfig, users : &UserStore, trace : &mut Trace ) -> i32 {
+    // The checks ValidatePassword makes on its input, in the same order.
+    // Lengths are in the client code page, as Db2 passes them.
+    let length = |text : Option<&str>| text.map_or( 0, |t| config.clientCodepage.EncodedLength( t ) as i64 );
+    let inputs = [
+        ("userid", length( Some( &login.userid ) ), DB2SEC_MAX_USERID_LENGTH),
+        ("password", length( login.password.as_ref().map( |p| p.as_str() ) ), DB2SEC_MAX_PASSWORD_LENGTH),
+        ("dbname", length( login.database.as_deref() ), DB2SEC_MAX_DBNAME_LENGTH),
+    ];
+    for (field, len, max) in inputs {
+        if let Err(e) = CheckInputLength( field, len, max ) {
+            trace.Step( || format!("input: {}", e) );
+            return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as i32;
+        }
+        if field == "userid" && login.userid.is_empty() {
+            trace.Step( || String::from( "input: the userid is empty" ) );
+            return Db2rc::DB2SEC_PLUGIN_BADUSER as i32;
+        }
+    }
+
+    // Db2 passes an empty password as no password at all.
+    let password = login.password.as_ref().filter( |p| ! p.is_empty() )
+                        .map( |p| SecretString::from( p.as_str() ) );
+
+    // These refuse or hold up attempts with a password by what the running
+    // plugin has counted, which is not known here.
+    if password.is_some() {
+        let limits = [
+            ("address blocks", config.stuffingUserids > 0 || config.sprayUserids > 0),
+            ("rate limits", config.rateLimitIp.is_some() || config.rateLimitUser.is_some() || config.rateLimitGlobal.is_some()),
+            ("delays", config.delayBaseMs > 0),
+        ];
+        let on : Vec<&str> = limits.iter().filter( |(_, on)| *on ).map( |(name, _)| *name ).collect();
+        if ! on.is_empty() {
+            trace.Step( || format!("not evaluated: {}, the running plugin keeps their counts, \
+                                    it may refuse or delay this login", on.join( ", " )) );
+        }
+    }
+    trace.Step( || String::from( "the database and platform only go into the audit record, \
+                                  the client address also counts for address blocks, rate limits and delays" ) );
+
+    let lockedOut = match login.lockedOut {
+        Some(l) => {
+            trace.Step( || format!("lockout: taken as {}locked out from the command line", if l { "" } else { "not " }) );
+            l
+        },
+        None if password.is_none() || config.lockoutThreshold == 0 => false,
+        None => RunningPluginLockout( config, &login.userid, trace ),
+    };
+
+    let attempt = LoginAttempt { userid : &login.userid,
+                                 password : password.as_ref(),
+                                 flags : ConnectionFlags::from_bits_truncate( login.connectionFlags ),
+                                 lockedOut : Some( lockedOut ) };
+    let decision = DecideLogin( users, config, &attempt, SystemTime::now(), trace );
+
+    trace.Step( || format!("ValidatePassword: rule {}{}", decision.rule,
+                           decision.message.as_ref().map_or( String::new(), |m| format!(", message \"{}\"", m) )) );
+    let authid = match decision.authid {
+        Some(a) if decision.rc == Db2rc::DB2SEC_PLUGIN_OK => a,
+        _ => return decision.rc as i32,
+    };
+
+    // GetAuthIDs returns what ValidatePassword put into the token.
+    let mut returned = Vec::new();
+    for (field, value, maxlen, policy) in AuthIDOutputs( &authid, &login.userid ) {
+        match Fitted( value, maxlen as usize, policy, field ) {
+            Ok(v) => returned.push( format!("{} {}", field, v) ),
+            Err(e) => {
+                trace.Step( || format!("GetAuthIDs: {}", e) );
+                return Db2rc::DB2SEC_PLUGIN_UNKNOWNERROR as i32;
+            }
+        }
+    }
+    trace.Step( || format!("GetAuthIDs: {}", returned.join( ", " )) );
+
+    let groups = users.ForGroups( Some( &authid ), Some( &login.userid ) )
+                      .map_or_else( Vec::new, |u| u.groups.clone() );
+    let (_, written) = GroupList( &groups );
+    trace.Step( || match written {
+        0 => String::from( "GetGroupsForUser: no groups" ),
+        n if n as usize == groups.len() => format!("GetGroupsForUser: {}", groups.join( "," )),
+        n => format!("GetGroupsForUser: {}, {} of {} groups fit the list", groups.join( "," ), n, groups.len()),
+    });
+
+    Db2rc::DB2SEC_PLUGIN_OK as i32
+}
+
+// Whether the running plugin has this user locked out, asked through its
+// control socket.
+fn RunningPluginLockout( config : &PluginConfig, userid : &str, trace : &mut Trace ) -> bool {
+    let socket = match &config.controlSocket {
+        Some(s) => s,
+        None => {
+            trace.Step( || String::from( "lockout: control_socket is not set, assuming the running plugin has no lockout" ) );
+            return false;
+        }
+    };
+
+    match AskControlSocket( socket, "locked" ) {
+        Ok(lines) => {
+            let key = userid.to_lowercase();
+            let since = lines.iter().find_map( |l| l.strip_prefix( &format!("{} locked since ", key) ) );
+            trace.Step( || match since {
+                Some(t) => format!("lockout: the running plugin has the user locked out since {}", t),
+                None => String::from( "lockout: the running plugin has not locked the user out" ),
+            });
+            since.is_some()
+        },
+        Err(e) => {
+            trace.Step( || format!("lockout: cannot ask the running plugin ({}), assuming no lockout", e) );
+            false
+        }
+    }
+}
+
+fn AskControlSocket( socket : &Path, command : &str ) -> Result<Vec<String>, String> {
+    let mut stream = UnixStream::connect( socket ).map_err( |e| format!("{}: {}", socket.display(), e) )?;
+    let _ = stream.set_read_timeout( Some( CONTROL_TIMEOUT ) );
+
+    let mut reply = String::new();
+    stream.write_all( format!("{}\n", command).as_bytes() )
+          .and_then( |_| stream.read_to_string( &mut reply ) )
+          .map_err( |e| format!("{}: {}", socket.display(), e) )?;
+
+    let mut lines = reply.lines();
+    match lines.next() {
+        Some("OK") => Ok( lines.map( String::from ).collect() ),
+        Some(error) => Err( String::from( error.strip_prefix( "ERR " ).unwrap_or( error ) ) ),
+        None => Err( String::from( "no reply" ) ),
+    }
+}
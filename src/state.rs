@@ -0,0 +1,134 @@
+//-----------------------------------------------------------------------------
+// Plugin state.
+//
+// Everything the plugin needs after db2secServerAuthPluginInit lives in a
+// single PluginState object.  Db2 calls us from many agent threads at once,
+// so the current state is published through an ArcSwap: readers get their
+// own reference without taking a lock, and a reload builds a complete new
+// state and swaps it in atomically.  A reader that is part way through a
+// call keeps using the state it started with until it drops its reference.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use arc_swap::ArcSwapOption;
+
+use crate::{Db2LogLevels, GetConDetailsFuncT, LogMessageFuncT, LogMessageWithCallback};
+use crate::userstore::UserStore;
+use crate::audit::AuditLog;
+use crate::siem::SiemSender;
+use crate::metrics::{self, MetricsWriter};
+use crate::control::ControlServer;
+use crate::diaglog::ClearLogLevelOverrides;
+use crate::config::{PluginConfig, LoadPluginConfig};
+
+pub struct PluginState {
+    // Callbacks into the Db2 engine, given to us at init time.
+    pub getConDetails : GetConDetailsFuncT,
+    pub logMessage : LogMessageFuncT,
+
+    pub config : PluginConfig,
+
+    pub audit : Option<Arc<AuditLog>>,
+    pub siem : Option<Arc<SiemSender>>,
+    pub metrics : Option<Arc<MetricsWriter>>,
+    pub control : Option<Arc<ControlServer>>,
+
+    // The users, see userstore.rs.
+    pub users : UserStore,
+}
+
+static PLUGIN_STATE : ArcSwapOption<PluginState> = ArcSwapOption::const_empty();
+
+// The library can be loaded as the server auth plugin and as the group
+// plugin at the same time.  Db2 initializes and terminates each of them on
+// its own, they share one state which goes when the last one terminates.
+pub const SERVER_AUTH_PLUGIN : u8 = 1;
+pub const GROUP_PLUGIN       : u8 = 2;
+
+static ATTACHED_PLUGINS : AtomicU8 = AtomicU8::new( 0 );
+
+impl PluginState {
+    // previous is the state being replaced, if any.  Resources whose
+    // settings have not changed are carried over rather than reopened.
+    pub fn Build( getConDetails : GetConDetailsFuncT,
+                  logMessage : LogMessageFuncT,
+                  previous : Option<&PluginState> ) -> Result<PluginState, String> {
+        let config = LoadPluginConfig()?;
+
+        let audit = match previous.and_then( |p| p.audit.as_ref() ) {
+            Some(a) if a.SameSettings( &config ) => Some( a.clone() ),
+            _ => {
+                let opened = AuditLog::Open( &config )?;
+                // A torn last record does not stop the plugin, db2diag says so.
+                if let Some(warning) = opened.as_ref().and_then( |a| a.RecoveryWarning() ) {
+                    LogMessageWithCallback( logMessage, Db2LogLevels::DB2SEC_LOG_WARNING, warning );
+                }
+                opened.map( Arc::new )
+            },
+        };
+
+        let siem = match previous.and_then( |p| p.siem.as_ref() ) {
+            Some(s) if s.SameSettings( &config ) => Some( s.clone() ),
+            _ => SiemSender::Open( &config )?.map( Arc::new ),
+        };
+
+        let metrics = match previous.and_then( |p| p.metrics.as_ref() ) {
+            Some(m) if m.SameSettings( &config ) => Some( m.clone() ),
+            _ => MetricsWriter::Open( &config )?.map( Arc::new ),
+        };
+
+        let controlSocket = config.controlSocket.as_deref();
+        let control = match previous.and_then( |p| p.control.as_ref() ) {
+            Some(c) if c.SameSettings( controlSocket ) => Some( c.clone() ),
+            _ => ControlServer::Open( controlSocket )?.map( Arc::new ),
+        };
+
+        let users = match &config.userStore {
+            Some(path) => UserStore::Load( path )?,
+            None => UserStore::BuiltIn(),
+        };
+        // Make the dummy hash now, not during the first login it is for.
+        if config.uniformFailures {
+            users.CheckDummyPassword( "" );
+        }
+
+        Ok( PluginState { getConDetails, logMessage, config, audit, siem, metrics, control, users } )
+    }
+}
+
+// The state in effect right now, or None outside of init/term.
+pub fn CurrentPluginState() -> Option<Arc<PluginState>> {
+    PLUGIN_STATE.load_full()
+}
+
+pub fn InstallPluginState( state : PluginState ) {
+    PLUGIN_STATE.store( Some( Arc::new( state ) ) );
+}
+
+// Build a fresh state with the same Db2 callbacks and swap it in.
+// If the new state cannot be built, the current one stays in effect.
+// Log levels set through the control socket give way to the configuration.
+pub fn ReloadPluginState() -> Result<(), String> {
+    let current = CurrentPluginState().ok_or( "The plugin is not initialized" )?;
+
+    InstallPluginState( PluginState::Build( current.getConDetails,
+                                            current.logMessage,
+                                            Some( &current ) )? );
+    metrics::CountReload();
+    ClearLogLevelOverrides();
+    Ok(())
+}
+
+pub fn AttachPluginState( plugin : u8 ) {
+    ATTACHED_PLUGINS.fetch_or( plugin, Ordering::SeqCst );
+}
+
+// Once no plugin is attached, drop the state so the plugin can be
+// initialized again in the same process.
+pub fn DetachPluginState( plugin : u8 ) {
+    let before = ATTACHED_PLUGINS.fetch_and( ! plugin, Ordering::SeqCst );
+    if before & ! plugin == 0 {
+        PLUGIN_STATE.store( None );
+    }
+}
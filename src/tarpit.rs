@@ -0,0 +1,198 @@
+//-----------------------------------------------------------------------------
+// Progressive delay.
+//
+// After a failed login, the next attempt for the same user or from the
+// same client address waits before its password is checked:
+// delay_base_ms after one failure, twice that after two, and so on up to
+// delay_max_ms.  The longer of the user and client delays applies.  A good
+// password clears the user's failures, and failures are forgotten after
+// delay_forget_seconds without another one.  This slows down online
+// guessing without locking anybody out.
+//
+// The wait happens in ValidatePassword on a Db2 agent thread, so it never
+// exceeds delay_max_ms, and at most delay_max_threads agents wait at any
+// time.  When that many are waiting, an attempt whose user or client
+// already has one waiting is refused without its password being checked.
+// Any other attempt takes the place of a waiting one for the user or
+// client that holds the most places, which is refused.  So a flood of
+// guesses can neither tie up every agent, nor get past the delay, nor
+// crowd out the users and addresses it does not come from.
+//
+// Userids are tracked whether they exist or not, as for the rate limits,
+// so the delay does not tell which do; uniform_failures relies on that.
+// The table is bounded, so random userids and addresses cannot grow it
+// without limit: in a full table the entry that failed longest ago makes
+// room, see lru.rs.
+// delay_base_ms = 0 turns all of this off.  The table is process wide.
+
+use std::net::IpAddr;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::config::PluginConfig;
+use crate::lru::LruTable;
+
+const MAX_ENTRIES : usize = 100_000;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key {
+    // Lower case, as userids are matched without regard to case.
+    User(String),
+    Client(IpAddr),
+}
+
+struct Failures {
+    count : u32,
+    last : Instant,
+}
+
+// How long an attempt has to wait, and the user and client whose failures
+// make it wait.
+pub struct Delayed {
+    pub wait : Duration,
+    keys : Vec<Key>,
+}
+
+impl Delayed {
+    pub fn None() -> Delayed {
+        Delayed { wait : Duration::ZERO, keys : Vec::new() }
+    }
+}
+
+// An attempt waiting out its delay.  refused is set when a newcomer takes
+// its place.
+struct Waiter {
+    id : u64,
+    keys : Vec<Key>,
+    refused : bool,
+}
+
+struct Waiting {
+    waiters : Vec<Waiter>,
+    nextId : u64,
+}
+
+static FAILURES : Mutex<Option<LruTable<Key, Failures>>> = Mutex::new( None );
+static WAITING : Mutex<Waiting> = Mutex::new( Waiting { waiters : Vec::new(), nextId : 0 } );
+static PLACE_TAKEN : Condvar = Condvar::new();
+
+fn Locked<T>( m : &Mutex<T> ) -> MutexGuard<'_, T> {
+    match m.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn Table() -> MutexGuard<'static, Option<LruTable<Key, Failures>>> {
+    Locked( &FAILURES )
+}
+
+fn Forgotten( f : &Failures, config : &PluginConfig ) -> bool {
+    f.last.elapsed() >= Duration::from_secs( config.delayForgetSeconds )
+}
+
+fn DelayFor( failures : u32, config : &PluginConfig ) -> Duration {
+    if failures == 0 {
+        return Duration::ZERO;
+    }
+    // Doubles with every failure, 2^20 is far beyond any sensible cap.
+    let ms = config.delayBaseMs.saturating_mul( 1u64 << (failures - 1).min( 20 ) );
+    Duration::from_millis( ms.min( config.delayMaxMs ) )
+}
+
+// How long an attempt for this user from this client should wait.
+pub fn Delay( userid : &str, client : Option<IpAddr>, config : &PluginConfig ) -> Delayed {
+    if config.delayBaseMs == 0 {
+        return Delayed::None();
+    }
+
+    let table = Table();
+    let failures = |key : &Key| table.as_ref()
+                                     .and_then( |t| t.Get( key ) )
+                                     .filter( |f| ! Forgotten( f, config ) )
+                                     .map_or( 0, |f| f.count );
+
+    let keys : Vec<(Key, u32)> = std::iter::once( Key::User( userid.to_lowercase() ) )
+                                     .chain( client.map( Key::Client ) )
+                                     .map( |k| { let n = failures( &k ); (k, n) } )
+                                     .filter( |(_, n)| *n > 0 )
+                                     .collect();
+    let most = keys.iter().map( |(_, n)| *n ).max().unwrap_or( 0 );
+    Delayed { wait : DelayFor( most, config ), keys : keys.into_iter().map( |(k, _)| k ).collect() }
+}
+
+// The waiter to refuse for a newcomer when every place is taken: the
+// oldest one of the user or client that holds the most places.
+fn Displaced( waiters : &[Waiter] ) -> Option<usize> {
+    let places = |key : &Key| waiters.iter().filter( |w| ! w.refused && w.keys.contains( key ) ).count();
+    waiters.iter()
+           .enumerate()
+           .filter( |(_, w)| ! w.refused )
+           .flat_map( |(i, w)| w.keys.iter().map( move |k| (i, k) ) )
+           .max_by_key( |(i, k)| (places( k ), std::cmp::Reverse( *i )) )
+           .map( |(i, _)| i )
+}
+
+// Wait out delayed, within the delay_max_threads places, see above.
+// Returns false if the attempt was refused, either at once or when a
+// newcomer took its place.
+pub fn Wait( delayed : &Delayed, config : &PluginConfig ) -> bool {
+    let mut waiting = Locked( &WAITING );
+    let taken = waiting.waiters.iter().filter( |w| ! w.refused ).count();
+    if taken >= config.delayMaxThreads as usize {
+        let waitsAlready = waiting.waiters.iter()
+                                  .any( |w| ! w.refused && w.keys.iter().any( |k| delayed.keys.contains( k ) ) );
+        if waitsAlready {
+            return false;
+        }
+        match Displaced( &waiting.waiters ) {
+            Some(i) => waiting.waiters[i].refused = true,
+            None => return false,
+        }
+        PLACE_TAKEN.notify_all();
+    }
+
+    let id = waiting.nextId;
+    waiting.nextId += 1;
+    waiting.waiters.push( Waiter { id, keys : delayed.keys.clone(), refused : false } );
+
+    let deadline = Instant::now() + delayed.wait.min( Duration::from_millis( config.delayMaxMs ) );
+    loop {
+        let refused = waiting.waiters.iter().any( |w| w.id == id && w.refused );
+        let now = Instant::now();
+        if refused || now >= deadline {
+            waiting.waiters.retain( |w| w.id != id );
+            return ! refused;
+        }
+        waiting = match PLACE_TAKEN.wait_timeout( waiting, deadline - now ) {
+            Ok((g, _)) => g,
+            Err(poisoned) => poisoned.into_inner().0,
+        };
+    }
+}
+
+// A failed login, for a user that may or may not exist.
+pub fn RecordFailure( userid : &str, client : Option<IpAddr>, config : &PluginConfig ) {
+    if config.delayBaseMs == 0 {
+        return;
+    }
+
+    let mut table = Table();
+    let table = table.get_or_insert_with( LruTable::New );
+    table.DropStale( |_, f| Forgotten( f, config ) );
+
+    let keys = std::iter::once( Key::User( userid.to_lowercase() ) ).chain( client.map( Key::Client ) );
+    for key in keys {
+        let f = table.Touch( key, MAX_ENTRIES, || Failures { count : 0, last : Instant::now() } );
+        f.count = if Forgotten( f, config ) { 1 } else { f.count.saturating_add( 1 ) };
+        f.last = Instant::now();
+    }
+}
+
+// The client keeps its failures, one good account does not clear an
+// address that guesses at others.
+pub fn RecordSuccess( userid : &str ) {
+    if let Some(table) = Table().as_mut() {
+        table.Remove( &Key::User( userid.to_lowercase() ) );
+    }
+}
@@ -0,0 +1,70 @@
+//-----------------------------------------------------------------------------
+// Token integrity.
+//
+// The token ValidatePassword hands to Db2 comes back to GetAuthIDs and
+// GetGroupsForUser, and those decide which authid and groups a connection
+// gets from it.  So the token carries the time it was made, the userid and
+// namespace that were validated, and a MAC over all of it.  A token is
+// refused if its MAC does not match, if it is older than
+// token_max_age_seconds, or if Db2 passes it along with a different user.
+//
+// The MAC key is random and made once per process, tokens never outlive
+// the process that made them.
+//
+// This module is shared with the tests, which check the age limit without
+// waiting for it.
+
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub type TokenMac = [u8; 32];
+
+fn TokenKey() -> &'static [u8; 32] {
+    static KEY : OnceLock<[u8; 32]> = OnceLock::new();
+    KEY.get_or_init( || {
+        let mut key = [0u8; 32];
+        // Without randomness there is no secret key, better fail the call.
+        getrandom::getrandom( &mut key ).expect( "no random numbers for the token key" );
+        key
+    })
+}
+
+fn NewMac( created : SystemTime, fields : &[&str] ) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice( TokenKey() ).expect( "HMAC accepts any key length" );
+    let nanos = created.duration_since( UNIX_EPOCH ).unwrap_or_default().as_nanos();
+    mac.update( b"rustsecp-token" );
+    mac.update( &nanos.to_be_bytes() );
+    // Length prefixed, so no two sets of fields run together the same way.
+    for f in fields {
+        mac.update( &(f.len() as u64).to_be_bytes() );
+        mac.update( f.as_bytes() );
+    }
+    mac
+}
+
+pub fn SealToken( created : SystemTime, fields : &[&str] ) -> TokenMac {
+    NewMac( created, fields ).finalize().into_bytes().into()
+}
+
+// Compared in constant time.
+pub fn TokenSealMatches( mac : &TokenMac, created : SystemTime, fields : &[&str] ) -> bool {
+    NewMac( created, fields ).verify_slice( mac ).is_ok()
+}
+
+// Why a token made at created is too old to use, if it is.  A maximum age
+// of 0 means tokens do not expire.
+pub fn TokenAgeError( created : SystemTime, maxAgeSeconds : u64, now : SystemTime ) -> Option<String> {
+    if maxAgeSeconds == 0 {
+        return None;
+    }
+    match now.duration_since( created ) {
+        Ok(age) if age > Duration::from_secs( maxAgeSeconds ) =>
+            Some( format!("The token is {} seconds old, token_max_age_seconds is {}", age.as_secs(), maxAgeSeconds) ),
+        Ok(_) => None,
+        // The clock went back, the token is no older than that.
+        Err(_) => None,
+    }
+}
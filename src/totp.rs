@@ -0,0 +1,200 @@
+//-----------------------------------------------------------------------------
+// TOTP second factor.
+//
+// A user with a totp secret in the user store must give a one-time code
+// with the password.  Db2 clients have only the one password field, so the
+// code goes in it: the password followed by the six digit code, or by
+// totp_separator and the code when that is set, e.g. "secret:123456" with
+// totp_separator = ":".  Without a separator the last six characters are
+// the code, so they must be digits.  mfa.rs splits them off and checks
+// the code along with the other second factors.
+//
+// Codes are RFC 6238 with the settings every authenticator app uses:
+// HMAC-SHA1, 30 second steps and six digits.  The secret is kept in base32,
+// as the apps take it.  A code from up to totp_skew_steps steps before or
+// after the current one is accepted, for clocks that are a little off.
+//
+// Every code is good only once.  The step of the last code accepted for a
+// user is remembered, and a code from that step or an earlier one is
+// refused, so a code seen over a shoulder or in a trace cannot be used
+// again.  The table is process wide, a reload keeps it, and mfa.rs keeps
+// the step in the .mfa file as well, so a restart keeps it too.
+//
+// Members of totp_required_groups without a TOTP or HOTP secret are
+// refused, so a privileged account cannot connect with a password alone.
+// A local OS user connecting without a password cannot give a code either,
+// a user with a secret or in totp_required_groups is refused that way
+// unless totp_local_os_exempt is on.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+pub const CODE_DIGITS : usize = 6;
+const STEP_SECONDS : u64 = 30;
+const SECRET_BYTES : usize = 20;
+const BASE32 : &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// Why a code was refused.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CodeRefusal {
+    // Not the code of any step within the skew.
+    Mismatch,
+    // The code of a step that was used already.
+    Replayed,
+}
+
+// Last step accepted by lower case userid.
+static USED : Mutex<Option<HashMap<String, u64>>> = Mutex::new( None );
+
+fn Used() -> MutexGuard<'static, Option<HashMap<String, u64>>> {
+    match USED.lock() {
+        Ok(u) => u,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+//-----------------------------------------------------------------------------
+// Secrets
+
+// RFC 4648 base32, without padding.
+pub fn EncodeSecret( secret : &[u8] ) -> String {
+    let mut text = String::new();
+    for chunk in secret.chunks( 5 ) {
+        let mut block = [0u8; 5];
+        block[..chunk.len()].copy_from_slice( chunk );
+        let bits = block.iter().fold( 0u64, |acc, b| acc << 8 | *b as u64 );
+        let chars = (chunk.len() * 8).div_ceil( 5 );
+        for i in 0..chars {
+            text.push( BASE32[(bits >> (35 - i * 5) & 31) as usize] as char );
+        }
+    }
+    text
+}
+
+// Base32 as authenticator apps show it: any case, spaces and padding are
+// ignored.  None if it is not base32 or is empty.
+pub fn DecodeSecret( text : &str ) -> Option<Vec<u8>> {
+    let mut secret = Vec::new();
+    let (mut bits, mut count) = (0u64, 0);
+    for c in text.chars().filter( |c| ! c.is_whitespace() && *c != '=' ) {
+        let value = BASE32.iter().position( |b| *b as char == c.to_ascii_uppercase() )?;
+        bits = bits << 5 | value as u64;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            secret.push( (bits >> count) as u8 );
+        }
+    }
+    if secret.is_empty() { None } else { Some( secret ) }
+}
+
+pub fn NewSecret() -> Result<String, String> {
+    let mut secret = [0u8; SECRET_BYTES];
+    getrandom::getrandom( &mut secret ).map_err( |e| format!("Cannot generate a TOTP secret: {}", e) )?;
+    Ok( EncodeSecret( &secret ) )
+}
+
+//-----------------------------------------------------------------------------
+// Codes
+
+pub fn Step( now : SystemTime ) -> u64 {
+    now.duration_since( UNIX_EPOCH ).unwrap_or_default().as_secs() / STEP_SECONDS
+}
+
+// RFC 4226 dynamic truncation, six digits.
+pub fn Code( secret : &[u8], step : u64 ) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice( secret ).expect( "HMAC accepts any key length" );
+    mac.update( &step.to_be_bytes() );
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let value = u32::from_be_bytes( [hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]] );
+    format!("{:06}", value % 10u32.pow( CODE_DIGITS as u32 ))
+}
+
+// Compares every byte whatever the first difference, the length of a code
+// is no secret.
+pub fn SameCode( expected : &str, given : &str ) -> bool {
+    expected.len() == given.len() &&
+    expected.bytes().zip( given.bytes() ).fold( 0u8, |acc, (a, b)| acc | (a ^ b) ) == 0
+}
+
+// Check a code for a user and return its step.  lastStep is the step of
+// the last code used as kept in the .mfa file, if any.  The code is not
+// used up until its step is given to RecordStep.
+pub fn CheckCode( userid : &str,
+                  secret : &[u8],
+                  code : &str,
+                  skewSteps : u64,
+                  now : SystemTime,
+                  lastStep : Option<u64> ) -> Result<u64, CodeRefusal> {
+    let current = Step( now );
+    // Every step is computed and compared, so the time taken does not
+    // depend on which one matches.
+    let mut matched = None;
+    for step in current.saturating_sub( skewSteps )..=current.saturating_add( skewSteps ) {
+        if SameCode( &Code( secret, step ), code ) {
+            matched = Some( step );
+        }
+    }
+    let step = matched.ok_or( CodeRefusal::Mismatch )?;
+
+    let last = Used().as_ref().and_then( |u| u.get( &userid.to_lowercase() ).copied() );
+    if last.max( lastStep ).is_some_and( |last| step <= last ) {
+        return Err( CodeRefusal::Replayed );
+    }
+    Ok( step )
+}
+
+// Use up the codes of a user up to and including step.  mfa.rs calls this
+// once the step is in the .mfa file, so a code is not burnt by a login that
+// failed because the file could not be written.
+pub fn RecordStep( userid : &str, step : u64 ) {
+    let mut used = Used();
+    let last = used.get_or_insert_with( HashMap::new ).entry( userid.to_lowercase() ).or_insert( step );
+    *last = step.max( *last );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4226 appendix D.
+    const RFC_SECRET : &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn CodesAreThoseOfRfc4226() {
+        let expected = ["755224", "287082", "359152", "969429", "338314",
+                        "254676", "287922", "162583", "399871", "520489"];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!( Code( RFC_SECRET, counter as u64 ), *code, "counter {}", counter );
+        }
+    }
+
+    #[test]
+    fn SameCodeNeedsEveryByte() {
+        assert!( SameCode( "755224", "755224" ) );
+        assert!( ! SameCode( "755224", "755225" ) );
+        assert!( ! SameCode( "755224", "855224" ) );
+        assert!( ! SameCode( "755224", "75522" ) );
+        assert!( ! SameCode( "755224", "7552240" ) );
+    }
+
+    #[test]
+    fn StepIsUsedUpOnlyWhenRecorded() {
+        let now = UNIX_EPOCH + std::time::Duration::from_secs( 30 * 1000 );
+        let code = Code( RFC_SECRET, 1000 );
+        assert_eq!( CheckCode( "unit-totp", RFC_SECRET, &code, 1, now, None ), Ok( 1000 ) );
+        assert_eq!( CheckCode( "unit-totp", RFC_SECRET, &code, 1, now, None ), Ok( 1000 ) );
+        RecordStep( "UNIT-TOTP", 1000 );
+        assert_eq!( CheckCode( "unit-totp", RFC_SECRET, &code, 1, now, None ), Err( CodeRefusal::Replayed ) );
+        // An older step does not move it back.
+        RecordStep( "unit-totp", 999 );
+        assert_eq!( CheckCode( "unit-totp", RFC_SECRET, &code, 1, now, None ), Err( CodeRefusal::Replayed ) );
+        assert_eq!( CheckCode( "unit-totp", RFC_SECRET, &Code( RFC_SECRET, 1001 ), 1, now, None ), Ok( 1001 ) );
+        assert_eq!( CheckCode( "unit-totp", RFC_SECRET, &Code( RFC_SECRET, 1002 ), 1, now, None ), Err( CodeRefusal::Mismatch ) );
+    }
+}
@@ -0,0 +1,483 @@
+//-----------------------------------------------------------------------------
+// User store.
+//
+// The users the plugin authenticates, kept in a JSON file named by
+// user_store in the plugin configuration:
+//
+//    {
+//      "version": 1,
+//      "users": [
+//        { "userid": "newton", "password": "$argon2id$v=19$...",
+//          "authid": "ISAAC", "groups": ["PHYSICS"],
+//          "locked": false, "expires": "2027-06-30",
+//          "totp": "JBSWY3DPEHPK3PXP...",
+//          "hotp": "KRSXG5CTMVRXEZLU...", "hotp_counter": 0,
+//          "recovery": ["5e884898da280471...", ...] }
+//      ],
+//      "canaries": ["9f86d081884c7d65..."]
+//    }
+//
+// Passwords are stored as Argon2id hashes in the PHC string format, with
+// the cost given by HashSettings when the password is set.
+// authid maps the user to a different Db2 authid, by default it is the
+// userid in upper case.  No two users may connect as the same authid, the
+// groups of that authid would be those of whichever came first.  A locked user cannot connect until unlocked, and
+// from the day after expires on the user cannot connect at all.
+//
+// A user with a totp or hotp secret or recovery codes must add a one-time
+// code to the password, see totp.rs and mfa.rs.  The file holds the
+// secrets as they are, keep it mode 0600.  Recovery codes are digests.
+//
+// A decoy is a honeypot account that never connects, whatever password is
+// given.  Canaries are passwords planted where only an intruder would find
+// them, e.g. in a fake leaked credentials file; they never connect either,
+// whatever userid they are tried with.  Either one tried raises an alert.
+// Canaries are kept as SHA-256 digests, fast enough to check on every
+// login.  They must never be anybody's real password.
+//
+// Userids are matched without regard to case.  The file is maintained with
+// rustsecp-admin, which writes it atomically and keeps the previous version
+// as a .bak file.  The plugin reads it at init and on every reload.
+//
+// Without user_store the built in demo users are used.
+//
+// This module is shared with the rustsecp-admin tool.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use argon2::password_hash::SaltString;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::auditchain::ToHex;
+use crate::audit::FormatTimestamp;
+use crate::totp::DecodeSecret;
+
+const STORE_VERSION : u32 = 1;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UserEntry {
+    pub userid : String,
+    // Argon2id PHC string.  Empty means no password is set and the user can
+    // only connect without one, from the local OS.
+    #[serde(default)]
+    pub password : String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authid : Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups : Vec<String>,
+    #[serde(default)]
+    pub locked : bool,
+    // Last day the user may connect, YYYY-MM-DD in UTC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires : Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub decoy : bool,
+    // Base32 TOTP secret.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp : Option<String>,
+    // Base32 HOTP secret and the first counter it may use, see mfa.rs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hotp : Option<String>,
+    #[serde(rename = "hotp_counter", default, skip_serializing_if = "IsZero")]
+    pub hotpCounter : u64,
+    // SHA-256 digests of the recovery codes, see mfa.rs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recovery : Vec<String>,
+}
+
+fn IsZero( n : &u64 ) -> bool {
+    *n == 0
+}
+
+impl UserEntry {
+    pub fn New( userid : &str ) -> UserEntry {
+        UserEntry { userid : String::from( userid ), password : String::new(), authid : None,
+                    groups : Vec::new(), locked : false, expires : None, decoy : false,
+                    totp : None, hotp : None, hotpCounter : 0, recovery : Vec::new() }
+    }
+
+    // The Db2 authid the user connects as.
+    pub fn Authid( &self ) -> String {
+        match &self.authid {
+            Some(a) => a.clone(),
+            None => self.userid.to_uppercase(),
+        }
+    }
+
+    pub fn SetPassword( &mut self, password : &str ) -> Result<(), String> {
+        self.SetPasswordWith( password, &HashSettings::default() )
+    }
+
+    pub fn SetPasswordWith( &mut self, password : &str, settings : &HashSettings ) -> Result<(), String> {
+        self.password = HashPasswordWith( password, settings )?;
+        Ok(())
+    }
+
+    pub fn CheckPassword( &self, password : &str ) -> bool {
+        VerifyPassword( &self.password, password )
+    }
+
+    pub fn IsExpired( &self, now : SystemTime ) -> bool {
+        match &self.expires {
+            // Dates in this format compare correctly as strings.
+            Some(last) => FormatTimestamp( now )[..10] > *last.as_str(),
+            None => false,
+        }
+    }
+}
+
+// Argon2id cost of new password hashes.  A hash keeps the settings it was
+// made with, they are part of the PHC string, so changing them only
+// affects passwords set from then on.  Memory is in KiB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HashSettings {
+    pub memoryKib : u32,
+    pub iterations : u32,
+    pub parallelism : u32,
+}
+
+impl Default for HashSettings {
+    // The Argon2id recommendation of OWASP, as used by the argon2 crate.
+    fn default() -> HashSettings {
+        HashSettings { memoryKib : Params::DEFAULT_M_COST,
+                       iterations : Params::DEFAULT_T_COST,
+                       parallelism : Params::DEFAULT_P_COST }
+    }
+}
+
+impl HashSettings {
+    // As in a PHC string, e.g. "m=65536,t=3,p=4".  Missing values are the
+    // defaults.
+    pub fn Parse( text : &str ) -> Result<HashSettings, String> {
+        let mut settings = HashSettings::default();
+        for part in text.split( ',' ).map( str::trim ).filter( |p| ! p.is_empty() ) {
+            let (name, value) = part.split_once( '=' ).ok_or( format!("{} is not name=value", part) )?;
+            let value : u32 = value.trim().parse().map_err( |_| format!("{} is not a number", value) )?;
+            match name.trim() {
+                "m" => settings.memoryKib = value,
+                "t" => settings.iterations = value,
+                "p" => settings.parallelism = value,
+                other => return Err( format!("unknown Argon2 parameter {}, use m, t and p", other) ),
+            }
+        }
+        settings.Hasher()?;
+        Ok( settings )
+    }
+
+    pub fn Name( &self ) -> String {
+        format!("m={},t={},p={}", self.memoryKib, self.iterations, self.parallelism)
+    }
+
+    // The settings a PHC string was made with.
+    pub fn Of( hash : &str ) -> Option<HashSettings> {
+        let hash = PasswordHash::new( hash ).ok()?;
+        let value = |name : &str| hash.params.get_decimal( name );
+        Some( HashSettings { memoryKib : value( "m" )?, iterations : value( "t" )?, parallelism : value( "p" )? } )
+    }
+
+    fn Hasher( &self ) -> Result<Argon2<'static>, String> {
+        let params = Params::new( self.memoryKib, self.iterations, self.parallelism, None )
+                         .map_err( |e| format!("Bad Argon2 parameters {}: {}", self.Name(), e) )?;
+        Ok( Argon2::new( Algorithm::Argon2id, Version::V0x13, params ) )
+    }
+}
+
+// The parameters come from the PHC string, not from Argon2::default().
+fn VerifyPassword( hash : &str, password : &str ) -> bool {
+    match PasswordHash::new( hash ) {
+        Ok(hash) => Argon2::default().verify_password( password.as_bytes(), &hash ).is_ok(),
+        Err(_) => false,
+    }
+}
+
+pub fn HashPassword( password : &str ) -> Result<String, String> {
+    HashPasswordWith( password, &HashSettings::default() )
+}
+
+pub fn HashPasswordWith( password : &str, settings : &HashSettings ) -> Result<String, String> {
+    let mut salt = [0u8; 16];
+    getrandom::getrandom( &mut salt ).map_err( |e| format!("Cannot generate a salt: {}", e) )?;
+    let salt = SaltString::encode_b64( &salt ).map_err( |e| e.to_string() )?;
+
+    settings.Hasher()?
+            .hash_password( password.as_bytes(), &salt )
+            .map( |h| h.to_string() )
+            .map_err( |e| format!("Cannot hash the password: {}", e) )
+}
+
+// YYYY-MM-DD, a day that exists.
+pub fn ValidExpiryDate( text : &str ) -> bool {
+    let parts : Vec<&str> = text.split('-').collect();
+    let number = |s : &str, len : usize, max : u32| if s.len() == len && s.bytes().all( |b| b.is_ascii_digit() ) {
+        s.parse::<u32>().ok().filter( |n| *n >= 1 && *n <= max )
+    } else {
+        None
+    };
+    if parts.len() != 3 {
+        return false;
+    }
+
+    let (year, month) = match (number( parts[0], 4, 9999 ), number( parts[1], 2, 12 )) {
+        (Some(y), Some(m)) => (y, m),
+        _ => return false,
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    number( parts[2], 2, days ).is_some()
+}
+
+// Db2 authids and group names are at most 128 bytes and are compared in
+// upper case.
+pub fn ValidDb2Name( text : &str ) -> bool {
+    ! text.is_empty() && text.len() <= 128 &&
+    text.bytes().all( |b| b.is_ascii_alphanumeric() || b == b'_' || b == b'$' || b == b'#' || b == b'@' )
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserStoreFile {
+    version : u32,
+    users : Vec<UserEntry>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    canaries : BTreeSet<String>,
+}
+
+pub struct UserStore {
+    // By lower case userid.
+    users : BTreeMap<String, UserEntry>,
+    // Canary passwords, as lower case hex SHA-256 digests.
+    canaries : BTreeSet<String>,
+    // See CheckDummyPassword.
+    dummyHash : OnceLock<String>,
+}
+
+fn CanaryDigest( password : &str ) -> String {
+    ToHex( &Sha256::digest( password.as_bytes() ) )
+}
+
+impl UserStore {
+    pub fn New() -> UserStore {
+        UserStore { users : BTreeMap::new(), canaries : BTreeSet::new(), dummyHash : OnceLock::new() }
+    }
+
+    // Take as long as checking a password of a user in the store, for a
+    // userid that is not in it, so the time of a failed login does not
+    // tell whether the userid exists.  The dummy hash has the cost of the
+    // first stored hash, it is made on first use.
+    pub fn CheckDummyPassword( &self, password : &str ) {
+        let hash = self.dummyHash.get_or_init( || {
+            let settings = self.users.values()
+                               .find_map( |u| HashSettings::Of( &u.password ) )
+                               .unwrap_or_default();
+            HashPasswordWith( "rustsecp-dummy", &settings ).unwrap_or_default()
+        });
+        VerifyPassword( hash, password );
+    }
+
+    // The demo users, for when there is no user_store.
+    // Obviously you wouldn't do this for a real system, this is just a demo.
+    pub fn BuiltIn() -> UserStore {
+        let demo = [
+            ("gstager", "$argon2id$v=19$m=19456,t=2,p=1$cnVzdHNlY3BkZW1vMDE$92+SdaX1O1zoexECkGyLkG/F1saEUU2kzPgJFjFeUQg"),
+            ("newton",  "$argon2id$v=19$m=19456,t=2,p=1$cnVzdHNlY3BkZW1vMDI$0YeopBtA+dqWjWUvvsTKlFiUaU0BNoFq0ZCodNHcjgU"),
+            ("zurbie",  "$argon2id$v=19$m=19456,t=2,p=1$cnVzdHNlY3BkZW1vMDM$e1p6tmkWYRwoex01KoHX8sG+jA9tsNvcHkv3MuXGLAo"),
+        ];
+
+        let mut store = UserStore::New();
+        for (userid, hash) in demo {
+            let mut entry = UserEntry::New( userid );
+            entry.password = String::from( hash );
+            store.users.insert( String::from( userid ), entry );
+        }
+        store
+    }
+
+    pub fn Load( path : &Path ) -> Result<UserStore, String> {
+        let text = std::fs::read_to_string( path )
+                       .map_err( |e| format!("Cannot read {}: {}", path.display(), e) )?;
+        UserStore::Parse( &text ).map_err( |e| format!("{}: {}", path.display(), e) )
+    }
+
+    pub fn Parse( text : &str ) -> Result<UserStore, String> {
+        let file : UserStoreFile = serde_json::from_str( text ).map_err( |e| e.to_string() )?;
+        if file.version != STORE_VERSION {
+            return Err( format!("version {} is not supported", file.version) );
+        }
+
+        let mut store = UserStore::New();
+        if let Some(bad) = file.canaries.iter().find( |c| c.len() != 64 || ! c.bytes().all( |b| b.is_ascii_hexdigit() ) ) {
+            return Err( format!("canary {} is not a SHA-256 digest in hex", bad) );
+        }
+        store.canaries = file.canaries.iter().map( |c| c.to_lowercase() ).collect();
+        for mut entry in file.users {
+            // As rustsecp-admin would have written them.  Db2 reads group
+            // lists and authids of at most 128 bytes.
+            entry.authid = entry.authid.map( |a| a.to_uppercase() );
+            if let Some(bad) = entry.authid.as_deref().filter( |a| ! ValidDb2Name( a ) ) {
+                return Err( format!("the authid {} of user {} is not a valid Db2 name", bad, entry.userid) );
+            }
+            entry.groups = entry.groups.iter().map( |g| g.to_uppercase() ).collect();
+            if let Some(bad) = entry.groups.iter().find( |g| ! ValidDb2Name( g ) ) {
+                return Err( format!("the group {} of user {} is not a valid Db2 name", bad, entry.userid) );
+            }
+            if let Some(bad) = entry.expires.as_deref().filter( |e| ! ValidExpiryDate( e ) ) {
+                return Err( format!("the expiry date {} of user {} is not a date as YYYY-MM-DD", bad, entry.userid) );
+            }
+            if entry.totp.as_deref().is_some_and( |t| DecodeSecret( t ).is_none() ) {
+                return Err( format!("the TOTP secret of user {} is not base32", entry.userid) );
+            }
+            if entry.hotp.as_deref().is_some_and( |t| DecodeSecret( t ).is_none() ) {
+                return Err( format!("the HOTP secret of user {} is not base32", entry.userid) );
+            }
+            if entry.recovery.iter().any( |d| d.len() != 64 || ! d.bytes().all( |b| b.is_ascii_hexdigit() ) ) {
+                return Err( format!("a recovery code of user {} is not a SHA-256 digest in hex", entry.userid) );
+            }
+            let key = entry.userid.to_lowercase();
+            if store.users.contains_key( &key ) {
+                return Err( format!("user {} is listed twice", entry.userid) );
+            }
+            store.users.insert( key, entry );
+        }
+        store.CheckAuthids()?;
+        Ok( store )
+    }
+
+    // Every authid belongs to one user, mapped or not.
+    fn CheckAuthids( &self ) -> Result<(), String> {
+        let mut seen : BTreeMap<String, &str> = BTreeMap::new();
+        for user in self.users.values() {
+            if let Some(other) = seen.insert( user.Authid(), &user.userid ) {
+                return Err( format!("users {} and {} both connect as authid {}", other, user.userid, user.Authid()) );
+            }
+        }
+        Ok(())
+    }
+
+    // Write the store atomically: a new file is written next to the old one
+    // and renamed over it, after the old one is copied to PATH.bak.
+    pub fn Save( &self, path : &Path ) -> Result<(), String> {
+        self.CheckAuthids()?;
+        let file = UserStoreFile { version : STORE_VERSION, users : self.users.values().cloned().collect(),
+                                   canaries : self.canaries.clone() };
+        let mut text = serde_json::to_string_pretty( &file ).map_err( |e| e.to_string() )?;
+        text.push( '\n' );
+
+        let tmp = WithSuffix( path, &format!(".tmp.{}", std::process::id()) );
+        let written = std::fs::OpenOptions::new().write( true ).create_new( true ).mode( 0o600 )
+                          .open( &tmp )
+                          .and_then( |mut f| { f.write_all( text.as_bytes() )?; f.sync_all() } );
+        if let Err(e) = written {
+            let _ = std::fs::remove_file( &tmp );
+            return Err( format!("Cannot write {}: {}", tmp.display(), e) );
+        }
+
+        if path.exists() {
+            let bak = WithSuffix( path, ".bak" );
+            if let Err(e) = std::fs::copy( path, &bak ) {
+                let _ = std::fs::remove_file( &tmp );
+                return Err( format!("Cannot back up {} to {}: {}", path.display(), bak.display(), e) );
+            }
+        }
+
+        std::fs::rename( &tmp, path ).map_err( |e| {
+            let _ = std::fs::remove_file( &tmp );
+            format!("Cannot replace {}: {}", path.display(), e)
+        })
+    }
+
+    pub fn Len( &self ) -> usize {
+        self.users.len()
+    }
+
+    pub fn Users( &self ) -> impl Iterator<Item = &UserEntry> {
+        self.users.values()
+    }
+
+    pub fn Get( &self, userid : &str ) -> Option<&UserEntry> {
+        self.users.get( &userid.to_lowercase() )
+    }
+
+    pub fn GetMut( &mut self, userid : &str ) -> Option<&mut UserEntry> {
+        self.users.get_mut( &userid.to_lowercase() )
+    }
+
+    // The user that connects as this authid, if any.
+    pub fn ByAuthid( &self, authid : &str ) -> Option<&UserEntry> {
+        let authid = authid.to_uppercase();
+        self.users.values().find( |u| u.Authid() == authid )
+    }
+
+    // The user GetGroupsForUser answers for.  The authid reflects any
+    // mapping, so it is looked up first.  A store has one user per authid,
+    // see CheckAuthids.
+    pub fn ForGroups( &self, authid : Option<&str>, userid : Option<&str> ) -> Option<&UserEntry> {
+        authid.and_then( |a| self.ByAuthid( a ) ).or_else( || userid.and_then( |u| self.Get( u ) ) )
+    }
+
+    pub fn GroupExists( &self, group : &str ) -> bool {
+        let group = group.to_uppercase();
+        self.users.values().any( |u| u.groups.contains( &group ) )
+    }
+
+    pub fn IsCanary( &self, password : &str ) -> bool {
+        ! self.canaries.is_empty() && self.canaries.contains( &CanaryDigest( password ) )
+    }
+
+    pub fn CanaryCount( &self ) -> usize {
+        self.canaries.len()
+    }
+
+    pub fn AddCanary( &mut self, password : &str ) -> Result<(), String> {
+        if ! self.canaries.insert( CanaryDigest( password ) ) {
+            return Err( String::from( "that password is a canary already" ) );
+        }
+        Ok(())
+    }
+
+    pub fn RemoveCanary( &mut self, password : &str ) -> Result<(), String> {
+        if ! self.canaries.remove( &CanaryDigest( password ) ) {
+            return Err( String::from( "that password is not a canary" ) );
+        }
+        Ok(())
+    }
+
+    pub fn Add( &mut self, userid : &str ) -> Result<&mut UserEntry, String> {
+        let key = userid.to_lowercase();
+        if self.users.contains_key( &key ) {
+            return Err( format!("user {} already exists", userid) );
+        }
+        Ok( self.users.entry( key ).or_insert( UserEntry::New( userid ) ) )
+    }
+
+    pub fn Remove( &mut self, userid : &str ) -> Result<UserEntry, String> {
+        self.users.remove( &userid.to_lowercase() ).ok_or_else( || format!("no user {}", userid) )
+    }
+
+    pub fn Rename( &mut self, userid : &str, newUserid : &str ) -> Result<(), String> {
+        let newKey = newUserid.to_lowercase();
+        if newKey != userid.to_lowercase() && self.users.contains_key( &newKey ) {
+            return Err( format!("user {} already exists", newUserid) );
+        }
+        let mut entry = self.Remove( userid )?;
+        entry.userid = String::from( newUserid );
+        self.users.insert( newKey, entry );
+        Ok(())
+    }
+}
+
+fn WithSuffix( path : &Path, suffix : &str ) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push( suffix );
+    PathBuf::from( name )
+}
@@ -0,0 +1,627 @@
+//-----------------------------------------------------------------------------
+// Mock Db2 host.
+//
+// Loads libdb2rustsecp.so with dlopen, the way the Db2 engine does, and
+// drives it only through the exported init functions and the function
+// tables they fill in.  The getConDetails and logMessage callbacks Db2 would
+// pass are stubs: the connection details they report can be scripted and
+// every message the plugin logs is captured.
+//
+// The harness also checks the ownership rules of the plugin interface as it
+// goes: a token is returned exactly when ValidatePassword succeeds and is
+// given back with FreeToken, error messages are NUL terminated, have the
+// length reported and are given back with FreeErrormsg, and output buffers
+// are not written past the length Db2 allows.
+//
+// The plugin keeps its state in process wide statics, so only one MockDb2
+// can run at a time.  Start waits for the previous one to terminate.  The
+// library stays loaded until the test process exits, as it would in Db2.
+
+#![allow(non_snake_case)]
+#![allow(non_camel_case_types)]
+#![allow(dead_code)]
+
+use std::ffi::{CStr, c_char, c_void};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use libloading::Library;
+
+pub const DB2SEC_PLUGIN_OK                  : i32 = 0;
+pub const DB2SEC_PLUGIN_UNKNOWNERROR        : i32 = -1;
+pub const DB2SEC_PLUGIN_BADUSER             : i32 = -2;
+pub const DB2SEC_PLUGIN_INVALIDUSERORGROUP  : i32 = -3;
+pub const DB2SEC_PLUGIN_UID_EXPIRED         : i32 = -6;
+pub const DB2SEC_PLUGIN_USER_REVOKED        : i32 = -8;
+pub const DB2SEC_PLUGIN_USER_SUSPENDED      : i32 = -9;
+pub const DB2SEC_PLUGIN_BADPWD              : i32 = -10;
+pub const DB2SEC_PLUGIN_CHANGEPASSWORD_NOTSUPPORTED : i32 = -12;
+pub const DB2SEC_PLUGIN_CONNECTION_DISALLOWED : i32 = -20;
+pub const DB2SEC_PLUGIN_NO_CON_DETAILS      : i32 = -24;
+pub const DB2SEC_PLUGIN_BAD_INPUT_PARAMETERS : i32 = -25;
+
+pub const DB2SEC_LOG_ERROR                  : i32 = 2;
+pub const DB2SEC_LOG_WARNING                : i32 = 3;
+pub const DB2SEC_LOG_INFO                   : i32 = 4;
+
+pub const DB2SEC_USERID_FROM_OS             : u32 = 0x1;
+pub const DB2SEC_CONNECTION_ISLOCAL         : u32 = 0x2;
+pub const DB2SEC_VALIDATING_ON_SERVER_SIDE  : u32 = 0x4;
+
+const DB2SEC_MAX_AUTHID_LENGTH              : usize = 255;
+const DB2SEC_MAX_USERID_LENGTH              : usize = 255;
+const DB2SEC_PLUGIN_TYPE_USERID_PASSWORD    : i32 = 0;
+const DB2SEC_PLUGIN_TYPE_GROUP              : i32 = 3;
+const DB2SEC_GENERIC                        : i32 = 0;
+const DB2SEC_LOCATION_CLIENT_IDENTITY       : i32 = 0;
+// The name the plugin expects its own tokens to come with.
+const PLUGIN_NAME                           : &str = "libdb2rustsecp";
+const DB2SEC_CON_DETAILS_VERSION_3          : i32 = 3;
+
+// Output buffers are this much longer than Db2 allows, filled with this
+// byte, to catch writes past the end.
+const GUARD_BYTES                           : usize = 64;
+const GUARD                                 : u8 = 0xA5;
+
+//-----------------------------------------------------------------------------
+// The plugin ABI, as in db2secPlugin.h.
+
+type GetConDetailsFuncT = extern "C" fn( i32, * mut c_void ) -> i32;
+type LogMessageFuncT = extern "C" fn( i32, * const c_char, i32 ) -> i32;
+
+type ServerAuthPluginInitFuncT = unsafe extern "C" fn( i32, * mut c_void,
+                                                       Option<GetConDetailsFuncT>, Option<LogMessageFuncT>,
+                                                       * mut * mut c_char, * mut i32 ) -> i32;
+
+type ValidatePasswordFuncT = extern "C" fn( * const c_char, i32, * const c_char, i32, i32,
+                                            * const c_char, i32, * const c_char, i32,
+                                            * const c_char, i32, u32,
+                                            * mut * mut c_void, * mut * mut c_char, * mut i32 ) -> i32;
+type GetAuthIDsFuncT = extern "C" fn( * const c_char, i32, * const c_char, i32, i32,
+                                      * const c_char, i32, * mut * mut c_void,
+                                      * mut c_char, * mut i32, * mut c_char, * mut i32,
+                                      * mut c_char, * mut i32, * mut i32,
+                                      * mut * mut c_char, * mut i32 ) -> i32;
+type DoesAuthIDExistFuncT = extern "C" fn( * const c_char, i32, * mut * mut c_char, * mut i32 ) -> i32;
+type FreeTokenFuncT = extern "C" fn( * mut c_void, * mut * mut c_char, * mut i32 ) -> i32;
+type FreeErrormsgFuncT = extern "C" fn( * mut c_char ) -> i32;
+type PluginTermFuncT = extern "C" fn( * mut * mut c_char, * mut i32 ) -> i32;
+
+type GroupPluginInitFuncT = unsafe extern "C" fn( i32, * mut c_void, Option<LogMessageFuncT>,
+                                                  * mut * mut c_char, * mut i32 ) -> i32;
+type GetGroupsForUserFuncT = extern "C" fn( * const c_char, i32, * const c_char, i32,
+                                            * const c_char, i32, i32, * const c_char, i32,
+                                            * mut c_void, i32, i32, * const c_char, i32,
+                                            * mut * mut c_void, * mut i32, * mut * mut c_char, * mut i32 ) -> i32;
+type DoesGroupExistFuncT = extern "C" fn( * const c_char, i32, * mut * mut c_char, * mut i32 ) -> i32;
+type FreeGroupListMemoryFuncT = extern "C" fn( * mut c_void, * mut * mut c_char, * mut i32 ) -> i32;
+
+#[repr(C)]
+#[derive(Default)]
+struct ServerAuthFunctions {
+    version : i32,
+    plugintype : i32,
+    validatePassword : Option<ValidatePasswordFuncT>,
+    getAuthIDs : Option<GetAuthIDsFuncT>,
+    doesAuthIDExist : Option<DoesAuthIDExistFuncT>,
+    freeToken : Option<FreeTokenFuncT>,
+    freeErrormsg : Option<FreeErrormsgFuncT>,
+    pluginTerm : Option<PluginTermFuncT>,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct GroupFunctions {
+    version : i32,
+    plugintype : i32,
+    getGroupsForUser : Option<GetGroupsForUserFuncT>,
+    doesGroupExist : Option<DoesGroupExistFuncT>,
+    freeGroupListMemory : Option<FreeGroupListMemoryFuncT>,
+    freeErrormsg : Option<FreeErrormsgFuncT>,
+    pluginTerm : Option<PluginTermFuncT>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct db2sec_con_details_3 {
+    clientProtocol : i32,
+    clientIPAddress : u32,
+    connect_info_bitmap : u32,
+    dbnameLen : i32,
+    dbname : [c_char; 128],
+    clientIP6Address : [u32; 4],
+    clientPlatform : u32,
+    reserved : [u32; 16],
+}
+
+//-----------------------------------------------------------------------------
+// Scripted callbacks.
+
+// What getConDetails reports.  None makes it fail, as it does outside of a
+// connection.
+#[derive(Clone)]
+pub struct ConnectionDetails {
+    pub clientIP : Ipv4Addr,
+    pub platform : u32,
+    pub database : String,
+}
+
+struct Host {
+    connection : Option<ConnectionDetails>,
+    logs : Vec<(i32, String)>,
+}
+
+static HOST : Mutex<Host> = Mutex::new( Host { connection : None, logs : Vec::new() } );
+
+// One MockDb2 at a time.
+static RUNNING : Mutex<()> = Mutex::new( () );
+
+fn Locked<T>( m : &Mutex<T> ) -> MutexGuard<'_, T> {
+    match m.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+extern "C" fn GetConDetails( version : i32, details : * mut c_void ) -> i32 {
+    // A panic must not unwind into the plugin, report a bad call instead.
+    if version != DB2SEC_CON_DETAILS_VERSION_3 || details.is_null() {
+        return DB2SEC_PLUGIN_BAD_INPUT_PARAMETERS;
+    }
+    let connection = match Locked( &HOST ).connection.clone() {
+        Some(c) => c,
+        None => return DB2SEC_PLUGIN_NO_CON_DETAILS,
+    };
+
+    let mut d : db2sec_con_details_3 = unsafe { std::mem::zeroed() };
+    d.clientIPAddress = u32::from( connection.clientIP ).to_be();
+    d.clientPlatform = connection.platform;
+    d.dbnameLen = connection.database.len() as i32;
+    for (i, b) in connection.database.bytes().take( d.dbname.len() ).enumerate() {
+        d.dbname[i] = b as c_char;
+    }
+    unsafe { *(details as * mut db2sec_con_details_3) = d };
+    DB2SEC_PLUGIN_OK
+}
+
+extern "C" fn LogMessage( level : i32, data : * const c_char, length : i32 ) -> i32 {
+    if data.is_null() || length < 0 {
+        return DB2SEC_PLUGIN_BAD_INPUT_PARAMETERS;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts( data as * const u8, length as usize ) };
+    Locked( &HOST ).logs.push( (level, String::from_utf8_lossy( bytes ).into_owned()) );
+    DB2SEC_PLUGIN_OK
+}
+
+//-----------------------------------------------------------------------------
+// The library.
+
+fn PluginLibrary() -> &'static Library {
+    static LIBRARY : OnceLock<Library> = OnceLock::new();
+    LIBRARY.get_or_init( || {
+        let path = PluginPath();
+        unsafe { Library::new( &path ) }.unwrap_or_else( |e| panic!("Cannot load {}: {}", path.display(), e) )
+    })
+}
+
+// Next to the test executable in target/*/deps, or one level up.
+fn PluginPath() -> PathBuf {
+    let exe = std::env::current_exe().expect( "no test executable path" );
+    let deps = exe.parent().expect( "no test executable directory" );
+    [deps, deps.parent().unwrap_or( deps )]
+        .iter()
+        .map( |d| d.join( "libdb2rustsecp.so" ) )
+        .find( |p| p.exists() )
+        .unwrap_or_else( || panic!("libdb2rustsecp.so is not built next to {}", exe.display()) )
+}
+
+//-----------------------------------------------------------------------------
+// The host.
+
+// A directory for the files of one test, e.g. a user store to write before
+// the plugin starts.  It is removed when the MockDb2 of that name ends.
+pub fn ScratchDir( name : &str ) -> PathBuf {
+    let dir = std::env::temp_dir().join( format!("rustsecp-test-{}-{}", std::process::id(), name) );
+    std::fs::create_dir_all( &dir ).expect( "cannot create the scratch directory" );
+    dir
+}
+
+// A token the plugin handed out, to be given back with FreeToken.
+pub struct Token( * mut c_void );
+
+impl Token {
+    // The raw pointer, for handing the plugin what it should refuse.
+    pub fn Pointer( &self ) -> * mut c_void {
+        self.0
+    }
+}
+
+pub struct Validated {
+    pub rc : i32,
+    pub token : Option<Token>,
+    pub errormsg : Option<String>,
+}
+
+pub struct AuthIDs {
+    pub rc : i32,
+    pub systemAuthid : String,
+    pub initialSessionAuthid : String,
+    pub username : String,
+    pub initialSessionIdType : i32,
+    pub errormsg : Option<String>,
+}
+
+pub struct Groups {
+    pub rc : i32,
+    pub groups : Vec<String>,
+    pub errormsg : Option<String>,
+}
+
+pub struct MockDb2 {
+    fns : ServerAuthFunctions,
+    // Once StartGroupPlugin has run.
+    groupFns : Option<GroupFunctions>,
+    dir : PathBuf,
+    outstandingTokens : usize,
+    terminated : bool,
+    _running : MutexGuard<'static, ()>,
+}
+
+impl MockDb2 {
+    // Write config as the plugin configuration and initialize the plugin.
+    // Paths in the configuration can use {dir}, the ScratchDir of name.  Panics if the plugin does not initialize.
+    pub fn Start( name : &str, config : &str ) -> MockDb2 {
+        match MockDb2::TryStart( name, config ) {
+            Ok(db2) => db2,
+            Err((rc, msg)) => panic!("init failed with {}: {:?}", rc, msg),
+        }
+    }
+
+    pub fn TryStart( name : &str, config : &str ) -> Result<MockDb2, (i32, Option<String>)> {
+        let running = Locked( &RUNNING );
+
+        let dir = ScratchDir( name );
+        let configPath = dir.join( "rustsecp.cfg" );
+        std::fs::write( &configPath, config.replace( "{dir}", &dir.to_string_lossy() ) )
+            .expect( "cannot write the configuration" );
+        std::env::set_var( "DB2RUSTSECP_CONFIG", &configPath );
+
+        {
+            let mut host = Locked( &HOST );
+            host.logs.clear();
+            host.connection = Some( ConnectionDetails { clientIP : Ipv4Addr::new( 10, 1, 2, 3 ),
+                                                        platform : 30,
+                                                        database : String::from( "TESTDB" ) } );
+        }
+
+        let library = PluginLibrary();
+        let init : libloading::Symbol<ServerAuthPluginInitFuncT> =
+            unsafe { library.get( b"db2secServerAuthPluginInit\0" ) }.expect( "db2secServerAuthPluginInit is not exported" );
+
+        let mut fns = ServerAuthFunctions::default();
+        let mut errormsg : * mut c_char = std::ptr::null_mut();
+        let mut errormsglen : i32 = 0;
+        let rc = unsafe { init( 1, &mut fns as * mut ServerAuthFunctions as * mut c_void,
+                                Some( GetConDetails ), Some( LogMessage ),
+                                &mut errormsg, &mut errormsglen ) };
+
+        if rc != DB2SEC_PLUGIN_OK {
+            // Nothing to free it with, the function table was not filled in.
+            let msg = ReadErrorMessage( errormsg, errormsglen );
+            let _ = std::fs::remove_dir_all( &dir );
+            return Err( (rc, msg) );
+        }
+        assert!( errormsg.is_null(), "init succeeded but set an error message" );
+        assert_eq!( fns.version, 1 );
+        assert_eq!( fns.plugintype, DB2SEC_PLUGIN_TYPE_USERID_PASSWORD );
+        assert!( fns.validatePassword.is_some() && fns.getAuthIDs.is_some() && fns.doesAuthIDExist.is_some() &&
+                 fns.freeToken.is_some() && fns.freeErrormsg.is_some() && fns.pluginTerm.is_some(),
+                 "init left a function pointer empty" );
+
+        Ok( MockDb2 { fns, groupFns : None, dir, outstandingTokens : 0, terminated : false, _running : running } )
+    }
+
+    // Initialize the library as the group plugin as well, as Db2 does when
+    // it is configured as both.
+    pub fn StartGroupPlugin( &mut self ) {
+        let init : libloading::Symbol<GroupPluginInitFuncT> =
+            unsafe { PluginLibrary().get( b"db2secGroupPluginInit\0" ) }.expect( "db2secGroupPluginInit is not exported" );
+
+        let mut fns = GroupFunctions::default();
+        let mut errormsg : * mut c_char = std::ptr::null_mut();
+        let mut errormsglen : i32 = 0;
+        let rc = unsafe { init( 1, &mut fns as * mut GroupFunctions as * mut c_void, Some( LogMessage ),
+                                &mut errormsg, &mut errormsglen ) };
+        assert_eq!( rc, DB2SEC_PLUGIN_OK, "group plugin init failed: {:?}", ReadErrorMessage( errormsg, errormsglen ) );
+        assert_eq!( fns.plugintype, DB2SEC_PLUGIN_TYPE_GROUP );
+        assert!( fns.getGroupsForUser.is_some() && fns.doesGroupExist.is_some() && fns.freeGroupListMemory.is_some() &&
+                 fns.freeErrormsg.is_some() && fns.pluginTerm.is_some(),
+                 "group plugin init left a function pointer empty" );
+        self.groupFns = Some( fns );
+    }
+
+    // The scratch directory of this test.
+    pub fn Dir( &self ) -> &Path {
+        &self.dir
+    }
+
+    pub fn SetConnectionDetails( &self, connection : Option<ConnectionDetails> ) {
+        Locked( &HOST ).connection = connection;
+    }
+
+    // Everything the plugin logged so far, as (level, message).
+    pub fn Logs( &self ) -> Vec<(i32, String)> {
+        Locked( &HOST ).logs.clone()
+    }
+
+    pub fn Logged( &self, text : &str ) -> bool {
+        Locked( &HOST ).logs.iter().any( |(_, m)| m.contains( text ) )
+    }
+
+    // The records in audit_file = {dir}/audit.log so far, oldest first.
+    pub fn AuditRecords( &self ) -> Vec<serde_json::Value> {
+        let audit = std::fs::read_to_string( self.dir.join( "audit.log" ) ).expect( "no audit.log" );
+        audit.lines().map( |l| serde_json::from_str( l ).expect( "an audit record is not JSON" ) ).collect()
+    }
+
+    // The rule of each of them.
+    pub fn AuditRules( &self ) -> Vec<String> {
+        self.AuditRecords().iter().map( |r| r["rule"].as_str().unwrap_or_default().to_string() ).collect()
+    }
+
+    pub fn ValidatePassword( &mut self, userid : &str, password : Option<&str>, flags : u32 ) -> Validated {
+        self.ValidatePasswordBytes( userid.as_bytes(), password.map( str::as_bytes ), flags )
+    }
+
+    // As a client in another code page would send them.
+    pub fn ValidatePasswordBytes( &mut self, userid : &[u8], password : Option<&[u8]>, flags : u32 ) -> Validated {
+        let dbname = "TESTDB";
+        let mut token : * mut c_void = std::ptr::null_mut();
+        let mut errormsg : * mut c_char = std::ptr::null_mut();
+        let mut errormsglen : i32 = 0;
+
+        let (pw, pwlen) = match password {
+            Some(p) => (p.as_ptr() as * const c_char, p.len() as i32),
+            None => (std::ptr::null(), 0),
+        };
+        let rc = (self.fns.validatePassword.unwrap())( userid.as_ptr() as * const c_char, userid.len() as i32,
+                                                       std::ptr::null(), 0, 0,
+                                                       pw, pwlen,
+                                                       std::ptr::null(), 0,
+                                                       dbname.as_ptr() as * const c_char, dbname.len() as i32,
+                                                       flags,
+                                                       &mut token, &mut errormsg, &mut errormsglen );
+
+        if rc == DB2SEC_PLUGIN_OK {
+            assert!( ! token.is_null(), "ValidatePassword succeeded without a token" );
+            self.outstandingTokens += 1;
+        }
+        else {
+            assert!( token.is_null(), "ValidatePassword failed with {} but handed out a token", rc );
+        }
+
+        Validated { rc,
+                    token : if token.is_null() { None } else { Some( Token( token ) ) },
+                    errormsg : self.TakeErrorMessage( errormsg, errormsglen ) }
+    }
+
+    // ValidateAndFree on a thread of its own, as another Db2 agent would
+    // call it while this one goes on.
+    pub fn ValidateOnAnotherAgent( &self, userid : &str, password : &str ) -> std::thread::JoinHandle<i32> {
+        let validatePassword = self.fns.validatePassword.unwrap();
+        let freeToken = self.fns.freeToken.unwrap();
+        let freeErrormsg = self.fns.freeErrormsg.unwrap();
+        let (userid, password) = (userid.to_string(), password.to_string());
+
+        std::thread::spawn( move || {
+            let dbname = "TESTDB";
+            let mut token : * mut c_void = std::ptr::null_mut();
+            let mut errormsg : * mut c_char = std::ptr::null_mut();
+            let mut errormsglen : i32 = 0;
+            let rc = validatePassword( userid.as_ptr() as * const c_char, userid.len() as i32,
+                                       std::ptr::null(), 0, 0,
+                                       password.as_ptr() as * const c_char, password.len() as i32,
+                                       std::ptr::null(), 0,
+                                       dbname.as_ptr() as * const c_char, dbname.len() as i32,
+                                       DB2SEC_VALIDATING_ON_SERVER_SIDE,
+                                       &mut token, &mut errormsg, &mut errormsglen );
+            if ! token.is_null() {
+                freeToken( token, &mut errormsg, &mut errormsglen );
+            }
+            if ! errormsg.is_null() {
+                freeErrormsg( errormsg );
+            }
+            rc
+        })
+    }
+
+    // ValidatePassword when only the return code matters.  A token is
+    // given straight back with FreeToken.
+    pub fn ValidateAndFree( &mut self, userid : &str, password : Option<&str>, flags : u32 ) -> i32 {
+        let v = self.ValidatePassword( userid, password, flags );
+        if let Some(t) = v.token {
+            self.FreeToken( t );
+        }
+        v.rc
+    }
+
+    pub fn GetAuthIDs( &mut self, userid : &str, token : &mut Token ) -> AuthIDs {
+        let dbname = "TESTDB";
+        let mut systemAuthid = [GUARD; DB2SEC_MAX_AUTHID_LENGTH + GUARD_BYTES];
+        let mut initialAuthid = [GUARD; DB2SEC_MAX_AUTHID_LENGTH + GUARD_BYTES];
+        let mut username = [GUARD; DB2SEC_MAX_USERID_LENGTH + GUARD_BYTES];
+        let (mut systemLen, mut initialLen, mut usernameLen) = (-1i32, -1i32, -1i32);
+        let mut idType : i32 = -1;
+        let mut errormsg : * mut c_char = std::ptr::null_mut();
+        let mut errormsglen : i32 = 0;
+
+        let rc = (self.fns.getAuthIDs.unwrap())( userid.as_ptr() as * const c_char, userid.len() as i32,
+                                                 std::ptr::null(), 0, 0,
+                                                 dbname.as_ptr() as * const c_char, dbname.len() as i32,
+                                                 &mut token.0,
+                                                 systemAuthid.as_mut_ptr() as * mut c_char, &mut systemLen,
+                                                 initialAuthid.as_mut_ptr() as * mut c_char, &mut initialLen,
+                                                 username.as_mut_ptr() as * mut c_char, &mut usernameLen,
+                                                 &mut idType,
+                                                 &mut errormsg, &mut errormsglen );
+
+        let errormsg = self.TakeErrorMessage( errormsg, errormsglen );
+        if rc != DB2SEC_PLUGIN_OK {
+            return AuthIDs { rc, systemAuthid : String::new(), initialSessionAuthid : String::new(),
+                             username : String::new(), initialSessionIdType : idType, errormsg };
+        }
+
+        AuthIDs { rc,
+                  systemAuthid : OutputBuffer( &systemAuthid, systemLen, DB2SEC_MAX_AUTHID_LENGTH, "SystemAuthID" ),
+                  initialSessionAuthid : OutputBuffer( &initialAuthid, initialLen, DB2SEC_MAX_AUTHID_LENGTH, "InitialSessionAuthID" ),
+                  username : OutputBuffer( &username, usernameLen, DB2SEC_MAX_USERID_LENGTH, "username" ),
+                  initialSessionIdType : idType,
+                  errormsg }
+    }
+
+    // The groups of a user, with the token ValidatePassword gave for it if
+    // any.  The list is read as numgroups says and given back with
+    // FreeGroupListMemory.
+    pub fn GetGroupsForUser( &mut self, authid : &str, userid : &str, token : Option<&Token> ) -> Groups {
+        let fns = self.groupFns.as_ref().expect( "StartGroupPlugin was not called" );
+        let dbname = "TESTDB";
+        let mut list : * mut c_void = std::ptr::null_mut();
+        let mut numgroups : i32 = -1;
+        let mut errormsg : * mut c_char = std::ptr::null_mut();
+        let mut errormsglen : i32 = 0;
+
+        let rc = (fns.getGroupsForUser.unwrap())( authid.as_ptr() as * const c_char, authid.len() as i32,
+                                                  userid.as_ptr() as * const c_char, userid.len() as i32,
+                                                  std::ptr::null(), 0, 0,
+                                                  dbname.as_ptr() as * const c_char, dbname.len() as i32,
+                                                  token.map_or( std::ptr::null_mut(), |t| t.0 ),
+                                                  DB2SEC_GENERIC, DB2SEC_LOCATION_CLIENT_IDENTITY,
+                                                  PLUGIN_NAME.as_ptr() as * const c_char, PLUGIN_NAME.len() as i32,
+                                                  &mut list, &mut numgroups, &mut errormsg, &mut errormsglen );
+
+        let errormsg = self.TakeErrorMessage( errormsg, errormsglen );
+        let mut groups = Vec::new();
+        if rc == DB2SEC_PLUGIN_OK {
+            assert!( ! list.is_null() && numgroups >= 0, "GetGroupsForUser succeeded without a list" );
+            let mut at = list as * const u8;
+            for _ in 0..numgroups {
+                let len = unsafe { *at } as usize;
+                let name = unsafe { std::slice::from_raw_parts( at.add( 1 ), len ) };
+                groups.push( String::from_utf8_lossy( name ).into_owned() );
+                at = unsafe { at.add( 1 + len ) };
+            }
+            let mut freemsg : * mut c_char = std::ptr::null_mut();
+            let mut freemsglen : i32 = 0;
+            let freed = (fns.freeGroupListMemory.unwrap())( list, &mut freemsg, &mut freemsglen );
+            let freemsg = self.TakeErrorMessage( freemsg, freemsglen );
+            assert_eq!( freed, DB2SEC_PLUGIN_OK, "FreeGroupListMemory failed: {:?}", freemsg );
+        }
+        Groups { rc, groups, errormsg }
+    }
+
+    pub fn DoesGroupExist( &mut self, group : &str ) -> (i32, Option<String>) {
+        let fns = self.groupFns.as_ref().expect( "StartGroupPlugin was not called" );
+        let mut errormsg : * mut c_char = std::ptr::null_mut();
+        let mut errormsglen : i32 = 0;
+        let rc = (fns.doesGroupExist.unwrap())( group.as_ptr() as * const c_char, group.len() as i32,
+                                                &mut errormsg, &mut errormsglen );
+        (rc, self.TakeErrorMessage( errormsg, errormsglen ))
+    }
+
+    pub fn DoesAuthIDExist( &mut self, authid : &str ) -> (i32, Option<String>) {
+        let mut errormsg : * mut c_char = std::ptr::null_mut();
+        let mut errormsglen : i32 = 0;
+        let rc = (self.fns.doesAuthIDExist.unwrap())( authid.as_ptr() as * const c_char, authid.len() as i32,
+                                                      &mut errormsg, &mut errormsglen );
+        (rc, self.TakeErrorMessage( errormsg, errormsglen ))
+    }
+
+    pub fn FreeToken( &mut self, token : Token ) -> i32 {
+        let mut errormsg : * mut c_char = std::ptr::null_mut();
+        let mut errormsglen : i32 = 0;
+        let rc = (self.fns.freeToken.unwrap())( token.0, &mut errormsg, &mut errormsglen );
+        let msg = self.TakeErrorMessage( errormsg, errormsglen );
+        assert_eq!( rc, DB2SEC_PLUGIN_OK, "FreeToken failed: {:?}", msg );
+        self.outstandingTokens -= 1;
+        rc
+    }
+
+    // Give FreeToken any pointer, as a misbehaving host would.
+    pub fn FreeTokenPointer( &mut self, ptr : * mut c_void ) -> (i32, Option<String>) {
+        let mut errormsg : * mut c_char = std::ptr::null_mut();
+        let mut errormsglen : i32 = 0;
+        let rc = (self.fns.freeToken.unwrap())( ptr, &mut errormsg, &mut errormsglen );
+        (rc, self.TakeErrorMessage( errormsg, errormsglen ))
+    }
+
+    pub fn FreeErrormsgPointer( &self, ptr : * mut c_char ) -> i32 {
+        (self.fns.freeErrormsg.unwrap())( ptr )
+    }
+
+    // Never give the token back, see TermWithLeaks.
+    pub fn LeakToken( &mut self, token : Token ) {
+        let _ = token;
+    }
+
+    // Db2 frees every token before it terminates the plugin.
+    pub fn Term( mut self ) -> i32 {
+        assert_eq!( self.outstandingTokens, 0, "tokens were not freed before term" );
+        self.DoTerm()
+    }
+
+    // Term with tokens still outstanding, as a buggy host would.  Returns
+    // what the plugin logged, including during term.
+    pub fn TermWithLeaks( mut self ) -> (i32, Vec<(i32, String)>) {
+        let rc = self.DoTerm();
+        (rc, self.Logs())
+    }
+
+    fn DoTerm( &mut self ) -> i32 {
+        self.terminated = true;
+        let mut errormsg : * mut c_char = std::ptr::null_mut();
+        let mut errormsglen : i32 = 0;
+        let rc = (self.fns.pluginTerm.unwrap())( &mut errormsg, &mut errormsglen );
+        let _ = self.TakeErrorMessage( errormsg, errormsglen );
+        if let Some(fns) = self.groupFns.take() {
+            let mut errormsg : * mut c_char = std::ptr::null_mut();
+            (fns.pluginTerm.unwrap())( &mut errormsg, &mut errormsglen );
+            let _ = self.TakeErrorMessage( errormsg, errormsglen );
+        }
+        rc
+    }
+
+    // Read an error message and give it back to the plugin, as Db2 does.
+    fn TakeErrorMessage( &self, errormsg : * mut c_char, errormsglen : i32 ) -> Option<String> {
+        let msg = ReadErrorMessage( errormsg, errormsglen )?;
+        assert_eq!( (self.fns.freeErrormsg.unwrap())( errormsg ), DB2SEC_PLUGIN_OK, "FreeErrormsg failed" );
+        Some( msg )
+    }
+}
+
+impl Drop for MockDb2 {
+    // A test that failed part way must not leave the plugin initialized for
+    // the next one.
+    fn drop( &mut self ) {
+        if ! self.terminated {
+            self.DoTerm();
+        }
+        let _ = std::fs::remove_dir_all( &self.dir );
+    }
+}
+
+fn ReadErrorMessage( errormsg : * mut c_char, errormsglen : i32 ) -> Option<String> {
+    if errormsg.is_null() {
+        return None;
+    }
+    let msg = unsafe { CStr::from_ptr( errormsg ) };
+    assert_eq!( msg.to_bytes().len(), errormsglen as usize, "errormsglen does not match the message" );
+    Some( msg.to_string_lossy().into_owned() )
+}
+
+// The value in an output buffer, checking nothing was written past the end.
+fn OutputBuffer( buffer : &[u8], len : i32, max : usize, name : &str ) -> String {
+    assert!( len >= 0 && len as usize <= max, "{} length {} is out of range", name, len );
+    assert!( buffer[max..].iter().all( |b| *b == GUARD ), "{} was written past {} bytes", name, max );
+    String::from_utf8_lossy( &buffer[..len as usize] ).into_owned()
+}
@@ -0,0 +1,1392 @@
+// The server auth plugin driven through its ABI by the mock Db2 host, see
+// mockdb2/mod.rs.  Runs with cargo test, no Db2 instance needed.
+
+#![allow(non_snake_case)]
+
+mod mockdb2;
+
+use std::net::Ipv4Addr;
+
+use db2rustsecp::{mfa, token, totp};
+use db2rustsecp::userstore::{UserStore, ValidExpiryDate};
+use mockdb2::*;
+
+const LOCAL_OS_USER : u32 = DB2SEC_USERID_FROM_OS | DB2SEC_CONNECTION_ISLOCAL | DB2SEC_VALIDATING_ON_SERVER_SIDE;
+
+#[test]
+fn InitAndTerm() {
+    let db2 = MockDb2::Start( "init", "log_level = info\n" );
+    assert!( db2.Logged( "RUST based security u/pw plugin is initialized" ) );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn InitFailsOnABadConfiguration() {
+    match MockDb2::TryStart( "badconfig", "no_such_key = 1\n" ) {
+        Ok(_) => panic!("init accepted an unknown key"),
+        Err((rc, msg)) => {
+            assert_eq!( rc, DB2SEC_PLUGIN_UNKNOWNERROR );
+            assert!( msg.unwrap_or_default().contains( "no_such_key" ) );
+        }
+    }
+}
+
+#[test]
+fn DemoUserConnects() {
+    let mut db2 = MockDb2::Start( "demo", "" );
+
+    let v = db2.ValidatePassword( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    assert_eq!( v.rc, DB2SEC_PLUGIN_OK );
+    assert_eq!( v.errormsg, None );
+
+    let mut token = v.token.unwrap();
+    let ids = db2.GetAuthIDs( "newton", &mut token );
+    assert_eq!( ids.rc, DB2SEC_PLUGIN_OK );
+    assert_eq!( ids.systemAuthid, "NEWTON" );
+    assert_eq!( ids.initialSessionAuthid, "NEWTON" );
+    assert_eq!( ids.username, "newton" );
+    assert_eq!( ids.initialSessionIdType, 0 );
+
+    db2.FreeToken( token );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn WrongPasswordAndUnknownUser() {
+    let mut db2 = MockDb2::Start( "reject", "" );
+
+    let v = db2.ValidatePassword( "newton", Some( "wrong" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    assert_eq!( v.rc, DB2SEC_PLUGIN_BADPWD );
+    assert!( v.errormsg.unwrap().contains( "The password is bad" ) );
+
+    let v = db2.ValidatePassword( "nobody", Some( "x" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    assert_eq!( v.rc, DB2SEC_PLUGIN_BADUSER );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn NoPasswordOnlyForLocalOsUsers() {
+    let mut db2 = MockDb2::Start( "nopassword", "" );
+
+    let v = db2.ValidatePassword( "newton", None, DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    assert_eq!( v.rc, DB2SEC_PLUGIN_UNKNOWNERROR );
+
+    let v = db2.ValidatePassword( "newton", None, LOCAL_OS_USER );
+    assert_eq!( v.rc, DB2SEC_PLUGIN_OK );
+    db2.FreeToken( v.token.unwrap() );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn InputIsDecodedWithTheClientCodePage() {
+    let mut store = UserStore::New();
+    store.Add( "josé" ).unwrap().SetPassword( "olé" ).unwrap();
+    store.Add( "müller" ).unwrap().SetPassword( "straße" ).unwrap();
+    store.Add( "euler" ).unwrap().SetPassword( "e=2.71" ).unwrap();
+    for name in ["latin1", "ebcdic", "utf8"] {
+        store.Save( &ScratchDir( name ).join( "users.json" ) ).unwrap();
+    }
+    let validate = |db2 : &mut MockDb2, userid : &[u8], password : &[u8]| {
+        let v = db2.ValidatePasswordBytes( userid, Some( password ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+        if let Some(t) = v.token {
+            db2.FreeToken( t );
+        }
+        (v.rc, v.errormsg)
+    };
+
+    // Latin-1 from old clients, UTF-8 from everybody else.
+    let mut db2 = MockDb2::Start( "latin1", "user_store = {dir}/users.json\nclient_codepage = ISO-8859-1\n" );
+    assert_eq!( validate( &mut db2, b"jos\xE9", b"ol\xE9" ).0, DB2SEC_PLUGIN_OK );
+    assert_eq!( validate( &mut db2, "müller".as_bytes(), "straße".as_bytes() ).0, DB2SEC_PLUGIN_OK );
+    // The engine passes authids in UTF-8 whatever the clients send.
+    assert_eq!( db2.DoesAuthIDExist( "EULER" ).0, DB2SEC_PLUGIN_OK );
+    assert_eq!( db2.DoesAuthIDExist( "MÜLLER" ).0, DB2SEC_PLUGIN_OK );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+
+    // IBM-1047 "euler" and "e=2.71".
+    let mut db2 = MockDb2::Start( "ebcdic", "user_store = {dir}/users.json\nclient_codepage = IBM-1047\n" );
+    assert_eq!( validate( &mut db2, b"\x85\xA4\x93\x85\x99", b"\x85\x7E\xF2\x4B\xF7\xF1" ).0, DB2SEC_PLUGIN_OK );
+    assert_eq!( validate( &mut db2, "müller".as_bytes(), "straße".as_bytes() ).0, DB2SEC_PLUGIN_OK );
+    assert_eq!( db2.DoesAuthIDExist( "EULER" ).0, DB2SEC_PLUGIN_OK );
+    let (rc, msg) = db2.DoesAuthIDExist( "NOBODY" );
+    assert_eq!( rc, DB2SEC_PLUGIN_INVALIDUSERORGROUP, "{:?}", msg );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+
+    let mut db2 = MockDb2::Start( "utf8", "user_store = {dir}/users.json\n" );
+    assert_eq!( validate( &mut db2, "josé".as_bytes(), "olé".as_bytes() ).0, DB2SEC_PLUGIN_OK );
+    let (rc, msg) = validate( &mut db2, b"jos\xE9", b"x" );
+    assert_eq!( rc, DB2SEC_PLUGIN_UNKNOWNERROR );
+    let msg = msg.unwrap();
+    assert!( msg.contains( "userid is not valid UTF-8 and client_codepage is UTF-8, valid up to byte 3" ), "{}", msg );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn InputLengthsAreLimited() {
+    let mut db2 = MockDb2::Start( "lengths", "" );
+    let longest = "u".repeat( 255 );
+    assert_eq!( db2.ValidatePassword( &longest, Some( &longest ), DB2SEC_VALIDATING_ON_SERVER_SIDE ).rc, DB2SEC_PLUGIN_BADUSER );
+
+    let tooLong = "u".repeat( 256 );
+    for (userid, password, field) in [(&tooLong, &longest, "userid"), (&longest, &tooLong, "password")] {
+        let v = db2.ValidatePassword( userid, Some( password ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+        assert_eq!( v.rc, DB2SEC_PLUGIN_UNKNOWNERROR );
+        let msg = v.errormsg.unwrap();
+        assert!( msg.contains( &format!("{} has length 256, the maximum is 255", field) ), "{}", msg );
+    }
+    assert_eq!( db2.DoesAuthIDExist( &tooLong ).0, DB2SEC_PLUGIN_UNKNOWNERROR );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn DoesAuthIDExist() {
+    let mut db2 = MockDb2::Start( "authid", "" );
+    assert_eq!( db2.DoesAuthIDExist( "ZURBIE" ).0, DB2SEC_PLUGIN_OK );
+    assert_eq!( db2.DoesAuthIDExist( "NOBODY" ).0, DB2SEC_PLUGIN_INVALIDUSERORGROUP );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn UserStoreMappingAndAccountStatus() {
+    let mut store = UserStore::New();
+    let isaac = store.Add( "Isaac" ).unwrap();
+    isaac.SetPassword( "apple" ).unwrap();
+    isaac.authid = Some( String::from( "NEWTON" ) );
+    let locked = store.Add( "locked" ).unwrap();
+    locked.SetPassword( "pw" ).unwrap();
+    locked.locked = true;
+    let expired = store.Add( "expired" ).unwrap();
+    expired.SetPassword( "pw" ).unwrap();
+    expired.expires = Some( String::from( "2001-01-01" ) );
+    store.Save( &ScratchDir( "userstore" ).join( "users.json" ) ).unwrap();
+
+    let mut db2 = MockDb2::Start( "userstore", "user_store = {dir}/users.json\n" );
+
+    let v = db2.ValidatePassword( "ISAAC", Some( "apple" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    assert_eq!( v.rc, DB2SEC_PLUGIN_OK );
+    let mut token = v.token.unwrap();
+    let ids = db2.GetAuthIDs( "ISAAC", &mut token );
+    assert_eq!( (ids.systemAuthid.as_str(), ids.username.as_str()), ("NEWTON", "ISAAC") );
+    db2.FreeToken( token );
+
+    // The demo users are gone once there is a user store.
+    assert_eq!( db2.ValidatePassword( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ).rc,
+                DB2SEC_PLUGIN_BADUSER );
+    assert_eq!( db2.ValidatePassword( "locked", Some( "pw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ).rc,
+                DB2SEC_PLUGIN_USER_REVOKED );
+    assert_eq!( db2.ValidatePassword( "expired", Some( "pw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ).rc,
+                DB2SEC_PLUGIN_UID_EXPIRED );
+    // Account status is only told once the password is right.
+    assert_eq!( db2.ValidatePassword( "locked", Some( "guess" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ).rc,
+                DB2SEC_PLUGIN_BADPWD );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn OneUserPerAuthid() {
+    let mut store = UserStore::New();
+    store.Add( "Isaac" ).unwrap().authid = Some( String::from( "NEWTON" ) );
+    store.Add( "leibniz" ).unwrap();
+    let path = ScratchDir( "authids" ).join( "users.json" );
+    store.Save( &path ).unwrap();
+
+    // Connecting as NEWTON by default is as much a mapping as any.
+    store.Add( "newton" ).unwrap();
+    assert_eq!( store.Save( &path ).unwrap_err(), "users Isaac and newton both connect as authid NEWTON" );
+    let twice = "{\"version\": 1, \"users\": [{\"userid\": \"gottfried\", \"authid\": \"leibniz\"}, \
+                                              {\"userid\": \"leibniz\"}]}";
+    assert_eq!( UserStore::Parse( twice ).err().unwrap(), "users gottfried and leibniz both connect as authid LEIBNIZ" );
+
+    let admin = |args : &[&str]| {
+        let out = std::process::Command::new( env!("CARGO_BIN_EXE_rustsecp-admin") )
+                      .arg( "--store" ).arg( &path ).args( args )
+                      .output().unwrap();
+        (out.status.code(), String::from_utf8_lossy( &out.stderr ).into_owned())
+    };
+    let before = std::fs::read_to_string( &path ).unwrap();
+    assert_eq!( admin( &["add", "newton", "--no-password"] ),
+                (Some( 1 ), String::from( "users Isaac and newton both connect as authid NEWTON\n" )) );
+    assert_eq!( admin( &["map", "leibniz", "newton"] ), (Some( 1 ), String::from( "Isaac already connects as NEWTON\n" )) );
+    assert_eq!( admin( &["rename", "leibniz", "newton"] ).0, Some( 1 ) );
+    assert_eq!( std::fs::read_to_string( &path ).unwrap(), before );
+    assert_eq!( admin( &["unmap", "isaac"] ).0, Some( 0 ) );
+    assert_eq!( admin( &["add", "newton", "--no-password"] ).0, Some( 0 ) );
+    std::fs::remove_dir_all( path.parent().unwrap() ).unwrap();
+}
+
+#[test]
+fn GroupsComeFromTheUserStore() {
+    let mut store = UserStore::New();
+    let emmy = store.Add( "emmy" ).unwrap();
+    emmy.SetPassword( "rings" ).unwrap();
+    emmy.groups = vec![ String::from( "ALGEBRA" ), String::from( "PHYSICS" ) ];
+    store.Save( &ScratchDir( "groups" ).join( "users.json" ) ).unwrap();
+
+    let mut db2 = MockDb2::Start( "groups", "user_store = {dir}/users.json\naudit_file = {dir}/audit.log\n" );
+    db2.StartGroupPlugin();
+    let groups = db2.GetGroupsForUser( "EMMY", "emmy", None );
+    assert_eq!( groups.rc, DB2SEC_PLUGIN_OK );
+    assert_eq!( groups.groups, ["ALGEBRA", "PHYSICS"] );
+    assert_eq!( db2.GetGroupsForUser( "NOBODY", "nobody", None ).groups, Vec::<String>::new() );
+
+    // A group exists while a user is in it, in any case.
+    assert_eq!( db2.DoesGroupExist( "algebra" ), (DB2SEC_PLUGIN_OK, None) );
+    assert_eq!( db2.DoesGroupExist( "GEOMETRY" ), (DB2SEC_PLUGIN_INVALIDUSERORGROUP, None) );
+    assert_eq!( db2.DoesGroupExist( "" ).0, DB2SEC_PLUGIN_INVALIDUSERORGROUP );
+    let calls : Vec<(String, String)> = db2.AuditRecords().iter()
+                                            .map( |r| (r["call"].as_str().unwrap().to_string(),
+                                                       r["rule"].as_str().unwrap().to_string()) )
+                                            .collect();
+    let calls : Vec<(&str, &str)> = calls.iter().map( |(c, r)| (c.as_str(), r.as_str()) ).collect();
+    assert_eq!( calls, [("GetGroupsForUser", "groups-found"), ("GetGroupsForUser", "user-not-found"),
+                        ("DoesGroupExist", "group-found"), ("DoesGroupExist", "group-not-found"),
+                        ("DoesGroupExist", "invalid-input")] );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+
+    // A hand edited store is held to what rustsecp-admin would write.
+    let user = |field : &str| format!("{{\"version\": 1, \"users\": [{{\"userid\": \"emmy\", {}}}]}}", field);
+    let parsed = UserStore::Parse( &user( "\"groups\": [\"algebra\"], \"authid\": \"noether\"" ) ).unwrap();
+    let emmy = parsed.Get( "emmy" ).unwrap();
+    assert_eq!( (emmy.groups.as_slice(), emmy.Authid()), (&[String::from( "ALGEBRA" )][..], String::from( "NOETHER" )) );
+    let long = "G".repeat( 300 );
+    for bad in [format!("\"groups\": [\"{}\"]", long), String::from( "\"groups\": [\"two words\"]" ),
+                format!("\"authid\": \"{}\"", long)] {
+        assert!( UserStore::Parse( &user( &bad ) ).err().unwrap().contains( "is not a valid Db2 name" ), "{}", bad );
+    }
+    assert!( UserStore::Parse( &user( "\"expires\": \"2027-02-31\"" ) ).is_err() );
+}
+
+#[test]
+fn ExpiryDatesMustExist() {
+    for good in ["2027-02-28", "2028-02-29", "2000-02-29", "2027-04-30", "2027-12-31"] {
+        assert!( ValidExpiryDate( good ), "{}", good );
+    }
+    for bad in ["2027-02-29", "2100-02-29", "2027-02-31", "2027-04-31", "2027-13-01", "2027-00-10",
+                "2027-1-01", "27-01-01", "2027-01-01-01", "2027-01-+1"] {
+        assert!( ! ValidExpiryDate( bad ), "{}", bad );
+    }
+}
+
+#[test]
+fn LockoutAfterWrongPasswords() {
+    let mut db2 = MockDb2::Start( "lockout", "lockout_threshold = 2\n" );
+
+    for _ in 0..2 {
+        assert_eq!( db2.ValidatePassword( "zurbie", Some( "guess" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ).rc,
+                    DB2SEC_PLUGIN_BADPWD );
+    }
+    assert!( db2.Logged( "user zurbie locked out after 2 wrong passwords" ) );
+
+    let v = db2.ValidatePassword( "zurbie", Some( "zurbiepw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    assert_eq!( v.rc, DB2SEC_PLUGIN_USER_SUSPENDED );
+    assert!( v.errormsg.unwrap().contains( "locked out" ) );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn LockoutCountsWrongPasswordsInARowAndEnds() {
+    // Lockout is process wide, so a user of its own.
+    let mut store = UserStore::New();
+    store.Add( "curie" ).unwrap().SetPassword( "radium" ).unwrap();
+    store.Save( &ScratchDir( "lockout-ends" ).join( "users.json" ) ).unwrap();
+    let mut db2 = MockDb2::Start( "lockout-ends", "user_store = {dir}/users.json\nlockout_threshold = 2\n\
+                                                   lockout_seconds = 1\n" );
+    let mut validate = |userid : &str, password : &str|
+        db2.ValidateAndFree( userid, Some( password ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+
+    // A good password starts the count again.
+    assert_eq!( validate( "curie", "polonium" ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( "curie", "radium" ), DB2SEC_PLUGIN_OK );
+    assert_eq!( validate( "curie", "polonium" ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( "curie", "radium" ), DB2SEC_PLUGIN_OK );
+
+    // Whatever the case of the userid.
+    assert_eq!( validate( "curie", "polonium" ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( "CURIE", "polonium" ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( "curie", "radium" ), DB2SEC_PLUGIN_USER_SUSPENDED );
+
+    // And it ends by itself after lockout_seconds.
+    std::thread::sleep( std::time::Duration::from_millis( 1100 ) );
+    assert_eq!( validate( "curie", "radium" ), DB2SEC_PLUGIN_OK );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn AuditRecordHasTheConnectionDetails() {
+    let mut db2 = MockDb2::Start( "audit", "audit_file = {dir}/audit.log\n" );
+    db2.SetConnectionDetails( Some( ConnectionDetails { clientIP : Ipv4Addr::new( 192, 168, 7, 9 ),
+                                                        platform : 18,
+                                                        database : String::from( "SAMPLE" ) } ) );
+    let v = db2.ValidatePassword( "gstager", Some( "temp4Now" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    db2.FreeToken( v.token.unwrap() );
+
+    // Outside of a connection getConDetails fails, the record goes without.
+    db2.SetConnectionDetails( None );
+    db2.DoesAuthIDExist( "GSTAGER" );
+
+    let records = db2.AuditRecords();
+    assert_eq!( records.len(), 2 );
+
+    assert_eq!( records[0]["call"], "ValidatePassword" );
+    assert_eq!( records[0]["client_ip"], "192.168.7.9" );
+    assert_eq!( records[0]["platform"], 18 );
+    assert_eq!( records[0]["authid"], "GSTAGER" );
+    assert_eq!( records[0]["rule"], "password-match" );
+
+    assert_eq!( records[1]["call"], "DoesAuthIDExist" );
+    assert!( records[1].get( "client_ip" ).is_none() );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn LogLevelAndNoPasswordsInTheLog() {
+    let mut db2 = MockDb2::Start( "quiet", "" );
+    let v = db2.ValidatePassword( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    db2.FreeToken( v.token.unwrap() );
+    assert!( db2.Logs().iter().all( |(level, _)| *level < DB2SEC_LOG_INFO ),
+             "info messages logged at the default level: {:?}", db2.Logs() );
+    drop( db2 );
+
+    let mut db2 = MockDb2::Start( "verbose", "log_level = info\n" );
+    let v = db2.ValidatePassword( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    db2.FreeToken( v.token.unwrap() );
+    assert!( db2.Logged( "field userid is newton" ) );
+    assert!( db2.Logged( "field password is <redacted, 8 bytes>" ) );
+    assert!( ! db2.Logged( "newtonpw" ), "the password was logged" );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn CorrelationIdTiesTheCallsOfAConnection() {
+    let mut db2 = MockDb2::Start( "correlation", "log_level = info\n" );
+
+    let v = db2.ValidatePassword( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    let mut token = v.token.unwrap();
+    db2.GetAuthIDs( "newton", &mut token );
+    db2.FreeToken( token );
+
+    let logs = db2.Logs();
+    let id = |text : &str| logs.iter()
+                               .find( |(_, m)| m.contains( text ) )
+                               .and_then( |(_, m)| m.rsplit( "correlation id " ).next() )
+                               .map( String::from );
+    let validated = id( "field userid is newton" ).expect( "no ValidatePassword message" );
+    assert_eq!( id( "Entering GetAuthIDs" ), Some( validated.clone() ) );
+    assert_eq!( id( "Freeing the token" ), Some( validated ) );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn FreeRefusesForeignAndFreedPointers() {
+    let mut db2 = MockDb2::Start( "badfree", "" );
+
+    let v = db2.ValidatePassword( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    let token = v.token.unwrap();
+    let stale = token.Pointer();
+    db2.FreeToken( token );
+
+    let (rc, msg) = db2.FreeTokenPointer( stale );
+    assert_eq!( rc, DB2SEC_PLUGIN_UNKNOWNERROR );
+    assert!( msg.unwrap().contains( "freed already" ) );
+
+    let mut foreign = [0u64; 8];
+    assert_eq!( db2.FreeTokenPointer( foreign.as_mut_ptr() as * mut _ ).0, DB2SEC_PLUGIN_UNKNOWNERROR );
+    assert_eq!( db2.FreeTokenPointer( std::ptr::null_mut() ).0, DB2SEC_PLUGIN_UNKNOWNERROR );
+
+    let mut text = *b"not ours\0";
+    assert_eq!( db2.FreeErrormsgPointer( text.as_mut_ptr() as * mut _ ), DB2SEC_PLUGIN_UNKNOWNERROR );
+    assert_eq!( db2.FreeErrormsgPointer( std::ptr::null_mut() ), DB2SEC_PLUGIN_UNKNOWNERROR );
+    assert!( db2.Logged( "FreeErrorMsg: refused to free" ) );
+
+    // Nothing was freed that should not have been, the plugin still works.
+    let v = db2.ValidatePassword( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    db2.FreeToken( v.token.unwrap() );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn TermReportsLeakedTokens() {
+    let mut db2 = MockDb2::Start( "leak", "" );
+
+    let v = db2.ValidatePassword( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    db2.LeakToken( v.token.unwrap() );
+
+    let (rc, logs) = db2.TermWithLeaks();
+    assert_eq!( rc, DB2SEC_PLUGIN_OK );
+    assert!( logs.iter().any( |(level, m)| *level == DB2SEC_LOG_WARNING &&
+                                           m.contains( "allocations were never freed: 1 token from ValidatePassword" ) ),
+             "no leak report: {:?}", logs );
+}
+
+#[test]
+fn TokenIsOnlyGoodForItsUser() {
+    let mut db2 = MockDb2::Start( "tokenuser", "" );
+
+    let v = db2.ValidatePassword( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    let mut token = v.token.unwrap();
+
+    let ids = db2.GetAuthIDs( "zurbie", &mut token );
+    assert_eq!( ids.rc, DB2SEC_PLUGIN_UNKNOWNERROR );
+    assert!( ids.errormsg.unwrap().contains( "made for userid newton, not zurbie" ) );
+
+    // Userids are not case sensitive.
+    assert_eq!( db2.GetAuthIDs( "NEWTON", &mut token ).rc, DB2SEC_PLUGIN_OK );
+
+    db2.FreeToken( token );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn StaleTokenIsRefused() {
+    use std::time::{Duration, SystemTime};
+
+    let created = SystemTime::now();
+    let at = |seconds : u64| created + Duration::from_secs( seconds );
+    assert_eq!( token::TokenAgeError( created, 300, at( 300 ) ), None );
+    assert_eq!( token::TokenAgeError( created, 300, at( 301 ) ).unwrap(),
+                "The token is 301 seconds old, token_max_age_seconds is 300" );
+    assert_eq!( token::TokenAgeError( created, 0, at( 100000 ) ), None );
+    // The clock went back.
+    assert_eq!( token::TokenAgeError( at( 10 ), 1, created ), None );
+
+    // The plugin applies the configured limit.
+    let mut db2 = MockDb2::Start( "tokenage", "token_max_age_seconds = 3600\n" );
+    let v = db2.ValidatePassword( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    let mut token = v.token.unwrap();
+    assert_eq!( db2.GetAuthIDs( "newton", &mut token ).rc, DB2SEC_PLUGIN_OK );
+    db2.FreeToken( token );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+// Flip the case of the first byte of the authid inside a token, as whoever
+// could write to the memory of the Db2 engine might.  The token is laid
+// out as in C: the tag, two numbers and then the authid.  Of the three
+// words of that String the pointer is the one that is no small number.
+fn AlterTokenAuthid( token : &Token ) {
+    let words = unsafe { (token.Pointer() as * const u8).add( 16 ) as * const usize };
+    let data = (0..3).map( |i| unsafe { *words.add( i ) } )
+                     .find( |w| *w > 0x10000 )
+                     .expect( "no pointer in the authid of the token" ) as * mut u8;
+    unsafe { *data ^= 0x20 };
+}
+
+#[test]
+fn AlteredTokensAreRefused() {
+    let mut store = UserStore::New();
+    let ada = store.Add( "ada" ).unwrap();
+    ada.SetPassword( "engine" ).unwrap();
+    ada.groups = vec![ String::from( "ANALYTICAL" ) ];
+    let charles = store.Add( "charles" ).unwrap();
+    charles.SetPassword( "difference" ).unwrap();
+    store.Save( &ScratchDir( "tokenforged" ).join( "users.json" ) ).unwrap();
+
+    let mut db2 = MockDb2::Start( "tokenforged", "user_store = {dir}/users.json\naudit_file = {dir}/audit.log\n" );
+    db2.StartGroupPlugin();
+    let mut token = db2.ValidatePassword( "ada", Some( "engine" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ).token.unwrap();
+    let other = db2.ValidatePassword( "charles", Some( "difference" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ).token.unwrap();
+
+    // The token is good for its own user only.
+    assert_eq!( db2.GetGroupsForUser( "ADA", "ada", Some( &token ) ).groups, ["ANALYTICAL"] );
+    let groups = db2.GetGroupsForUser( "ADA", "ada", Some( &other ) );
+    assert_eq!( groups.rc, DB2SEC_PLUGIN_UNKNOWNERROR );
+    assert!( groups.errormsg.unwrap().contains( "made for userid charles, not ada" ) );
+    let groups = db2.GetGroupsForUser( "CHARLES", "ada", Some( &token ) );
+    assert_eq!( groups.rc, DB2SEC_PLUGIN_UNKNOWNERROR );
+    assert!( groups.errormsg.unwrap().contains( "made for authid ADA, not CHARLES" ) );
+
+    // Changed behind the plugin's back, the MAC no longer matches.
+    AlterTokenAuthid( &token );
+    let ids = db2.GetAuthIDs( "ada", &mut token );
+    assert_eq!( ids.rc, DB2SEC_PLUGIN_UNKNOWNERROR );
+    assert!( ids.errormsg.unwrap().contains( "The token was altered after ValidatePassword made it" ) );
+    assert!( db2.Logged( "GetAuthIDs: token refused" ) );
+    let groups = db2.GetGroupsForUser( "ADA", "ada", Some( &token ) );
+    assert_eq!( groups.rc, DB2SEC_PLUGIN_UNKNOWNERROR );
+    assert!( groups.errormsg.unwrap().contains( "The token was altered" ) );
+    assert!( db2.Logged( "GetGroupsForUser: token refused" ) );
+
+    // Put back, it is the token it was.
+    AlterTokenAuthid( &token );
+    assert_eq!( db2.GetAuthIDs( "ada", &mut token ).systemAuthid, "ADA" );
+
+    let rules : Vec<String> = db2.AuditRecords().iter()
+                                 .filter( |r| r["call"] == "GetGroupsForUser" )
+                                 .map( |r| r["rule"].as_str().unwrap().to_string() )
+                                 .collect();
+    assert_eq!( rules, ["groups-found", "token-refused", "token-refused", "token-refused"] );
+
+    // An altered token is still freed.
+    AlterTokenAuthid( &token );
+    db2.FreeToken( token );
+    db2.FreeToken( other );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn UniformFailuresHideWhichUsersExist() {
+    // Lockout is process wide, so a user of its own.
+    let mut store = UserStore::New();
+    store.Add( "hedy" ).unwrap().SetPassword( "lamarr" ).unwrap();
+    let grace = store.Add( "grace" ).unwrap();
+    grace.SetPassword( "cobol" ).unwrap();
+    grace.groups = vec![ String::from( "NAVY" ) ];
+    store.Save( &ScratchDir( "uniform" ).join( "users.json" ) ).unwrap();
+
+    let mut db2 = MockDb2::Start( "uniform", "uniform_failures = on\nlockout_threshold = 2\n\
+                                              user_store = {dir}/users.json\naudit_file = {dir}/audit.log\n\
+                                              totp_required_groups = navy\n" );
+
+    let timed = |db2 : &mut MockDb2, userid : &str, password : &str| {
+        let started = std::time::Instant::now();
+        let v = db2.ValidatePassword( userid, Some( password ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+        (v, started.elapsed())
+    };
+
+    let (unknown, unknownTime) = timed( &mut db2, "nobody", "guess" );
+    let (wrong, wrongTime) = timed( &mut db2, "hedy", "guess" );
+    timed( &mut db2, "hedy", "guess" );
+    let (locked, _) = timed( &mut db2, "hedy", "lamarr" );
+
+    for v in [&unknown, &wrong, &locked] {
+        assert_eq!( v.rc, DB2SEC_PLUGIN_BADPWD );
+        let msg = v.errormsg.as_deref().unwrap();
+        assert!( msg.contains( "The userid or password is not valid" ), "{}", msg );
+        assert!( ! msg.contains( "hedy" ) && ! msg.contains( "nobody" ), "{}", msg );
+    }
+    // An unknown user costs a hash check as well, not next to nothing.
+    assert!( unknownTime * 3 > wrongTime, "unknown user {:?}, wrong password {:?}", unknownTime, wrongTime );
+
+    // No guess: the password was right, the account may not be used.
+    let (notEnrolled, _) = timed( &mut db2, "grace", "cobol" );
+    assert_eq!( notEnrolled.rc, DB2SEC_PLUGIN_USER_REVOKED );
+    assert!( notEnrolled.errormsg.unwrap().contains( "has no TOTP or HOTP secret" ) );
+
+    // The audit log still has the real reasons.
+    assert_eq!( db2.AuditRules(), ["unknown-user", "password-mismatch", "password-mismatch", "account-locked",
+                                   "totp-not-enrolled"] );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+// The delay and rate limit tables are process wide, so these tests use
+// users and client addresses of their own.
+fn DelayTestStore( name : &str, userid : &str, password : &str ) {
+    let mut store = UserStore::New();
+    store.Add( userid ).unwrap().SetPassword( password ).unwrap();
+    store.Save( &ScratchDir( name ).join( "users.json" ) ).unwrap();
+}
+
+fn From( ip : Ipv4Addr ) -> Option<ConnectionDetails> {
+    Some( ConnectionDetails { clientIP : ip, platform : 30, database : String::from( "TESTDB" ) } )
+}
+
+#[test]
+fn FailuresDelayTheNextAttempt() {
+    DelayTestStore( "delay", "ada", "engine" );
+    let mut db2 = MockDb2::Start( "delay", "user_store = {dir}/users.json\ndelay_base_ms = 700\ndelay_max_ms = 1000\n" );
+    db2.SetConnectionDetails( From( Ipv4Addr::new( 10, 45, 0, 1 ) ) );
+
+    let timed = |db2 : &mut MockDb2, password : &str| {
+        let started = std::time::Instant::now();
+        let v = db2.ValidatePassword( "ada", Some( password ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+        if let Some(t) = v.token {
+            db2.FreeToken( t );
+        }
+        (v.rc, started.elapsed())
+    };
+    let ms = std::time::Duration::from_millis;
+
+    // Without failures the check is all there is.
+    let (_, check) = timed( &mut db2, "guess" );
+    let (rc, first) = timed( &mut db2, "guess" );
+    assert_eq!( rc, DB2SEC_PLUGIN_BADPWD );
+    assert!( first >= ms( 700 ), "one failure: {:?}, the check alone {:?}", first, check );
+
+    // Doubled, but never more than delay_max_ms.
+    let (_, capped) = timed( &mut db2, "guess" );
+    assert!( capped >= ms( 1000 ) && capped < check + ms( 1900 ), "capped: {:?}", capped );
+
+    // The right password waits as well, then clears the user but not the client.
+    assert_eq!( timed( &mut db2, "engine" ).0, DB2SEC_PLUGIN_OK );
+    let (_, client) = timed( &mut db2, "engine" );
+    assert!( client >= ms( 1000 ), "the client delay went: {:?}", client );
+
+    // From another client, the user is fine again.
+    db2.SetConnectionDetails( From( Ipv4Addr::new( 10, 45, 0, 2 ) ) );
+    let (rc, other) = timed( &mut db2, "engine" );
+    assert_eq!( rc, DB2SEC_PLUGIN_OK );
+    assert!( other < check + ms( 500 ), "another client waited: {:?}", other );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn DelayedLoginsAreCapped() {
+    DelayTestStore( "delaycap", "grace", "cobol" );
+    let mut db2 = MockDb2::Start( "delaycap", "user_store = {dir}/users.json\ndelay_base_ms = 100\ndelay_max_threads = 0\n" );
+    db2.SetConnectionDetails( From( Ipv4Addr::new( 10, 45, 0, 3 ) ) );
+
+    // Nothing to wait for yet.
+    assert_eq!( db2.ValidatePassword( "grace", Some( "guess" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ).rc,
+                DB2SEC_PLUGIN_BADPWD );
+
+    // No agent may wait, so the next attempt is refused, even with the right password.
+    let v = db2.ValidatePassword( "grace", Some( "cobol" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    assert_eq!( v.rc, DB2SEC_PLUGIN_CONNECTION_DISALLOWED );
+    assert!( v.errormsg.unwrap().contains( "Too many logins are being delayed" ) );
+    assert!( db2.Logged( "login for grace from 10.45.0.3 refused" ) );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn CappedDelaysRefuseOnlyTheFlood() {
+    let mut store = UserStore::New();
+    store.Add( "flood" ).unwrap().SetPassword( "deluge" ).unwrap();
+    store.Add( "bystander" ).unwrap().SetPassword( "umbrella" ).unwrap();
+    store.Save( &ScratchDir( "delayflood" ).join( "users.json" ) ).unwrap();
+    let mut db2 = MockDb2::Start( "delayflood", "user_store = {dir}/users.json\n\
+                                                 delay_base_ms = 3000\ndelay_max_ms = 3000\ndelay_max_threads = 1\n" );
+    // Without an address only the users are delayed.
+    db2.SetConnectionDetails( None );
+    let ms = std::time::Duration::from_millis;
+
+    assert_eq!( db2.ValidateAndFree( "flood", Some( "guess" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( db2.ValidateAndFree( "bystander", Some( "guess" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ),
+                DB2SEC_PLUGIN_BADPWD );
+
+    // One guess for flood takes the only place.
+    let started = std::time::Instant::now();
+    let waiting = db2.ValidateOnAnotherAgent( "flood", "guess" );
+    std::thread::sleep( ms( 300 ) );
+
+    // The next one for flood is refused at once.
+    let v = db2.ValidatePassword( "flood", Some( "deluge" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    assert_eq!( v.rc, DB2SEC_PLUGIN_CONNECTION_DISALLOWED );
+    assert!( started.elapsed() < ms( 1500 ), "the refusal waited: {:?}", started.elapsed() );
+
+    // bystander is not crowded out, it takes the place of the waiting guess.
+    let bystander = db2.ValidateOnAnotherAgent( "bystander", "umbrella" );
+    assert_eq!( waiting.join().unwrap(), DB2SEC_PLUGIN_CONNECTION_DISALLOWED );
+    assert!( started.elapsed() < ms( 2500 ), "the displaced guess waited: {:?}", started.elapsed() );
+    assert_eq!( bystander.join().unwrap(), DB2SEC_PLUGIN_OK );
+    assert!( started.elapsed() >= ms( 3300 ), "bystander did not wait: {:?}", started.elapsed() );
+
+    assert!( db2.Logged( "login for flood from an unknown address refused" ) );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn UnknownUseridsAreDelayedLikeKnownOnes() {
+    DelayTestStore( "delayunknown", "hopper", "cobol" );
+    let mut db2 = MockDb2::Start( "delayunknown", "user_store = {dir}/users.json\nuniform_failures = on\n\
+                                                   delay_base_ms = 600\ndelay_max_ms = 600\n" );
+    // Without an address only the userids are delayed.
+    db2.SetConnectionDetails( None );
+
+    let timed = |db2 : &mut MockDb2, userid : &str| {
+        let started = std::time::Instant::now();
+        let rc = db2.ValidateAndFree( userid, Some( "guess" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+        (rc, started.elapsed())
+    };
+    let ms = std::time::Duration::from_millis;
+
+    for userid in ["hopper", "nohopper"] {
+        let (rc, check) = timed( &mut db2, userid );
+        assert_eq!( rc, DB2SEC_PLUGIN_BADPWD );
+        let (rc, delayed) = timed( &mut db2, userid );
+        assert_eq!( rc, DB2SEC_PLUGIN_BADPWD );
+        assert!( delayed >= check + ms( 450 ), "{}: the check alone {:?}, after a failure {:?}", userid, check, delayed );
+    }
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn RateLimitsPerAddressAndPerUser() {
+    DelayTestStore( "ratelimit", "alan", "enigma" );
+    let mut db2 = MockDb2::Start( "ratelimit", "user_store = {dir}/users.json\n\
+                                                rate_limit_ip = 2/3600\nrate_limit_user = 2/3600\n\
+                                                audit_file = {dir}/audit.log\n\
+                                                metrics_file = {dir}/metrics.prom\nmetrics_interval_seconds = 1\n" );
+    db2.SetConnectionDetails( From( Ipv4Addr::new( 10, 46, 0, 1 ) ) );
+    assert_eq!( db2.ValidateAndFree( "alan", Some( "guess" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( db2.ValidateAndFree( "nobody46", Some( "guess" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_BADUSER );
+    let v = db2.ValidatePassword( "alan", Some( "guess" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    assert_eq!( v.rc, DB2SEC_PLUGIN_CONNECTION_DISALLOWED );
+    assert!( v.errormsg.unwrap().contains( "Too many login attempts" ) );
+
+    // Another address has its own bucket, until the user's runs out.
+    db2.SetConnectionDetails( From( Ipv4Addr::new( 10, 46, 0, 2 ) ) );
+    assert_eq!( db2.ValidateAndFree( "alan", Some( "guess" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( db2.ValidateAndFree( "ALAN", Some( "guess" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ),
+                DB2SEC_PLUGIN_CONNECTION_DISALLOWED );
+
+    assert_eq!( db2.AuditRules(), ["password-mismatch", "unknown-user", "rate-limit-ip", "password-mismatch", "rate-limit-user"] );
+
+    std::thread::sleep( std::time::Duration::from_millis( 1500 ) );
+    let metrics = std::fs::read_to_string( db2.Dir().join( "metrics.prom" ) ).unwrap();
+    for limit in ["ip", "user"] {
+        let line = format!("rustsecp_rate_limited_total{{limit=\"{}\"}} ", limit);
+        let count : u64 = metrics.lines().find_map( |l| l.strip_prefix( &line ) ).unwrap().parse().unwrap();
+        assert!( count >= 1, "{}", metrics );
+    }
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn FullRateLimitTableDropsTheLeastRecentlyUsed() {
+    let mut db2 = MockDb2::Start( "ratelimit-full", "rate_limit_user = 1/3600\n" );
+    let attempt = |db2 : &mut MockDb2, userid : &str| db2.ValidateAndFree( userid, Some( "guess" ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+    assert_eq!( attempt( &mut db2, "lru-first" ), DB2SEC_PLUGIN_BADUSER );
+    assert_eq!( attempt( &mut db2, "lru-first" ), DB2SEC_PLUGIN_CONNECTION_DISALLOWED );
+
+    // As many new userids as the table holds push the first one out, one
+    // at a time.
+    for i in 0..100_000 {
+        assert_eq!( attempt( &mut db2, &format!("lru-{}", i) ), DB2SEC_PLUGIN_BADUSER );
+    }
+    assert_eq!( attempt( &mut db2, "lru-first" ), DB2SEC_PLUGIN_BADUSER );
+    assert_eq!( attempt( &mut db2, "lru-99999" ), DB2SEC_PLUGIN_CONNECTION_DISALLOWED );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn SprayAndStuffingAreDetectedAndBlocked() {
+    DelayTestStore( "detect", "linus", "kernel" );
+    let mut db2 = MockDb2::Start( "detect", "user_store = {dir}/users.json\n\
+                                             stuffing_userids = 3\nspray_userids = 3\ndetect_block_seconds = 3600\n\
+                                             audit_file = {dir}/audit.log\n" );
+    let validate = |db2 : &mut MockDb2, ip : u8, userid : &str, password : &str| {
+        db2.SetConnectionDetails( From( Ipv4Addr::new( 10, 47, 0, ip ) ) );
+        db2.ValidateAndFree( userid, Some( password ), DB2SEC_VALIDATING_ON_SERVER_SIDE )
+    };
+
+    // Many userids from one address, each with its own password.
+    assert_eq!( validate( &mut db2, 1, "stuffed1", "first" ), DB2SEC_PLUGIN_BADUSER );
+    assert_eq!( validate( &mut db2, 1, "stuffed2", "second" ), DB2SEC_PLUGIN_BADUSER );
+    assert_eq!( validate( &mut db2, 1, "linus", "third" ), DB2SEC_PLUGIN_BADPWD );
+    assert!( db2.Logged( "credential-stuffing detected: 3 userids failed from 10.47.0.1" ) );
+    assert_eq!( validate( &mut db2, 1, "linus", "kernel" ), DB2SEC_PLUGIN_CONNECTION_DISALLOWED );
+    assert_eq!( validate( &mut db2, 9, "linus", "kernel" ), DB2SEC_PLUGIN_OK );
+
+    // One password against many userids, from a new address every time.
+    assert_eq!( validate( &mut db2, 2, "sprayed1", "Autumn2026!" ), DB2SEC_PLUGIN_BADUSER );
+    assert_eq!( validate( &mut db2, 3, "sprayed2", "Autumn2026!" ), DB2SEC_PLUGIN_BADUSER );
+    assert_eq!( validate( &mut db2, 4, "linus", "Autumn2026!" ), DB2SEC_PLUGIN_BADPWD );
+    assert!( db2.Logged( "password-spray detected: the same wrong password failed for 3 userids" ) );
+    assert_eq!( validate( &mut db2, 5, "sprayed3", "Autumn2026!" ), DB2SEC_PLUGIN_BADUSER );
+    for ip in [4, 5] {
+        assert_eq!( validate( &mut db2, ip, "linus", "kernel" ), DB2SEC_PLUGIN_CONNECTION_DISALLOWED );
+    }
+    assert_eq!( validate( &mut db2, 2, "linus", "kernel" ), DB2SEC_PLUGIN_OK );
+
+    let audit = std::fs::read_to_string( db2.Dir().join( "audit.log" ) ).unwrap();
+    assert!( ! audit.contains( "Autumn2026" ), "a password reached the audit log" );
+    let alerts : Vec<serde_json::Value> = db2.AuditRecords().into_iter().filter( |r| r["call"] == "Alert" ).collect();
+    // Once per pattern, the fourth spray attempt only got its address blocked.
+    assert_eq!( alerts.len(), 2, "{}", audit );
+    assert_eq!( alerts[0]["rule"], "credential-stuffing" );
+    assert_eq!( alerts[0]["client_ip"], "10.47.0.1" );
+    assert_eq!( alerts[0]["result"], "RUSTSECP_ALERT" );
+    assert_eq!( alerts[1]["rule"], "password-spray" );
+    assert_eq!( alerts[1]["client_ip"], "10.47.0.4" );
+    assert!( alerts[1]["detail"].as_str().unwrap().contains( "3 userids" ) );
+    assert!( audit.contains( "\"rule\":\"address-blocked\"" ) );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn DecoysAndCanariesRaiseAlerts() {
+    let mut store = UserStore::New();
+    store.Add( "marie" ).unwrap().SetPassword( "radium" ).unwrap();
+    store.Add( "db2backup" ).unwrap().decoy = true;
+    let decoy = store.Add( "svc_etl" ).unwrap();
+    decoy.SetPassword( "etl2026" ).unwrap();
+    decoy.decoy = true;
+    // Nobody's password may be a canary, but if it is the canary wins.
+    store.Add( "pierre" ).unwrap().SetPassword( "Spring2026!" ).unwrap();
+    store.AddCanary( "Spring2026!" ).unwrap();
+    store.Save( &ScratchDir( "traps" ).join( "users.json" ) ).unwrap();
+
+    let mut db2 = MockDb2::Start( "traps", "user_store = {dir}/users.json\naudit_file = {dir}/audit.log\n" );
+    db2.SetConnectionDetails( From( Ipv4Addr::new( 10, 48, 0, 1 ) ) );
+    let mut validate = |userid : &str, password : Option<&str>, flags : u32| db2.ValidateAndFree( userid, password, flags );
+
+    assert_eq!( validate( "db2backup", Some( "anything" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( "SVC_ETL", Some( "etl2026" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( "db2backup", None, LOCAL_OS_USER ), DB2SEC_PLUGIN_USER_REVOKED );
+    assert_eq!( validate( "marie", Some( "Spring2026!" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( "pierre", Some( "Spring2026!" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( "nobody48", Some( "Spring2026!" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_BADUSER );
+    assert_eq!( validate( "marie", Some( "radium" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_OK );
+
+    assert!( db2.Logs().iter().any( |(level, m)| *level == DB2SEC_LOG_ERROR &&
+                                                 m.contains( "honeypot-account detected: the decoy user was tried for userid db2backup: \
+                                                              client 10.48.0.1" ) ),
+             "{:?}", db2.Logs() );
+
+    let audit = std::fs::read_to_string( db2.Dir().join( "audit.log" ) ).unwrap();
+    assert!( ! audit.contains( "Spring2026" ) && ! audit.contains( "etl2026" ), "a password reached the audit log" );
+    let records = db2.AuditRecords();
+    let alerts : Vec<(&str, &str)> = records.iter()
+                                            .filter( |r| r["call"] == "Alert" )
+                                            .map( |r| (r["rule"].as_str().unwrap(), r["userid"].as_str().unwrap()) )
+                                            .collect();
+    assert_eq!( alerts, [("honeypot-account", "db2backup"), ("honeypot-account", "SVC_ETL"),
+                         ("honeypot-account", "db2backup"), ("canary-password", "marie"),
+                         ("canary-password", "pierre"), ("canary-password", "nobody48")] );
+    let first = records.iter().find( |r| r["call"] == "Alert" ).unwrap();
+    assert_eq!( first["client_ip"], "10.48.0.1" );
+    assert_eq!( first["database"], "TESTDB" );
+    assert!( first["detail"].as_str().unwrap().contains( "platform 30, database TESTDB" ), "{}", first );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn TotpCodeFollowsThePassword() {
+    // RFC 6238 appendix B, the last six of the eight digits.
+    let rfcSecret = totp::DecodeSecret( "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ" ).unwrap();
+    assert_eq!( rfcSecret, b"12345678901234567890" );
+    assert_eq!( totp::EncodeSecret( &rfcSecret ), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ" );
+    assert_eq!( totp::Code( &rfcSecret, 59 / 30 ), "287082" );
+    assert_eq!( totp::Code( &rfcSecret, 1111111109 / 30 ), "081804" );
+
+    let secret = totp::NewSecret().unwrap();
+    let mut store = UserStore::New();
+    let hopper = store.Add( "hopper" ).unwrap();
+    hopper.SetPassword( "nanosecond" ).unwrap();
+    hopper.groups = vec![ String::from( "DBADM" ) ];
+    hopper.totp = Some( secret.clone() );
+    let knuth = store.Add( "knuth" ).unwrap();
+    knuth.SetPassword( "tex" ).unwrap();
+    knuth.groups = vec![ String::from( "DBADM" ) ];
+    store.Save( &ScratchDir( "totp" ).join( "users.json" ) ).unwrap();
+    store.Save( &ScratchDir( "totp-separator" ).join( "users.json" ) ).unwrap();
+
+    let key = totp::DecodeSecret( &secret ).unwrap();
+    let step = totp::Step( std::time::SystemTime::now() );
+    let code = |offset : u64| totp::Code( &key, step + offset );
+    let validate = |db2 : &mut MockDb2, userid : &str, password : &str|
+        db2.ValidateAndFree( userid, Some( password ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+
+    let mut db2 = MockDb2::Start( "totp", "user_store = {dir}/users.json\naudit_file = {dir}/audit.log\n\
+                                           totp_required_groups = dbadm\n" );
+    assert_eq!( validate( &mut db2, "hopper", &format!("nanosecond{}", code( 0 )) ), DB2SEC_PLUGIN_OK );
+    assert_eq!( validate( &mut db2, "hopper", &format!("nanosecond{}", code( 0 )) ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( &mut db2, "hopper", "nanosecond" ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( &mut db2, "hopper", &format!("nanosecond{}", code( 5 )) ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( &mut db2, "hopper", &format!("microsecond{}", code( 1 )) ), DB2SEC_PLUGIN_BADPWD );
+    // The next step is within the default skew of one.
+    assert_eq!( validate( &mut db2, "hopper", &format!("nanosecond{}", code( 1 )) ), DB2SEC_PLUGIN_OK );
+    assert_eq!( validate( &mut db2, "knuth", "tex" ), DB2SEC_PLUGIN_USER_REVOKED );
+    // Without a password there is no code, local OS users or not.
+    assert_eq!( db2.ValidateAndFree( "hopper", None, LOCAL_OS_USER ), DB2SEC_PLUGIN_USER_REVOKED );
+    assert_eq!( db2.ValidateAndFree( "knuth", None, LOCAL_OS_USER ), DB2SEC_PLUGIN_USER_REVOKED );
+
+    assert_eq!( db2.AuditRules(), ["password-match", "totp-replayed", "otp-missing", "otp-mismatch",
+                        "password-mismatch", "password-match", "totp-not-enrolled",
+                        "local-os-needs-otp", "totp-not-enrolled"] );
+
+    // The step of the last code is kept in the .mfa file, a process that
+    // has not seen it in memory still refuses the code.
+    let store = UserStore::Load( &db2.Dir().join( "users.json" ) ).unwrap();
+    let refused = mfa::CheckFactor( store.Get( "hopper" ).unwrap(), mfa::Factor::Code( &code( 1 ) ),
+                                    Some( &db2.Dir().join( "users.json" ) ), 1, 0, std::time::SystemTime::now(), false );
+    assert!( matches!( refused, Err(mfa::Refusal { rule : "totp-replayed", error : None }) ) );
+    let accepted = mfa::CheckFactor( store.Get( "hopper" ).unwrap(), mfa::Factor::Code( &code( 1 ) ),
+                                     None, 1, 0, std::time::SystemTime::now(), false );
+    assert!( matches!( accepted, Ok(mfa::Accepted::Totp) ) );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+
+    // The code after a separator, and the replay table outlives the plugin state.
+    let mut db2 = MockDb2::Start( "totp-separator", "user_store = {dir}/users.json\ntotp_separator = :\ntotp_skew_steps = 2\n\
+                                                     totp_required_groups = dbadm\ntotp_local_os_exempt = on\n" );
+    assert_eq!( validate( &mut db2, "hopper", &format!("nanosecond{}", code( 2 )) ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( &mut db2, "hopper", &format!("nanosecond:{}", code( 1 )) ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( &mut db2, "hopper", &format!("nanosecond:{}", code( 2 )) ), DB2SEC_PLUGIN_OK );
+    assert_eq!( db2.ValidateAndFree( "hopper", None, LOCAL_OS_USER ), DB2SEC_PLUGIN_OK );
+    assert_eq!( db2.ValidateAndFree( "knuth", None, LOCAL_OS_USER ), DB2SEC_PLUGIN_OK );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn HotpAndRecoveryCodes() {
+    let secret = totp::NewSecret().unwrap();
+    let codes : Vec<String> = (0..2).map( |_| mfa::NewRecoveryCode().unwrap() ).collect();
+    let mut store = UserStore::New();
+    let lovelace = store.Add( "lovelace" ).unwrap();
+    lovelace.SetPassword( "engine" ).unwrap();
+    lovelace.hotp = Some( secret.clone() );
+    lovelace.recovery = codes.iter().map( |c| mfa::RecoveryDigest( c ) ).collect();
+    let path = ScratchDir( "hotp" ).join( "users.json" );
+    store.Save( &path ).unwrap();
+
+    let key = totp::DecodeSecret( &secret ).unwrap();
+    let code = |counter : u64| totp::Code( &key, counter );
+    let validate = |db2 : &mut MockDb2, password : &str|
+        db2.ValidateAndFree( "lovelace", Some( password ), DB2SEC_VALIDATING_ON_SERVER_SIDE );
+
+    let mut db2 = MockDb2::Start( "hotp", "user_store = {dir}/users.json\naudit_file = {dir}/audit.log\nhotp_window = 3\n" );
+    assert_eq!( validate( &mut db2, &format!("engine{}", code( 0 )) ), DB2SEC_PLUGIN_OK );
+    assert_eq!( validate( &mut db2, &format!("engine{}", code( 0 )) ), DB2SEC_PLUGIN_BADPWD );
+    // Within the window, and the counters before it are used up.
+    assert_eq!( validate( &mut db2, &format!("engine{}", code( 3 )) ), DB2SEC_PLUGIN_OK );
+    assert_eq!( validate( &mut db2, &format!("engine{}", code( 2 )) ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( &mut db2, &format!("engine{}", code( 9 )) ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( validate( &mut db2, &format!("engine{}", codes[0]) ), DB2SEC_PLUGIN_OK );
+    assert!( db2.Logged( "RUSTSECP user lovelace connected with a recovery code, 1 left" ) );
+    assert_eq!( validate( &mut db2, &format!("engine{}", codes[0]) ), DB2SEC_PLUGIN_BADPWD );
+    // Case does not make it another code.
+    assert_eq!( validate( &mut db2, &format!("engine{}", codes[0].to_uppercase()) ), DB2SEC_PLUGIN_BADPWD );
+
+    assert_eq!( db2.AuditRules(), ["password-match", "otp-mismatch", "password-match", "otp-mismatch", "otp-mismatch",
+                        "password-match", "recovery-code-reused", "recovery-code-reused"] );
+
+    // What was used is in the .mfa file, where the next start reads it.
+    let state = mfa::MfaState::Load( &mfa::StatePath( &path ) ).unwrap();
+    let lovelace = store.Get( "lovelace" ).unwrap();
+    assert_eq!( state.NextHotp( lovelace ), 4 );
+    assert_eq!( state.RecoveryCodesLeft( lovelace ), 1 );
+    assert_eq!( mfa::ResyncHotp( lovelace, &state, &code( 40 ), &code( 41 ) ), Some( 42 ) );
+    assert_eq!( mfa::ResyncHotp( lovelace, &state, &code( 40 ), &code( 42 ) ), None );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn RefusedLoginsKeepTheirCodes() {
+    let secret = totp::NewSecret().unwrap();
+    let code = mfa::NewRecoveryCode().unwrap();
+    let mut store = UserStore::New();
+    let babbage = store.Add( "babbage" ).unwrap();
+    babbage.SetPassword( "difference" ).unwrap();
+    babbage.hotp = Some( secret.clone() );
+    babbage.recovery = vec![ mfa::RecoveryDigest( &code ) ];
+    babbage.expires = Some( String::from( "2001-01-01" ) );
+    // Recovery codes alone are no second factor.
+    let somerville = store.Add( "somerville" ).unwrap();
+    somerville.SetPassword( "tides" ).unwrap();
+    somerville.recovery = vec![ mfa::RecoveryDigest( &code ) ];
+    let path = ScratchDir( "mfa-refused" ).join( "users.json" );
+    store.Save( &path ).unwrap();
+
+    let key = totp::DecodeSecret( &secret ).unwrap();
+    let mut db2 = MockDb2::Start( "mfa-refused", "user_store = {dir}/users.json\naudit_file = {dir}/audit.log\n" );
+    let hotp = format!("difference{}", totp::Code( &key, 0 ));
+    assert_eq!( db2.ValidateAndFree( "babbage", Some( &hotp ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_UID_EXPIRED );
+    let recovery = format!("difference{}", code);
+    assert_eq!( db2.ValidateAndFree( "babbage", Some( &recovery ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_UID_EXPIRED );
+    assert_eq!( db2.ValidateAndFree( "somerville", Some( "tides" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_OK );
+    let recovery = format!("tides{}", code);
+    assert_eq!( db2.ValidateAndFree( "somerville", Some( &recovery ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( db2.AuditRules(), ["account-expired", "account-expired", "password-match", "password-mismatch"] );
+
+    let state = mfa::MfaState::Load( &mfa::StatePath( &path ) ).unwrap();
+    let babbage = store.Get( "babbage" ).unwrap();
+    assert_eq!( state.NextHotp( babbage ), 0 );
+    assert_eq!( state.RecoveryCodesLeft( babbage ), 1 );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn TotpCodeIsKeptWhenTheMfaFileCannotBeWritten() {
+    let secret = totp::NewSecret().unwrap();
+    let mut store = UserStore::New();
+    let lamarr = store.Add( "lamarr" ).unwrap();
+    lamarr.SetPassword( "hopping" ).unwrap();
+    lamarr.totp = Some( secret.clone() );
+    let path = ScratchDir( "totp-unsaved" ).join( "users.json" );
+    store.Save( &path ).unwrap();
+
+    let key = totp::DecodeSecret( &secret ).unwrap();
+    let password = format!("hopping{}", totp::Code( &key, totp::Step( std::time::SystemTime::now() ) ));
+    let mut db2 = MockDb2::Start( "totp-unsaved", "user_store = {dir}/users.json\naudit_file = {dir}/audit.log\n" );
+
+    // A directory where the new .mfa file would be written.
+    let mut tmp = mfa::StatePath( &path ).into_os_string();
+    tmp.push( format!(".tmp.{}", std::process::id()) );
+    std::fs::create_dir_all( std::path::Path::new( &tmp ).join( "in-the-way" ) ).unwrap();
+    assert_ne!( db2.ValidateAndFree( "lamarr", Some( &password ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_OK );
+
+    // The code was not used up by the login that failed.
+    std::fs::remove_dir_all( &tmp ).unwrap();
+    assert_eq!( db2.ValidateAndFree( "lamarr", Some( &password ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_OK );
+    assert_eq!( db2.ValidateAndFree( "lamarr", Some( &password ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( db2.AuditRules(), ["mfa-state-error", "password-match", "totp-replayed"] );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn ControlSocketAndRustsecpCtl() {
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+
+    let db2 = MockDb2::Start( "control", "control_socket = {dir}/rustsecp.sock\n" );
+    let socket = db2.Dir().join( "rustsecp.sock" );
+
+    // Only the instance owner, and no private directory left behind.
+    let mode = std::fs::metadata( &socket ).unwrap().permissions().mode();
+    assert_eq!( mode & 0o777, 0o600 );
+    let leftovers : Vec<_> = std::fs::read_dir( db2.Dir() ).unwrap()
+                                 .map( |e| e.unwrap().file_name().to_string_lossy().into_owned() )
+                                 .filter( |n| n.starts_with( ".rustsecp-control" ) )
+                                 .collect();
+    assert!( leftovers.is_empty(), "{:?}", leftovers );
+
+    let mut stream = UnixStream::connect( &socket ).unwrap();
+    stream.write_all( b"status\n" ).unwrap();
+    let mut reply = String::new();
+    stream.read_to_string( &mut reply ).unwrap();
+    assert!( reply.starts_with( "OK\n" ), "{}", reply );
+    assert!( reply.contains( &format!("pid {}\n", std::process::id()) ), "{}", reply );
+
+    let ctl = |args : &[&str]| {
+        let out = std::process::Command::new( env!("CARGO_BIN_EXE_rustsecp-ctl") )
+                      .arg( "--socket" ).arg( &socket ).args( args )
+                      .output().unwrap();
+        (out.status.code(), String::from_utf8_lossy( &out.stdout ).into_owned(),
+         String::from_utf8_lossy( &out.stderr ).into_owned())
+    };
+
+    let (code, out, _) = ctl( &["status"] );
+    assert_eq!( code, Some( 0 ) );
+    assert!( out.contains( "lockout_threshold " ), "{}", out );
+
+    let (code, _, err) = ctl( &["unlock", "nobody"] );
+    assert_eq!( code, Some( 1 ) );
+    assert_eq!( err.trim(), "nobody is not locked" );
+
+    let (code, _, err) = ctl( &["log-level", "loud"] );
+    assert_eq!( code, Some( 1 ) );
+    assert!( err.contains( "unknown log level loud" ), "{}", err );
+    // Nothing is cached, so there is nothing to flush.
+    for command in ["no-such-command", "flush-cache"] {
+        let (code, _, err) = ctl( &[command] );
+        assert_eq!( code, Some( 1 ) );
+        assert!( err.contains( "unknown command" ), "{}", err );
+    }
+
+    let (code, _, _) = ctl( &[] );
+    assert_eq!( code, Some( 2 ) );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+    assert!( ! socket.exists() );
+
+    // With the plugin gone there is nobody to answer.
+    let (code, _, err) = ctl( &["status"] );
+    assert_eq!( code, Some( 2 ) );
+    assert!( err.contains( "Cannot connect" ), "{}", err );
+}
+
+#[test]
+fn AuditChainShowsTampering() {
+    use db2rustsecp::auditchain::{LoadCheckpointKey, RotatedAuditFiles, VerifyAuditFiles};
+
+    let mut db2 = MockDb2::Start( "auditchain", "audit_file = {dir}/audit.log\naudit_key_file = {dir}/audit.key\n\
+                                                audit_checkpoint_records = 3\naudit_max_bytes = 2000\n\
+                                                audit_keep_files = 100\n" );
+    for _ in 0..12 {
+        assert_eq!( db2.ValidateAndFree( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_OK );
+    }
+
+    // Files next to the log that are not rotated files of it.
+    let log = db2.Dir().join( "audit.log" );
+    for name in ["audit.log.key", "audit.log.bak", "audit.log.tmp", "audit.log.20231105T101500123Z-x",
+                 "audit.log.20231105T101500123", "audit.log.2023110ST101500123Z"] {
+        std::fs::write( db2.Dir().join( name ), "" ).unwrap();
+    }
+    let mut files = RotatedAuditFiles( &log );
+    assert!( files.len() >= 2, "{:?}", files );
+    assert!( files.iter().all( |f| f.to_string_lossy().ends_with( 'Z' ) || f.to_string_lossy().contains( "Z-" ) ), "{:?}", files );
+    files.push( log.clone() );
+
+    let key = LoadCheckpointKey( &db2.Dir().join( "audit.key" ) ).unwrap();
+    let report = VerifyAuditFiles( &files, Some( &key ) );
+    assert!( report.problems.is_empty(), "{:?}", report.problems );
+    assert_eq!( report.firstSeq, Some( 1 ) );
+    assert!( report.checkpoints >= 4 && report.lastSealedSeq.is_some() );
+
+    // The oldest file pruned or deleted.
+    let report = VerifyAuditFiles( &files[1..], Some( &key ) );
+    assert_eq!( report.problems.len(), 1 );
+    assert!( report.problems[0].contains( "the records before it are missing" ), "{:?}", report.problems );
+
+    // The same lines tampered with in one file.
+    let lines : Vec<String> = files.iter()
+                                   .flat_map( |f| std::fs::read_to_string( f ).unwrap().lines().map( String::from ).collect::<Vec<_>>() )
+                                   .collect();
+    let tampered = db2.Dir().join( "tampered.log" );
+    let verify = |lines : &[String]| {
+        std::fs::write( &tampered, lines.join( "\n" ) + "\n" ).unwrap();
+        VerifyAuditFiles( std::slice::from_ref( &tampered ), Some( &key ) ).problems
+    };
+    assert!( verify( &lines ).is_empty() );
+
+    let mut deleted = lines.clone();
+    deleted.remove( 4 );
+    let problems = verify( &deleted );
+    assert!( problems.iter().any( |p| p.contains( "tampered.log:5: seq 6 follows 4" ) ), "{:?}", problems );
+
+    let mut reordered = lines.clone();
+    reordered.swap( 4, 5 );
+    let problems = verify( &reordered );
+    assert!( problems.iter().any( |p| p.contains( "tampered.log:5: seq 6 follows 4" ) ), "{:?}", problems );
+    assert!( problems.iter().any( |p| p.contains( "tampered.log:6: seq 5 follows 6" ) ), "{:?}", problems );
+
+    let mut edited = lines.clone();
+    let record = edited.iter().position( |l| l.contains( "newton" ) ).unwrap();
+    edited[record] = edited[record].replace( "newton", "hopper" );
+    let problems = verify( &edited );
+    assert_eq!( problems.len(), 1 );
+    assert!( problems[0].contains( &format!("tampered.log:{}: prev_hash does not match", record + 2) ), "{:?}", problems );
+
+    let mut forged = lines.clone();
+    let checkpoint = forged.iter().position( |l| l.contains( "\"checkpoint\"" ) ).unwrap();
+    let mac = serde_json::from_str::<serde_json::Value>( &forged[checkpoint] ).unwrap()["mac"].as_str().unwrap().to_string();
+    let forgedMac : String = mac.chars().rev().collect();
+    forged[checkpoint] = forged[checkpoint].replace( &mac, &forgedMac );
+    let problems = verify( &forged );
+    assert!( problems.iter().any( |p| p.contains( &format!("tampered.log:{}: checkpoint MAC is not valid", checkpoint + 1) ) ),
+             "{:?}", problems );
+
+    let problems = verify( &lines[3..] );
+    assert_eq!( problems.len(), 1 );
+    assert!( problems[0].contains( "tampered.log:1: the chain starts at seq 4" ), "{:?}", problems );
+
+    // Files rotated within the same millisecond sort by their number.
+    for name in ["other.log.20231105T101500123Z-10", "other.log.20231105T101500123Z-9",
+                 "other.log.20231105T101500123Z", "other.log.20231105T101500122Z-1"] {
+        std::fs::write( db2.Dir().join( name ), "" ).unwrap();
+    }
+    let names : Vec<String> = RotatedAuditFiles( &db2.Dir().join( "other.log" ) ).iter()
+                                  .map( |f| f.file_name().unwrap().to_string_lossy().into_owned() )
+                                  .collect();
+    assert_eq!( names, ["other.log.20231105T101500122Z-1", "other.log.20231105T101500123Z",
+                        "other.log.20231105T101500123Z-9", "other.log.20231105T101500123Z-10"] );
+
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+}
+
+#[test]
+fn SiemFeedOverUdpTcpAndUnix() {
+    use std::io::Read;
+    use std::net::{TcpListener, UdpSocket};
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    // Characters that CEF and LEEF must escape, in an unknown userid.
+    let userid = "eve=1|\\\t\n";
+    let checkHeader = |message : &str| {
+        // RFC 5424: PRI (authpriv, notice), version, timestamp, host, app,
+        // procid, msgid, no structured data.
+        assert!( message.starts_with( "<85>1 " ), "{}", message );
+        let fields : Vec<&str> = message.splitn( 8, ' ' ).collect();
+        assert!( fields[1].ends_with( 'Z' ) && fields[1].contains( 'T' ), "{}", message );
+        assert_eq!( fields[3..7], ["db2rustsecp", &std::process::id().to_string(), "ValidatePassword", "-"] );
+        fields[7].to_string()
+    };
+
+    // UDP, CEF.
+    let udp = UdpSocket::bind( "127.0.0.1:0" ).unwrap();
+    udp.set_read_timeout( Some( Duration::from_millis( 200 ) ) ).unwrap();
+    let mut db2 = MockDb2::Start( "siem-udp", &format!("siem_target = udp:{}\nsiem_format = cef\n", udp.local_addr().unwrap()) );
+    assert_eq!( db2.ValidatePassword( userid, Some( "x" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ).rc, DB2SEC_PLUGIN_BADUSER );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+
+    // Sent by the time Term returns.
+    let mut buf = [0u8; 8192];
+    let n = udp.recv( &mut buf ).unwrap();
+    let body = checkHeader( std::str::from_utf8( &buf[..n] ).unwrap() );
+    assert!( body.starts_with( &format!("CEF:0|db2rustsecp|Db2 security plugin|{}|unknown-user|Db2 authentication failed|5|",
+                                        env!("CARGO_PKG_VERSION")) ), "{}", body );
+    assert!( body.contains( " suser=eve\\=1|\\\\\t\\n " ), "{}", body );
+
+    // Unix datagram, LEEF.
+    let socketPath = ScratchDir( "siem-unix-listener" ).join( "log" );
+    let unix = UnixDatagram::bind( &socketPath ).unwrap();
+    unix.set_read_timeout( Some( Duration::from_millis( 200 ) ) ).unwrap();
+    let mut db2 = MockDb2::Start( "siem-unix", &format!("siem_target = unix:{}\nsiem_format = leef\n", socketPath.display()) );
+    assert_eq!( db2.ValidatePassword( userid, Some( "x" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ).rc, DB2SEC_PLUGIN_BADUSER );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+
+    let n = unix.recv( &mut buf ).unwrap();
+    let body = checkHeader( std::str::from_utf8( &buf[..n] ).unwrap() );
+    assert!( body.starts_with( &format!("LEEF:1.0|db2rustsecp|Db2 security plugin|{}|unknown-user|", env!("CARGO_PKG_VERSION")) ),
+             "{}", body );
+    assert!( body.contains( "\tusrName=eve=1|\\\\\\t\\n\t" ), "{}", body );
+    let _ = std::fs::remove_dir_all( socketPath.parent().unwrap() );
+
+    // TCP, JSON, octet counted.
+    let tcp = TcpListener::bind( "127.0.0.1:0" ).unwrap();
+    let mut db2 = MockDb2::Start( "siem-tcp", &format!("siem_target = tcp:{}\n", tcp.local_addr().unwrap()) );
+    assert_eq!( db2.ValidatePassword( userid, Some( "x" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ).rc, DB2SEC_PLUGIN_BADUSER );
+    assert_eq!( db2.ValidateAndFree( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_OK );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+
+    // The sender thread was joined at Term, so the stream ends after the
+    // two frames.
+    let (mut stream, _) = tcp.accept().unwrap();
+    stream.set_read_timeout( Some( Duration::from_secs( 5 ) ) ).unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end( &mut received ).unwrap();
+    let mut rest = received.as_slice();
+    let mut messages = Vec::new();
+    while ! rest.is_empty() {
+        let space = rest.iter().position( |b| *b == b' ' ).unwrap();
+        let len : usize = std::str::from_utf8( &rest[..space] ).unwrap().parse().unwrap();
+        messages.push( String::from_utf8( rest[space + 1..space + 1 + len].to_vec() ).unwrap() );
+        rest = &rest[space + 1 + len..];
+    }
+    assert_eq!( messages.len(), 2 );
+    let first : serde_json::Value = serde_json::from_str( &checkHeader( &messages[0] ) ).unwrap();
+    assert_eq!( first["userid"], userid );
+    assert_eq!( first["rule"], "unknown-user" );
+    assert!( first.get( "prev_hash" ).is_none() );
+    // authpriv, informational.
+    assert!( messages[1].starts_with( "<86>1 " ), "{}", messages[1] );
+}
+
+#[test]
+fn SimulateExplainsEachStep() {
+    use std::io::Write;
+
+    let dir = ScratchDir( "simulate" );
+    let mut store = UserStore::New();
+    let ida = store.Add( "ida" ).unwrap();
+    ida.SetPassword( "jazz" ).unwrap();
+    ida.groups = vec![ String::from( "MUSIC" ) ];
+    store.Save( &dir.join( "users.json" ) ).unwrap();
+    let config = dir.join( "rustsecp.cfg" );
+    std::fs::write( &config, format!("user_store = {}/users.json\nrate_limit_ip = 5/60\ndelay_base_ms = 100\n",
+                                     dir.display()) ).unwrap();
+
+    let simulate = |args : &[&str], password : &str| {
+        let mut child = std::process::Command::new( env!("CARGO_BIN_EXE_rustsecp-simulate") )
+                            .arg( "--config" ).arg( &config ).arg( "--not-locked-out" ).args( args )
+                            .stdin( std::process::Stdio::piped() )
+                            .stdout( std::process::Stdio::piped() )
+                            .spawn().unwrap();
+        child.stdin.take().unwrap().write_all( format!("{}\n", password).as_bytes() ).unwrap();
+        let out = child.wait_with_output().unwrap();
+        (out.status.code(), String::from_utf8_lossy( &out.stdout ).into_owned())
+    };
+
+    let (code, out) = simulate( &["--client-ip", "10.39.0.1", "--database", "TESTDB", "ida"], "jazz" );
+    assert_eq!( code, Some( 0 ), "{}", out );
+    assert!( out.contains( "ValidatePassword: userid ida, database TESTDB, client 10.39.0.1" ), "{}", out );
+    assert!( out.contains( "not evaluated: rate limits, delays, the running plugin keeps their counts" ), "{}", out );
+    assert!( out.contains( "GetAuthIDs: SystemAuthID IDA, InitialSessionAuthID IDA, username ida" ), "{}", out );
+    assert!( out.contains( "GetGroupsForUser: MUSIC" ), "{}", out );
+    assert!( out.contains( "result: DB2SEC_PLUGIN_OK (0)" ), "{}", out );
+
+    // The input checks of ValidatePassword.
+    let long = "d".repeat( 129 );
+    let (code, out) = simulate( &["--database", &long, "ida"], "jazz" );
+    assert_eq!( code, Some( 1 ) );
+    assert!( out.contains( "input: dbname has length 129, the maximum is 128" ), "{}", out );
+    assert!( out.contains( "result: DB2SEC_PLUGIN_UNKNOWNERROR (-1)" ), "{}", out );
+
+    let (code, out) = simulate( &["ida"], "blues" );
+    assert_eq!( code, Some( 1 ) );
+    assert!( out.contains( "result: DB2SEC_PLUGIN_BADPWD (-10)" ), "{}", out );
+    assert!( ! out.contains( "GetAuthIDs" ), "{}", out );
+
+    std::fs::remove_dir_all( &dir ).unwrap();
+}
+
+#[test]
+fn MetricsAreWrittenOnceMoreAtTerm() {
+    let file = std::env::temp_dir().join( format!("rustsecp-test-{}-final-metrics.prom", std::process::id()) );
+    let attempts = |file : &std::path::Path| -> u64 {
+        let metrics = std::fs::read_to_string( file ).unwrap();
+        metrics.lines()
+               .filter( |l| l.starts_with( "rustsecp_attempts_total{call=\"ValidatePassword\"" ) )
+               .map( |l| l.rsplit( ' ' ).next().unwrap().parse::<u64>().unwrap() )
+               .sum()
+    };
+
+    // Only the first and the last write, the interval never comes around.
+    let mut db2 = MockDb2::Start( "metrics-final", &format!("metrics_file = {}\nmetrics_interval_seconds = 3600\n",
+                                                            file.display()) );
+    let before = attempts( &file );
+    assert_eq!( db2.ValidateAndFree( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_OK );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+
+    // Term waited for the writer, which is gone now.
+    assert!( attempts( &file ) > before );
+    std::fs::remove_file( &file ).unwrap();
+    std::thread::sleep( std::time::Duration::from_millis( 200 ) );
+    assert!( ! file.exists() );
+}
+
+#[test]
+fn LockoutsAreCountedWithUniformFailures() {
+    DelayTestStore( "metrics-lockout", "lamarr44", "spread" );
+    let file = std::env::temp_dir().join( format!("rustsecp-test-{}-lockout-metrics.prom", std::process::id()) );
+    let lockouts = |file : &std::path::Path| -> u64 {
+        let metrics = std::fs::read_to_string( file ).unwrap();
+        metrics.lines().find_map( |l| l.strip_prefix( "rustsecp_lockouts_total " ) ).unwrap().parse().unwrap()
+    };
+
+    let mut db2 = MockDb2::Start( "metrics-lockout", &format!("user_store = {{dir}}/users.json\nuniform_failures = on\n\
+                                                              lockout_threshold = 2\nmetrics_file = {}\n\
+                                                              metrics_interval_seconds = 3600\n", file.display()) );
+    let before = lockouts( &file );
+    for _ in 0..2 {
+        assert_eq!( db2.ValidateAndFree( "lamarr44", Some( "guess" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_BADPWD );
+    }
+    // Answered like a wrong password, counted as a lockout.
+    assert_eq!( db2.ValidateAndFree( "lamarr44", Some( "spread" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_BADPWD );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+
+    assert_eq!( lockouts( &file ), before + 1 );
+    std::fs::remove_file( &file ).unwrap();
+}
+
+#[test]
+fn TornAuditRecordDoesNotStopThePlugin() {
+    use std::io::Write;
+    use db2rustsecp::auditchain::VerifyAuditFiles;
+
+    // The log outlives both plugin runs.
+    let logDir = ScratchDir( "tornlog" );
+    let log = logDir.join( "audit.log" );
+    let config = format!("audit_file = {}\n", log.display());
+
+    let mut db2 = MockDb2::Start( "torn", &config );
+    for _ in 0..2 {
+        assert_eq!( db2.ValidateAndFree( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_OK );
+    }
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+
+    // The engine went down in the middle of a write.
+    let half = "{\"seq\":3,\"prev_hash\":\"12ab";
+    std::fs::OpenOptions::new().append( true ).open( &log ).unwrap().write_all( half.as_bytes() ).unwrap();
+
+    let mut db2 = MockDb2::Start( "torn", &config );
+    assert!( db2.Logged( &format!("ends in {} bytes of a torn record", half.len()) ), "{:?}", db2.Logs() );
+    assert_eq!( db2.ValidateAndFree( "newton", Some( "newtonpw" ), DB2SEC_VALIDATING_ON_SERVER_SIDE ), DB2SEC_PLUGIN_OK );
+    assert_eq!( db2.Term(), DB2SEC_PLUGIN_OK );
+
+    // The chain goes on from the last whole record, past the torn one and
+    // the note of it.
+    let report = VerifyAuditFiles( std::slice::from_ref( &log ), None );
+    assert_eq!( (report.records, report.lastSeq), (3, Some( 4 )) );
+    assert_eq!( report.problems.len(), 2, "{:?}", report.problems );
+    assert!( report.problems[0].contains( "audit.log:3: not a JSON record" ), "{:?}", report.problems );
+    assert!( report.problems[1].contains( &format!("audit.log:4: the plugin found {} bytes of a torn record", half.len()) ),
+             "{:?}", report.problems );
+
+    std::fs::remove_dir_all( &logDir ).unwrap();
+}